@@ -0,0 +1,32 @@
+use std::fs;
+use std::process::Command;
+
+fn main() {
+    // The migrations are embedded with `sqlx::migrate!`, so a new one must trigger a rebuild.
+    println!("cargo:rerun-if-changed=migrations");
+
+    // The commit reported by `/version`: `TIMELY_GIT_COMMIT` if the build sets it (e.g. a
+    // container build without `.git`), otherwise asked of git.
+    println!("cargo:rerun-if-env-changed=TIMELY_GIT_COMMIT");
+    // A missing file counts as changed, so only files that exist are watched
+    if let Ok(head) = fs::read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(branch) = head.strip_prefix("ref: ") {
+            let branch = format!(".git/{}", branch.trim());
+            if fs::exists(&branch).unwrap_or(false) {
+                println!("cargo:rerun-if-changed={}", branch);
+            }
+        }
+    }
+    if std::env::var_os("TIMELY_GIT_COMMIT").is_none() {
+        let commit = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok());
+        if let Some(commit) = commit {
+            println!("cargo:rustc-env=TIMELY_GIT_COMMIT={}", commit.trim());
+        }
+    }
+}
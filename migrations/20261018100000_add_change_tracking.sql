@@ -0,0 +1,32 @@
+-- Add migration script here
+CREATE SEQUENCE IF NOT EXISTS todo_change_seq;
+
+ALTER TABLE todos
+ADD change_seq BIGINT NOT NULL DEFAULT nextval('todo_change_seq');
+
+-- Deleted todos are remembered so that syncing clients can drop them too
+CREATE TABLE IF NOT EXISTS todo_tombstones
+(
+    id         BIGINT PRIMARY KEY,
+    change_seq BIGINT NOT NULL DEFAULT nextval('todo_change_seq')
+);
+
+CREATE OR REPLACE FUNCTION todos_track_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO todo_tombstones (id) VALUES (OLD.id)
+        ON CONFLICT (id) DO UPDATE SET change_seq = nextval('todo_change_seq');
+        RETURN OLD;
+    END IF;
+    NEW.change_seq := nextval('todo_change_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todos_track_upsert
+BEFORE INSERT OR UPDATE ON todos
+FOR EACH ROW EXECUTE FUNCTION todos_track_change();
+
+CREATE TRIGGER todos_track_delete
+AFTER DELETE ON todos
+FOR EACH ROW EXECUTE FUNCTION todos_track_change();
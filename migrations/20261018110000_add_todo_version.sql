@@ -0,0 +1,19 @@
+-- Add migration script here
+ALTER TABLE todos
+ADD version BIGINT NOT NULL DEFAULT 1;
+
+-- Every update of a row, including ones cascaded to children, bumps its version
+CREATE OR REPLACE FUNCTION todos_track_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO todo_tombstones (id) VALUES (OLD.id)
+        ON CONFLICT (id) DO UPDATE SET change_seq = nextval('todo_change_seq');
+        RETURN OLD;
+    END IF;
+    IF TG_OP = 'UPDATE' THEN
+        NEW.version := OLD.version + 1;
+    END IF;
+    NEW.change_seq := nextval('todo_change_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
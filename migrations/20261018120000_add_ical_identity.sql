@@ -0,0 +1,4 @@
+-- UID and resource name of todos created by CalDAV clients; todos created elsewhere
+-- are served as todo-<id>@timely at <id>.ics
+ALTER TABLE todos ADD ical_uid TEXT UNIQUE;
+ALTER TABLE todos ADD ical_href TEXT UNIQUE;
@@ -0,0 +1,3 @@
+-- priority: -1 low, 0 normal, 1 high
+ALTER TABLE todos ADD priority SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE todos ADD tags TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,40 @@
+-- Completed top-level todos are archived together with their descendants. Archived todos
+-- are left out of the todo list and sync, and kept for the archive browser.
+ALTER TABLE todos ADD archived BOOLEAN NOT NULL DEFAULT false;
+
+-- When the todo was last marked as done, to archive the ones completed a while ago
+ALTER TABLE todos ADD completed_at TIMESTAMPTZ;
+
+-- The completion time of todos done before now is unknown; count it from now on, without
+-- bumping their versions
+ALTER TABLE todos DISABLE TRIGGER todos_track_upsert;
+UPDATE todos SET completed_at = now() WHERE done;
+ALTER TABLE todos ENABLE TRIGGER todos_track_upsert;
+
+CREATE OR REPLACE FUNCTION todos_track_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO todo_tombstones (id) VALUES (OLD.id)
+        ON CONFLICT (id) DO UPDATE SET change_seq = nextval('todo_change_seq');
+        RETURN OLD;
+    END IF;
+    IF TG_OP = 'UPDATE' THEN
+        NEW.version := OLD.version + 1;
+        IF NEW.done AND NOT OLD.done THEN
+            NEW.completed_at := now();
+        END IF;
+    ELSE
+        IF NEW.done THEN
+            NEW.completed_at := now();
+        END IF;
+        -- Todos created under an archived todo are archived with it
+        NEW.archived := NEW.archived
+            OR COALESCE((SELECT archived FROM todos WHERE id = NEW.parent_id), false);
+    END IF;
+    IF NOT NEW.done THEN
+        NEW.completed_at := NULL;
+    END IF;
+    NEW.change_seq := nextval('todo_change_seq');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
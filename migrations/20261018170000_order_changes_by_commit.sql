@@ -0,0 +1,42 @@
+-- Change tokens must follow commit order: a sync hands out the highest change_seq it can
+-- see, so a change that commits later with a lower change_seq would never reach that
+-- client. Writers take this lock before drawing from todo_change_seq and hold it until
+-- they commit or roll back, so whatever is still in flight is numbered above every token
+-- already handed out.
+CREATE OR REPLACE FUNCTION next_change_seq() RETURNS BIGINT AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('todo_change_seq'));
+    RETURN nextval('todo_change_seq');
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE todos ALTER change_seq SET DEFAULT next_change_seq();
+ALTER TABLE todo_tombstones ALTER change_seq SET DEFAULT next_change_seq();
+
+CREATE OR REPLACE FUNCTION todos_track_change() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        INSERT INTO todo_tombstones (id) VALUES (OLD.id)
+        ON CONFLICT (id) DO UPDATE SET change_seq = next_change_seq();
+        RETURN OLD;
+    END IF;
+    IF TG_OP = 'UPDATE' THEN
+        NEW.version := OLD.version + 1;
+        IF NEW.done AND NOT OLD.done THEN
+            NEW.completed_at := now();
+        END IF;
+    ELSE
+        IF NEW.done THEN
+            NEW.completed_at := now();
+        END IF;
+        -- Todos created under an archived todo are archived with it
+        NEW.archived := NEW.archived
+            OR COALESCE((SELECT archived FROM todos WHERE id = NEW.parent_id), false);
+    END IF;
+    IF NOT NEW.done THEN
+        NEW.completed_at := NULL;
+    END IF;
+    NEW.change_seq := next_change_seq();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,6 @@
+-- Random secrets the server generates on first use, such as the key of the calendar token.
+CREATE TABLE IF NOT EXISTS server_secrets
+(
+    name  TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
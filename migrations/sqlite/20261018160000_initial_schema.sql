@@ -0,0 +1,73 @@
+-- The schema of a SQLite database, matching the one the Postgres migrations build up.
+-- What the Postgres triggers keep up to date (versions, change tokens, tombstones,
+-- completion times and archiving of new children) is set by the queries in
+-- src/storage/sqlite.rs instead.
+CREATE TABLE IF NOT EXISTS todos
+(
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    name         TEXT    NOT NULL,
+    done         BOOLEAN NOT NULL DEFAULT FALSE,
+    description  TEXT,
+    parent_id    INTEGER REFERENCES todos (id),
+    date         TEXT,
+    change_seq   INTEGER NOT NULL,
+    version      INTEGER NOT NULL DEFAULT 1,
+    -- UID and resource name of todos created by CalDAV clients
+    ical_uid     TEXT UNIQUE,
+    ical_href    TEXT UNIQUE,
+    -- -1 low, 0 normal, 1 high
+    priority     INTEGER NOT NULL DEFAULT 0,
+    -- a JSON array of strings
+    tags         TEXT    NOT NULL DEFAULT '[]',
+    archived     BOOLEAN NOT NULL DEFAULT FALSE,
+    completed_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS todos_by_parent ON todos (parent_id);
+CREATE INDEX IF NOT EXISTS todos_by_change ON todos (change_seq);
+
+-- Deleted todos are remembered so that syncing clients can drop them too
+CREATE TABLE IF NOT EXISTS todo_tombstones
+(
+    id         INTEGER PRIMARY KEY,
+    change_seq INTEGER NOT NULL
+);
+
+-- The last change token handed out, in a single row
+CREATE TABLE IF NOT EXISTS todo_change_seq
+(
+    value INTEGER NOT NULL
+);
+INSERT INTO todo_change_seq (value) VALUES (0);
+
+CREATE TABLE IF NOT EXISTS webhooks
+(
+    id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    url        TEXT NOT NULL,
+    secret     TEXT NOT NULL,
+    -- a JSON array of the events to send; empty means all of them
+    events     TEXT NOT NULL DEFAULT '[]',
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Both the queue of payloads still to be sent and the log of past deliveries.
+CREATE TABLE IF NOT EXISTS webhook_deliveries
+(
+    id               INTEGER PRIMARY KEY AUTOINCREMENT,
+    webhook_id       INTEGER NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event            TEXT    NOT NULL,
+    payload          TEXT    NOT NULL,
+    status           TEXT    NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'delivered', 'failed')),
+    attempts         INTEGER NOT NULL DEFAULT 0,
+    last_status_code INTEGER,
+    last_error       TEXT,
+    created_at       TEXT    NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    next_attempt_at  TEXT    NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    delivered_at     TEXT
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_pending
+    ON webhook_deliveries (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS webhook_deliveries_by_webhook
+    ON webhook_deliveries (webhook_id, id);
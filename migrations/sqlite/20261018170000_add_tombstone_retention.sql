@@ -0,0 +1,12 @@
+-- Tombstones are pruned once they are older than TOMBSTONE_RETENTION_DAYS. Clients whose
+-- token is older than the newest pruned tombstone may have missed deletions, so they get
+-- the whole list instead of changes.
+ALTER TABLE todo_tombstones ADD deleted_at TEXT;
+UPDATE todo_tombstones SET deleted_at = datetime('now');
+
+-- The newest change token of the pruned tombstones, in a single row
+CREATE TABLE IF NOT EXISTS sync_horizon
+(
+    token INTEGER NOT NULL
+);
+INSERT INTO sync_horizon (token) VALUES (0);
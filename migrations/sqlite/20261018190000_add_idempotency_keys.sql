@@ -0,0 +1,9 @@
+-- Keys clients send with a create so that retrying it never makes a second todo. The id
+-- is kept after the todo is deleted, so a late retry is refused instead of recreating it.
+-- Keys are pruned with the tombstones.
+CREATE TABLE IF NOT EXISTS idempotency_keys
+(
+    key        TEXT PRIMARY KEY,
+    todo_id    INTEGER NOT NULL,
+    created_at TEXT    NOT NULL DEFAULT (datetime('now'))
+);
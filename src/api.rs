@@ -0,0 +1,459 @@
+//! Version 1 of the JSON API, nested under `/api/v1`. Todos are resources at
+//! `/todos/{id}`, read with GET, changed with PATCH and deleted with DELETE.
+//!
+//! The routes at the root (`DELETE /todos` with the id as the body, `POST /todos/toggle`,
+//! `POST /todos/done`, ...) predate it and are kept as deprecated aliases.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use serde::{Deserialize, Deserializer};
+use timely_lib::{Priority, Todo, TodoHierarchy, TreePage};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::archive::{self, ArchiveApi};
+use crate::batch::{self, BatchApi};
+use crate::storage::{TodoChanges, TodoFilter};
+use crate::webhooks::{self, WebhooksApi};
+use crate::{
+    authenticate, check_page_size, delete_subtree, etag, expected_version, extract_provided,
+    get_subtrees_inner, get_tree_page, idempotency_key, insert_todo, parse_payload_date, AppState,
+    ArchivedQuery, CreateTodo, DateQuery, PasswordQuery, TreeQuery, TREE_PAGE_SIZE,
+};
+
+/// The endpoints of `/api/v1`, with paths relative to it.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        crate::get_todos,
+        get_tree,
+        create_todo,
+        get_todo,
+        get_ancestors,
+        get_subtree,
+        update_todo,
+        delete_todo,
+        create_child,
+        crate::quick_add_todo,
+        crate::export_todos,
+        crate::import_todos,
+        crate::sync_todos,
+    ),
+    components(schemas(UpdateTodo, TodoHierarchy, TreePage))
+)]
+struct ApiV1;
+
+pub fn document() -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiV1::openapi();
+    openapi.merge(ArchiveApi::openapi());
+    openapi.merge(BatchApi::openapi());
+    openapi.merge(WebhooksApi::openapi());
+    openapi
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/todos", get(crate::get_todos).post(create_todo))
+        .route("/todos/tree", get(get_tree))
+        .route(
+            "/todos/{id}",
+            get(get_todo).patch(update_todo).delete(delete_todo),
+        )
+        .route("/todos/{id}/ancestors", get(get_ancestors))
+        .route("/todos/{id}/subtree", get(get_subtree))
+        .route("/todos/{id}/children", post(create_child))
+        .route("/todos/quick", post(crate::quick_add_todo))
+        .route("/todos/export", get(crate::export_todos))
+        .route("/todos/import", post(crate::import_todos))
+        .route("/sync", get(crate::sync_todos))
+        .merge(archive::router())
+        .merge(batch::router())
+        .merge(webhooks::router())
+}
+
+/// Body of `PATCH /api/v1/todos/{id}`. Fields left out are not changed; `null` clears the
+/// description or date, or moves the todo to the top level.
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateTodo {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>)]
+    description: Option<Option<String>>,
+    /// `YYYY-MM-DD`
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<String>, format = Date)]
+    date: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    #[schema(value_type = Option<i64>)]
+    parent_id: Option<Option<i64>>,
+    /// Applied to the descendants too
+    done: Option<bool>,
+    priority: Option<Priority>,
+    tags: Option<Vec<String>>,
+}
+
+impl UpdateTodo {
+    /// The changes to make, once the name and date are checked.
+    pub fn changes(self) -> Result<TodoChanges, (StatusCode, String)> {
+        if self
+            .name
+            .as_deref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The todo needs a name".to_owned(),
+            ));
+        }
+        let date = match &self.date {
+            Some(date) => Some(parse_payload_date(date.as_deref())?),
+            None => None,
+        };
+        Ok(TodoChanges {
+            name: self.name,
+            description: self.description,
+            date,
+            parent_id: self.parent_id,
+            done: self.done,
+            priority: self.priority.map(Priority::level),
+            tags: self.tags,
+        })
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SubtreeQuery {
+    /// Leave out descendants deeper than this below the todo; 0 returns only the todo
+    #[param(minimum = 0)]
+    max_depth: Option<i32>,
+}
+
+/// Helper for the tree endpoints: rejects a negative `max_depth`.
+pub fn check_max_depth(max_depth: Option<i32>) -> Result<(), (StatusCode, String)> {
+    if max_depth.is_some_and(|depth| depth < 0) {
+        Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "max_depth must not be negative".to_owned(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Tells a field set to `null` (`Some(None)`) apart from a missing one (`None`).
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+type TodoResponse = ([(HeaderName, String); 1], Json<Todo>);
+
+/// API: Create a new todo.
+#[utoipa::path(
+    post,
+    path = "/todos",
+    tag = "todos",
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Retrying with the same key returns the todo created first"),
+    ),
+    request_body = CreateTodo,
+    responses(
+        (status = 201, description = "The created todo", body = Todo,
+            headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 400, description = "Invalid `Idempotency-Key` header"),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 409, description = "The todo created with this `Idempotency-Key` was deleted"),
+        (status = 422, description = "Blank name, invalid date or unknown parent"),
+    )
+)]
+async fn create_todo(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateTodo>,
+) -> Result<(StatusCode, TodoResponse), (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let key = idempotency_key(&headers)?;
+        let todo = insert_todo(&state, key.as_deref(), payload).await?;
+        Ok((StatusCode::CREATED, (etag(todo.version), Json(todo))))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Get a single todo.
+#[utoipa::path(
+    get,
+    path = "/todos/{id}",
+    tag = "todos",
+    params(("id" = i64, Path, description = "Id of the todo")),
+    responses(
+        (status = 200, description = "The todo", body = Todo,
+            headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+    )
+)]
+async fn get_todo(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<TodoResponse, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let todo = state
+            .storage
+            .get_todo(id)
+            .await?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Todo {} not found", id)))?;
+        Ok((etag(todo.version), Json(todo)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: The ancestors of a todo, from the top-level todo down to its parent, for showing
+/// a breadcrumb path.
+#[utoipa::path(
+    get,
+    path = "/todos/{id}/ancestors",
+    tag = "todos",
+    params(("id" = i64, Path, description = "Id of the todo")),
+    responses(
+        (status = 200, description = "The ancestors, top-level todo first; empty for a top-level todo", body = Vec<Todo>),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+    )
+)]
+async fn get_ancestors(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Todo>>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let ancestors = state
+            .storage
+            .ancestors(id)
+            .await?
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Todo {} not found", id)))?;
+        Ok(Json(ancestors))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: A page of top-level todos with their descendants nested inside, optionally only
+/// down to `max_depth` levels. Todos whose children were cut off have a `child_count`
+/// larger than the number of `children`, to load them with `/todos/{id}/subtree`.
+#[utoipa::path(
+    get,
+    path = "/todos/tree",
+    tag = "todos",
+    params(DateQuery, TreeQuery, ArchivedQuery),
+    responses(
+        (status = 200, description = "A page of the todo tree", body = TreePage),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 422, description = "`limit` out of range or negative `max_depth`"),
+    )
+)]
+async fn get_tree(
+    Query(query): Query<PasswordQuery>,
+    Query(date_query): Query<DateQuery>,
+    Query(tree_query): Query<TreeQuery>,
+    Query(archived_query): Query<ArchivedQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+) -> Result<Json<TreePage>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let limit = check_page_size(tree_query.limit.unwrap_or(TREE_PAGE_SIZE))?;
+        check_max_depth(tree_query.max_depth)?;
+        let (todos, next_cursor) = get_tree_page(
+            &*state.storage,
+            date_query.filter(archived_query.filter()),
+            tree_query.after,
+            limit,
+            tree_query.max_depth,
+        )
+        .await?;
+        Ok(Json(TreePage { todos, next_cursor }))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: A todo with its descendants nested inside, optionally only down to `max_depth`.
+#[utoipa::path(
+    get,
+    path = "/todos/{id}/subtree",
+    tag = "todos",
+    params(("id" = i64, Path, description = "Id of the todo"), SubtreeQuery),
+    responses(
+        (status = 200, description = "The todo and its descendants", body = TodoHierarchy),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 422, description = "Negative `max_depth`"),
+    )
+)]
+async fn get_subtree(
+    Query(query): Query<PasswordQuery>,
+    Query(subtree_query): Query<SubtreeQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<TodoHierarchy>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        check_max_depth(subtree_query.max_depth)?;
+        get_subtrees_inner(
+            &*state.storage,
+            &[id],
+            subtree_query.max_depth,
+            TodoFilter::default(),
+        )
+        .await?
+        .pop()
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Todo {} not found", id)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Change some fields of a todo. Setting `done` sets its descendants too, like
+/// `POST /todos/done`.
+#[utoipa::path(
+    patch,
+    path = "/todos/{id}",
+    tag = "todos",
+    params(
+        ("id" = i64, Path, description = "Id of the todo"),
+        ("If-Match" = Option<String>, Header, description = "Only update the todo at this version"),
+    ),
+    request_body = UpdateTodo,
+    responses(
+        (status = 200, description = "The updated todo", body = Todo,
+            headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 409, description = "The new parent is the todo itself or one of its descendants, or one of them is archived"),
+        (status = 412, description = "The todo was changed since the `If-Match` version"),
+        (status = 422, description = "Empty name, invalid date or unknown parent"),
+    )
+)]
+async fn update_todo(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(payload): Json<UpdateTodo>,
+) -> Result<TodoResponse, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if !provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        return Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()));
+    }
+    let version = expected_version(&headers)?;
+    let changes = payload.changes()?;
+    let updated = state.storage.update_todo(id, version, &changes).await?;
+
+    webhooks::enqueue(
+        &*state.storage,
+        &state.webhook_notify,
+        changes.event(),
+        &updated,
+    )
+    .await;
+    Ok((etag(updated.version), Json(updated)))
+}
+
+/// API: Delete a todo and its descendants.
+#[utoipa::path(
+    delete,
+    path = "/todos/{id}",
+    tag = "todos",
+    params(
+        ("id" = i64, Path, description = "Id of the todo"),
+        ("If-Match" = Option<String>, Header, description = "Only delete the todo at this version"),
+    ),
+    responses(
+        (status = 204, description = "The todo was deleted"),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo was changed since the `If-Match` version"),
+    )
+)]
+async fn delete_todo(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let version = expected_version(&headers)?;
+        delete_subtree(&state, id, version).await?;
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Create a todo under another one. A `parent_id` in the body is ignored.
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/children",
+    tag = "todos",
+    params(
+        ("id" = i64, Path, description = "Id of the parent todo"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Retrying with the same key returns the todo created first"),
+    ),
+    request_body = CreateTodo,
+    responses(
+        (status = 201, description = "The created todo", body = Todo,
+            headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 400, description = "Invalid `Idempotency-Key` header"),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such parent todo"),
+        (status = 409, description = "The todo created with this `Idempotency-Key` was deleted"),
+        (status = 422, description = "Blank name or invalid date"),
+    )
+)]
+async fn create_child(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(parent_id): Path<i64>,
+    headers: HeaderMap,
+    Json(mut payload): Json<CreateTodo>,
+) -> Result<(StatusCode, TodoResponse), (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        if !state.storage.todo_exists(parent_id).await? {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Todo {} not found", parent_id),
+            ));
+        }
+        let key = idempotency_key(&headers)?;
+        payload.parent_id = Some(parent_id);
+        let todo = insert_todo(&state, key.as_deref(), payload).await?;
+        Ok((StatusCode::CREATED, (etag(todo.version), Json(todo))))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
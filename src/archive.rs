@@ -0,0 +1,175 @@
+//! Archiving of completed todos. A completed top-level todo is archived together with its
+//! descendants, on request or on a schedule (`ARCHIVE_AFTER_DAYS`), once every todo in
+//! its tree is done. Archived todos are left out of the todo list, the tree and sync, and
+//! can be browsed and unarchived. Webhooks are not notified of archiving.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderName, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use timely_lib::{Todo, TreePage};
+use tokio_util::sync::CancellationToken;
+use utoipa::{IntoParams, OpenApi};
+
+use crate::api::check_max_depth;
+use crate::storage::{Storage, TodoFilter};
+use crate::{
+    authenticate, check_page_size, etag, extract_provided, get_tree_page, AppState, PasswordQuery,
+    TreeQuery, TREE_PAGE_SIZE,
+};
+
+/// How often the scheduled archiving runs.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(OpenApi)]
+#[openapi(paths(get_archive, archive_completed, unarchive_todo))]
+pub struct ArchiveApi;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/archive", get(get_archive).post(archive_completed))
+        .route("/todos/{id}/unarchive", post(unarchive_todo))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ArchiveQuery {
+    /// Archive the top-level todos completed at least this many days ago
+    #[param(minimum = 0)]
+    older_than_days: i32,
+}
+
+/// API: A page of archived top-level todos with their descendants.
+#[utoipa::path(
+    get,
+    path = "/archive",
+    tag = "archive",
+    params(TreeQuery),
+    responses(
+        (status = 200, description = "A page of the archived trees", body = TreePage),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 422, description = "`limit` out of range or negative `max_depth`"),
+    )
+)]
+async fn get_archive(
+    Query(query): Query<PasswordQuery>,
+    Query(tree_query): Query<TreeQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+) -> Result<Json<TreePage>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let limit = check_page_size(tree_query.limit.unwrap_or(TREE_PAGE_SIZE))?;
+        check_max_depth(tree_query.max_depth)?;
+        let filter = TodoFilter {
+            archived: Some(true),
+            ..Default::default()
+        };
+        let (todos, next_cursor) = get_tree_page(
+            &*state.storage,
+            filter,
+            tree_query.after,
+            limit,
+            tree_query.max_depth,
+        )
+        .await?;
+        Ok(Json(TreePage { todos, next_cursor }))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Archive the top-level todos completed at least `older_than_days` days ago, with
+/// their descendants. Trees with a todo that is not done are left alone.
+#[utoipa::path(
+    post,
+    path = "/archive",
+    tag = "archive",
+    params(ArchiveQuery),
+    responses(
+        (status = 200, description = "The top-level todos that were archived", body = Vec<Todo>),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 422, description = "Negative `older_than_days`"),
+    )
+)]
+async fn archive_completed(
+    Query(query): Query<PasswordQuery>,
+    Query(archive_query): Query<ArchiveQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Todo>>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        if archive_query.older_than_days < 0 {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "older_than_days must not be negative".to_owned(),
+            ));
+        }
+        state
+            .storage
+            .archive_completed(archive_query.older_than_days)
+            .await
+            .map(Json)
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Bring an archived top-level todo and its descendants back to the todo list.
+#[utoipa::path(
+    post,
+    path = "/todos/{id}/unarchive",
+    tag = "archive",
+    params(("id" = i64, Path, description = "Id of the archived top-level todo")),
+    responses(
+        (status = 200, description = "The unarchived todo", body = Todo,
+            headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 409, description = "The todo is not an archived top-level todo"),
+    )
+)]
+async fn unarchive_todo(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<([(HeaderName, String); 1], Json<Todo>), (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let todo = state.storage.unarchive(id).await?;
+        Ok((etag(todo.version), Json(todo)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// Archives completed trees every hour when `ARCHIVE_AFTER_DAYS` is set, until `stop` is
+/// cancelled. A run in progress is finished first.
+pub async fn run_scheduled(
+    storage: Arc<dyn Storage>,
+    older_than_days: i32,
+    stop: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.cancelled() => return,
+        }
+        match storage.archive_completed(older_than_days).await {
+            Ok(archived) if !archived.is_empty() => {
+                tracing::info!(todos = archived.len(), "Archived completed todos")
+            }
+            Ok(_) => {}
+            Err((_, err)) => tracing::error!(error = %err, "Archiving completed todos failed"),
+        }
+    }
+}
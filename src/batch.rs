@@ -0,0 +1,239 @@
+//! `POST /api/v1/batch`: several changes to todos in one request. The operations run in
+//! order in a single transaction, so either all of them are applied or none is, and the
+//! webhooks only hear about them once the transaction is committed.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::post,
+    Json, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use serde::{Deserialize, Serialize};
+use timely_lib::Todo;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::api::UpdateTodo;
+use crate::storage::{Change, Changed, TodoChanges};
+use crate::webhooks::{self, Event};
+use crate::{authenticate, extract_provided, AppState, CreateTodo, PasswordQuery};
+
+/// Most operations a batch may hold.
+const MAX_OPERATIONS: usize = 500;
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(run_batch),
+    components(schemas(BatchRequest, Operation, BatchResponse, OperationResult, BatchError))
+)]
+pub struct BatchApi;
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/batch", post(run_batch))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BatchRequest {
+    operations: Vec<Operation>,
+}
+
+/// A change to make. `version`, where taken, works like the `If-Match` header of the
+/// single-todo routes: the operation fails if the todo is at another version.
+#[derive(Deserialize, ToSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Operation {
+    /// Create a todo, like `POST /todos`
+    Create(CreateTodo),
+    /// Change some fields of a todo, like `PATCH /todos/{id}`
+    Update {
+        id: i64,
+        version: Option<i64>,
+        changes: UpdateTodo,
+    },
+    /// Set a todo and its descendants as done or not done
+    SetDone {
+        id: i64,
+        done: bool,
+        version: Option<i64>,
+    },
+    /// Flip the done state of a todo and set its descendants to match
+    Toggle { id: i64, version: Option<i64> },
+    /// Move a todo under another one, or to the top level when `parent_id` is null
+    Move {
+        id: i64,
+        parent_id: Option<i64>,
+        version: Option<i64>,
+    },
+    /// Delete a todo and its descendants
+    Delete { id: i64, version: Option<i64> },
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatchResponse {
+    /// One result per operation, in the same order
+    results: Vec<OperationResult>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct OperationResult {
+    /// Status the operation would have had on its own route
+    status: u16,
+    /// The created or changed todo
+    #[serde(skip_serializing_if = "Option::is_none")]
+    todo: Option<Todo>,
+    /// Ids of the deleted todo and its descendants
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted: Option<Vec<i64>>,
+}
+
+/// Why a batch was rejected. None of its operations were applied.
+#[derive(Serialize, ToSchema)]
+struct BatchError {
+    /// Index of the operation that failed; missing when the batch as a whole was refused
+    index: Option<usize>,
+    error: String,
+}
+
+/// An error of the batch endpoint: the status of the failed operation with its index.
+type BatchFailure = (StatusCode, Json<BatchError>);
+
+fn reject(index: Option<usize>, (status, error): (StatusCode, String)) -> BatchFailure {
+    (status, Json(BatchError { index, error }))
+}
+
+/// API: Create, change, move and delete todos in one request, atomically. Operations see
+/// the changes of the ones before them. If one fails the whole batch is rolled back and
+/// the response has the failed operation's status.
+#[utoipa::path(
+    post,
+    path = "/batch",
+    tag = "todos",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "Every operation was applied", body = BatchResponse),
+        (status = 401, description = "Missing or wrong password", body = BatchError),
+        (status = 404, description = "An operation's todo does not exist", body = BatchError),
+        (status = 409, description = "A move would nest a todo inside itself, or involves an archived one", body = BatchError),
+        (status = 412, description = "An operation's todo is at another `version`", body = BatchError),
+        (status = 422, description = "Too many operations, or an invalid one", body = BatchError),
+    )
+)]
+async fn run_batch(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Json(payload): Json<BatchRequest>,
+) -> Result<Json<BatchResponse>, BatchFailure> {
+    let provided = extract_provided(&query, &cookies);
+    if !provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        return Err(reject(
+            None,
+            (StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()),
+        ));
+    }
+    if payload.operations.len() > MAX_OPERATIONS {
+        return Err(reject(
+            None,
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("A batch can hold at most {} operations", MAX_OPERATIONS),
+            ),
+        ));
+    }
+
+    let mut changes = Vec::with_capacity(payload.operations.len());
+    let mut outcomes = Vec::with_capacity(payload.operations.len());
+    for (index, operation) in payload.operations.into_iter().enumerate() {
+        let (change, outcome) = change_for(operation).map_err(|err| reject(Some(index), err))?;
+        changes.push(change);
+        outcomes.push(outcome);
+    }
+    let changed = state
+        .storage
+        .apply_changes(changes)
+        .await
+        .map_err(|(index, err)| reject(index, err))?;
+
+    let mut results = Vec::with_capacity(changed.len());
+    for (changed, (status, event)) in changed.into_iter().zip(outcomes) {
+        let (result, todo) = match changed {
+            Changed::Todo(todo) => {
+                let result = OperationResult {
+                    status: status.as_u16(),
+                    todo: Some(todo.clone()),
+                    deleted: None,
+                };
+                (result, todo)
+            }
+            Changed::Deleted(todo, ids) => {
+                let result = OperationResult {
+                    status: status.as_u16(),
+                    todo: None,
+                    deleted: Some(ids),
+                };
+                (result, todo)
+            }
+        };
+        // A toggle's event depends on where it left the todo
+        let event = event.unwrap_or(Event::for_done(todo.done));
+        webhooks::enqueue(&*state.storage, &state.webhook_notify, event, &todo).await;
+        results.push(result);
+    }
+    Ok(Json(BatchResponse { results }))
+}
+
+/// The status an operation reports and its webhook event, if known before it runs.
+type Outcome = (StatusCode, Option<Event>);
+
+/// The change an operation of a batch makes, with its outcome.
+fn change_for(operation: Operation) -> Result<(Change, Outcome), (StatusCode, String)> {
+    Ok(match operation {
+        Operation::Create(payload) => (
+            Change::Create(payload.into_new_todo()?),
+            (StatusCode::CREATED, Some(Event::Created)),
+        ),
+        Operation::Update {
+            id,
+            version,
+            changes,
+        } => {
+            let changes = changes.changes()?;
+            let event = changes.event();
+            (
+                Change::Update {
+                    id,
+                    version,
+                    changes,
+                },
+                (StatusCode::OK, Some(event)),
+            )
+        }
+        Operation::SetDone { id, done, version } => (
+            Change::Update {
+                id,
+                version,
+                changes: TodoChanges::done(done),
+            },
+            (StatusCode::OK, Some(Event::for_done(done))),
+        ),
+        Operation::Toggle { id, version } => {
+            (Change::Toggle { id, version }, (StatusCode::OK, None))
+        }
+        Operation::Move {
+            id,
+            parent_id,
+            version,
+        } => (
+            Change::Update {
+                id,
+                version,
+                changes: TodoChanges::parent(parent_id),
+            },
+            (StatusCode::OK, Some(Event::Updated)),
+        ),
+        Operation::Delete { id, version } => (
+            Change::Delete { id, version },
+            (StatusCode::NO_CONTENT, Some(Event::Deleted)),
+        ),
+    })
+}
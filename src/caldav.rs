@@ -0,0 +1,831 @@
+//! A subset of CalDAV, so task clients such as Thunderbird can edit todos.
+//! Timely has a single todo list, which is served as one calendar holding a VTODO
+//! resource per todo. Clients authenticate with HTTP Basic auth and the usual password
+//! (any user name is accepted).
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    routing::any,
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::collections::HashMap;
+use std::fmt::Write;
+use time::{Date, OffsetDateTime};
+use timely_lib::{
+    ical::{begin_calendar, end_calendar, parse_vtodo, todo_uid, write_vtodo},
+    Todo,
+};
+
+use crate::storage::NewTodo;
+use crate::webhooks::{self, Event};
+use crate::{authenticate, expected_version, AppState, BasePath};
+
+const DAV_NS: &str = "DAV:";
+const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
+const CALENDARSERVER_NS: &str = "http://calendarserver.org/ns/";
+
+const CALENDAR_CONTENT_TYPE: &str = "text/calendar; charset=utf-8; component=vtodo";
+
+type DavResult = Result<Response, Response>;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/.well-known/caldav", any(well_known))
+        .route("/dav", any(principal))
+        .route("/dav/", any(principal))
+        .route("/dav/calendars", any(calendar_home))
+        .route("/dav/calendars/", any(calendar_home))
+        .route("/dav/calendars/todos", any(calendar))
+        .route("/dav/calendars/todos/", any(calendar))
+        .route("/dav/calendars/todos/{resource}", any(calendar_object))
+}
+
+/// A todo together with its CalDAV identity.
+pub struct CalendarObject {
+    pub id: i64,
+    pub name: String,
+    pub done: bool,
+    pub description: Option<String>,
+    pub parent_id: Option<i64>,
+    pub date: Option<Date>,
+    pub version: i64,
+    pub priority: i16,
+    pub tags: Vec<String>,
+    pub ical_href: Option<String>,
+}
+
+impl CalendarObject {
+    /// The last segment of the resource's URL.
+    fn resource_name(&self) -> String {
+        self.ical_href
+            .clone()
+            .unwrap_or_else(|| format!("{}.ics", self.id))
+    }
+
+    fn todo(&self) -> Todo {
+        Todo {
+            id: self.id,
+            name: self.name.clone(),
+            done: self.done,
+            description: self.description.clone(),
+            parent_id: self.parent_id,
+            date: self.date,
+            version: self.version,
+            priority: self.priority,
+            tags: self.tags.clone(),
+        }
+    }
+}
+
+/// A property of a resource, with its value as XML.
+struct Prop {
+    namespace: &'static str,
+    name: &'static str,
+    value: String,
+}
+
+impl Prop {
+    fn new(namespace: &'static str, name: &'static str, value: impl Into<String>) -> Self {
+        Prop {
+            namespace,
+            name,
+            value: value.into(),
+        }
+    }
+}
+
+/// The properties a PROPFIND or REPORT asked for.
+enum PropRequest {
+    All,
+    Names(Vec<(String, String)>),
+}
+
+// -----------------
+// Helpers
+// -----------------
+
+fn dav_error(status: StatusCode, message: impl Into<String>) -> Response {
+    (status, message.into()).into_response()
+}
+
+/// Checks the password sent with HTTP Basic auth.
+fn is_authenticated(state: &AppState, headers: &HeaderMap) -> bool {
+    basic_credentials(headers)
+        .is_some_and(|(_, password)| authenticate(&state.hashed_password, &password))
+}
+
+/// The user name and password sent with HTTP Basic auth.
+pub fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|credentials| BASE64.decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(user, password)| (user.to_owned(), password.to_owned()))
+        })
+}
+
+/// A change of a resource that matched no todo: it was changed or deleted since the client
+/// read it.
+fn precondition_error((status, message): (StatusCode, String)) -> Response {
+    match status {
+        StatusCode::PRECONDITION_FAILED | StatusCode::NOT_FOUND => {
+            dav_error(StatusCode::PRECONDITION_FAILED, "The resource was changed")
+        }
+        _ => dav_error(status, message),
+    }
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Basic realm=\"Timely\"")],
+        "Failed authentication",
+    )
+        .into_response()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn href(path: &str) -> String {
+    format!("<d:href>{}</d:href>", escape_xml(path))
+}
+
+fn options() -> Response {
+    (
+        StatusCode::OK,
+        [
+            ("DAV", "1, 3, calendar-access"),
+            ("Allow", "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT"),
+        ],
+    )
+        .into_response()
+}
+
+fn method_not_allowed() -> Response {
+    dav_error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+}
+
+/// Whether a PROPFIND should include the members of a collection.
+fn includes_members(headers: &HeaderMap) -> bool {
+    headers
+        .get("Depth")
+        .and_then(|depth| depth.to_str().ok())
+        .is_none_or(|depth| depth.trim() != "0")
+}
+
+fn parse_xml(body: &str) -> Result<roxmltree::Document<'_>, (StatusCode, String)> {
+    roxmltree::Document::parse(body)
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("Invalid XML: {}", err)))
+}
+
+/// Reads the `prop` element of a PROPFIND or REPORT body; anything else asks for all.
+fn requested_props(root: roxmltree::Node) -> PropRequest {
+    match root
+        .children()
+        .find(|node| node.has_tag_name((DAV_NS, "prop")))
+    {
+        Some(prop) => PropRequest::Names(
+            prop.children()
+                .filter(|node| node.is_element())
+                .map(|node| {
+                    let name = node.tag_name();
+                    (
+                        name.namespace().unwrap_or_default().to_owned(),
+                        name.name().to_owned(),
+                    )
+                })
+                .collect(),
+        ),
+        None => PropRequest::All,
+    }
+}
+
+fn parse_propfind(body: &str) -> Result<PropRequest, (StatusCode, String)> {
+    if body.trim().is_empty() {
+        return Ok(PropRequest::All);
+    }
+    let document = parse_xml(body)?;
+    Ok(requested_props(document.root_element()))
+}
+
+/// Writes a `response` element with the requested properties that exist and, when
+/// specific properties were asked for, the ones that don't.
+fn write_response(xml: &mut String, path: &str, props: &[Prop], request: &PropRequest) {
+    let _ = write!(xml, "<d:response>{}", href(path));
+    let (found, missing): (Vec<&Prop>, Vec<(&str, &str)>) = match request {
+        PropRequest::All => (props.iter().collect(), Vec::new()),
+        PropRequest::Names(names) => {
+            let mut found = Vec::new();
+            let mut missing = Vec::new();
+            for (namespace, name) in names {
+                match props
+                    .iter()
+                    .find(|prop| prop.namespace == namespace && prop.name == name)
+                {
+                    Some(prop) => found.push(prop),
+                    None => missing.push((namespace.as_str(), name.as_str())),
+                }
+            }
+            (found, missing)
+        }
+    };
+    if !found.is_empty() {
+        xml.push_str("<d:propstat><d:prop>");
+        for prop in found {
+            let prefix = match prop.namespace {
+                DAV_NS => "d",
+                CALDAV_NS => "c",
+                _ => "cs",
+            };
+            if prop.value.is_empty() {
+                let _ = write!(xml, "<{}:{}/>", prefix, prop.name);
+            } else {
+                let _ = write!(xml, "<{0}:{1}>{2}</{0}:{1}>", prefix, prop.name, prop.value);
+            }
+        }
+        xml.push_str("</d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat>");
+    }
+    if !missing.is_empty() {
+        xml.push_str("<d:propstat><d:prop>");
+        for (namespace, name) in missing {
+            let _ = write!(
+                xml,
+                "<x:{} xmlns:x=\"{}\"/>",
+                escape_xml(name),
+                escape_xml(namespace)
+            );
+        }
+        xml.push_str("</d:prop><d:status>HTTP/1.1 404 Not Found</d:status></d:propstat>");
+    }
+    xml.push_str("</d:response>");
+}
+
+fn multistatus(responses: String) -> Response {
+    (
+        StatusCode::MULTI_STATUS,
+        [(header::CONTENT_TYPE, "application/xml; charset=utf-8")],
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <d:multistatus xmlns:d=\"{}\" xmlns:c=\"{}\" xmlns:cs=\"{}\">{}</d:multistatus>",
+            DAV_NS, CALDAV_NS, CALENDARSERVER_NS, responses
+        ),
+    )
+        .into_response()
+}
+
+fn etag_value(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The id of a todo from a UID handed out by `todo_uid`, for looking up the todos that
+/// were not created by CalDAV clients.
+pub fn timely_id(uid: &str) -> Option<i64> {
+    uid.strip_prefix("todo-")
+        .and_then(|rest| rest.strip_suffix("@timely"))
+        .and_then(|id| id.parse().ok())
+}
+
+fn calendar_data(object: &CalendarObject, uids: &HashMap<i64, String>) -> String {
+    let mut ical = String::new();
+    begin_calendar(&mut ical, "Timely");
+    write_vtodo(
+        &mut ical,
+        &object.todo(),
+        |id| uids.get(&id).cloned().unwrap_or_else(|| todo_uid(id)),
+        OffsetDateTime::now_utc(),
+    );
+    end_calendar(&mut ical);
+    ical
+}
+
+// -----------------
+// Properties
+// -----------------
+
+fn principal_props(base: &str) -> Vec<Prop> {
+    vec![
+        Prop::new(DAV_NS, "resourcetype", "<d:collection/><d:principal/>"),
+        Prop::new(DAV_NS, "displayname", "Timely"),
+        Prop::new(
+            DAV_NS,
+            "current-user-principal",
+            href(&format!("{}/dav/", base)),
+        ),
+        Prop::new(DAV_NS, "principal-URL", href(&format!("{}/dav/", base))),
+        Prop::new(
+            CALDAV_NS,
+            "calendar-home-set",
+            href(&format!("{}/dav/calendars/", base)),
+        ),
+    ]
+}
+
+fn calendar_home_props(base: &str) -> Vec<Prop> {
+    vec![
+        Prop::new(DAV_NS, "resourcetype", "<d:collection/>"),
+        Prop::new(DAV_NS, "displayname", "Calendars"),
+        Prop::new(
+            DAV_NS,
+            "current-user-principal",
+            href(&format!("{}/dav/", base)),
+        ),
+    ]
+}
+
+fn calendar_props(base: &str, tag: i64) -> Vec<Prop> {
+    vec![
+        Prop::new(DAV_NS, "resourcetype", "<d:collection/><c:calendar/>"),
+        Prop::new(DAV_NS, "displayname", "Timely"),
+        Prop::new(
+            DAV_NS,
+            "current-user-principal",
+            href(&format!("{}/dav/", base)),
+        ),
+        Prop::new(
+            DAV_NS,
+            "current-user-privilege-set",
+            ["read", "write", "write-content", "bind", "unbind"]
+                .iter()
+                .map(|privilege| format!("<d:privilege><d:{}/></d:privilege>", privilege))
+                .collect::<String>(),
+        ),
+        Prop::new(
+            DAV_NS,
+            "supported-report-set",
+            ["calendar-query", "calendar-multiget"]
+                .iter()
+                .map(|report| {
+                    format!(
+                        "<d:supported-report><d:report><c:{}/></d:report></d:supported-report>",
+                        report
+                    )
+                })
+                .collect::<String>(),
+        ),
+        Prop::new(
+            CALDAV_NS,
+            "supported-calendar-component-set",
+            "<c:comp name=\"VTODO\"/>",
+        ),
+        Prop::new(CALENDARSERVER_NS, "getctag", tag.to_string()),
+    ]
+}
+
+fn object_props(object: &CalendarObject) -> Vec<Prop> {
+    vec![
+        Prop::new(DAV_NS, "resourcetype", ""),
+        Prop::new(DAV_NS, "getetag", escape_xml(&etag_value(object.version))),
+        Prop::new(DAV_NS, "getcontenttype", CALENDAR_CONTENT_TYPE),
+    ]
+}
+
+fn object_path(base: &str, object: &CalendarObject) -> String {
+    format!("{}/dav/calendars/todos/{}", base, object.resource_name())
+}
+
+// -----------------
+// Handlers
+// -----------------
+
+/// GET "/.well-known/caldav" – points clients at the principal.
+async fn well_known(BasePath(base): BasePath) -> impl IntoResponse {
+    Redirect::permanent(&format!("{}/dav/", base))
+}
+
+/// "/dav/" – the principal of the (only) user.
+async fn principal(
+    method: Method,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    BasePath(base): BasePath,
+    body: String,
+) -> DavResult {
+    match method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => {
+            if !is_authenticated(&state, &headers) {
+                return Err(unauthorized());
+            }
+            let request = parse_propfind(&body).map_err(IntoResponse::into_response)?;
+            let mut xml = String::new();
+            write_response(
+                &mut xml,
+                &format!("{}/dav/", base),
+                &principal_props(&base),
+                &request,
+            );
+            Ok(multistatus(xml))
+        }
+        _ => Err(method_not_allowed()),
+    }
+}
+
+/// "/dav/calendars/" – the collection holding the todo calendar.
+async fn calendar_home(
+    method: Method,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    BasePath(base): BasePath,
+    body: String,
+) -> DavResult {
+    match method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => {
+            if !is_authenticated(&state, &headers) {
+                return Err(unauthorized());
+            }
+            let request = parse_propfind(&body).map_err(IntoResponse::into_response)?;
+            let mut xml = String::new();
+            write_response(
+                &mut xml,
+                &format!("{}/dav/calendars/", base),
+                &calendar_home_props(&base),
+                &request,
+            );
+            if includes_members(&headers) {
+                let tag = state
+                    .storage
+                    .collection_tag()
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                write_response(
+                    &mut xml,
+                    &format!("{}/dav/calendars/todos/", base),
+                    &calendar_props(&base, tag),
+                    &request,
+                );
+            }
+            Ok(multistatus(xml))
+        }
+        _ => Err(method_not_allowed()),
+    }
+}
+
+/// "/dav/calendars/todos/" – the calendar with every todo as a VTODO.
+async fn calendar(
+    method: Method,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    BasePath(base): BasePath,
+    body: String,
+) -> DavResult {
+    match method.as_str() {
+        "OPTIONS" => Ok(options()),
+        "PROPFIND" => {
+            if !is_authenticated(&state, &headers) {
+                return Err(unauthorized());
+            }
+            let request = parse_propfind(&body).map_err(IntoResponse::into_response)?;
+            let tag = state
+                .storage
+                .collection_tag()
+                .await
+                .map_err(IntoResponse::into_response)?;
+            let mut xml = String::new();
+            write_response(
+                &mut xml,
+                &format!("{}/dav/calendars/todos/", base),
+                &calendar_props(&base, tag),
+                &request,
+            );
+            if includes_members(&headers) {
+                for object in state
+                    .storage
+                    .calendar_objects()
+                    .await
+                    .map_err(IntoResponse::into_response)?
+                {
+                    write_response(
+                        &mut xml,
+                        &object_path(&base, &object),
+                        &object_props(&object),
+                        &request,
+                    );
+                }
+            }
+            Ok(multistatus(xml))
+        }
+        "REPORT" => {
+            if !is_authenticated(&state, &headers) {
+                return Err(unauthorized());
+            }
+            report(&state, &base, &body).await
+        }
+        _ => Err(method_not_allowed()),
+    }
+}
+
+/// REPORT on the calendar. `calendar-query` returns every todo unless its filter asks
+/// for components other than VTODOs (time ranges are not evaluated);
+/// `calendar-multiget` returns the listed resources.
+async fn report(state: &AppState, base: &str, body: &str) -> DavResult {
+    let document = parse_xml(body).map_err(IntoResponse::into_response)?;
+    let root = document.root_element();
+    let request = requested_props(root);
+    let objects = state
+        .storage
+        .calendar_objects()
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let uids = state
+        .storage
+        .client_uids()
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    let with_data = |object: &CalendarObject| {
+        let mut props = object_props(object);
+        props.push(Prop::new(
+            CALDAV_NS,
+            "calendar-data",
+            escape_xml(&calendar_data(object, &uids)),
+        ));
+        props
+    };
+
+    let mut xml = String::new();
+    if root.has_tag_name((CALDAV_NS, "calendar-query")) {
+        let only_todos = root
+            .descendants()
+            .filter(|node| node.has_tag_name((CALDAV_NS, "comp-filter")))
+            .filter_map(|node| node.attribute("name"))
+            .all(|name| {
+                name.eq_ignore_ascii_case("VCALENDAR") || name.eq_ignore_ascii_case("VTODO")
+            });
+        if only_todos {
+            for object in &objects {
+                write_response(
+                    &mut xml,
+                    &object_path(base, object),
+                    &with_data(object),
+                    &request,
+                );
+            }
+        }
+    } else if root.has_tag_name((CALDAV_NS, "calendar-multiget")) {
+        for requested in root
+            .children()
+            .filter(|node| node.has_tag_name((DAV_NS, "href")))
+            .filter_map(|node| node.text())
+        {
+            let requested = requested.trim();
+            let name = requested.rsplit('/').next().unwrap_or_default();
+            match objects.iter().find(|object| object.resource_name() == name) {
+                Some(object) => write_response(
+                    &mut xml,
+                    &object_path(base, object),
+                    &with_data(object),
+                    &request,
+                ),
+                None => {
+                    let _ = write!(
+                        xml,
+                        "<d:response>{}<d:status>HTTP/1.1 404 Not Found</d:status></d:response>",
+                        href(requested)
+                    );
+                }
+            }
+        }
+    } else {
+        return Err(dav_error(StatusCode::FORBIDDEN, "Unsupported report"));
+    }
+    Ok(multistatus(xml))
+}
+
+/// "/dav/calendars/todos/{resource}" – a single todo.
+async fn calendar_object(
+    method: Method,
+    headers: HeaderMap,
+    Path(resource): Path<String>,
+    State(state): State<AppState>,
+    BasePath(base): BasePath,
+    body: String,
+) -> DavResult {
+    if method == Method::OPTIONS {
+        return Ok(options());
+    }
+    if !is_authenticated(&state, &headers) {
+        return Err(unauthorized());
+    }
+    match method.as_str() {
+        "GET" | "HEAD" => {
+            let object = state
+                .storage
+                .calendar_object(&resource)
+                .await
+                .map_err(IntoResponse::into_response)?
+                .ok_or_else(|| dav_error(StatusCode::NOT_FOUND, "Not found"))?;
+            let uids = state
+                .storage
+                .client_uids()
+                .await
+                .map_err(IntoResponse::into_response)?;
+            Ok((
+                [
+                    (header::CONTENT_TYPE, CALENDAR_CONTENT_TYPE.to_owned()),
+                    (header::ETAG, etag_value(object.version)),
+                ],
+                calendar_data(&object, &uids),
+            )
+                .into_response())
+        }
+        "PROPFIND" => {
+            let request = parse_propfind(&body).map_err(IntoResponse::into_response)?;
+            let object = state
+                .storage
+                .calendar_object(&resource)
+                .await
+                .map_err(IntoResponse::into_response)?
+                .ok_or_else(|| dav_error(StatusCode::NOT_FOUND, "Not found"))?;
+            let mut xml = String::new();
+            write_response(
+                &mut xml,
+                &object_path(&base, &object),
+                &object_props(&object),
+                &request,
+            );
+            Ok(multistatus(xml))
+        }
+        "PUT" => put_object(&state, &headers, &resource, &body).await,
+        "DELETE" => delete_object(&state, &headers, &resource).await,
+        _ => Err(method_not_allowed()),
+    }
+}
+
+/// PUT of a VTODO: updates the todo at that URL or creates one. Honours `If-Match` and
+/// `If-None-Match: *`.
+async fn put_object(
+    state: &AppState,
+    headers: &HeaderMap,
+    resource: &str,
+    body: &str,
+) -> DavResult {
+    let vtodo = parse_vtodo(body).map_err(|err| dav_error(StatusCode::BAD_REQUEST, err))?;
+    if vtodo.summary.trim().is_empty() {
+        return Err(dav_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "The todo needs a SUMMARY",
+        ));
+    }
+    let expected = expected_version(headers).map_err(IntoResponse::into_response)?;
+    let if_match = headers.contains_key(header::IF_MATCH);
+    let create_only = headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == b"*");
+
+    let parent_id = match &vtodo.parent_uid {
+        Some(uid) => match state
+            .storage
+            .resolve_uid(uid)
+            .await
+            .map_err(IntoResponse::into_response)?
+        {
+            Some((id, false)) => Some(id),
+            // An archived tree is kept whole, so nothing is added to one.
+            Some((_, true)) => {
+                return Err(dav_error(
+                    StatusCode::CONFLICT,
+                    format!("The parent todo {} (RELATED-TO) is archived", uid),
+                ))
+            }
+            None => {
+                return Err(dav_error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("The parent todo {} (RELATED-TO) does not exist", uid),
+                ))
+            }
+        },
+        None => None,
+    };
+    let todo = NewTodo {
+        name: vtodo.summary,
+        description: vtodo.description,
+        parent_id,
+        date: vtodo.due,
+        done: vtodo.completed,
+        priority: vtodo.priority.level(),
+        tags: vtodo.categories,
+        ical_uid: Some(vtodo.uid),
+        ical_href: Some(resource.to_owned()),
+    };
+
+    match state
+        .storage
+        .calendar_object(resource)
+        .await
+        .map_err(IntoResponse::into_response)?
+    {
+        Some(object) => {
+            if create_only {
+                return Err(dav_error(
+                    StatusCode::PRECONDITION_FAILED,
+                    "The resource already exists",
+                ));
+            }
+            let updated = state
+                .storage
+                .replace_todo(object.id, expected, &todo)
+                .await
+                .map_err(precondition_error)?;
+            let event = if updated.done != object.done {
+                Event::for_done(updated.done)
+            } else {
+                Event::Updated
+            };
+            webhooks::enqueue(&*state.storage, &state.webhook_notify, event, &updated).await;
+            Ok((
+                StatusCode::NO_CONTENT,
+                [(header::ETAG, etag_value(updated.version))],
+            )
+                .into_response())
+        }
+        None => {
+            if if_match {
+                return Err(dav_error(
+                    StatusCode::PRECONDITION_FAILED,
+                    "The resource does not exist",
+                ));
+            }
+            if state
+                .storage
+                .archived_object(resource)
+                .await
+                .map_err(IntoResponse::into_response)?
+            {
+                return Err(dav_error(
+                    StatusCode::CONFLICT,
+                    "The todo is archived, unarchive it first",
+                ));
+            }
+            if let Some(uid) = &todo.ical_uid {
+                if state
+                    .storage
+                    .resolve_uid(uid)
+                    .await
+                    .map_err(IntoResponse::into_response)?
+                    .is_some()
+                {
+                    return Err(dav_error(
+                        StatusCode::CONFLICT,
+                        "Another resource already has this UID",
+                    ));
+                }
+            }
+            let created = state
+                .storage
+                .create_todo(todo)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            webhooks::enqueue(
+                &*state.storage,
+                &state.webhook_notify,
+                Event::Created,
+                &created,
+            )
+            .await;
+            Ok((
+                StatusCode::CREATED,
+                [(header::ETAG, etag_value(created.version))],
+            )
+                .into_response())
+        }
+    }
+}
+
+/// DELETE of a todo and its descendants, like `DELETE /todos`.
+async fn delete_object(state: &AppState, headers: &HeaderMap, resource: &str) -> DavResult {
+    let expected = expected_version(headers).map_err(IntoResponse::into_response)?;
+    let object = state
+        .storage
+        .calendar_object(resource)
+        .await
+        .map_err(IntoResponse::into_response)?
+        .ok_or_else(|| dav_error(StatusCode::NOT_FOUND, "Not found"))?;
+
+    state
+        .storage
+        .delete_todo(object.id, expected)
+        .await
+        .map_err(precondition_error)?;
+    webhooks::enqueue(
+        &*state.storage,
+        &state.webhook_notify,
+        Event::Deleted,
+        &object.todo(),
+    )
+    .await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
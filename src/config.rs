@@ -0,0 +1,508 @@
+//! Server configuration, read in layers that override each other in order:
+//!
+//! 1. a TOML file: `--config`, `TIMELY_CONFIG`, or `timely.toml` in the working directory
+//!    if there is one;
+//! 2. the environment, including a `.env` file in the working directory if there is one;
+//! 3. command-line flags.
+//!
+//! ```toml
+//! database_url = "postgres://timely@localhost/timely"
+//! service_url = "127.0.0.1:3000"
+//! password = "..."
+//! base_path = "/apps/todo"
+//! trust_forwarded_prefix = false
+//! archive_after_days = 30
+//! tombstone_retention_days = 90
+//! drain_timeout_seconds = 30
+//! log_level = "info,timely=debug"
+//! log_format = "json"
+//! ```
+//!
+//! The password has no flag, as command lines are visible to every user of the machine.
+
+use clap::Args;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::logging::{self, LogFormat};
+use crate::storage::{Backend, UNSUPPORTED_URL};
+
+const DEFAULT_FILE: &str = "timely.toml";
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_DRAIN_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_TOMBSTONE_RETENTION_DAYS: i32 = 90;
+
+/// The flags that override the configuration file and the environment.
+#[derive(Args)]
+pub struct ConfigArgs {
+    /// TOML configuration file [env: TIMELY_CONFIG] [default: timely.toml, if there is one]
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Database to use: postgres://..., sqlite:... or memory: [env: DATABASE_URL]
+    #[arg(long, global = true, value_name = "URL")]
+    database_url: Option<String>,
+    /// Address to listen on, e.g. 127.0.0.1:3000 [env: SERVICE_URL]
+    #[arg(long, global = true, value_name = "HOST:PORT")]
+    service_url: Option<String>,
+    /// Path to serve everything under, e.g. /apps/todo [env: BASE_PATH]
+    #[arg(long, global = true, value_name = "PATH")]
+    base_path: Option<String>,
+    /// Serve everything under /timely, the same as --base-path /timely [env: RUN_ON_SUBPATH]
+    #[arg(long, global = true, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    run_on_subpath: Option<bool>,
+    /// Prepend the X-Forwarded-Prefix header of a reverse proxy to the URLs the app
+    /// generates [env: TRUST_FORWARDED_PREFIX]
+    #[arg(long, global = true, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    trust_forwarded_prefix: Option<bool>,
+    /// Archive completed todos this many days after they were done [env: ARCHIVE_AFTER_DAYS]
+    #[arg(long, global = true, value_name = "DAYS")]
+    archive_after_days: Option<i32>,
+    /// Remember deleted todos for sync this many days; clients away for longer get the
+    /// whole list [env: TOMBSTONE_RETENTION_DAYS] [default: 90]
+    #[arg(long, global = true, value_name = "DAYS")]
+    tombstone_retention_days: Option<i32>,
+    /// On SIGTERM or Ctrl-C, give requests in flight and the webhook queue this long to
+    /// finish before exiting [env: DRAIN_TIMEOUT_SECONDS] [default: 30]
+    #[arg(long, global = true, value_name = "SECONDS")]
+    drain_timeout_seconds: Option<u64>,
+    /// Events to log: a level such as debug, or per-module directives such as
+    /// info,sqlx=warn [env: LOG_LEVEL] [default: info]
+    #[arg(long, global = true, value_name = "FILTER")]
+    log_level: Option<String>,
+    /// How to write the log [env: LOG_FORMAT] [default: pretty]
+    #[arg(long, global = true, value_name = "FORMAT")]
+    log_format: Option<LogFormat>,
+}
+
+/// One layer of the configuration; what it leaves out comes from the layers below it.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    database_url: Option<String>,
+    service_url: Option<String>,
+    password: Option<String>,
+    base_path: Option<String>,
+    run_on_subpath: Option<bool>,
+    trust_forwarded_prefix: Option<bool>,
+    archive_after_days: Option<i32>,
+    tombstone_retention_days: Option<i32>,
+    drain_timeout_seconds: Option<u64>,
+    log_level: Option<String>,
+    log_format: Option<LogFormat>,
+}
+
+impl Layer {
+    /// Takes the values `other` sets over those of `self`.
+    fn merge(self, other: Layer) -> Layer {
+        Layer {
+            database_url: other.database_url.or(self.database_url),
+            service_url: other.service_url.or(self.service_url),
+            password: other.password.or(self.password),
+            base_path: other.base_path.or(self.base_path),
+            run_on_subpath: other.run_on_subpath.or(self.run_on_subpath),
+            trust_forwarded_prefix: other.trust_forwarded_prefix.or(self.trust_forwarded_prefix),
+            archive_after_days: other.archive_after_days.or(self.archive_after_days),
+            tombstone_retention_days: (other.tombstone_retention_days)
+                .or(self.tombstone_retention_days),
+            drain_timeout_seconds: other.drain_timeout_seconds.or(self.drain_timeout_seconds),
+            log_level: other.log_level.or(self.log_level),
+            log_format: other.log_format.or(self.log_format),
+        }
+    }
+
+    fn from_file(args: &ConfigArgs) -> Result<Layer, String> {
+        // Read after `.env`, which may set `TIMELY_CONFIG`
+        let explicit =
+            (args.config.clone()).or_else(|| env::var_os("TIMELY_CONFIG").map(PathBuf::from));
+        let path = match explicit {
+            Some(path) => path,
+            None if fs::exists(DEFAULT_FILE).unwrap_or(false) => PathBuf::from(DEFAULT_FILE),
+            None => return Ok(Layer::default()),
+        };
+        let contents = fs::read_to_string(&path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        toml::from_str(&contents).map_err(|err| format!("Invalid {}: {}", path.display(), err))
+    }
+
+    fn from_env() -> Result<Layer, String> {
+        Ok(Layer {
+            database_url: env_var("DATABASE_URL")?,
+            service_url: env_var("SERVICE_URL")?,
+            password: env_var("PASSWORD")?,
+            base_path: env_var("BASE_PATH")?,
+            run_on_subpath: parsed_env_var("RUN_ON_SUBPATH", parse_bool)?,
+            trust_forwarded_prefix: parsed_env_var("TRUST_FORWARDED_PREFIX", parse_bool)?,
+            archive_after_days: parsed_env_var("ARCHIVE_AFTER_DAYS", |days| days.parse().ok())?,
+            tombstone_retention_days: parsed_env_var("TOMBSTONE_RETENTION_DAYS", |days| {
+                days.parse().ok()
+            })?,
+            drain_timeout_seconds: parsed_env_var("DRAIN_TIMEOUT_SECONDS", |seconds| {
+                seconds.parse().ok()
+            })?,
+            log_level: env_var("LOG_LEVEL")?,
+            log_format: parsed_env_var("LOG_FORMAT", LogFormat::parse)?,
+        })
+    }
+
+    fn from_args(args: &ConfigArgs) -> Layer {
+        Layer {
+            database_url: args.database_url.clone(),
+            service_url: args.service_url.clone(),
+            password: None,
+            base_path: args.base_path.clone(),
+            run_on_subpath: args.run_on_subpath,
+            trust_forwarded_prefix: args.trust_forwarded_prefix,
+            archive_after_days: args.archive_after_days,
+            tombstone_retention_days: args.tombstone_retention_days,
+            drain_timeout_seconds: args.drain_timeout_seconds,
+            log_level: args.log_level.clone(),
+            log_format: args.log_format,
+        }
+    }
+}
+
+fn env_var(name: &str) -> Result<Option<String>, String> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(format!("Invalid {}: {}", name, err)),
+    }
+}
+
+fn parsed_env_var<T>(name: &str, parse: fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+    env_var(name)?
+        .map(|value| parse(value.trim()).ok_or_else(|| format!("Invalid {}: {:?}", name, value)))
+        .transpose()
+}
+
+/// `RUN_ON_SUBPATH` has always meant true only when it was `true`; `1` and `0` are
+/// accepted too, anything else is refused rather than silently read as false. The same
+/// goes for `TRUST_FORWARDED_PREFIX`.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// The configuration from all the layers, before it is checked.
+pub struct Settings(Layer);
+
+/// Reads every layer of the configuration.
+pub fn load(args: &ConfigArgs) -> Result<Settings, String> {
+    match dotenvy::dotenv() {
+        Ok(_) => {}
+        Err(err) if err.not_found() => {}
+        Err(err) => return Err(format!("Invalid .env file: {}", err)),
+    }
+    let layer = Layer::from_file(args)?
+        .merge(Layer::from_env()?)
+        .merge(Layer::from_args(args));
+    Ok(Settings(layer))
+}
+
+impl Settings {
+    /// The database, for the commands that need nothing else.
+    pub fn database_url(&self) -> Result<String, String> {
+        check_database_url(self.0.database_url.as_deref())
+    }
+
+    /// How to log, for every command.
+    pub fn logging(&self) -> Result<Logging, String> {
+        let level = (self.0.log_level.clone()).unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_owned());
+        logging::check_filter(&level)?;
+        Ok(Logging {
+            level,
+            format: self.0.log_format.unwrap_or(LogFormat::Pretty),
+        })
+    }
+
+    /// Checks everything the server needs, reporting every problem at once.
+    pub fn server(self) -> Result<Config, String> {
+        let logging = self.logging();
+        let layer = self.0;
+        let mut problems = Vec::new();
+
+        let database_url = check_database_url(layer.database_url.as_deref())
+            .map_err(|err| problems.push(err))
+            .ok();
+        let service_url = match layer.service_url {
+            Some(url) if is_socket_address(&url) => Some(url),
+            Some(url) => {
+                problems.push(format!(
+                    "SERVICE_URL must be a host and port such as 127.0.0.1:3000, not {:?}",
+                    url
+                ));
+                None
+            }
+            None => {
+                problems.push("SERVICE_URL is not set".to_owned());
+                None
+            }
+        };
+        let password = match layer.password {
+            Some(password) if !password.is_empty() => Some(password),
+            Some(_) => {
+                problems.push("PASSWORD must not be empty".to_owned());
+                None
+            }
+            None => {
+                problems.push("PASSWORD is not set".to_owned());
+                None
+            }
+        };
+        let base_path = match (layer.base_path, layer.run_on_subpath) {
+            (Some(_), Some(true)) => {
+                problems.push(
+                    "BASE_PATH and RUN_ON_SUBPATH can't both be set, use BASE_PATH=/timely"
+                        .to_owned(),
+                );
+                String::new()
+            }
+            (Some(path), _) => normalize_base_path(&path).unwrap_or_else(|| {
+                problems.push(format!(
+                    "BASE_PATH must be a path such as /apps/todo, not {:?}",
+                    path
+                ));
+                String::new()
+            }),
+            (None, Some(true)) => "/timely".to_owned(),
+            (None, _) => String::new(),
+        };
+        if layer.archive_after_days.is_some_and(|days| days < 0) {
+            problems.push("ARCHIVE_AFTER_DAYS must not be negative".to_owned());
+        }
+        if layer.tombstone_retention_days.is_some_and(|days| days < 1) {
+            problems.push("TOMBSTONE_RETENTION_DAYS must be at least 1".to_owned());
+        }
+        let logging = logging.map_err(|err| problems.push(err)).ok();
+
+        match (database_url, service_url, password, logging) {
+            (Some(database_url), Some(service_url), Some(password), Some(logging))
+                if problems.is_empty() =>
+            {
+                Ok(Config {
+                    database_url,
+                    service_url,
+                    password,
+                    base_path,
+                    trust_forwarded_prefix: layer.trust_forwarded_prefix.unwrap_or(false),
+                    archive_after_days: layer.archive_after_days,
+                    tombstone_retention_days: (layer.tombstone_retention_days)
+                        .unwrap_or(DEFAULT_TOMBSTONE_RETENTION_DAYS),
+                    drain_timeout: Duration::from_secs(
+                        (layer.drain_timeout_seconds).unwrap_or(DEFAULT_DRAIN_TIMEOUT_SECONDS),
+                    ),
+                    logging,
+                })
+            }
+            _ => Err(format!(
+                "Invalid configuration:\n  {}",
+                problems.join("\n  ")
+            )),
+        }
+    }
+}
+
+fn check_database_url(database_url: Option<&str>) -> Result<String, String> {
+    match database_url {
+        Some(url) if Backend::for_url(url).is_some() => Ok(url.to_owned()),
+        Some(_) => Err(UNSUPPORTED_URL.to_owned()),
+        None => Err("DATABASE_URL is not set".to_owned()),
+    }
+}
+
+/// Whether the address looks like `host:port`; the host is resolved when binding.
+fn is_socket_address(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+/// A base path without its trailing slash, so `/` and the empty path become `""`; `None`
+/// if it is not an absolute path of plain segments (letters, digits, `-`, `.`, `_`, `~`).
+/// Used for `X-Forwarded-Prefix` too, so it must keep anything that could link elsewhere
+/// out of the site.
+pub fn normalize_base_path(path: &str) -> Option<String> {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        return Some(String::new());
+    }
+    let segments = path.strip_prefix('/')?;
+    segments
+        .split('/')
+        .all(|segment| {
+            !segment.is_empty()
+                && segment != "."
+                && segment != ".."
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c))
+        })
+        .then(|| path.to_owned())
+}
+
+/// The checked configuration of the server.
+pub struct Config {
+    pub database_url: String,
+    pub service_url: String,
+    pub password: String,
+    /// Path everything is served under, `""` for the root
+    pub base_path: String,
+    /// Whether a proxy's `X-Forwarded-Prefix` goes in front of the base path
+    pub trust_forwarded_prefix: bool,
+    /// Archive completed todos this many days after they were done, if set
+    pub archive_after_days: Option<i32>,
+    /// Deleted todos are reported to syncing clients for this many days
+    pub tombstone_retention_days: i32,
+    /// How long a shutdown waits for requests in flight and the background tasks
+    pub drain_timeout: Duration,
+    pub logging: Logging,
+}
+
+/// The checked logging configuration.
+pub struct Logging {
+    /// An `EnvFilter` directive, such as `info` or `info,sqlx=warn`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+/// The database URL without the password it may contain.
+pub fn redacted_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_owned();
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    match authority
+        .rsplit_once('@')
+        .and_then(|(credentials, host)| Some((credentials.split_once(':')?.0, host)))
+    {
+        Some((user, host)) => format!("{}://{}:***@{}{}", scheme, user, host, path),
+        None => url.to_owned(),
+    }
+}
+
+impl fmt::Display for Config {
+    /// For `--check-config`; the passwords are left out.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "database_url = {:?}", redacted_url(&self.database_url))?;
+        writeln!(f, "service_url = {:?}", self.service_url)?;
+        writeln!(f, "password = \"***\"")?;
+        writeln!(f, "base_path = {:?}", self.base_path)?;
+        writeln!(
+            f,
+            "trust_forwarded_prefix = {}",
+            self.trust_forwarded_prefix
+        )?;
+        match self.archive_after_days {
+            Some(days) => writeln!(f, "archive_after_days = {}", days)?,
+            None => writeln!(f, "# archive_after_days is not set")?,
+        }
+        writeln!(
+            f,
+            "tombstone_retention_days = {}",
+            self.tombstone_retention_days
+        )?;
+        writeln!(
+            f,
+            "drain_timeout_seconds = {}",
+            self.drain_timeout.as_secs()
+        )?;
+        writeln!(f, "log_level = {:?}", self.logging.level)?;
+        write!(f, "log_format = {:?}", self.logging.format.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(toml: &str) -> Settings {
+        Settings(toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let file: Layer = toml::from_str(
+            r#"
+            database_url = "memory:"
+            service_url = "127.0.0.1:3000"
+            run_on_subpath = true
+            "#,
+        )
+        .unwrap();
+        let flags = Layer {
+            service_url: Some("127.0.0.1:4000".to_owned()),
+            password: Some("pw".to_owned()),
+            ..Layer::default()
+        };
+        let config = Settings(file.merge(flags)).server().unwrap();
+        assert_eq!(config.database_url, "memory:");
+        assert_eq!(config.service_url, "127.0.0.1:4000");
+        assert_eq!(config.base_path, "/timely");
+    }
+
+    #[test]
+    fn base_paths() {
+        assert_eq!(normalize_base_path("/").as_deref(), Some(""));
+        assert_eq!(
+            normalize_base_path("/apps/todo/").as_deref(),
+            Some("/apps/todo")
+        );
+        for invalid in ["apps", "//evil.example", "/a//b", "/a/../b", "/a b", "/a?b"] {
+            assert_eq!(normalize_base_path(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let err = settings(
+            r#"
+            database_url = "mysql://localhost/timely"
+            service_url = "localhost"
+            archive_after_days = -1
+            tombstone_retention_days = 0
+            log_level = "timely=loud"
+            "#,
+        )
+        .server()
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.lines().collect::<Vec<_>>(),
+            [
+                "Invalid configuration:",
+                "  DATABASE_URL must be a postgres:// or sqlite: URL, or memory:",
+                "  SERVICE_URL must be a host and port such as 127.0.0.1:3000, not \"localhost\"",
+                "  PASSWORD is not set",
+                "  ARCHIVE_AFTER_DAYS must not be negative",
+                "  TOMBSTONE_RETENTION_DAYS must be at least 1",
+                "  LOG_LEVEL \"timely=loud\" is not a valid filter: invalid filter directive",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_refused() {
+        assert!(toml::from_str::<Layer>("pasword = \"pw\"").is_err());
+    }
+
+    #[test]
+    fn redacts_the_database_password() {
+        assert_eq!(
+            redacted_url("postgres://timely:secret@db:5432/timely"),
+            "postgres://timely:***@db:5432/timely"
+        );
+        assert_eq!(
+            redacted_url("postgres://timely@db/timely"),
+            "postgres://timely@db/timely"
+        );
+        assert_eq!(redacted_url("sqlite:timely.db"), "sqlite:timely.db");
+    }
+}
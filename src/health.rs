@@ -0,0 +1,73 @@
+//! Probes for orchestrators and load balancers: `/healthz` (the process is up), `/readyz`
+//! (the database answers and its schema is current) and `/version`. They need no password
+//! and are left out of the request log, as they are polled every few seconds.
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+
+use crate::migrations;
+use crate::storage::Backend;
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/version", get(version))
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    /// Why the database could not be checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Migrations the database has yet to get, e.g. when started with `--no-migrate`
+    pending_migrations: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    /// Commit the binary was built from, if known
+    commit: Option<&'static str>,
+    /// Backend of the running server
+    backend: &'static str,
+    /// Backends this build supports
+    backends: Vec<&'static str>,
+}
+
+/// GET "/healthz" – answers as long as the server does.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// GET "/readyz" – 200 when the database can be queried and has every migration, 503
+/// otherwise.
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let (ready, error, pending) = match migrations::pending(&*state.storage).await {
+        Ok(pending) => (pending.is_empty(), None, pending),
+        Err(err) => (false, Some(err), Vec::new()),
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let readiness = Readiness {
+        ready,
+        error,
+        pending_migrations: pending.into_iter().map(migrations::describe).collect(),
+    };
+    (status, Json(readiness))
+}
+
+/// GET "/version" – what is running.
+async fn version(State(state): State<AppState>) -> Json<Version> {
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: option_env!("TIMELY_GIT_COMMIT"),
+        backend: state.storage.backend().name(),
+        backends: Backend::ALL.into_iter().map(Backend::name).collect(),
+    })
+}
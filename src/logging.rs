@@ -0,0 +1,176 @@
+//! Logging, the same in debug and release builds: events and spans go to stdout, as
+//! human-readable lines or as JSON, filtered by `LOG_LEVEL` (`info` unless set otherwise,
+//! e.g. `debug` or `timely=debug,sqlx=warn`).
+//!
+//! Every request gets a span with its method and URI, with the `password` query parameter
+//! and the calendar token redacted, and, once known, the user and the todo it is about.
+
+use axum::{
+    body::Body,
+    extract::{FromRequestParts, MatchedPath, Query, RawPathParams, Request, State},
+    http::{HeaderMap, Uri},
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::extract::cookie::CookieJar;
+use serde::Deserialize;
+use std::io::IsTerminal;
+use tracing::{field, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::caldav::basic_credentials;
+use crate::{authenticate, extract_provided, AppState, PasswordQuery};
+
+#[derive(Clone, Copy, Debug, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human-readable line per event
+    Pretty,
+    /// One JSON object per event, for log collectors
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format.to_lowercase().as_str() {
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogFormat::Pretty => "pretty",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+/// Checks a `LOG_LEVEL` filter.
+pub fn check_filter(filter: &str) -> Result<(), String> {
+    EnvFilter::try_new(filter)
+        .map(|_| ())
+        .map_err(|err| format!("LOG_LEVEL {:?} is not a valid filter: {}", filter, err))
+}
+
+/// Installs the logger. `filter` must have passed `check_filter`.
+pub fn init(filter: &str, format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .with_ansi(std::io::stdout().is_terminal());
+    match format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+/// The span of a request, for the `TraceLayer`. `user` and `todo_id` are filled in by
+/// `record_request_fields`.
+pub fn request_span(request: &axum::http::Request<Body>) -> Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %redacted_uri(request.uri()),
+        version = ?request.version(),
+        user = field::Empty,
+        todo_id = field::Empty,
+    )
+}
+
+/// The URI with the token of a calendar feed and the value of any `password` query
+/// parameter replaced.
+fn redacted_uri(uri: &Uri) -> String {
+    let mut segments: Vec<&str> = uri.path().split('/').collect();
+    for index in 1..segments.len().saturating_sub(1) {
+        if segments[index - 1] == "calendar" && segments[index + 1] == "todos.ics" {
+            segments[index] = "***";
+        }
+    }
+    let path = segments.join("/");
+    let Some(query) = uri.query() else {
+        return path;
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("password", _)) => "password=***",
+            _ => pair,
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", path, query)
+}
+
+/// Middleware recording on the request span who made the request and, for the routes with
+/// a todo id in the path, which todo it is about. The app has a single user: requests with
+/// the password are recorded as `owner`, except CalDAV ones, which carry the user name the
+/// client was set up with.
+pub async fn record_request_fields(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let span = Span::current();
+    let (mut parts, body) = request.into_parts();
+    if let Some(user) = user(&state, &parts.uri, &parts.headers) {
+        span.record("user", user);
+    }
+    let about_todo = parts
+        .extensions
+        .get::<MatchedPath>()
+        .is_some_and(|path| path.as_str().contains("/todos/{id}"));
+    if about_todo {
+        if let Ok(params) = RawPathParams::from_request_parts(&mut parts, &state).await {
+            if let Some((_, id)) = params.iter().find(|(name, _)| *name == "id") {
+                span.record("todo_id", id);
+            }
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Records the todo a request is about, for the legacy routes that take its id in the body.
+pub fn record_todo_id(id: i64) {
+    Span::current().record("todo_id", id);
+}
+
+fn user(state: &AppState, uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    if let Some((name, password)) = basic_credentials(headers) {
+        return authenticate(&state.hashed_password, &password).then_some(name);
+    }
+    let query = Query::<PasswordQuery>::try_from_uri(uri)
+        .map(|Query(query)| query)
+        .unwrap_or_default();
+    extract_provided(&query, &CookieJar::from_headers(headers))
+        .filter(|password| authenticate(&state.hashed_password, password))
+        .map(|_| "owner".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_the_password() {
+        let uri: Uri = "/todos?date_less=2026-01-01&password=hunter2&limit=5"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            redacted_uri(&uri),
+            "/todos?date_less=2026-01-01&password=***&limit=5"
+        );
+        let uri: Uri = "/api/v1/todos/3".parse().unwrap();
+        assert_eq!(redacted_uri(&uri), "/api/v1/todos/3");
+    }
+
+    #[test]
+    fn redacts_the_calendar_token() {
+        let uri: Uri = "/calendar/s3cr3t/todos.ics?events=true".parse().unwrap();
+        assert_eq!(redacted_uri(&uri), "/calendar/***/todos.ics?events=true");
+        let uri: Uri = "/timely/calendar/s3cr3t/todos.ics".parse().unwrap();
+        assert_eq!(redacted_uri(&uri), "/timely/calendar/***/todos.ics");
+        let uri: Uri = "/calendar/token".parse().unwrap();
+        assert_eq!(redacted_uri(&uri), "/calendar/token");
+    }
+}
@@ -1,12 +1,14 @@
 use axum::{
-    extract::{self, Form, Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    extract::{self, Form, FromRequestParts, Query, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::{self, map_response_with_state},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::{get, post},
     Json, Router,
 };
 use axum_extra::extract::cookie::{Cookie, CookieJar};
-use dotenvy::dotenv;
+use clap::{Parser, Subcommand};
+use hmac::{Hmac, Mac};
 use serde::Deserialize;
 use sha2::{
     digest::{
@@ -18,87 +20,636 @@ use sha2::{
     },
     Digest, Sha256,
 };
-use sqlx::postgres::PgPool;
-use std::env;
-use time::{self, Date, Month};
-use timely_lib::{build_hierarchy, month_num_to_month, Done, Todo};
-use tower_http::trace::{
-    DefaultMakeSpan, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer,
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fs;
+use std::future::{self, Future, IntoFuture};
+use std::path::PathBuf;
+use std::pin::pin;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use time::{self, Date};
+use timely_lib::{
+    build_hierarchy, build_subtrees,
+    export::{ExportFormat, NestedTodo},
+    ical::dated_todos_calendar,
+    import::{count_todos, ImportFormat, ImportResult},
+    parse_iso_date,
+    quick_add::parse_quick_add,
+    Priority, SetDone, SyncResponse, Todo, TodoHierarchy,
 };
+use tower_http::trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer};
+use utoipa::{IntoParams, ToSchema};
 
 use tera::Tera;
 
+mod api;
+mod archive;
+mod batch;
+mod caldav;
+mod config;
+mod health;
+mod logging;
+mod metrics;
+mod migrations;
+mod openapi;
+mod storage;
+mod sync;
+#[cfg(test)]
+mod tests;
+mod webhooks;
+
+use metrics::Metrics;
+use storage::{NewTodo, Storage, StorageResult, TimedStorage, TodoChanges, TodoFilter};
+use tokio::{
+    net::TcpListener,
+    signal,
+    sync::{Notify, OnceCell},
+    time::{timeout_at, Instant},
+};
+use tokio_util::sync::CancellationToken;
 use tracing::Level;
-use tracing_subscriber;
+use uuid::Uuid;
+use webhooks::Event;
 
 #[derive(Clone)]
 struct AppState {
-    pool: PgPool,
+    storage: Arc<dyn Storage>,
     hashed_password: DigestedHash,
+    // key of the calendar token, read from the database on first use
+    calendar_key: Arc<OnceCell<String>>,
     templates: Tera,
-    running_on_subpath: bool,
+    // path the app is nested under, "" at the root
+    base_path: String,
+    trust_forwarded_prefix: bool,
+    // wakes the webhook delivery task when deliveries are queued
+    webhook_notify: Arc<Notify>,
+    metrics: Arc<Metrics>,
 }
 
-#[derive(Deserialize)]
+impl AppState {
+    fn new(
+        storage: Arc<dyn Storage>,
+        password: &str,
+        templates: Tera,
+        base_path: String,
+        trust_forwarded_prefix: bool,
+    ) -> Self {
+        // Compute the hash of the password (we use this both for API and web authentication)
+        let hashed_password = Sha256::digest(password);
+        // Every storage operation is timed for the metrics.
+        let metrics = Arc::new(Metrics::new());
+        let storage = Arc::new(TimedStorage::new(storage, metrics.query_duration.clone()));
+        AppState {
+            storage,
+            hashed_password,
+            calendar_key: Arc::new(OnceCell::new()),
+            templates,
+            base_path,
+            trust_forwarded_prefix,
+            webhook_notify: Arc::new(Notify::new()),
+            metrics,
+        }
+    }
+
+    /// The secret path segment of the calendar feed. The feed URL can't carry the password
+    /// (calendar apps store and share it), so it gets an HMAC of the password hash, which
+    /// changes along with the password. It is keyed with a random secret kept in the
+    /// database, so a leaked feed URL gives nothing away about the password.
+    async fn calendar_token(&self) -> StorageResult<String> {
+        Ok(format!(
+            "{:x}",
+            self.calendar_mac().await?.finalize().into_bytes()
+        ))
+    }
+
+    /// Whether `token` is the calendar token, compared in constant time.
+    async fn is_calendar_token(&self, token: &str) -> StorageResult<bool> {
+        let mut bytes = [0; 32];
+        let Ok(bytes) = base16ct::lower::decode(token, &mut bytes) else {
+            return Ok(false);
+        };
+        Ok(self.calendar_mac().await?.verify_slice(bytes).is_ok())
+    }
+
+    async fn calendar_mac(&self) -> StorageResult<Hmac<Sha256>> {
+        let key = self
+            .calendar_key
+            .get_or_try_init(|| async {
+                let generated = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+                self.storage.secret("calendar_token_key", &generated).await
+            })
+            .await?;
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(&self.hashed_password);
+        Ok(mac)
+    }
+}
+
+/// The path clients reach the app under, for the URLs it generates: the configured base
+/// path, after the `X-Forwarded-Prefix` of the reverse proxy in front of it if that is
+/// trusted. Empty when the app is at the root of the site. It holds only plain path
+/// characters, so the templates use it unescaped.
+struct BasePath(String);
+
+impl BasePath {
+    /// The URL of the web interface.
+    fn root(&self) -> &str {
+        if self.0.is_empty() {
+            "/"
+        } else {
+            &self.0
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for BasePath {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        let forwarded = if state.trust_forwarded_prefix {
+            parts
+                .headers
+                .get("x-forwarded-prefix")
+                .and_then(|prefix| prefix.to_str().ok())
+                .and_then(config::normalize_base_path)
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+        Ok(BasePath(forwarded + &state.base_path))
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
 struct CreateTodo {
     name: String,
     description: Option<String>,
     parent_id: Option<i64>,
+    /// `YYYY-MM-DD`
+    #[schema(format = Date)]
     date: Option<String>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 
-#[derive(Deserialize)]
+impl CreateTodo {
+    /// The todo to insert, once its name is checked and its date parsed.
+    fn into_new_todo(self) -> Result<NewTodo, (StatusCode, String)> {
+        if self.name.trim().is_empty() {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The todo needs a name".to_owned(),
+            ));
+        }
+        Ok(NewTodo {
+            date: parse_payload_date(self.date.as_deref())?,
+            name: self.name,
+            description: self.description,
+            parent_id: self.parent_id,
+            priority: self.priority.level(),
+            tags: self.tags,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct QuickAddRequest {
+    /// e.g. "Pay rent tomorrow !high #home under Finances"
+    text: String,
+    /// The client's date (`YYYY-MM-DD`), which relative dates are counted from; the
+    /// server's UTC date if omitted
+    #[schema(format = Date)]
+    today: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
 struct PasswordQuery {
     password: Option<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct DateQuery {
+    /// Only todos dated on or before this day (`YYYY-MM-DD`)
+    #[param(value_type = Option<String>, format = Date)]
     date_less: Option<Date>,
+    /// Only todos dated on or after this day (`YYYY-MM-DD`)
+    #[param(value_type = Option<String>, format = Date)]
     date_more: Option<Date>,
 }
 
+impl DateQuery {
+    /// The todo filter for the date range, and only archived or unarchived todos if
+    /// `archived` is set.
+    fn filter(&self, archived: Option<bool>) -> TodoFilter {
+        TodoFilter {
+            date_less: self.date_less,
+            date_more: self.date_more,
+            archived,
+        }
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ArchivedQuery {
+    /// Include archived todos, which are left out by default
+    #[serde(default)]
+    include_archived: bool,
+}
+
+impl ArchivedQuery {
+    /// The `archived` filter of the todo queries: only unarchived todos unless asked.
+    fn filter(&self) -> Option<bool> {
+        if self.include_archived {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+/// Cursor pagination of a flat list ordered by id.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PageQuery {
+    /// Cursor from the previous page: only todos after it are returned
+    after: Option<i64>,
+    /// Page size, from 1 to 500; everything after the cursor if omitted
+    limit: Option<i64>,
+}
+
+/// Pagination of the top-level todos of a tree, and how deep to go below them.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct TreeQuery {
+    /// Cursor from the previous page: only top-level todos after it are returned
+    after: Option<i64>,
+    /// Number of top-level todos, from 1 to 500 (default 50)
+    limit: Option<i64>,
+    /// Levels of descendants to include below each top-level todo; all if omitted
+    #[param(minimum = 0)]
+    max_depth: Option<i32>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ExportQuery {
+    format: ExportFormat,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImportQuery {
+    format: ImportFormat,
+    /// Id of the todo to import the todos under
+    parent_id: Option<i64>,
+    /// Only parse the input, without creating anything
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CalendarQuery {
+    /// Add an all-day event for each todo next to its VTODO
+    #[serde(default)]
+    events: bool,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SyncQuery {
+    /// Change token from the previous sync; every todo is returned without one
+    since: Option<i64>,
+}
+
+/// How the web interface loads the todo tree: the cursor of the page of top-level todos
+/// and how many levels of descendants to show before loading them on demand.
+#[derive(Deserialize)]
+struct WebTreeQuery {
+    after: Option<i64>,
+    depth: Option<i32>,
+}
+
+impl WebTreeQuery {
+    fn depth(&self) -> i32 {
+        self.depth.unwrap_or(WEB_TREE_DEPTH).max(0)
+    }
+}
+
 // For the login form (from the web UI)
 #[derive(Deserialize)]
 struct LoginForm {
     password: String,
 }
 
+/// Largest page the paginated API endpoints return.
+const MAX_PAGE_SIZE: i64 = 500;
+/// Top-level todos in a page of the tree, unless the client asks for another size.
+const TREE_PAGE_SIZE: i64 = 50;
+/// Levels of descendants the web interface shows below a top-level todo (or below a todo
+/// whose children were loaded on demand) unless the page asks for another depth.
+const WEB_TREE_DEPTH: i32 = 3;
+
 type DigestedHash =
     GenericArray<u8, UInt<UInt<UInt<UInt<UInt<UInt<UTerm, B1>, B0>, B0>, B0>, B0>, B0>>;
 
+#[derive(Parser)]
+#[command(version, about = "Timely todo server")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Don't apply pending database migrations when the server starts
+    #[arg(long, global = true)]
+    no_migrate: bool,
+    /// Check the configuration, print it and exit
+    #[arg(long)]
+    check_config: bool,
+    #[command(flatten)]
+    config: config::ConfigArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the web server (the default)
+    Serve,
+    /// Apply pending database migrations and exit
+    Migrate,
+    /// Import todos from a Markdown checklist, todo.txt or JSON file
+    Import {
+        file: PathBuf,
+        /// json, markdown or todotxt; guessed from the file extension if omitted
+        #[arg(long)]
+        format: Option<ImportFormat>,
+        /// Id of the todo to import the todos under
+        #[arg(long)]
+        parent: Option<i64>,
+        /// Only print what would be created
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Export the todo tree as JSON, CSV, Markdown or todo.txt
+    Export {
+        /// json, csv, markdown or todotxt; guessed from the output file extension if
+        /// omitted, JSON otherwise
+        #[arg(long)]
+        format: Option<ExportFormat>,
+        /// Only todos due on or after this day (YYYY-MM-DD), with their ancestors
+        #[arg(long, value_parser = parse_date_arg)]
+        from: Option<Date>,
+        /// Only todos due on or before this day (YYYY-MM-DD), with their ancestors
+        #[arg(long, value_parser = parse_date_arg)]
+        to: Option<Date>,
+        /// File to write the export to instead of standard output
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+fn parse_date_arg(date: &str) -> Result<Date, String> {
+    parse_iso_date(date).ok_or_else(|| format!("{:?} is not a YYYY-MM-DD date", date))
+}
+
 #[tokio::main]
 async fn main() {
-    if cfg!(debug_assertions) {
-        tracing_subscriber::fmt()
-            .with_max_level(Level::TRACE)
-            .init();
+    let cli = Cli::parse();
+    let settings = config::load(&cli.config).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if cli.check_config {
+        match settings.server() {
+            Ok(config) => println!("{}", config),
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
     }
-    dotenv().expect(".env file not found");
+    let logging = settings.logging().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    logging::init(&logging.level, logging.format);
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
-    let service_url = env::var("SERVICE_URL").expect("SERVICE_URL not set");
-    let password = env::var("PASSWORD").expect("PASSWORD not set");
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(settings, cli.no_migrate).await,
+        Command::Migrate => migrate_database(settings).await,
+        Command::Import {
+            file,
+            format,
+            parent,
+            dry_run,
+        } => import_file(settings, file, format, parent, dry_run).await,
+        Command::Export {
+            format,
+            from,
+            to,
+            output,
+        } => export_file(settings, format, from, to, output).await,
+    }
+}
 
-    // Compute the hash of the password (we use this both for API and web authentication)
-    let hashed_password = Sha256::digest(password);
+async fn serve(settings: config::Settings, no_migrate: bool) {
+    let config = settings.server().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
 
-    println!("Using database url: {}", &database_url);
-    let pool = PgPool::connect(&database_url).await.unwrap();
+    tracing::info!(
+        database_url = %config::redacted_url(&config.database_url),
+        "Connecting to the database"
+    );
+    let storage = connect_storage(&config.database_url).await;
+    if no_migrate {
+        let pending = migrations::pending(&*storage).await.unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        if !pending.is_empty() {
+            tracing::warn!(
+                pending = pending.len(),
+                "Not applying the pending migrations (--no-migrate)"
+            );
+        }
+    } else {
+        let applied = migrations::run(&*storage).await.unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        for migration in applied {
+            tracing::info!(
+                migration = %migrations::describe(migration),
+                "Applied migration"
+            );
+        }
+    }
 
     // Initialize Tera – assuming your templates are in a folder named "templates"
-    let templates = Tera::new("templates/**/*").expect("Error initializing Tera");
+    let templates = Tera::new("templates/**/*").unwrap_or_else(|err| {
+        eprintln!("Could not load the templates: {}", err);
+        process::exit(1);
+    });
 
-    let run_on_subpath_env = env::var("RUN_ON_SUBPATH");
+    let base_path = config.base_path;
+    let app_state = AppState::new(
+        storage,
+        &config.password,
+        templates,
+        base_path.clone(),
+        config.trust_forwarded_prefix,
+    );
 
-    let run_on_subpath = run_on_subpath_env.is_ok_and(|run| run.to_lowercase() == "true");
+    let service_url = config.service_url;
+    let listener = TcpListener::bind(&service_url).await.unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {}", service_url, err);
+        process::exit(1);
+    });
+    tracing::info!("Listening on http://{}{}", service_url, base_path);
+    run(
+        listener,
+        app_state,
+        config.archive_after_days,
+        config.tombstone_retention_days,
+        config.drain_timeout,
+        shutdown_signal(),
+    )
+    .await;
+}
 
-    let app_state = AppState {
-        pool,
-        hashed_password,
-        templates,
-        running_on_subpath: run_on_subpath,
+/// Serves, with the background tasks, until `signal` resolves. Then no more connections
+/// are taken, and within `drain_timeout` the requests in flight get to finish, the
+/// background tasks send what is left in their queues, and the pool is closed.
+async fn run(
+    listener: TcpListener,
+    app_state: AppState,
+    archive_after_days: Option<i32>,
+    tombstone_retention_days: i32,
+    drain_timeout: Duration,
+    signal: impl Future<Output = ()> + Send + 'static,
+) {
+    let storage = app_state.storage.clone();
+    let stop = CancellationToken::new();
+    let mut tasks = vec![
+        tokio::spawn(webhooks::run_deliveries(
+            storage.clone(),
+            app_state.webhook_notify.clone(),
+            stop.clone(),
+        )),
+        tokio::spawn(sync::run_pruning(
+            storage.clone(),
+            tombstone_retention_days,
+            stop.clone(),
+        )),
+    ];
+    if let Some(days) = archive_after_days {
+        tasks.push(tokio::spawn(archive::run_scheduled(
+            storage.clone(),
+            days,
+            stop.clone(),
+        )));
+    }
+
+    let signalled = CancellationToken::new();
+    let server = axum::serve(listener, app(app_state))
+        .with_graceful_shutdown(signalled.clone().cancelled_owned())
+        .into_future();
+    let mut server = pin!(server);
+    tokio::select! {
+        result = &mut server => {
+            // Only ends by itself if it fails
+            if let Err(err) = result {
+                tracing::error!(error = %err, "The server failed");
+            }
+        }
+        _ = signal => {
+            tracing::info!(
+                timeout_seconds = drain_timeout.as_secs(),
+                "Shutting down, waiting for the requests in flight"
+            );
+            signalled.cancel();
+        }
+    }
+    let deadline = Instant::now() + drain_timeout;
+    match timeout_at(deadline, &mut server).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::error!(error = %err, "The server failed"),
+        Err(_) => tracing::warn!("Requests still in flight after the drain timeout are cut off"),
+    }
+
+    // After the requests, so that what they queued is sent too
+    stop.cancel();
+    let tasks = async {
+        for task in tasks {
+            if let Err(err) = task.await {
+                tracing::error!(error = %err, "A background task failed");
+            }
+        }
     };
+    if timeout_at(deadline, tasks).await.is_err() {
+        tracing::warn!("The background tasks did not finish before the drain timeout");
+    }
+    if timeout_at(deadline, storage.close()).await.is_err() {
+        tracing::warn!("The database connections were not all given back before the drain timeout");
+    }
+    tracing::info!("Stopped");
+}
+
+/// Resolves on SIGTERM, as sent by service managers and orchestrators, or Ctrl-C.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = signal::ctrl_c().await {
+            tracing::error!(error = %err, "Could not listen for Ctrl-C");
+            future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "Could not listen for SIGTERM");
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
+
+/// The whole app: the web interface, both APIs, the calendar feed and CalDAV, under the
+/// base path if there is one.
+fn app(app_state: AppState) -> Router {
+    let base_path = app_state.base_path.clone();
+
+    // The API routes from before /api/v1, kept for existing clients.
+    #[allow(deprecated)]
+    let legacy_api = Router::new()
+        .route(
+            "/todos",
+            get(get_todos).post(create_todo).delete(delete_todo),
+        )
+        .route("/todos/toggle", post(toggle_todo))
+        .route("/todos/done", post(set_done))
+        .route("/todos/quick", post(quick_add_todo))
+        .route("/todos/export", get(export_todos))
+        .route("/todos/import", post(import_todos))
+        .route("/sync", get(sync_todos))
+        .layer(map_response_with_state(app_state.clone(), mark_deprecated));
 
     // Build the app with both web and API routes.
     let app = Router::new()
@@ -106,292 +657,865 @@ async fn main() {
         .route("/", get(web_index))
         .route("/login", post(login))
         .route("/logout", get(logout))
+        .route("/archive", get(web_archive))
+        .route("/fragments/todos", get(web_todo_page))
+        .route("/fragments/todos/{id}/children", get(web_todo_children))
         // API endpoints:
-        .route(
-            "/todos",
-            get(get_todos).post(create_todo).delete(delete_todo),
-        )
-        .route("/todos/toggle", post(toggle_todo))
+        .nest("/api/v1", api::router())
+        .merge(legacy_api)
+        .route("/calendar/{token}/todos.ics", get(calendar_feed))
+        // CalDAV, for task clients
+        .merge(caldav::router())
+        .merge(openapi::router())
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            metrics::record_request,
+        ))
+        // Inside the trace layer, so it runs in the request's span
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            logging::record_request_fields,
+        ))
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO)) // Log requests
+                .make_span_with(logging::request_span) // Log requests, without their password
                 .on_request(DefaultOnRequest::new().level(Level::INFO)) // Log request details
                 .on_response(DefaultOnResponse::new().level(Level::INFO)) // Log response details
                 .on_failure(DefaultOnFailure::new().level(Level::ERROR)),
         )
+        // After the metrics and trace layers, which only wrap the routes before them
+        .merge(health::router())
+        .merge(metrics::router())
         .with_state(app_state);
-    let listener = tokio::net::TcpListener::bind(&service_url).await.unwrap();
-    if run_on_subpath {
-        let subpath_router = Router::new().nest("/timely", app);
-        println!("Listening on http://{}/timely", service_url);
-        axum::serve(listener, subpath_router).await.unwrap()
+    if !base_path.is_empty() {
+        Router::new().nest(&base_path, app)
     } else {
-        println!("Listening on http://{}", service_url);
-        axum::serve(listener, app).await.unwrap()
+        app
+    }
+}
+
+/// CLI: Apply the pending migrations to the database, printing them.
+async fn migrate_database(settings: config::Settings) {
+    let storage = connect_storage(&database_url(&settings)).await;
+    let applied = migrations::run(&*storage).await.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    if applied.is_empty() {
+        println!("The database is up to date");
+    }
+    for migration in applied {
+        println!("Applied migration {}", migrations::describe(migration));
+    }
+}
+
+/// CLI: Import todos from a file, printing what is (or would be) created.
+async fn import_file(
+    settings: config::Settings,
+    file: PathBuf,
+    format: Option<ImportFormat>,
+    parent_id: Option<i64>,
+    dry_run: bool,
+) {
+    let Some(format) = format.or_else(|| ImportFormat::from_path(&file)) else {
+        eprintln!(
+            "Cannot guess the format of {}, pass --format",
+            file.display()
+        );
+        process::exit(1);
+    };
+    let mut forest = fs::read_to_string(&file)
+        .map_err(|err| err.to_string())
+        .and_then(|input| format.parse(&input))
+        .unwrap_or_else(|err| {
+            eprintln!("Could not read {}: {}", file.display(), err);
+            process::exit(1);
+        });
+
+    if !dry_run {
+        let storage = connect_storage(&database_url(&settings)).await;
+        match migrations::pending(&*storage).await {
+            Ok(pending) if pending.is_empty() => {}
+            Ok(pending) => {
+                eprintln!(
+                    "The database has {} pending migration(s), run `timely migrate` first",
+                    pending.len()
+                );
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        let created = storage
+            .create_forest(parent_id, &mut forest)
+            .await
+            .unwrap_or_else(|(_, err)| {
+                eprintln!("Import failed, nothing was created: {}", err);
+                process::exit(1);
+            });
+        // A running server sends the queued webhooks on its next poll.
+        let notify = Notify::new();
+        for todo in &created {
+            webhooks::enqueue(&*storage, &notify, Event::Created, todo).await;
+        }
+    }
+
+    print_import_preview(&forest, 0);
+    let created = count_todos(&forest);
+    if dry_run {
+        println!("{} todo(s) would be created", created);
+    } else {
+        println!("Created {} todo(s)", created);
+    }
+}
+
+async fn export_file(
+    settings: config::Settings,
+    format: Option<ExportFormat>,
+    from: Option<Date>,
+    to: Option<Date>,
+    output: Option<PathBuf>,
+) {
+    let format = format
+        .or_else(|| output.as_deref().and_then(ExportFormat::from_path))
+        .unwrap_or(ExportFormat::Json);
+    let storage = connect_storage(&database_url(&settings)).await;
+    let trees = export_trees(&*storage, to, from)
+        .await
+        .unwrap_or_else(|(_, err)| {
+            eprintln!("Export failed: {}", err);
+            process::exit(1);
+        });
+    let export = format.export(&trees);
+    match output {
+        Some(file) => fs::write(&file, export).unwrap_or_else(|err| {
+            eprintln!("Could not write {}: {}", file.display(), err);
+            process::exit(1);
+        }),
+        None => print!("{}", export),
+    }
+}
+
+/// The database of the configuration, or exits with the reason it has none.
+fn database_url(settings: &config::Settings) -> String {
+    settings.database_url().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    })
+}
+
+/// Connects to the database, or exits with the reason it can't.
+async fn connect_storage(database_url: &str) -> Arc<dyn Storage> {
+    storage::connect(database_url).await.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    })
+}
+
+fn print_import_preview(forest: &[NestedTodo], depth: usize) {
+    for todo in forest {
+        let mut line = format!(
+            "{}- [{}] {}",
+            "  ".repeat(depth),
+            if todo.done { "x" } else { " " },
+            todo.name
+        );
+        if let Some(date) = todo.date {
+            line.push_str(&format!(" due:{}", date));
+        }
+        if let Some(id) = todo.id {
+            line.push_str(&format!(" (id {})", id));
+        }
+        println!("{}", line);
+        print_import_preview(&todo.children, depth + 1);
     }
 }
 
 fn authenticate(original_hash: &DigestedHash, provided_pass: &String) -> bool {
-    if Sha256::digest(provided_pass).eq(original_hash) {
-        return true;
+    Sha256::digest(provided_pass).eq(original_hash)
+}
+
+/// Helper for API endpoints: the version a mutation expects from its `If-Match` header,
+/// or `None` if any version is accepted.
+fn expected_version(headers: &HeaderMap) -> Result<Option<i64>, (StatusCode, String)> {
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Invalid If-Match header".to_owned(),
+            )
+        })?
+        .trim();
+    if value == "*" {
+        return Ok(None);
+    }
+    value
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .map(Some)
+        .map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Invalid If-Match header".to_owned(),
+            )
+        })
+}
+
+/// Longest `Idempotency-Key` accepted.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Helper for create endpoints: the key from the `Idempotency-Key` header, which makes
+/// retrying the same create return the first todo instead of adding another.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, (StatusCode, String)> {
+    let Some(value) = headers.get("idempotency-key") else {
+        return Ok(None);
+    };
+    match value.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => {
+            Ok(Some(key.to_owned()))
+        }
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            "Invalid Idempotency-Key header".to_owned(),
+        )),
+    }
+}
+
+/// Helper for API endpoints: the `ETag` header for a todo at the given version.
+fn etag(version: i64) -> [(HeaderName, String); 1] {
+    [(header::ETAG, format!("\"{}\"", version))]
+}
+
+/// Helper for paginated endpoints: rejects page sizes out of range.
+fn check_page_size(limit: i64) -> Result<i64, (StatusCode, String)> {
+    if (1..=MAX_PAGE_SIZE).contains(&limit) {
+        Ok(limit)
     } else {
-        return false;
+        Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("limit must be between 1 and {}", MAX_PAGE_SIZE),
+        ))
+    }
+}
+
+/// Helper for the legacy API routes: marks their responses as deprecated (RFC 9745),
+/// pointing clients at `/api/v1`.
+async fn mark_deprecated(BasePath(base): BasePath, mut response: Response) -> Response {
+    let successor = format!("<{}/api/v1/todos>; rel=\"successor-version\"", base);
+    let headers = response.headers_mut();
+    // @-prefixed Unix time of the deprecation, 2026-10-18
+    headers.insert("deprecation", HeaderValue::from_static("@1792281600"));
+    if let Ok(successor) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, successor);
     }
+    response
 }
 
 /// Helper for API endpoints: extract a provided password from either the query or a cookie.
 fn extract_provided(query: &PasswordQuery, cookies: &CookieJar) -> Option<String> {
     query
         .password
-        .as_ref()
-        .and_then(|p| Some(p.clone()))
+        .clone()
         .or_else(|| cookies.get("auth").map(|c| c.value().to_owned()))
 }
 
-/// API: Get all todos.
+/// API: Get all todos, or a page of them when `limit` is given.
+#[utoipa::path(
+    get,
+    path = "/todos",
+    tag = "todos",
+    params(DateQuery, PageQuery, ArchivedQuery),
+    responses(
+        (status = 200, description = "The todos, or those in the date range, ordered by id", body = Vec<Todo>,
+            headers(("X-Next-Cursor" = i64, description = "Pass as `after` to get the next page; only sent when there is one"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 422, description = "`limit` out of range"),
+    )
+)]
 async fn get_todos(
     Query(query): Query<PasswordQuery>,
     Query(date_query): Query<DateQuery>,
+    Query(page_query): Query<PageQuery>,
+    Query(archived_query): Query<ArchivedQuery>,
     cookies: CookieJar,
     State(state): State<AppState>,
-) -> Result<Json<Vec<Todo>>, (StatusCode, String)> {
-    println!("getting todos");
+) -> Result<(HeaderMap, Json<Vec<Todo>>), (StatusCode, String)> {
     let provided = extract_provided(&query, &cookies);
     if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
-        println!("getting todos inner");
-        get_todos_json_inner(&state.pool, date_query.date_less, date_query.date_more).await
+        let limit = page_query.limit.map(check_page_size).transpose()?;
+        let (todos, next_cursor) = get_todo_page(
+            &*state.storage,
+            date_query.filter(archived_query.filter()),
+            page_query.after,
+            limit,
+        )
+        .await?;
+        let mut headers = HeaderMap::new();
+        if let Some(cursor) = next_cursor {
+            headers.insert("x-next-cursor", HeaderValue::from(cursor));
+        }
+        Ok((headers, Json(todos)))
     } else {
         Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
     }
 }
 
+/// Helper to get the unarchived todos.
 async fn get_todos_inner(
-    pool: &PgPool,
+    storage: &dyn Storage,
     date_less: Option<Date>,
     date_more: Option<Date>,
 ) -> Result<Vec<Todo>, (StatusCode, String)> {
-    let todos = if date_less.is_none() && date_more.is_none() {
-        sqlx::query_as!(
-            Todo,
-            r#"
-                        SELECT id, name, done, description, parent_id, date
-                        FROM todos
-                        ORDER BY id
-                    "#
-        )
-        .fetch_all(pool)
-        .await
-    } else if date_less.is_some() {
-        sqlx::query_as!(
-            Todo,
-            r#"
-                        SELECT id, name, done, description, parent_id, date
-                        FROM todos
-                        WHERE date <= $1
-                        ORDER BY id
-                    "#,
-            date_less.unwrap()
-        )
-        .fetch_all(pool)
-        .await
-    } else if date_more.is_some() {
-        sqlx::query_as!(
-            Todo,
-            r#"
-                        SELECT id, name, done, description, parent_id, date
-                        FROM todos
-                        WHERE date >= $1
-                        ORDER BY id
-                    "#,
-            date_more.unwrap()
-        )
-        .fetch_all(pool)
+    let filter = TodoFilter {
+        date_less,
+        date_more,
+        archived: Some(false),
+    };
+    get_todo_page(storage, filter, None, None)
         .await
+        .map(|(todos, _)| todos)
+}
+
+/// Helper to get the unarchived trees to export. With a date filter, the ancestors of the
+/// matching todos come along, so that every match keeps its place and its full path.
+async fn export_trees(
+    storage: &dyn Storage,
+    date_less: Option<Date>,
+    date_more: Option<Date>,
+) -> Result<Vec<TodoHierarchy>, (StatusCode, String)> {
+    let all = get_todos_inner(storage, None, None).await?;
+    if date_less.is_none() && date_more.is_none() {
+        return Ok(build_hierarchy(all));
+    }
+    let matching = get_todos_inner(storage, date_less, date_more).await?;
+    let parents: HashMap<i64, Option<i64>> =
+        all.iter().map(|todo| (todo.id, todo.parent_id)).collect();
+    let mut keep = HashSet::new();
+    for todo in &matching {
+        let mut id = Some(todo.id);
+        while let Some(current) = id {
+            if !keep.insert(current) {
+                break;
+            }
+            id = parents.get(&current).copied().flatten();
+        }
+    }
+    let todos = all
+        .into_iter()
+        .filter(|todo| keep.contains(&todo.id))
+        .collect();
+    Ok(build_hierarchy(todos))
+}
+
+/// Helper to get the todos matching `filter` after the `after` cursor (an id), at most
+/// `limit` of them. Also returns the cursor of the next page, if there is one.
+async fn get_todo_page(
+    storage: &dyn Storage,
+    filter: TodoFilter,
+    after: Option<i64>,
+    limit: Option<i64>,
+) -> Result<(Vec<Todo>, Option<i64>), (StatusCode, String)> {
+    // One more than asked for, to tell whether there is a next page
+    let mut todos = storage
+        .list_todos(filter, after, limit.map(|limit| limit + 1))
+        .await?;
+    let next_cursor = match limit {
+        Some(limit) if todos.len() as i64 > limit => {
+            todos.truncate(limit as usize);
+            todos.last().map(|todo| todo.id)
+        }
+        _ => None,
+    };
+    Ok((todos, next_cursor))
+}
+
+/// Helper for the tree views: a page of top-level todos matching `filter` after the
+/// `after` cursor (an id), with their descendants down to `max_depth` levels below them.
+/// A tree is archived as a whole. Also returns the cursor of the next page, if there is
+/// one.
+async fn get_tree_page(
+    storage: &dyn Storage,
+    filter: TodoFilter,
+    after: Option<i64>,
+    limit: i64,
+    max_depth: Option<i32>,
+) -> Result<(Vec<TodoHierarchy>, Option<i64>), (StatusCode, String)> {
+    let mut root_ids = storage.root_ids(filter, after, limit + 1).await?;
+    let next_cursor = if root_ids.len() as i64 > limit {
+        root_ids.truncate(limit as usize);
+        root_ids.last().copied()
     } else {
-        sqlx::query_as!(
-            Todo,
-            r#"
-                        SELECT id, name, done, description, parent_id, date
-                        FROM todos
-                        WHERE date BETWEEN $1 AND $2
-                        ORDER BY id
-                    "#,
-            date_more.unwrap(),
-            date_less.unwrap()
-        )
-        .fetch_all(pool)
-        .await
+        None
     };
-    match todos {
-        Ok(todos_vec) => Ok(todos_vec),
-        Err(err) => Err(internal_error(err)),
+    let trees = get_subtrees_inner(storage, &root_ids, max_depth, filter).await?;
+    Ok((trees, next_cursor))
+}
+
+/// Helper to get the trees under `root_ids`, down to `max_depth` levels below them.
+/// Descendants outside the date range of `filter` are left out along with their own
+/// descendants. Todos whose children were cut off still get their `child_count`.
+async fn get_subtrees_inner(
+    storage: &dyn Storage,
+    root_ids: &[i64],
+    max_depth: Option<i32>,
+    filter: TodoFilter,
+) -> Result<Vec<TodoHierarchy>, (StatusCode, String)> {
+    let todos = storage.subtree_todos(root_ids, max_depth, filter).await?;
+    let mut trees = build_subtrees(root_ids, todos);
+
+    if max_depth.is_some() {
+        let leaf_ids: Vec<i64> = trees.iter().flat_map(TodoHierarchy::leaf_ids).collect();
+        let counts = storage.child_counts(&leaf_ids, filter).await?;
+        for tree in &mut trees {
+            tree.set_unloaded_child_counts(&counts);
+        }
     }
+    Ok(trees)
 }
+
 /// API: Helper function to get todos.
 async fn get_todos_json_inner(
-    pool: &PgPool,
+    storage: &dyn Storage,
     date_less: Option<Date>,
     date_more: Option<Date>,
 ) -> Result<Json<Vec<Todo>>, (StatusCode, String)> {
-    let todos = get_todos_inner(pool, date_less, date_more).await;
+    let todos = get_todos_inner(storage, date_less, date_more).await;
     match todos {
         Ok(todos_vec) => Ok(Json(todos_vec)),
         Err(err) => Err(err),
     }
 }
 
+/// API: Export the todo tree as JSON, CSV, Markdown or todo.txt.
+/// Takes the same date filters as `GET /todos`.
+#[utoipa::path(
+    get,
+    path = "/todos/export",
+    tag = "import and export",
+    params(DateQuery, ExportQuery),
+    responses(
+        (status = 200, description = "The todo tree as a file download", content(
+            (Vec<NestedTodo> = "application/json"),
+            (String = "text/csv"),
+            (String = "text/markdown"),
+            (String = "text/plain"),
+        )),
+        (status = 401, description = "Missing or wrong password"),
+    )
+)]
+async fn export_todos(
+    Query(query): Query<PasswordQuery>,
+    Query(date_query): Query<DateQuery>,
+    Query(export_query): Query<ExportQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let trees =
+            export_trees(&*state.storage, date_query.date_less, date_query.date_more).await?;
+        let format = export_query.format;
+        Ok((
+            [
+                (header::CONTENT_TYPE, format.content_type().to_owned()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}\"", format.file_name()),
+                ),
+            ],
+            format.export(&trees),
+        ))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: iCalendar feed of the dated todos, for subscribing from calendar apps.
+/// Authenticated by the secret token in the path; `events=true` adds an all-day event
+/// for each todo next to its VTODO.
+#[utoipa::path(
+    get,
+    path = "/calendar/{token}/todos.ics",
+    tag = "calendar",
+    params(
+        ("token" = String, Path, description = "The calendar token shown in the web interface"),
+        CalendarQuery,
+    ),
+    security(()),
+    responses(
+        (status = 200, description = "iCalendar feed of the dated todos", content_type = "text/calendar", body = String),
+        (status = 404, description = "Wrong token"),
+    )
+)]
+async fn calendar_feed(
+    extract::Path(token): extract::Path<String>,
+    Query(calendar_query): Query<CalendarQuery>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if !state.is_calendar_token(&token).await? {
+        return Err((StatusCode::NOT_FOUND, "Not found".to_owned()));
+    }
+    let todos = get_todos_inner(&*state.storage, None, None).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        dated_todos_calendar(
+            &todos,
+            calendar_query.events,
+            time::OffsetDateTime::now_utc(),
+        ),
+    ))
+}
+
+/// API: Import todos from a Markdown checklist, todo.txt or JSON request body, under an
+/// optional parent. With `dry_run=true` nothing is created and the parsed todos are returned.
+#[utoipa::path(
+    post,
+    path = "/todos/import",
+    tag = "import and export",
+    params(ImportQuery),
+    request_body(
+        content = String,
+        description = "A Markdown checklist, todo.txt file or JSON export",
+        content_type = "text/plain",
+    ),
+    responses(
+        (status = 200, description = "The created (or, on a dry run, parsed) todos", body = ImportResult),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "The parent todo does not exist"),
+        (status = 422, description = "The input could not be parsed"),
+    )
+)]
+async fn import_todos(
+    Query(query): Query<PasswordQuery>,
+    Query(import_query): Query<ImportQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    body: String,
+) -> Result<Json<ImportResult>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let mut todos = import_query
+            .format
+            .parse(&body)
+            .map_err(|err| (StatusCode::UNPROCESSABLE_ENTITY, err))?;
+        if !import_query.dry_run {
+            let created = state
+                .storage
+                .create_forest(import_query.parent_id, &mut todos)
+                .await?;
+            for todo in &created {
+                webhooks::enqueue(&*state.storage, &state.webhook_notify, Event::Created, todo)
+                    .await;
+            }
+        }
+        Ok(Json(ImportResult {
+            dry_run: import_query.dry_run,
+            created: count_todos(&todos),
+            todos,
+        }))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
 /// API: Create a new todo.
 async fn create_todo(
     query: Query<PasswordQuery>,
     cookies: CookieJar,
     State(state): State<AppState>,
+    headers: HeaderMap,
     extract::Json(payload): extract::Json<CreateTodo>,
-) -> Result<Json<Todo>, (StatusCode, String)> {
-    println!("creating todo!");
+) -> Result<([(HeaderName, String); 1], Json<Todo>), (StatusCode, String)> {
     let provided = extract_provided(&query, &cookies);
-    let date_from_payload_opt = payload.date;
-    let converted_date: Option<Date> = if let Some(date_from_payload) = date_from_payload_opt {
-        let trimmed_date = date_from_payload.trim().to_owned();
-        if trimmed_date != "" {
-            tracing::debug!("Date is: {}", &trimmed_date);
-            let mut split_date = trimmed_date.split("-");
-            let year: i32 = split_date.next().unwrap().parse().unwrap();
-            let month: Option<Month> =
-                month_num_to_month(split_date.next().unwrap().parse().unwrap());
-            let day: u8 = split_date.next().unwrap().parse().unwrap();
-            Some(Date::from_calendar_date(year, month.unwrap(), day).unwrap())
-        } else {
-            None
-        }
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let key = idempotency_key(&headers)?;
+        let record = insert_todo(&state, key.as_deref(), payload).await?;
+        Ok((etag(record.version), Json(record)))
     } else {
-        tracing::debug!("Date is none");
-        None
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// Helper to parse an optional `YYYY-MM-DD` date sent by a client; blank means no date.
+fn parse_payload_date(date: Option<&str>) -> Result<Option<Date>, (StatusCode, String)> {
+    match date.map(str::trim).filter(|date| !date.is_empty()) {
+        Some(date) => parse_iso_date(date).map(Some).ok_or_else(|| {
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Invalid date {:?}", date),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Helper to insert a todo sent by a client and notify the webhooks. With an idempotency
+/// key, a create already done under that key returns its todo without notifying again.
+async fn insert_todo(
+    state: &AppState,
+    key: Option<&str>,
+    payload: CreateTodo,
+) -> Result<Todo, (StatusCode, String)> {
+    let todo = payload.into_new_todo()?;
+    let record = match key {
+        Some(key) => match state.storage.create_todo_once(key, todo).await? {
+            (record, true) => record,
+            (record, false) => return Ok(record),
+        },
+        None => state.storage.create_todo(todo).await?,
     };
+    webhooks::enqueue(
+        &*state.storage,
+        &state.webhook_notify,
+        Event::Created,
+        &record,
+    )
+    .await;
+    Ok(record)
+}
+
+/// API: Create a todo from a single line such as "Pay rent tomorrow !high #home under
+/// Finances"; see `timely_lib::quick_add` for the syntax. The parent is looked up by name.
+#[utoipa::path(
+    post,
+    path = "/todos/quick",
+    tag = "todos",
+    request_body = QuickAddRequest,
+    responses(
+        (status = 200, description = "The created todo", body = Todo, headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 422, description = "The text has no name, an invalid `today` or an unknown parent"),
+    )
+)]
+async fn quick_add_todo(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    extract::Json(payload): extract::Json<QuickAddRequest>,
+) -> Result<([(HeaderName, String); 1], Json<Todo>), (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
     if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
-        let new_todo = sqlx::query_as!(
-            Todo,
-            r#"
-            INSERT INTO todos (name, description, parent_id, date)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, name, done, description, parent_id, date
-            "#,
-            payload.name,
-            payload.description,
-            payload.parent_id,
-            converted_date
+        let today = match payload.today.as_deref() {
+            Some(today) => parse_iso_date(today).ok_or_else(|| {
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Invalid date {:?}", today),
+                )
+            })?,
+            None => time::OffsetDateTime::now_utc().date(),
+        };
+        let parsed = parse_quick_add(&payload.text, today);
+        if parsed.name.is_empty() {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The todo needs a name".to_owned(),
+            ));
+        }
+
+        let parent_id = match &parsed.parent {
+            // Prefer open todos when several have the name.
+            Some(parent) => Some(state.storage.find_by_name(parent).await?.ok_or_else(|| {
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("There is no todo named {:?}", parent),
+                )
+            })?),
+            None => None,
+        };
+
+        let new_todo = state
+            .storage
+            .create_todo(NewTodo {
+                name: parsed.name,
+                parent_id,
+                date: parsed.date,
+                priority: parsed.priority.level(),
+                tags: parsed.tags,
+                ..Default::default()
+            })
+            .await?;
+        webhooks::enqueue(
+            &*state.storage,
+            &state.webhook_notify,
+            Event::Created,
+            &new_todo,
         )
-        .fetch_one(&state.pool)
         .await;
-
-        match new_todo {
-            Ok(record) => Ok(Json(record)),
-            Err(err) => Err(internal_error(err)),
-        }
+        Ok((etag(new_todo.version), Json(new_todo)))
     } else {
         Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
     }
 }
 
 /// API: Delete a todo (and its descendants).
+#[deprecated = "use DELETE /api/v1/todos/{id}"]
+#[utoipa::path(
+    delete,
+    path = "/todos",
+    tag = "todos",
+    params(
+        DateQuery,
+        ("If-Match" = Option<String>, Header, description = "Only delete the todo at this version"),
+    ),
+    request_body(content = i64, description = "Id of the todo to delete"),
+    responses(
+        (status = 200, description = "The remaining todos, filtered like `GET /todos`", body = Vec<Todo>),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo was changed since the `If-Match` version"),
+    )
+)]
 async fn delete_todo(
     Query(query): Query<PasswordQuery>,
     Query(date_query): Query<DateQuery>,
     cookies: CookieJar,
+    headers: HeaderMap,
     State(state): State<AppState>,
     extract::Json(id_to_delete): extract::Json<i64>,
 ) -> Result<Json<Vec<Todo>>, (StatusCode, String)> {
+    logging::record_todo_id(id_to_delete);
     let provided = extract_provided(&query, &cookies);
     if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
-        // 1. Fetch the todo to delete (ensure it exists)
-        let todo_to_delete =
-            sqlx::query_as!(Todo, "SELECT * FROM todos WHERE id = $1", id_to_delete)
-                .fetch_one(&state.pool)
-                .await
-                .map_err(|e| internal_error(e))?;
-
-        // 2. Use a recursive CTE to delete the todo and all its descendants.
-        let delete_successful = sqlx::query!(
-            r#"
-            WITH RECURSIVE todo_hierarchy AS (
-                SELECT id FROM todos WHERE id = $1
-                UNION
-                SELECT t.id FROM todos t
-                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
-            )
-            DELETE FROM todos WHERE id IN (SELECT id FROM todo_hierarchy);
-            "#,
-            todo_to_delete.id
-        )
-        .execute(&state.pool)
-        .await
-        .map(|res| res.rows_affected() > 0)
-        .unwrap_or(false);
-
-        // 3. Fetch updated todo list after deletion.
-        let new_todos =
-            get_todos_json_inner(&state.pool, date_query.date_less, date_query.date_more)
-                .await
-                .unwrap();
-
-        if delete_successful {
-            Ok(new_todos)
-        } else {
-            Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Could not delete".to_owned(),
-            ))
-        }
+        let version = expected_version(&headers)?;
+        delete_subtree(&state, id_to_delete, version).await?;
+        get_todos_json_inner(&*state.storage, date_query.date_less, date_query.date_more).await
     } else {
         Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
     }
 }
 
+/// Helper to delete a todo (if at the expected version) and all its descendants, and
+/// notify the webhooks.
+async fn delete_subtree(
+    state: &AppState,
+    id: i64,
+    version: Option<i64>,
+) -> Result<(), (StatusCode, String)> {
+    let (deleted_todo, _) = state.storage.delete_todo(id, version).await?;
+    webhooks::enqueue(
+        &*state.storage,
+        &state.webhook_notify,
+        Event::Deleted,
+        &deleted_todo,
+    )
+    .await;
+    Ok(())
+}
+
 /// API: Toggle a todo (and its children).
+/// Prefer `/todos/done`, which does not undo a change made concurrently by another client.
+#[deprecated = "use PATCH /api/v1/todos/{id}"]
+#[utoipa::path(
+    post,
+    path = "/todos/toggle",
+    tag = "todos",
+    params(("If-Match" = Option<String>, Header, description = "Only toggle the todo at this version")),
+    request_body(content = i64, description = "Id of the todo to toggle"),
+    responses(
+        (status = 200, description = "The new done state", body = bool, headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo was changed since the `If-Match` version"),
+    )
+)]
 async fn toggle_todo(
     Query(query): Query<PasswordQuery>,
     cookies: CookieJar,
+    headers: HeaderMap,
     State(state): State<AppState>,
     extract::Json(todo_id): extract::Json<i64>,
-) -> Result<Json<bool>, (StatusCode, String)> {
+) -> Result<([(HeaderName, String); 1], Json<bool>), (StatusCode, String)> {
+    logging::record_todo_id(todo_id);
     let provided = extract_provided(&query, &cookies);
     if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
-        let toggle_result = sqlx::query_as!(
-            Done,
-            r#"
-            WITH RECURSIVE updated_parent AS (
-                -- Toggle parent's state and return the new value
-                UPDATE todos
-                SET done = NOT done
-                WHERE id = $1
-                RETURNING done
-            ),
-            todo_hierarchy AS (
-                -- Recursively select all children (and grandchildren, etc.)
-                SELECT id FROM todos WHERE parent_id = $1
-                UNION ALL
-                SELECT t.id
-                FROM todos t
-                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
-            ),
-            updated_children AS (
-                -- Update all descendants to match parent's new state
-                UPDATE todos
-                SET done = (SELECT done FROM updated_parent)
-                WHERE id IN (SELECT id FROM todo_hierarchy)
-                RETURNING id
-            )
-            -- Return the parent's new done state.
-            SELECT done FROM updated_parent;
-            "#,
-            todo_id
+        let version = expected_version(&headers)?;
+        let toggled = state.storage.toggle_todo(todo_id, version).await?;
+        webhooks::enqueue(
+            &*state.storage,
+            &state.webhook_notify,
+            Event::for_done(toggled.done),
+            &toggled,
         )
-        .fetch_one(&state.pool)
         .await;
+        Ok((etag(toggled.version), Json(toggled.done)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
 
-        match toggle_result {
-            Ok(done) => Ok(Json(done.done)),
-            Err(err) => Err(internal_error(err)),
-        }
+/// API: Set a todo (and its children) as done or not done.
+#[deprecated = "use PATCH /api/v1/todos/{id}"]
+#[utoipa::path(
+    post,
+    path = "/todos/done",
+    tag = "todos",
+    params(("If-Match" = Option<String>, Header, description = "Only update the todo at this version")),
+    request_body = SetDone,
+    responses(
+        (status = 200, description = "The updated todo", body = Todo, headers(("ETag" = String, description = "Current version of the todo"))),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such todo"),
+        (status = 412, description = "The todo was changed since the `If-Match` version"),
+    )
+)]
+async fn set_done(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    extract::Json(payload): extract::Json<SetDone>,
+) -> Result<([(HeaderName, String); 1], Json<Todo>), (StatusCode, String)> {
+    logging::record_todo_id(payload.id);
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        let version = expected_version(&headers)?;
+        let todo = state
+            .storage
+            .update_todo(payload.id, version, &TodoChanges::done(payload.done))
+            .await?;
+        webhooks::enqueue(
+            &*state.storage,
+            &state.webhook_notify,
+            Event::for_done(todo.done),
+            &todo,
+        )
+        .await;
+        Ok((etag(todo.version), Json(todo)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Get the todos changed and deleted since the `since` change token.
+/// Without a token, every todo is returned along with the current token, marked `full`;
+/// the same goes for a token older than the deletions the server remembers
+/// (`TOMBSTONE_RETENTION_DAYS`). Archived todos are reported as deleted.
+#[utoipa::path(
+    get,
+    path = "/sync",
+    tag = "sync",
+    params(SyncQuery),
+    responses(
+        (status = 200, description = "The changes since the token", body = SyncResponse),
+        (status = 401, description = "Missing or wrong password"),
+    )
+)]
+async fn sync_todos(
+    Query(query): Query<PasswordQuery>,
+    Query(sync_query): Query<SyncQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+) -> Result<Json<SyncResponse>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        state.storage.sync(sync_query.since).await.map(Json)
     } else {
         Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
     }
@@ -405,32 +1529,45 @@ where
     (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
 }
 
-/// -----------------
-/// Web Handlers
-/// -----------------
+// -----------------
+// Web Handlers
+// -----------------
 
 /// GET "/" – renders the web interface. If the user is not authenticated,
 /// the page shows a login form. If authenticated, it shows the todo UI.
 async fn web_index(
     cookies: CookieJar,
     State(state): State<AppState>,
-
+    BasePath(base): BasePath,
     Query(date_query): Query<DateQuery>,
+    Query(tree_query): Query<WebTreeQuery>,
 ) -> impl IntoResponse {
-    let auth_cookie = cookies.get("auth").map(|cookie| cookie.value().to_owned());
-    let is_auth = if auth_cookie.is_some() {
-        authenticate(&state.hashed_password, &auth_cookie.unwrap())
-    } else {
-        false
-    };
+    let is_auth = cookies
+        .get("auth")
+        .is_some_and(|cookie| authenticate(&state.hashed_password, &cookie.value().to_owned()));
     let mut context = tera::Context::new();
-    let todos = get_todos_inner(&state.pool, date_query.date_less, date_query.date_more).await;
-    if let Ok(ok_todos) = todos {
-        let hierarchy = build_hierarchy(ok_todos);
-        context.insert("todos", &hierarchy);
+    if is_auth {
+        // Only a page of top-level todos and a few levels below them are rendered; the
+        // page loads the rest on demand from the fragments below.
+        let page = get_tree_page(
+            &*state.storage,
+            date_query.filter(Some(false)),
+            tree_query.after,
+            TREE_PAGE_SIZE,
+            Some(tree_query.depth()),
+        )
+        .await;
+        if let Ok((todos, next_cursor)) = page {
+            context.insert("todos", &todos);
+            context.insert("next_cursor", &next_cursor);
+        }
+        context.insert("depth", &tree_query.depth());
+        if let Ok(calendar_token) = state.calendar_token().await {
+            context.insert("calendar_token", &calendar_token);
+        }
     }
     context.insert("authenticated", &is_auth);
-    context.insert("subpath", &state.running_on_subpath);
+    context.insert("base_path", &base);
     // You can also pass additional variables as needed.
     let rendered = state
         .templates
@@ -439,21 +1576,121 @@ async fn web_index(
     Html(rendered)
 }
 
+/// GET "/archive" – the archive browser: archived trees, a page at a time, with buttons to
+/// unarchive them and to archive completed todos.
+async fn web_archive(
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    base: BasePath,
+    Query(tree_query): Query<WebTreeQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let is_auth = cookies
+        .get("auth")
+        .is_some_and(|cookie| authenticate(&state.hashed_password, &cookie.value().to_owned()));
+    if !is_auth {
+        return Ok(Redirect::to(base.root()).into_response());
+    }
+    let filter = TodoFilter {
+        archived: Some(true),
+        ..Default::default()
+    };
+    let (todos, next_cursor) = get_tree_page(
+        &*state.storage,
+        filter,
+        tree_query.after,
+        TREE_PAGE_SIZE,
+        None,
+    )
+    .await?;
+    let mut context = tera::Context::new();
+    context.insert("todos", &todos);
+    context.insert("next_cursor", &next_cursor);
+    context.insert("base_path", &base.0);
+    state
+        .templates
+        .render("archive.html", &context)
+        .map(|rendered| Html(rendered).into_response())
+        .map_err(internal_error)
+}
+
+/// GET "/fragments/todos" – the next page of top-level todos, rendered as HTML for the
+/// "Load more" button of the web interface.
+async fn web_todo_page(
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Query(date_query): Query<DateQuery>,
+    Query(tree_query): Query<WebTreeQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let is_auth = cookies
+        .get("auth")
+        .is_some_and(|cookie| authenticate(&state.hashed_password, &cookie.value().to_owned()));
+    if !is_auth {
+        return Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()));
+    }
+    let (todos, next_cursor) = get_tree_page(
+        &*state.storage,
+        date_query.filter(Some(false)),
+        tree_query.after,
+        TREE_PAGE_SIZE,
+        Some(tree_query.depth()),
+    )
+    .await?;
+    let mut context = tera::Context::new();
+    context.insert("todos", &todos);
+    context.insert("next_cursor", &next_cursor);
+    state
+        .templates
+        .render("todo_page.html", &context)
+        .map(Html)
+        .map_err(internal_error)
+}
+
+/// GET "/fragments/todos/{id}/children" – the children of a todo (with `depth` levels
+/// below them), rendered as HTML for todos whose children were not loaded with the page.
+async fn web_todo_children(
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    extract::Path(id): extract::Path<i64>,
+    Query(date_query): Query<DateQuery>,
+    Query(tree_query): Query<WebTreeQuery>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let is_auth = cookies
+        .get("auth")
+        .is_some_and(|cookie| authenticate(&state.hashed_password, &cookie.value().to_owned()));
+    if !is_auth {
+        return Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()));
+    }
+    let todo = get_subtrees_inner(
+        &*state.storage,
+        &[id],
+        Some(tree_query.depth() + 1),
+        date_query.filter(None),
+    )
+    .await?
+    .pop()
+    .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Todo {} not found", id)))?;
+    let mut context = tera::Context::new();
+    context.insert("todos", &todo.children);
+    state
+        .templates
+        .render("todo_children.html", &context)
+        .map(Html)
+        .map_err(internal_error)
+}
+
 /// POST "/login" – processes the login form. If the password is correct,
-/// it sets a cookie (with the hashed password in hex) and redirects to "/".
+/// it sets a cookie (with the hashed password in hex), scoped to the base path, and
+/// redirects to the web interface.
 async fn login(
     cookies: CookieJar,
     State(state): State<AppState>,
+    base: BasePath,
     Form(form): Form<LoginForm>,
 ) -> impl IntoResponse {
-    let redirect = if state.running_on_subpath {
-        Redirect::to("/timely")
-    } else {
-        Redirect::to("/")
-    };
+    let redirect = Redirect::to(base.root());
     if authenticate(&state.hashed_password, &form.password) {
         let cookie = Cookie::build(("auth", form.password))
-            .path("/")
+            .path(base.root().to_owned())
             // For web UI usage you may want JS to read it, so not HTTP-only.
             .http_only(false);
 
@@ -465,17 +1702,12 @@ async fn login(
     }
 }
 
-/// GET "/logout" – clears the auth cookie and redirects to "/".
-async fn logout(cookies: CookieJar, State(state): State<AppState>) -> impl IntoResponse {
+/// GET "/logout" – clears the auth cookie and redirects to the web interface.
+async fn logout(cookies: CookieJar, base: BasePath) -> impl IntoResponse {
     let cookie = Cookie::build(("auth", ""))
-        .path("/")
+        .path(base.root().to_owned())
         // Set cookie to expire immediately.
         .max_age(time::Duration::seconds(0));
     let cookies = cookies.remove(cookie);
-    let redirect = if state.running_on_subpath {
-        Redirect::to("/timely")
-    } else {
-        Redirect::to("/")
-    };
-    (cookies, redirect)
+    (cookies, Redirect::to(base.root()))
 }
@@ -0,0 +1,178 @@
+//! `GET /metrics`: Prometheus metrics. Requests are counted and timed per route and
+//! status, every storage operation is timed, and the pool and todo gauges are read from
+//! the database when scraped. Like the probes, the endpoint needs no password and is left
+//! out of the request log and of the request metrics.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::time::Instant;
+use time::OffsetDateTime;
+
+use crate::{internal_error, AppState};
+
+pub fn router() -> Router<AppState> {
+    Router::new().route("/metrics", get(metrics))
+}
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    /// Labelled with the storage operation, see `TimedStorage`
+    pub query_duration: HistogramVec,
+    pool_connections: IntGaugeVec,
+    pool_max_connections: IntGauge,
+    todos: IntGauge,
+    open_todos: IntGauge,
+    overdue_todos: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("timely".to_owned()), None)
+            .expect("the metrics prefix is valid");
+        let metrics = Metrics {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests handled"),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to answer HTTP requests",
+                ),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            query_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "db_query_duration_seconds",
+                    "Time taken by storage operations, including waiting for a connection",
+                ),
+                &["operation"],
+            )
+            .unwrap(),
+            pool_connections: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Connections of the database pool"),
+                &["state"],
+            )
+            .unwrap(),
+            pool_max_connections: IntGauge::new(
+                "db_pool_max_connections",
+                "Most connections the database pool opens",
+            )
+            .unwrap(),
+            todos: IntGauge::new("todos", "Todos, leaving out archived ones").unwrap(),
+            open_todos: IntGauge::new("open_todos", "Todos not done yet").unwrap(),
+            overdue_todos: IntGauge::new(
+                "overdue_todos",
+                "Todos not done yet whose date has passed",
+            )
+            .unwrap(),
+            registry,
+        };
+        let collectors: [Box<dyn prometheus::core::Collector>; 8] = [
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.query_duration.clone()),
+            Box::new(metrics.pool_connections.clone()),
+            Box::new(metrics.pool_max_connections.clone()),
+            Box::new(metrics.todos.clone()),
+            Box::new(metrics.open_todos.clone()),
+            Box::new(metrics.overdue_todos.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+        metrics
+    }
+}
+
+/// Middleware counting and timing the requests. Routes are labelled with their pattern
+/// (`/api/v1/todos/{id}`), so the labels stay few whatever the URLs requested.
+pub async fn record_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+    // CalDAV routes take any method, so unknown ones are counted together.
+    let method = match *request.method() {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::PATCH
+        | Method::DELETE
+        | Method::OPTIONS => request.method().as_str().to_owned(),
+        ref method if ["PROPFIND", "REPORT"].contains(&method.as_str()) => {
+            method.as_str().to_owned()
+        }
+        _ => "other".to_owned(),
+    };
+    let started = Instant::now();
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    let metrics = &state.metrics;
+    metrics.http_requests.with_label_values(&labels).inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// GET "/metrics" – every metric, in the Prometheus text format.
+async fn metrics(State(state): State<AppState>) -> Result<Response, (StatusCode, String)> {
+    let metrics = &state.metrics;
+    if let Some(pool) = state.storage.pool_status() {
+        let idle = i64::from(pool.idle);
+        metrics
+            .pool_connections
+            .with_label_values(&["idle"])
+            .set(idle);
+        metrics
+            .pool_connections
+            .with_label_values(&["in_use"])
+            .set(i64::from(pool.size) - idle);
+        metrics.pool_max_connections.set(i64::from(pool.max));
+    }
+    match state
+        .storage
+        .todo_counts(OffsetDateTime::now_utc().date())
+        .await
+    {
+        Ok(counts) => {
+            metrics.todos.set(counts.total);
+            metrics.open_todos.set(counts.open);
+            metrics.overdue_todos.set(counts.overdue);
+        }
+        // The request metrics matter most while the database is down, so they are served
+        // with the last counts.
+        Err((_, err)) => tracing::warn!("Could not count the todos for /metrics: {}", err),
+    }
+
+    let body = TextEncoder::new()
+        .encode_to_string(&metrics.registry.gather())
+        .map_err(internal_error)?;
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response())
+}
@@ -0,0 +1,64 @@
+//! The database migrations, embedded into the binary: those in `migrations/` for Postgres
+//! and those in `migrations/sqlite/` for SQLite. The server applies the pending ones when
+//! it starts (unless run with `--no-migrate`), and `timely migrate` applies them without
+//! starting it.
+//!
+//! A database migrated by a newer version of the server has migrations this binary does
+//! not know about; it refuses to work with such a schema rather than guess.
+
+use sqlx::migrate::{Migration, Migrator};
+
+use crate::storage::Storage;
+
+pub static POSTGRES: Migrator = sqlx::migrate!();
+pub static SQLITE: Migrator = sqlx::migrate!("migrations/sqlite");
+
+/// Migrations embedded in the binary that the database has not had yet, oldest first.
+/// Fails if the database has a migration the binary does not know.
+pub async fn pending(storage: &dyn Storage) -> Result<Vec<&'static Migration>, String> {
+    let Some(migrator) = storage.migrator() else {
+        return Ok(Vec::new());
+    };
+    let applied = storage
+        .applied_migrations()
+        .await
+        .map_err(|(_, err)| format!("Could not read the schema version: {}", err))?;
+
+    if let Some(unknown) = applied
+        .iter()
+        .filter(|version| !migrator.version_exists(**version))
+        .max()
+    {
+        let latest = migrator.iter().map(|migration| migration.version).max();
+        return Err(format!(
+            "The database schema is at migration {}, which is newer than this version of \
+             timely understands (its latest is {}). Upgrade timely to use this database.",
+            unknown,
+            latest.unwrap_or_default()
+        ));
+    }
+
+    Ok(migrator
+        .iter()
+        .filter(|migration| {
+            !migration.migration_type.is_down_migration() && !applied.contains(&migration.version)
+        })
+        .collect())
+}
+
+/// Applies the pending migrations, returning them.
+pub async fn run(storage: &dyn Storage) -> Result<Vec<&'static Migration>, String> {
+    let pending = pending(storage).await?;
+    if !pending.is_empty() {
+        storage
+            .run_migrations()
+            .await
+            .map_err(|(_, err)| format!("Could not migrate the database: {}", err))?;
+    }
+    Ok(pending)
+}
+
+/// "20261018150000 add archive", for messages.
+pub fn describe(migration: &Migration) -> String {
+    format!("{} {}", migration.version, migration.description)
+}
@@ -0,0 +1,108 @@
+//! The OpenAPI 3 description of the JSON API, served at `/openapi.json`, and a Swagger UI
+//! for browsing and trying it at `/docs/`. The Swagger UI files are bundled into the
+//! binary, so the page works without access to a CDN.
+//!
+//! The document is generated from the `#[utoipa::path]` attributes on the handlers and the
+//! `ToSchema` types they exchange. The web interface and CalDAV are left out.
+
+use axum::{routing::get, Json, Router};
+use timely_lib::{
+    export::{ExportFormat, NestedTodo},
+    import::{ImportFormat, ImportResult},
+    Priority, SetDone, SyncResponse, Todo,
+};
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, SecurityScheme},
+        server::Server,
+    },
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+use crate::{api, AppState, BasePath, CreateTodo, QuickAddRequest};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Timely",
+        description = "A hierarchical todo list. Every endpoint except the calendar feed \
+                       needs the server password, either as the `password` query parameter \
+                       or in the `auth` cookie set by logging in to the web interface.",
+    ),
+    paths(
+        crate::calendar_feed,
+        crate::delete_todo,
+        crate::toggle_todo,
+        crate::set_done,
+    ),
+    components(schemas(
+        Todo,
+        Priority,
+        CreateTodo,
+        QuickAddRequest,
+        SetDone,
+        SyncResponse,
+        ExportFormat,
+        ImportFormat,
+        ImportResult,
+        NestedTodo,
+    )),
+    modifiers(&Authentication),
+    security(("password" = []), ("cookie" = [])),
+    tags(
+        (name = "todos"),
+        (name = "sync", description = "Incremental sync for offline clients"),
+        (name = "archive", description = "Completed todos moved out of the todo list"),
+        (name = "import and export"),
+        (name = "calendar", description = "iCalendar feed for calendar apps"),
+        (name = "webhooks", description = "Signed notifications of changes to todos"),
+    )
+)]
+struct ApiDoc;
+
+struct Authentication;
+
+impl Modify for Authentication {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "password",
+            SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::with_description(
+                "password",
+                "The server password",
+            ))),
+        );
+        components.add_security_scheme(
+            "cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                "auth",
+                "Set by logging in to the web interface",
+            ))),
+        );
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    // The document is fetched relative to `/docs/`, so it is found on a subpath too.
+    let swagger_ui = SwaggerUi::new("/docs").config(Config::new(["../openapi.json"]));
+    Router::new()
+        .route("/openapi.json", get(openapi_json))
+        .merge(swagger_ui)
+}
+
+/// The full document: `/api/v1`, the calendar feed and the deprecated routes that work
+/// differently from their `/api/v1` replacements.
+fn document() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi().nest("/api/v1", api::document())
+}
+
+/// GET "/openapi.json" – the OpenAPI document, with the base path as its server when the
+/// app has one.
+async fn openapi_json(BasePath(base): BasePath) -> Json<utoipa::openapi::OpenApi> {
+    let mut openapi = document();
+    if !base.is_empty() {
+        openapi.servers = Some(vec![Server::new(base)]);
+    }
+    Json(openapi)
+}
@@ -0,0 +1,948 @@
+//! The in-memory backend, picked with `DATABASE_URL=memory:`. Nothing outlives the
+//! process, which makes it handy for tests and demos. It keeps the same bookkeeping as the
+//! Postgres triggers: every write to a todo bumps its version and change token, deleted
+//! todos leave tombstones, and `completed_at` and `archived` are kept up to date.
+//!
+//! Everything sits behind one lock. Changes that must happen together work on a copy of
+//! the data that replaces it once they all succeeded.
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use sqlx::migrate::Migrator;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use time::{Date, Duration, OffsetDateTime};
+use timely_lib::{export::NestedTodo, SyncResponse, Todo};
+
+use super::{
+    archived_move, deleted_since_created, missing_parent, nested_in_itself, not_archived_root,
+    precondition_failed, Backend, Change, Changed, ChangesError, FailedAttempt, NewTodo,
+    PoolStatus, Storage, StorageResult, TodoChanges, TodoCounts, TodoFilter,
+};
+use crate::caldav::CalendarObject;
+use crate::webhooks::{Delivery, Event, PendingDelivery, Webhook};
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    data: Mutex<Data>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn data(&self) -> MutexGuard<'_, Data> {
+        // The data is only changed in place once nothing can fail, so it is consistent
+        // even if a holder of the lock panicked.
+        self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Data {
+    todos: BTreeMap<i64, TodoRow>,
+    /// Change tokens and deletion times of the deleted todos
+    tombstones: HashMap<i64, (i64, OffsetDateTime)>,
+    /// The last change token handed out
+    change_seq: i64,
+    /// The newest change token of the pruned tombstones
+    sync_horizon: i64,
+    /// Todo ids and creation times by idempotency key
+    idempotency_keys: HashMap<String, (i64, OffsetDateTime)>,
+    last_todo_id: i64,
+    webhooks: BTreeMap<i64, WebhookRow>,
+    deliveries: BTreeMap<i64, DeliveryRow>,
+    last_webhook_id: i64,
+    last_delivery_id: i64,
+    secrets: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+struct TodoRow {
+    todo: Todo,
+    change_seq: i64,
+    ical_uid: Option<String>,
+    ical_href: Option<String>,
+    archived: bool,
+    completed_at: Option<OffsetDateTime>,
+}
+
+#[derive(Clone)]
+struct WebhookRow {
+    webhook: Webhook,
+    secret: String,
+}
+
+#[derive(Clone)]
+struct DeliveryRow {
+    webhook_id: i64,
+    payload: String,
+    delivery: Delivery,
+}
+
+/// Whether a date is in the range of `filter`. Like the SQL comparisons, a todo without a
+/// date is outside any range.
+fn in_range(filter: &TodoFilter, date: Option<Date>) -> bool {
+    let before = filter
+        .date_less
+        .is_none_or(|less| date.is_some_and(|date| date <= less));
+    let after = filter
+        .date_more
+        .is_none_or(|more| date.is_some_and(|date| date >= more));
+    before && after
+}
+
+fn not_found(id: i64) -> (StatusCode, String) {
+    (StatusCode::NOT_FOUND, format!("Todo {} not found", id))
+}
+
+impl Data {
+    fn next_change(&mut self) -> i64 {
+        self.change_seq += 1;
+        self.change_seq
+    }
+
+    fn exists(&self, id: i64) -> bool {
+        self.todos.contains_key(&id)
+    }
+
+    /// The todo, if it is at the expected version.
+    fn matching(&self, id: i64, version: Option<i64>) -> StorageResult<&TodoRow> {
+        match self.todos.get(&id) {
+            Some(row) if version.is_none_or(|version| version == row.todo.version) => Ok(row),
+            row => Err(precondition_failed(id, row.map(|row| row.todo.version))),
+        }
+    }
+
+    fn children(&self, id: i64) -> impl Iterator<Item = &TodoRow> {
+        self.todos
+            .values()
+            .filter(move |row| row.todo.parent_id == Some(id))
+    }
+
+    /// Ids of the descendants of a todo, parents before their children.
+    fn descendants(&self, id: i64) -> Vec<i64> {
+        let mut ids: Vec<i64> = self.children(id).map(|row| row.todo.id).collect();
+        let mut next = 0;
+        while next < ids.len() {
+            let parent = ids[next];
+            ids.extend(self.children(parent).map(|row| row.todo.id));
+            next += 1;
+        }
+        ids
+    }
+
+    fn creates_cycle(&self, id: i64, parent_id: i64) -> bool {
+        let mut ancestor = Some(parent_id);
+        while let Some(ancestor_id) = ancestor {
+            if ancestor_id == id {
+                return true;
+            }
+            ancestor = self
+                .todos
+                .get(&ancestor_id)
+                .and_then(|row| row.todo.parent_id);
+        }
+        false
+    }
+
+    /// Changes a todo the way an `UPDATE` does, with what the triggers do on top.
+    fn write(&mut self, id: i64, change: impl FnOnce(&mut Todo)) -> Todo {
+        let change_seq = self.next_change();
+        let row = self.todos.get_mut(&id).expect("written todos exist");
+        let was_done = row.todo.done;
+        change(&mut row.todo);
+        row.todo.version += 1;
+        row.change_seq = change_seq;
+        if !row.todo.done {
+            row.completed_at = None;
+        } else if !was_done {
+            row.completed_at = Some(OffsetDateTime::now_utc());
+        }
+        row.todo.clone()
+    }
+
+    fn set_archived(&mut self, id: i64, archived: bool) -> Todo {
+        let todo = self.write(id, |_| {});
+        if let Some(row) = self.todos.get_mut(&id) {
+            row.archived = archived;
+        }
+        todo
+    }
+
+    fn insert(&mut self, todo: &NewTodo) -> StorageResult<Todo> {
+        let archived = match todo.parent_id {
+            Some(parent_id) => match self.todos.get(&parent_id) {
+                Some(parent) => parent.archived,
+                None => return Err(missing_parent(parent_id)),
+            },
+            None => false,
+        };
+        self.last_todo_id += 1;
+        let record = Todo {
+            id: self.last_todo_id,
+            name: todo.name.clone(),
+            done: todo.done,
+            description: todo.description.clone(),
+            parent_id: todo.parent_id,
+            date: todo.date,
+            version: 1,
+            priority: todo.priority,
+            tags: todo.tags.clone(),
+        };
+        let row = TodoRow {
+            todo: record.clone(),
+            change_seq: self.next_change(),
+            ical_uid: todo.ical_uid.clone(),
+            ical_href: todo.ical_href.clone(),
+            archived,
+            completed_at: todo.done.then(OffsetDateTime::now_utc),
+        };
+        self.todos.insert(record.id, row);
+        Ok(record)
+    }
+
+    /// Checks that the todo can be put under `parent_id`, or at the top level for `None`.
+    fn check_move(&self, id: i64, parent_id: Option<i64>) -> StorageResult<()> {
+        // An archived tree is kept whole, so nothing is moved into or out of one.
+        let involves_archived = [Some(id), parent_id]
+            .into_iter()
+            .flatten()
+            .any(|id| self.todos.get(&id).is_some_and(|row| row.archived));
+        if involves_archived {
+            return Err(archived_move());
+        }
+
+        if let Some(parent_id) = parent_id {
+            if !self.exists(parent_id) {
+                return Err(missing_parent(parent_id));
+            }
+            if self.creates_cycle(id, parent_id) {
+                return Err(nested_in_itself());
+            }
+        }
+        Ok(())
+    }
+
+    fn update(
+        &mut self,
+        id: i64,
+        version: Option<i64>,
+        changes: &TodoChanges,
+    ) -> StorageResult<Todo> {
+        if let Some(parent_id) = changes.parent_id {
+            self.check_move(id, parent_id)?;
+        }
+
+        self.matching(id, version)?;
+        let updated = self.write(id, |todo| {
+            if let Some(name) = &changes.name {
+                todo.name = name.clone();
+            }
+            if let Some(description) = &changes.description {
+                todo.description = description.clone();
+            }
+            if let Some(date) = changes.date {
+                todo.date = date;
+            }
+            if let Some(parent_id) = changes.parent_id {
+                todo.parent_id = parent_id;
+            }
+            if let Some(done) = changes.done {
+                todo.done = done;
+            }
+            if let Some(priority) = changes.priority {
+                todo.priority = priority;
+            }
+            if let Some(tags) = &changes.tags {
+                todo.tags = tags.clone();
+            }
+        });
+
+        if let Some(done) = changes.done {
+            self.set_descendants_done(id, done);
+        }
+        Ok(updated)
+    }
+
+    fn set_descendants_done(&mut self, id: i64, done: bool) {
+        for descendant in self.descendants(id) {
+            self.write(descendant, |todo| todo.done = done);
+        }
+    }
+
+    fn toggle(&mut self, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+        self.matching(id, version)?;
+        let toggled = self.write(id, |todo| todo.done = !todo.done);
+        self.set_descendants_done(id, toggled.done);
+        Ok(toggled)
+    }
+
+    fn delete(&mut self, id: i64, version: Option<i64>) -> StorageResult<(Todo, Vec<i64>)> {
+        let deleted_todo = self.matching(id, version)?.todo.clone();
+        let mut ids = vec![id];
+        ids.extend(self.descendants(id));
+        for &id in &ids {
+            self.todos.remove(&id);
+            let change_seq = self.next_change();
+            self.tombstones
+                .insert(id, (change_seq, OffsetDateTime::now_utc()));
+        }
+        Ok((deleted_todo, ids))
+    }
+
+    fn apply(&mut self, change: Change) -> StorageResult<Changed> {
+        match change {
+            Change::Create(todo) => self.insert(&todo).map(Changed::Todo),
+            Change::Update {
+                id,
+                version,
+                changes,
+            } => self.update(id, version, &changes).map(Changed::Todo),
+            Change::Toggle { id, version } => self.toggle(id, version).map(Changed::Todo),
+            Change::Delete { id, version } => self
+                .delete(id, version)
+                .map(|(todo, ids)| Changed::Deleted(todo, ids)),
+        }
+    }
+
+    fn calendar_object(row: &TodoRow) -> CalendarObject {
+        let todo = row.todo.clone();
+        CalendarObject {
+            id: todo.id,
+            name: todo.name,
+            done: todo.done,
+            description: todo.description,
+            parent_id: todo.parent_id,
+            date: todo.date,
+            version: todo.version,
+            priority: todo.priority,
+            tags: todo.tags,
+            ical_href: row.ical_href.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn backend(&self) -> Backend {
+        Backend::Memory
+    }
+
+    fn migrator(&self) -> Option<&'static Migrator> {
+        None
+    }
+
+    async fn applied_migrations(&self) -> StorageResult<HashSet<i64>> {
+        Ok(HashSet::new())
+    }
+
+    async fn run_migrations(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    async fn list_todos(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: Option<i64>,
+    ) -> StorageResult<Vec<Todo>> {
+        let data = self.data();
+        Ok(data
+            .todos
+            .values()
+            .filter(|row| after.is_none_or(|after| row.todo.id > after))
+            .filter(|row| in_range(&filter, row.todo.date))
+            .filter(|row| {
+                filter
+                    .archived
+                    .is_none_or(|archived| row.archived == archived)
+            })
+            .take(limit.map_or(usize::MAX, |limit| limit.max(0) as usize))
+            .map(|row| row.todo.clone())
+            .collect())
+    }
+
+    async fn root_ids(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: i64,
+    ) -> StorageResult<Vec<i64>> {
+        let data = self.data();
+        Ok(data
+            .todos
+            .values()
+            .filter(|row| row.todo.parent_id.is_none())
+            .filter(|row| after.is_none_or(|after| row.todo.id > after))
+            .filter(|row| in_range(&filter, row.todo.date))
+            .filter(|row| {
+                filter
+                    .archived
+                    .is_none_or(|archived| row.archived == archived)
+            })
+            .take(limit.max(0) as usize)
+            .map(|row| row.todo.id)
+            .collect())
+    }
+
+    async fn subtree_todos(
+        &self,
+        root_ids: &[i64],
+        max_depth: Option<i32>,
+        filter: TodoFilter,
+    ) -> StorageResult<Vec<Todo>> {
+        let data = self.data();
+        let mut found: BTreeMap<i64, Todo> = BTreeMap::new();
+        let mut level: Vec<i64> = root_ids
+            .iter()
+            .filter_map(|id| data.todos.get(id))
+            .map(|row| {
+                found.insert(row.todo.id, row.todo.clone());
+                row.todo.id
+            })
+            .collect();
+        let mut depth = 0;
+        while !level.is_empty() && max_depth.is_none_or(|max_depth| depth < max_depth) {
+            let mut next = Vec::new();
+            for id in level {
+                for child in data.children(id) {
+                    if in_range(&filter, child.todo.date) {
+                        found.insert(child.todo.id, child.todo.clone());
+                        next.push(child.todo.id);
+                    }
+                }
+            }
+            level = next;
+            depth += 1;
+        }
+        Ok(found.into_values().collect())
+    }
+
+    async fn child_counts(
+        &self,
+        parent_ids: &[i64],
+        filter: TodoFilter,
+    ) -> StorageResult<HashMap<i64, usize>> {
+        let data = self.data();
+        let mut counts = HashMap::new();
+        for row in data.todos.values() {
+            if let Some(parent_id) = row.todo.parent_id {
+                if parent_ids.contains(&parent_id) && in_range(&filter, row.todo.date) {
+                    *counts.entry(parent_id).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn get_todo(&self, id: i64) -> StorageResult<Option<Todo>> {
+        Ok(self.data().todos.get(&id).map(|row| row.todo.clone()))
+    }
+
+    async fn todo_exists(&self, id: i64) -> StorageResult<bool> {
+        Ok(self.data().exists(id))
+    }
+
+    async fn ancestors(&self, id: i64) -> StorageResult<Option<Vec<Todo>>> {
+        let data = self.data();
+        let Some(row) = data.todos.get(&id) else {
+            return Ok(None);
+        };
+        let mut ancestors = Vec::new();
+        let mut parent_id = row.todo.parent_id;
+        while let Some(parent) = parent_id.and_then(|id| data.todos.get(&id)) {
+            ancestors.push(parent.todo.clone());
+            parent_id = parent.todo.parent_id;
+        }
+        ancestors.reverse();
+        Ok(Some(ancestors))
+    }
+
+    async fn find_by_name(&self, name: &str) -> StorageResult<Option<i64>> {
+        let name = name.to_lowercase();
+        Ok(self
+            .data()
+            .todos
+            .values()
+            .filter(|row| row.todo.name.to_lowercase() == name)
+            .min_by_key(|row| (row.todo.done, row.todo.id))
+            .map(|row| row.todo.id))
+    }
+
+    async fn sync(&self, since: Option<i64>) -> StorageResult<SyncResponse> {
+        let data = self.data();
+        let full = since.is_none_or(|since| since < data.sync_horizon);
+        let since = if full { 0 } else { since.unwrap_or(0) };
+
+        let changed = data
+            .todos
+            .values()
+            .filter(|row| row.change_seq > since && !row.archived)
+            .map(|row| row.todo.clone())
+            .collect();
+
+        // Archived todos are gone as far as clients are concerned.
+        let mut deleted: Vec<i64> = if full {
+            Vec::new()
+        } else {
+            data.tombstones
+                .iter()
+                .filter(|(_, &(change_seq, _))| change_seq > since)
+                .map(|(&id, _)| id)
+                .chain(
+                    data.todos
+                        .values()
+                        .filter(|row| row.change_seq > since && row.archived)
+                        .map(|row| row.todo.id),
+                )
+                .collect()
+        };
+        deleted.sort_unstable();
+
+        Ok(SyncResponse {
+            token: data.change_seq.max(since),
+            changed,
+            deleted,
+            full,
+        })
+    }
+
+    async fn prune_tombstones(&self, older_than_days: i32) -> StorageResult<u64> {
+        let cutoff = OffsetDateTime::now_utc() - Duration::days(older_than_days.into());
+        let mut data = self.data();
+        let before = data.tombstones.len();
+        let mut horizon = data.sync_horizon;
+        data.tombstones.retain(|_, &mut (change_seq, deleted_at)| {
+            let keep = deleted_at >= cutoff;
+            if !keep {
+                horizon = horizon.max(change_seq);
+            }
+            keep
+        });
+        data.sync_horizon = horizon;
+        Ok((before - data.tombstones.len()) as u64)
+    }
+
+    async fn prune_idempotency_keys(&self, older_than_days: i32) -> StorageResult<u64> {
+        let cutoff = OffsetDateTime::now_utc() - Duration::days(older_than_days.into());
+        let mut data = self.data();
+        let before = data.idempotency_keys.len();
+        data.idempotency_keys
+            .retain(|_, &mut (_, created_at)| created_at >= cutoff);
+        Ok((before - data.idempotency_keys.len()) as u64)
+    }
+
+    async fn create_todo(&self, todo: NewTodo) -> StorageResult<Todo> {
+        self.data().insert(&todo)
+    }
+
+    async fn create_todo_once(&self, key: &str, todo: NewTodo) -> StorageResult<(Todo, bool)> {
+        let mut data = self.data();
+        if let Some((todo_id, _)) = data.idempotency_keys.get(key) {
+            return match data.todos.get(todo_id) {
+                Some(row) => Ok((row.todo.clone(), false)),
+                None => Err(deleted_since_created()),
+            };
+        }
+        let created = data.insert(&todo)?;
+        data.idempotency_keys
+            .insert(key.to_owned(), (created.id, OffsetDateTime::now_utc()));
+        Ok((created, true))
+    }
+
+    async fn create_forest(
+        &self,
+        parent_id: Option<i64>,
+        forest: &mut [NestedTodo],
+    ) -> StorageResult<Vec<Todo>> {
+        let mut data = self.data();
+        if let Some(parent_id) = parent_id {
+            if !data.exists(parent_id) {
+                return Err(not_found(parent_id));
+            }
+        }
+
+        // Depth first, so every parent gets its id before its children are inserted.
+        let mut stack: Vec<(Option<i64>, &mut NestedTodo)> = forest
+            .iter_mut()
+            .rev()
+            .map(|todo| (parent_id, todo))
+            .collect();
+        let mut created = Vec::new();
+        while let Some((parent_id, todo)) = stack.pop() {
+            let record = data.insert(&NewTodo {
+                name: todo.name.clone(),
+                description: todo.description.clone(),
+                parent_id,
+                date: todo.date,
+                done: todo.done,
+                priority: todo.priority.level(),
+                tags: todo.tags.clone(),
+                ..Default::default()
+            })?;
+            let id = record.id;
+            todo.id = Some(id);
+            created.push(record);
+            stack.extend(
+                todo.children
+                    .iter_mut()
+                    .rev()
+                    .map(|child| (Some(id), child)),
+            );
+        }
+        Ok(created)
+    }
+
+    async fn update_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        changes: &TodoChanges,
+    ) -> StorageResult<Todo> {
+        // Every check comes before the first write.
+        self.data().update(id, version, changes)
+    }
+
+    async fn replace_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        todo: &NewTodo,
+    ) -> StorageResult<Todo> {
+        let mut data = self.data();
+        data.check_move(id, todo.parent_id)?;
+        data.matching(id, version)?;
+        Ok(data.write(id, |record| {
+            record.name = todo.name.clone();
+            record.description = todo.description.clone();
+            record.done = todo.done;
+            record.date = todo.date;
+            record.parent_id = todo.parent_id;
+            record.priority = todo.priority;
+            record.tags = todo.tags.clone();
+        }))
+    }
+
+    async fn toggle_todo(&self, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+        self.data().toggle(id, version)
+    }
+
+    async fn delete_todo(&self, id: i64, version: Option<i64>) -> StorageResult<(Todo, Vec<i64>)> {
+        self.data().delete(id, version)
+    }
+
+    async fn apply_changes(&self, changes: Vec<Change>) -> Result<Vec<Changed>, ChangesError> {
+        let mut data = self.data();
+        // Work on a copy, so a failed change leaves the data as it was.
+        let mut changed_data = data.clone();
+        let mut results = Vec::with_capacity(changes.len());
+        for (index, change) in changes.into_iter().enumerate() {
+            let changed = changed_data
+                .apply(change)
+                .map_err(|err| (Some(index), err))?;
+            results.push(changed);
+        }
+        *data = changed_data;
+        Ok(results)
+    }
+
+    async fn archive_completed(&self, older_than_days: i32) -> StorageResult<Vec<Todo>> {
+        let mut data = self.data();
+        let cutoff = OffsetDateTime::now_utc() - Duration::days(older_than_days.into());
+        let completed: Vec<i64> = data
+            .todos
+            .values()
+            .filter(|row| row.todo.parent_id.is_none() && row.todo.done && !row.archived)
+            .filter(|row| row.completed_at.is_some_and(|at| at <= cutoff))
+            .map(|row| row.todo.id)
+            .filter(|&id| {
+                // only trees that are done throughout
+                data.descendants(id)
+                    .iter()
+                    .all(|descendant| data.todos[descendant].todo.done)
+            })
+            .collect();
+
+        let mut archived = Vec::with_capacity(completed.len());
+        for id in completed {
+            archived.push(data.set_archived(id, true));
+            for descendant in data.descendants(id) {
+                data.set_archived(descendant, true);
+            }
+        }
+        Ok(archived)
+    }
+
+    async fn unarchive(&self, id: i64) -> StorageResult<Todo> {
+        let mut data = self.data();
+        match data.todos.get(&id) {
+            Some(row) if row.todo.parent_id.is_none() && row.archived => {}
+            Some(_) => return Err(not_archived_root(id)),
+            None => return Err(not_found(id)),
+        }
+        let todo = data.set_archived(id, false);
+        for descendant in data.descendants(id) {
+            data.set_archived(descendant, false);
+        }
+        Ok(todo)
+    }
+
+    async fn calendar_objects(&self) -> StorageResult<Vec<CalendarObject>> {
+        Ok(self
+            .data()
+            .todos
+            .values()
+            .filter(|row| !row.archived)
+            .map(Data::calendar_object)
+            .collect())
+    }
+
+    async fn calendar_object(&self, resource: &str) -> StorageResult<Option<CalendarObject>> {
+        let data = self.data();
+        let mut current = data.todos.values().filter(|row| !row.archived);
+        let by_href = current
+            .clone()
+            .find(|row| row.ical_href.as_deref() == Some(resource));
+        let by_id = || {
+            current
+                .find(|row| row.ical_href.is_none() && format!("{}.ics", row.todo.id) == resource)
+        };
+        Ok(by_href.or_else(by_id).map(Data::calendar_object))
+    }
+
+    async fn archived_object(&self, resource: &str) -> StorageResult<bool> {
+        Ok(self.data().todos.values().any(|row| {
+            row.archived
+                && match &row.ical_href {
+                    Some(href) => href == resource,
+                    None => format!("{}.ics", row.todo.id) == resource,
+                }
+        }))
+    }
+
+    async fn client_uids(&self) -> StorageResult<HashMap<i64, String>> {
+        Ok(self
+            .data()
+            .todos
+            .values()
+            .filter_map(|row| row.ical_uid.clone().map(|uid| (row.todo.id, uid)))
+            .collect())
+    }
+
+    async fn resolve_uid(&self, uid: &str) -> StorageResult<Option<(i64, bool)>> {
+        let data = self.data();
+        let by_uid = data
+            .todos
+            .values()
+            .find(|row| row.ical_uid.as_deref() == Some(uid));
+        let by_id = || {
+            crate::caldav::timely_id(uid)
+                .and_then(|id| data.todos.get(&id))
+                .filter(|row| row.ical_uid.is_none())
+        };
+        Ok(by_uid.or_else(by_id).map(|row| (row.todo.id, row.archived)))
+    }
+
+    async fn collection_tag(&self) -> StorageResult<i64> {
+        Ok(self.data().change_seq)
+    }
+
+    async fn list_webhooks(&self) -> StorageResult<Vec<Webhook>> {
+        Ok(self
+            .data()
+            .webhooks
+            .values()
+            .map(|row| row.webhook.clone())
+            .collect())
+    }
+
+    async fn create_webhook(
+        &self,
+        url: &str,
+        secret: &str,
+        events: &[String],
+    ) -> StorageResult<Webhook> {
+        let mut data = self.data();
+        data.last_webhook_id += 1;
+        let webhook = Webhook {
+            id: data.last_webhook_id,
+            url: url.to_owned(),
+            events: events.to_vec(),
+            created_at: OffsetDateTime::now_utc(),
+        };
+        data.webhooks.insert(
+            webhook.id,
+            WebhookRow {
+                webhook: webhook.clone(),
+                secret: secret.to_owned(),
+            },
+        );
+        Ok(webhook)
+    }
+
+    async fn delete_webhook(&self, id: i64) -> StorageResult<bool> {
+        let mut data = self.data();
+        if data.webhooks.remove(&id).is_none() {
+            return Ok(false);
+        }
+        data.deliveries.retain(|_, row| row.webhook_id != id);
+        Ok(true)
+    }
+
+    async fn list_deliveries(
+        &self,
+        webhook_id: i64,
+        limit: i64,
+    ) -> StorageResult<Option<Vec<Delivery>>> {
+        let data = self.data();
+        if !data.webhooks.contains_key(&webhook_id) {
+            return Ok(None);
+        }
+        Ok(Some(
+            data.deliveries
+                .values()
+                .rev()
+                .filter(|row| row.webhook_id == webhook_id)
+                .take(limit.max(0) as usize)
+                .map(|row| row.delivery.clone())
+                .collect(),
+        ))
+    }
+
+    async fn queue_deliveries(&self, event: Event, payload: &str) -> StorageResult<u64> {
+        let mut data = self.data();
+        let webhook_ids: Vec<i64> = data
+            .webhooks
+            .values()
+            .filter(|row| {
+                let events = &row.webhook.events;
+                events.is_empty() || events.iter().any(|name| name == event.as_str())
+            })
+            .map(|row| row.webhook.id)
+            .collect();
+        let now = OffsetDateTime::now_utc();
+        for &webhook_id in &webhook_ids {
+            data.last_delivery_id += 1;
+            let id = data.last_delivery_id;
+            let delivery = Delivery {
+                id,
+                event: event.as_str().to_owned(),
+                status: "pending".to_owned(),
+                attempts: 0,
+                last_status_code: None,
+                last_error: None,
+                created_at: now,
+                next_attempt_at: now,
+                delivered_at: None,
+            };
+            data.deliveries.insert(
+                id,
+                DeliveryRow {
+                    webhook_id,
+                    payload: payload.to_owned(),
+                    delivery,
+                },
+            );
+        }
+        Ok(webhook_ids.len() as u64)
+    }
+
+    async fn due_deliveries(&self, limit: i64) -> StorageResult<Vec<PendingDelivery>> {
+        let data = self.data();
+        let now = OffsetDateTime::now_utc();
+        Ok(data
+            .deliveries
+            .values()
+            .filter(|row| row.delivery.status == "pending" && row.delivery.next_attempt_at <= now)
+            .filter_map(|row| {
+                let webhook = data.webhooks.get(&row.webhook_id)?;
+                Some(PendingDelivery {
+                    id: row.delivery.id,
+                    event: row.delivery.event.clone(),
+                    payload: row.payload.clone(),
+                    attempts: row.delivery.attempts,
+                    url: webhook.webhook.url.clone(),
+                    secret: webhook.secret.clone(),
+                })
+            })
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn delivery_succeeded(&self, id: i64, status_code: i32) -> StorageResult<()> {
+        if let Some(row) = self.data().deliveries.get_mut(&id) {
+            let delivery = &mut row.delivery;
+            delivery.status = "delivered".to_owned();
+            delivery.attempts += 1;
+            delivery.last_status_code = Some(status_code);
+            delivery.last_error = None;
+            delivery.delivered_at = Some(OffsetDateTime::now_utc());
+        }
+        Ok(())
+    }
+
+    async fn delivery_failed(&self, id: i64, attempt: FailedAttempt<'_>) -> StorageResult<()> {
+        if let Some(row) = self.data().deliveries.get_mut(&id) {
+            let delivery = &mut row.delivery;
+            delivery.status = if attempt.give_up { "failed" } else { "pending" }.to_owned();
+            delivery.attempts = attempt.attempts;
+            delivery.last_status_code = attempt.status_code;
+            delivery.last_error = Some(attempt.error.to_owned());
+            delivery.next_attempt_at = OffsetDateTime::now_utc() + attempt.retry_delay;
+        }
+        Ok(())
+    }
+
+    async fn trim_delivery_log(&self, keep: i64) -> StorageResult<u64> {
+        let mut data = self.data();
+        let mut newer: HashMap<i64, i64> = HashMap::new();
+        let before = data.deliveries.len();
+        // newest first, counting each webhook's deliveries
+        let old: Vec<i64> = data
+            .deliveries
+            .values()
+            .rev()
+            .filter(|row| {
+                let rank = newer.entry(row.webhook_id).or_default();
+                *rank += 1;
+                *rank > keep && row.delivery.status != "pending"
+            })
+            .map(|row| row.delivery.id)
+            .collect();
+        for id in old {
+            data.deliveries.remove(&id);
+        }
+        Ok((before - data.deliveries.len()) as u64)
+    }
+
+    async fn secret(&self, name: &str, generated: &str) -> StorageResult<String> {
+        Ok(self
+            .data()
+            .secrets
+            .entry(name.to_owned())
+            .or_insert_with(|| generated.to_owned())
+            .clone())
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+
+    async fn todo_counts(&self, today: Date) -> StorageResult<TodoCounts> {
+        let data = self.data();
+        let current = data.todos.values().filter(|row| !row.archived);
+        let open: Vec<_> = current.clone().filter(|row| !row.todo.done).collect();
+        Ok(TodoCounts {
+            total: current.count() as i64,
+            open: open.len() as i64,
+            overdue: open
+                .iter()
+                .filter(|row| row.todo.date.is_some_and(|date| date < today))
+                .count() as i64,
+        })
+    }
+
+    async fn close(&self) {}
+}
@@ -0,0 +1,463 @@
+//! Where the todos, webhooks and their bookkeeping are kept. Every query the server makes
+//! goes through the `Storage` trait, which is implemented for Postgres, for SQLite and in
+//! memory; the backend is picked by the scheme of `DATABASE_URL` (`postgres://...`,
+//! `sqlite:...` or `memory:`).
+//!
+//! Errors are returned the way the handlers report them, as a status code and a message,
+//! so a missing todo (404), a stale `If-Match` version (412) or a move that would nest a
+//! todo inside itself (409) mean the same thing whatever the backend.
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use sqlx::migrate::Migrator;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use time::Date;
+use timely_lib::{export::NestedTodo, SyncResponse, Todo};
+
+use crate::caldav::CalendarObject;
+use crate::webhooks::{Delivery, Event, PendingDelivery, Webhook};
+
+mod memory;
+mod postgres;
+mod sqlite;
+#[cfg(test)]
+mod tests;
+mod timed;
+
+pub use memory::MemoryStorage;
+pub use postgres::PgStorage;
+pub use sqlite::SqliteStorage;
+pub use timed::TimedStorage;
+
+pub type StorageResult<T> = Result<T, (StatusCode, String)>;
+
+/// Why a list of changes was rolled back: the index of the change that failed, if it was
+/// one of them, and the error.
+pub type ChangesError = (Option<usize>, (StatusCode, String));
+
+/// Which todos a listing returns.
+#[derive(Clone, Copy, Default)]
+pub struct TodoFilter {
+    /// Only todos dated on or before this day
+    pub date_less: Option<Date>,
+    /// Only todos dated on or after this day
+    pub date_more: Option<Date>,
+    /// Only archived or only unarchived todos; both if `None`
+    pub archived: Option<bool>,
+}
+
+/// A todo to insert.
+#[derive(Default)]
+pub struct NewTodo {
+    pub name: String,
+    pub description: Option<String>,
+    pub parent_id: Option<i64>,
+    pub date: Option<Date>,
+    pub done: bool,
+    pub priority: i16,
+    pub tags: Vec<String>,
+    /// UID and resource name of a todo created by a CalDAV client
+    pub ical_uid: Option<String>,
+    pub ical_href: Option<String>,
+}
+
+/// Fields of a todo to change; those left `None` are kept. `Some(None)` clears the
+/// description or date, or moves the todo to the top level.
+#[derive(Default)]
+pub struct TodoChanges {
+    pub name: Option<String>,
+    pub description: Option<Option<String>>,
+    pub date: Option<Option<Date>>,
+    pub parent_id: Option<Option<i64>>,
+    /// Applied to the descendants too
+    pub done: Option<bool>,
+    pub priority: Option<i16>,
+    pub tags: Option<Vec<String>>,
+}
+
+impl TodoChanges {
+    /// A change of only the done state.
+    pub fn done(done: bool) -> Self {
+        TodoChanges {
+            done: Some(done),
+            ..Default::default()
+        }
+    }
+
+    /// A change of only the parent; `None` moves the todo to the top level.
+    pub fn parent(parent_id: Option<i64>) -> Self {
+        TodoChanges {
+            parent_id: Some(parent_id),
+            ..Default::default()
+        }
+    }
+
+    /// The webhook event for the change.
+    pub fn event(&self) -> Event {
+        match self.done {
+            Some(done) => Event::for_done(done),
+            None => Event::Updated,
+        }
+    }
+}
+
+/// One of the changes applied together by `Storage::apply_changes`. `version`, where
+/// taken, is the version the todo must be at.
+pub enum Change {
+    Create(NewTodo),
+    Update {
+        id: i64,
+        version: Option<i64>,
+        changes: TodoChanges,
+    },
+    Toggle {
+        id: i64,
+        version: Option<i64>,
+    },
+    Delete {
+        id: i64,
+        version: Option<i64>,
+    },
+}
+
+/// What a `Change` did.
+pub enum Changed {
+    /// The created or changed todo
+    Todo(Todo),
+    /// The deleted todo as it was, and the ids of it and its deleted descendants
+    Deleted(Todo, Vec<i64>),
+}
+
+/// The connections of a database pool.
+pub struct PoolStatus {
+    /// Open connections, idle or not
+    pub size: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+pub struct TodoCounts {
+    pub total: i64,
+    pub open: i64,
+    pub overdue: i64,
+}
+
+/// A failed attempt at sending a webhook delivery.
+pub struct FailedAttempt<'a> {
+    /// Attempts made so far, this one included
+    pub attempts: i32,
+    pub status_code: Option<i32>,
+    pub error: &'a str,
+    /// When to try again, unless giving up
+    pub retry_delay: Duration,
+    pub give_up: bool,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // Schema
+
+    /// Which backend this is.
+    fn backend(&self) -> Backend;
+
+    /// The migrations that make up the schema of this backend, if it has one.
+    fn migrator(&self) -> Option<&'static Migrator>;
+
+    /// Versions of the migrations applied to the database.
+    async fn applied_migrations(&self) -> StorageResult<HashSet<i64>>;
+
+    /// Applies the pending migrations.
+    async fn run_migrations(&self) -> StorageResult<()>;
+
+    // Reading todos
+
+    /// The todos matching `filter` after the `after` cursor (an id), ordered by id, at
+    /// most `limit` of them.
+    async fn list_todos(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: Option<i64>,
+    ) -> StorageResult<Vec<Todo>>;
+
+    /// Ids of the top-level todos matching `filter` after the `after` cursor, in order,
+    /// at most `limit` of them.
+    async fn root_ids(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: i64,
+    ) -> StorageResult<Vec<i64>>;
+
+    /// The todos with the ids in `root_ids` and their descendants down to `max_depth`
+    /// levels below them, ordered by id. Descendants outside the date range of `filter`
+    /// are left out along with their own descendants; its `archived` is not looked at.
+    async fn subtree_todos(
+        &self,
+        root_ids: &[i64],
+        max_depth: Option<i32>,
+        filter: TodoFilter,
+    ) -> StorageResult<Vec<Todo>>;
+
+    /// How many children in the date range of `filter` each of the todos in `parent_ids`
+    /// has; todos without any are left out.
+    async fn child_counts(
+        &self,
+        parent_ids: &[i64],
+        filter: TodoFilter,
+    ) -> StorageResult<HashMap<i64, usize>>;
+
+    async fn get_todo(&self, id: i64) -> StorageResult<Option<Todo>>;
+
+    async fn todo_exists(&self, id: i64) -> StorageResult<bool>;
+
+    /// The ancestors of a todo, top-level todo first, or `None` if there is no such todo.
+    async fn ancestors(&self, id: i64) -> StorageResult<Option<Vec<Todo>>>;
+
+    /// The id of a todo with this name, ignoring case. Open todos win when several have
+    /// the name, then older ones.
+    async fn find_by_name(&self, name: &str) -> StorageResult<Option<i64>>;
+
+    /// The todos changed and deleted since the `since` change token, with the current
+    /// token, read from a single snapshot. Archived todos count as deleted. Without a token,
+    /// or with one from before the last pruned tombstone, every todo is returned as `full`.
+    async fn sync(&self, since: Option<i64>) -> StorageResult<SyncResponse>;
+
+    /// Forgets the todos deleted more than `older_than_days` ago, returning how many. Later
+    /// syncs from tokens before them are answered with every todo.
+    async fn prune_tombstones(&self, older_than_days: i32) -> StorageResult<u64>;
+
+    /// Forgets the idempotency keys of creates made more than `older_than_days` ago,
+    /// returning how many.
+    async fn prune_idempotency_keys(&self, older_than_days: i32) -> StorageResult<u64>;
+
+    // Changing todos
+
+    /// Inserts a todo. Fails with 422 if the parent doesn't exist.
+    async fn create_todo(&self, todo: NewTodo) -> StorageResult<Todo>;
+
+    /// `create_todo` that only happens once per idempotency `key`: when a todo was already
+    /// created with it, returns that todo instead, and `false`. Fails with 409 if that todo
+    /// has been deleted since.
+    async fn create_todo_once(&self, key: &str, todo: NewTodo) -> StorageResult<(Todo, bool)>;
+
+    /// Inserts an imported forest under `parent_id` in a single transaction, filling in
+    /// the ids of the created todos. Returns them. Fails with 404 if the parent doesn't
+    /// exist.
+    async fn create_forest(
+        &self,
+        parent_id: Option<i64>,
+        forest: &mut [NestedTodo],
+    ) -> StorageResult<Vec<Todo>>;
+
+    /// Changes a todo (if at the expected version), setting the done state of its
+    /// descendants too. Fails with 409 if it would move a todo inside itself or into or
+    /// out of an archived tree, and with 422 if the new parent doesn't exist.
+    async fn update_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        changes: &TodoChanges,
+    ) -> StorageResult<Todo>;
+
+    /// Overwrites every field of a todo but its CalDAV identity (if at the expected
+    /// version), without touching its descendants. Fails like `update_todo` for a parent
+    /// it can't be moved under.
+    async fn replace_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        todo: &NewTodo,
+    ) -> StorageResult<Todo>;
+
+    /// Flips the done state of a todo (if at the expected version) and sets its
+    /// descendants to match.
+    async fn toggle_todo(&self, id: i64, version: Option<i64>) -> StorageResult<Todo>;
+
+    /// Deletes a todo (if at the expected version) and its descendants. Returns the
+    /// deleted todo and the ids of everything deleted.
+    async fn delete_todo(&self, id: i64, version: Option<i64>) -> StorageResult<(Todo, Vec<i64>)>;
+
+    /// Applies the changes in order in a single transaction: all of them or none.
+    async fn apply_changes(&self, changes: Vec<Change>) -> Result<Vec<Changed>, ChangesError>;
+
+    // Archive
+
+    /// Archives the completed trees whose top-level todo was done at least
+    /// `older_than_days` days ago. Returns the archived top-level todos.
+    async fn archive_completed(&self, older_than_days: i32) -> StorageResult<Vec<Todo>>;
+
+    /// Unarchives an archived top-level todo and its descendants. Fails with 409 if the
+    /// todo is not an archived top-level todo.
+    async fn unarchive(&self, id: i64) -> StorageResult<Todo>;
+
+    // CalDAV
+
+    /// Every todo outside the archive with its CalDAV identity, ordered by id.
+    async fn calendar_objects(&self) -> StorageResult<Vec<CalendarObject>>;
+
+    /// Looks a todo outside the archive up by the last segment of its CalDAV URL.
+    async fn calendar_object(&self, resource: &str) -> StorageResult<Option<CalendarObject>>;
+
+    /// Whether the last segment of a CalDAV URL belongs to an archived todo.
+    async fn archived_object(&self, resource: &str) -> StorageResult<bool>;
+
+    /// UIDs of the todos created by CalDAV clients.
+    async fn client_uids(&self) -> StorageResult<HashMap<i64, String>>;
+
+    /// The id of the todo with the given CalDAV UID, and whether it is archived.
+    async fn resolve_uid(&self, uid: &str) -> StorageResult<Option<(i64, bool)>>;
+
+    /// A value that changes whenever any todo does.
+    async fn collection_tag(&self) -> StorageResult<i64>;
+
+    // Webhooks
+
+    async fn list_webhooks(&self) -> StorageResult<Vec<Webhook>>;
+
+    async fn create_webhook(
+        &self,
+        url: &str,
+        secret: &str,
+        events: &[String],
+    ) -> StorageResult<Webhook>;
+
+    /// Removes a webhook with its deliveries; false if there is no such webhook.
+    async fn delete_webhook(&self, id: i64) -> StorageResult<bool>;
+
+    /// The newest `limit` deliveries of a webhook, newest first, or `None` if there is no
+    /// such webhook.
+    async fn list_deliveries(
+        &self,
+        webhook_id: i64,
+        limit: i64,
+    ) -> StorageResult<Option<Vec<Delivery>>>;
+
+    /// Queues the payload for every webhook subscribed to the event. Returns how many
+    /// deliveries were queued.
+    async fn queue_deliveries(&self, event: Event, payload: &str) -> StorageResult<u64>;
+
+    /// Pending deliveries that are due, oldest first, at most `limit` of them.
+    async fn due_deliveries(&self, limit: i64) -> StorageResult<Vec<PendingDelivery>>;
+
+    async fn delivery_succeeded(&self, id: i64, status_code: i32) -> StorageResult<()>;
+
+    async fn delivery_failed(&self, id: i64, attempt: FailedAttempt<'_>) -> StorageResult<()>;
+
+    /// Removes the finished deliveries of each webhook that are not among its newest
+    /// `keep`; pending ones stay. Returns how many were removed.
+    async fn trim_delivery_log(&self, keep: i64) -> StorageResult<u64>;
+
+    // Secrets
+
+    /// The secret stored under `name`, storing `generated` first if there is none yet.
+    async fn secret(&self, name: &str, generated: &str) -> StorageResult<String>;
+
+    // Monitoring
+
+    /// Connections of the pool, for backends that have one.
+    fn pool_status(&self) -> Option<PoolStatus>;
+
+    /// How many todos there are, leaving out archived ones. Overdue todos are open ones
+    /// dated before `today`.
+    async fn todo_counts(&self, today: Date) -> StorageResult<TodoCounts>;
+
+    // Shutdown
+
+    /// Closes the pool, waiting for the connections in use to be given back. Queries fail
+    /// from then on.
+    async fn close(&self);
+}
+
+/// The kinds of database the server can use, told apart by the scheme of `DATABASE_URL`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Postgres,
+    Sqlite,
+    Memory,
+}
+
+impl Backend {
+    /// Every backend this build can use.
+    pub const ALL: [Backend; 3] = [Backend::Postgres, Backend::Sqlite, Backend::Memory];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Postgres => "postgres",
+            Backend::Sqlite => "sqlite",
+            Backend::Memory => "memory",
+        }
+    }
+
+    pub fn for_url(database_url: &str) -> Option<Self> {
+        if database_url.starts_with("postgres:") || database_url.starts_with("postgresql:") {
+            Some(Backend::Postgres)
+        } else if database_url.starts_with("sqlite:") {
+            Some(Backend::Sqlite)
+        } else if database_url == "memory:" {
+            Some(Backend::Memory)
+        } else {
+            None
+        }
+    }
+}
+
+pub const UNSUPPORTED_URL: &str = "DATABASE_URL must be a postgres:// or sqlite: URL, or memory:";
+
+/// Connects to the database `DATABASE_URL` points at.
+pub async fn connect(database_url: &str) -> Result<Arc<dyn Storage>, String> {
+    match Backend::for_url(database_url) {
+        Some(Backend::Postgres) => Ok(Arc::new(PgStorage::connect(database_url).await?)),
+        Some(Backend::Sqlite) => Ok(Arc::new(SqliteStorage::connect(database_url).await?)),
+        Some(Backend::Memory) => Ok(Arc::new(MemoryStorage::new())),
+        None => Err(UNSUPPORTED_URL.to_owned()),
+    }
+}
+
+/// The error for a change that matched no todo: a missing todo, or one at another version.
+fn precondition_failed(id: i64, current_version: Option<i64>) -> (StatusCode, String) {
+    match current_version {
+        Some(version) => (
+            StatusCode::PRECONDITION_FAILED,
+            format!("Todo {} was changed, it is now at version {}", id, version),
+        ),
+        None => (StatusCode::NOT_FOUND, format!("Todo {} not found", id)),
+    }
+}
+
+fn deleted_since_created() -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        "The todo created with this idempotency key has been deleted".to_owned(),
+    )
+}
+
+fn archived_move() -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        "Archived todos can't be moved, unarchive them first".to_owned(),
+    )
+}
+
+fn nested_in_itself() -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        "A todo can't be nested inside itself".to_owned(),
+    )
+}
+
+fn missing_parent(parent_id: i64) -> (StatusCode, String) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Todo {} not found", parent_id),
+    )
+}
+
+fn not_archived_root(id: i64) -> (StatusCode, String) {
+    (
+        StatusCode::CONFLICT,
+        format!("Todo {} is not an archived top-level todo", id),
+    )
+}
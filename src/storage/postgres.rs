@@ -0,0 +1,1178 @@
+//! The Postgres backend. Triggers (see `migrations/`) bump the version and change token of
+//! every changed todo, record deleted ones for sync and keep `completed_at` and `archived`
+//! up to date, so the queries here don't have to.
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgConnection, PgPool};
+use std::collections::{HashMap, HashSet};
+use time::Date;
+use timely_lib::{export::NestedTodo, SyncResponse, Todo};
+
+use super::{
+    archived_move, deleted_since_created, missing_parent, nested_in_itself, not_archived_root,
+    precondition_failed, Backend, Change, Changed, ChangesError, FailedAttempt, NewTodo,
+    PoolStatus, Storage, StorageResult, TodoChanges, TodoCounts, TodoFilter,
+};
+use crate::caldav::CalendarObject;
+use crate::internal_error;
+use crate::migrations;
+use crate::webhooks::{Delivery, Event, PendingDelivery, Webhook};
+
+pub struct PgStorage {
+    pool: PgPool,
+}
+
+impl PgStorage {
+    pub async fn connect(database_url: &str) -> Result<Self, String> {
+        let pool = PgPool::connect(database_url)
+            .await
+            .map_err(|err| format!("Could not connect to the database: {}", err))?;
+        Ok(PgStorage { pool })
+    }
+}
+
+/// The error for a change that matched no row: tells a missing todo apart from a stale
+/// version.
+async fn precondition_error(conn: &mut PgConnection, id: i64) -> (StatusCode, String) {
+    match sqlx::query_scalar!("SELECT version FROM todos WHERE id = $1", id)
+        .fetch_optional(conn)
+        .await
+    {
+        Ok(version) => precondition_failed(id, version),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn exists(conn: &mut PgConnection, id: i64) -> StorageResult<bool> {
+    sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM todos WHERE id = $1) AS "exists!""#,
+        id
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(internal_error)
+}
+
+async fn creates_cycle(conn: &mut PgConnection, id: i64, parent_id: i64) -> StorageResult<bool> {
+    sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id FROM todos WHERE id = $1
+            UNION
+            SELECT t.id, t.parent_id FROM todos t
+            INNER JOIN ancestors a ON t.id = a.parent_id
+        )
+        SELECT EXISTS(SELECT 1 FROM ancestors WHERE id = $2) AS "exists!"
+        "#,
+        parent_id,
+        id
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(internal_error)
+}
+
+async fn insert(conn: &mut PgConnection, todo: &NewTodo) -> StorageResult<Todo> {
+    if let Some(parent_id) = todo.parent_id {
+        if !exists(&mut *conn, parent_id).await? {
+            return Err(missing_parent(parent_id));
+        }
+    }
+    sqlx::query_as!(
+        Todo,
+        r#"
+        INSERT INTO todos
+            (name, description, parent_id, date, done, priority, tags, ical_uid, ical_href)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        "#,
+        todo.name,
+        todo.description,
+        todo.parent_id,
+        todo.date,
+        todo.done,
+        todo.priority,
+        &todo.tags,
+        todo.ical_uid,
+        todo.ical_href
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(internal_error)
+}
+
+/// Checks that the todo can be put under `parent_id`, or at the top level for `None`.
+/// Runs several queries, so `conn` should be a transaction.
+async fn check_move(conn: &mut PgConnection, id: i64, parent_id: Option<i64>) -> StorageResult<()> {
+    // An archived tree is kept whole, so nothing is moved into or out of one.
+    let involves_archived = sqlx::query_scalar!(
+        r#"SELECT COALESCE(bool_or(archived), false) AS "archived!" FROM todos WHERE id IN ($1, $2)"#,
+        id,
+        parent_id
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+    if involves_archived {
+        return Err(archived_move());
+    }
+
+    if let Some(parent_id) = parent_id {
+        if !exists(&mut *conn, parent_id).await? {
+            return Err(missing_parent(parent_id));
+        }
+        if creates_cycle(&mut *conn, id, parent_id).await? {
+            return Err(nested_in_itself());
+        }
+    }
+    Ok(())
+}
+
+/// Runs several queries, so `conn` should be a transaction.
+async fn update(
+    conn: &mut PgConnection,
+    id: i64,
+    version: Option<i64>,
+    changes: &TodoChanges,
+) -> StorageResult<Todo> {
+    if let Some(parent_id) = changes.parent_id {
+        check_move(&mut *conn, id, parent_id).await?;
+    }
+
+    let updated = sqlx::query_as!(
+        Todo,
+        r#"
+        UPDATE todos
+        SET name = COALESCE($2, name),
+            description = CASE WHEN $3 THEN $4 ELSE description END,
+            date = CASE WHEN $5 THEN $6 ELSE date END,
+            parent_id = CASE WHEN $7 THEN $8 ELSE parent_id END,
+            done = COALESCE($9, done),
+            priority = COALESCE($10, priority),
+            tags = COALESCE($11, tags)
+        WHERE id = $1 AND ($12::BIGINT IS NULL OR version = $12)
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        "#,
+        id,
+        changes.name,
+        changes.description.is_some(),
+        changes.description.clone().flatten(),
+        changes.date.is_some(),
+        changes.date.flatten(),
+        changes.parent_id.is_some(),
+        changes.parent_id.flatten(),
+        changes.done,
+        changes.priority,
+        changes.tags.as_deref(),
+        version
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+    let Some(updated) = updated else {
+        return Err(precondition_error(&mut *conn, id).await);
+    };
+
+    if let Some(done) = changes.done {
+        sqlx::query!(
+            r#"
+            WITH RECURSIVE todo_hierarchy AS (
+                SELECT id FROM todos WHERE parent_id = $1
+                UNION ALL
+                SELECT t.id
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+            )
+            UPDATE todos
+            SET done = $2
+            WHERE id IN (SELECT id FROM todo_hierarchy)
+            "#,
+            id,
+            done
+        )
+        .execute(&mut *conn)
+        .await
+        .map_err(internal_error)?;
+    }
+
+    Ok(updated)
+}
+
+async fn toggle(conn: &mut PgConnection, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+    let toggled = sqlx::query_as!(
+        Todo,
+        r#"
+        WITH RECURSIVE updated_parent AS (
+            -- Toggle parent's state and return the new value
+            UPDATE todos
+            SET done = NOT done
+            WHERE id = $1 AND ($2::BIGINT IS NULL OR version = $2)
+            RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        ),
+        todo_hierarchy AS (
+            -- Recursively select all children (and grandchildren, etc.)
+            SELECT id FROM todos WHERE parent_id = $1
+            UNION ALL
+            SELECT t.id
+            FROM todos t
+            INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+        ),
+        updated_children AS (
+            -- Update all descendants to match parent's new state
+            UPDATE todos
+            SET done = (SELECT done FROM updated_parent)
+            WHERE id IN (SELECT id FROM todo_hierarchy)
+            AND EXISTS (SELECT 1 FROM updated_parent)
+            RETURNING id
+        )
+        -- Return the parent with its new done state.
+        SELECT
+            id AS "id!", name AS "name!", done AS "done!", description,
+            parent_id, date, version AS "version!", priority AS "priority!", tags AS "tags!"
+        FROM updated_parent;
+        "#,
+        id,
+        version
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+    match toggled {
+        Some(toggled) => Ok(toggled),
+        None => Err(precondition_error(&mut *conn, id).await),
+    }
+}
+
+async fn delete(
+    conn: &mut PgConnection,
+    id: i64,
+    version: Option<i64>,
+) -> StorageResult<(Todo, Vec<i64>)> {
+    let deleted = sqlx::query_as!(
+        Todo,
+        r#"
+        WITH RECURSIVE todo_hierarchy AS (
+            SELECT id FROM todos WHERE id = $1 AND ($2::BIGINT IS NULL OR version = $2)
+            UNION
+            SELECT t.id FROM todos t
+            INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+        )
+        DELETE FROM todos WHERE id IN (SELECT id FROM todo_hierarchy)
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags;
+        "#,
+        id,
+        version
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+
+    let ids = deleted.iter().map(|todo| todo.id).collect();
+    match deleted.into_iter().find(|todo| todo.id == id) {
+        Some(deleted_todo) => Ok((deleted_todo, ids)),
+        None => Err(precondition_error(&mut *conn, id).await),
+    }
+}
+
+async fn apply(conn: &mut PgConnection, change: Change) -> StorageResult<Changed> {
+    match change {
+        Change::Create(todo) => insert(conn, &todo).await.map(Changed::Todo),
+        Change::Update {
+            id,
+            version,
+            changes,
+        } => update(conn, id, version, &changes).await.map(Changed::Todo),
+        Change::Toggle { id, version } => toggle(conn, id, version).await.map(Changed::Todo),
+        Change::Delete { id, version } => delete(conn, id, version)
+            .await
+            .map(|(todo, ids)| Changed::Deleted(todo, ids)),
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    fn backend(&self) -> Backend {
+        Backend::Postgres
+    }
+
+    fn migrator(&self) -> Option<&'static Migrator> {
+        Some(&migrations::POSTGRES)
+    }
+
+    async fn applied_migrations(&self) -> StorageResult<HashSet<i64>> {
+        // The table is created by the first migration run, so a fresh database lacks it.
+        let has_table: bool =
+            sqlx::query_scalar("SELECT to_regclass('_sqlx_migrations') IS NOT NULL")
+                .fetch_one(&self.pool)
+                .await
+                .map_err(internal_error)?;
+        if !has_table {
+            return Ok(HashSet::new());
+        }
+        let versions: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(versions.into_iter().collect())
+    }
+
+    async fn run_migrations(&self) -> StorageResult<()> {
+        migrations::POSTGRES
+            .run(&self.pool)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn list_todos(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: Option<i64>,
+    ) -> StorageResult<Vec<Todo>> {
+        sqlx::query_as!(
+            Todo,
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags
+            FROM todos
+            WHERE ($1::DATE IS NULL OR date <= $1)
+              AND ($2::DATE IS NULL OR date >= $2)
+              AND ($3::BIGINT IS NULL OR id > $3)
+              AND ($5::BOOLEAN IS NULL OR archived = $5)
+            ORDER BY id
+            LIMIT $4
+            "#,
+            filter.date_less,
+            filter.date_more,
+            after,
+            limit,
+            filter.archived
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn root_ids(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: i64,
+    ) -> StorageResult<Vec<i64>> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT id
+            FROM todos
+            WHERE parent_id IS NULL
+              AND ($1::DATE IS NULL OR date <= $1)
+              AND ($2::DATE IS NULL OR date >= $2)
+              AND ($3::BIGINT IS NULL OR id > $3)
+              AND ($5::BOOLEAN IS NULL OR archived = $5)
+            ORDER BY id
+            LIMIT $4
+            "#,
+            filter.date_less,
+            filter.date_more,
+            after,
+            limit,
+            filter.archived
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn subtree_todos(
+        &self,
+        root_ids: &[i64],
+        max_depth: Option<i32>,
+        filter: TodoFilter,
+    ) -> StorageResult<Vec<Todo>> {
+        sqlx::query_as!(
+            Todo,
+            r#"
+            WITH RECURSIVE todo_hierarchy AS (
+                SELECT id, 0 AS depth FROM todos WHERE id = ANY($1)
+                UNION ALL
+                SELECT t.id, th.depth + 1
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+                WHERE ($2::INTEGER IS NULL OR th.depth < $2)
+                  AND ($3::DATE IS NULL OR t.date <= $3)
+                  AND ($4::DATE IS NULL OR t.date >= $4)
+            )
+            SELECT t.id, t.name, t.done, t.description, t.parent_id, t.date, t.version,
+                   t.priority, t.tags
+            FROM todos t
+            INNER JOIN todo_hierarchy th ON t.id = th.id
+            ORDER BY t.id
+            "#,
+            root_ids,
+            max_depth,
+            filter.date_less,
+            filter.date_more
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn child_counts(
+        &self,
+        parent_ids: &[i64],
+        filter: TodoFilter,
+    ) -> StorageResult<HashMap<i64, usize>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT parent_id AS "parent_id!", COUNT(*) AS "count!"
+            FROM todos
+            WHERE parent_id = ANY($1)
+              AND ($2::DATE IS NULL OR date <= $2)
+              AND ($3::DATE IS NULL OR date >= $3)
+            GROUP BY parent_id
+            "#,
+            parent_ids,
+            filter.date_less,
+            filter.date_more
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.parent_id, row.count as usize))
+            .collect())
+    }
+
+    async fn get_todo(&self, id: i64) -> StorageResult<Option<Todo>> {
+        sqlx::query_as!(
+            Todo,
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags
+            FROM todos
+            WHERE id = $1
+            "#,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn todo_exists(&self, id: i64) -> StorageResult<bool> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        exists(&mut conn, id).await
+    }
+
+    async fn ancestors(&self, id: i64) -> StorageResult<Option<Vec<Todo>>> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        let ancestors = sqlx::query_as!(
+            Todo,
+            r#"
+            WITH RECURSIVE ancestors AS (
+                SELECT parent_id, 1 AS distance FROM todos WHERE id = $1
+                UNION ALL
+                SELECT t.parent_id, a.distance + 1
+                FROM todos t
+                INNER JOIN ancestors a ON t.id = a.parent_id
+            )
+            SELECT t.id, t.name, t.done, t.description, t.parent_id, t.date, t.version,
+                   t.priority, t.tags
+            FROM todos t
+            INNER JOIN ancestors a ON t.id = a.parent_id
+            ORDER BY a.distance DESC
+            "#,
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(internal_error)?;
+        // no ancestors: a top-level todo, or none at all
+        if ancestors.is_empty() && !exists(&mut conn, id).await? {
+            return Ok(None);
+        }
+        Ok(Some(ancestors))
+    }
+
+    async fn find_by_name(&self, name: &str) -> StorageResult<Option<i64>> {
+        sqlx::query_scalar!(
+            "SELECT id FROM todos WHERE lower(name) = lower($1) ORDER BY done, id LIMIT 1",
+            name
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn sync(&self, since: Option<i64>) -> StorageResult<SyncResponse> {
+        // Read the changes and the new token from a single snapshot, so that the token
+        // covers exactly the changes returned. Writers draw their change_seq in commit order
+        // (`next_change_seq`), so nothing still in flight can commit below the token.
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+
+        let horizon = sqlx::query_scalar!("SELECT token FROM sync_horizon")
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+        let full = since.is_none_or(|since| since < horizon);
+        let since = if full { 0 } else { since.unwrap_or(0) };
+
+        let changed = sqlx::query_as!(
+            Todo,
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags
+            FROM todos
+            WHERE change_seq > $1 AND NOT archived
+            ORDER BY id
+            "#,
+            since
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+
+        // Archived todos are gone as far as clients are concerned; unarchiving them changes
+        // them again, so they come back.
+        let deleted = if full {
+            Vec::new()
+        } else {
+            sqlx::query_scalar!(
+                r#"
+                SELECT id AS "id!" FROM todo_tombstones WHERE change_seq > $1
+                UNION
+                SELECT id FROM todos WHERE change_seq > $1 AND archived
+                ORDER BY 1
+                "#,
+                since
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(internal_error)?
+        };
+
+        let token = sqlx::query_scalar!(
+            r#"
+            SELECT GREATEST(
+                (SELECT MAX(change_seq) FROM todos),
+                (SELECT MAX(change_seq) FROM todo_tombstones),
+                $1,
+                $2
+            ) AS "token!"
+            "#,
+            since,
+            horizon
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+
+        tx.commit().await.map_err(internal_error)?;
+
+        Ok(SyncResponse {
+            token,
+            changed,
+            deleted,
+            full,
+        })
+    }
+
+    async fn prune_tombstones(&self, older_than_days: i32) -> StorageResult<u64> {
+        let pruned = sqlx::query_scalar!(
+            r#"
+            WITH pruned AS (
+                DELETE FROM todo_tombstones
+                WHERE deleted_at < now() - make_interval(days => $1)
+                RETURNING change_seq
+            )
+            UPDATE sync_horizon
+            SET token = GREATEST(token, (SELECT MAX(change_seq) FROM pruned))
+            RETURNING (SELECT COUNT(*) FROM pruned) AS "pruned!"
+            "#,
+            older_than_days
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(pruned as u64)
+    }
+
+    async fn prune_idempotency_keys(&self, older_than_days: i32) -> StorageResult<u64> {
+        sqlx::query!(
+            "DELETE FROM idempotency_keys WHERE created_at < now() - make_interval(days => $1)",
+            older_than_days
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(internal_error)
+    }
+
+    async fn create_todo(&self, todo: NewTodo) -> StorageResult<Todo> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        insert(&mut conn, &todo).await
+    }
+
+    async fn create_todo_once(&self, key: &str, todo: NewTodo) -> StorageResult<(Todo, bool)> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let known = sqlx::query_scalar!("SELECT todo_id FROM idempotency_keys WHERE key = $1", key)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+        if known.is_none() {
+            let created = insert(&mut tx, &todo).await?;
+            // Waits for a create with the same key running concurrently to finish. If that
+            // one commits, this one is rolled back and returns its todo instead.
+            let claimed = sqlx::query!(
+                "INSERT INTO idempotency_keys (key, todo_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                key,
+                created.id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+            if claimed.rows_affected() == 1 {
+                tx.commit().await.map_err(internal_error)?;
+                return Ok((created, true));
+            }
+        }
+        tx.rollback().await.map_err(internal_error)?;
+
+        let todo_id =
+            sqlx::query_scalar!("SELECT todo_id FROM idempotency_keys WHERE key = $1", key)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(internal_error)?;
+        match self.get_todo(todo_id).await? {
+            Some(todo) => Ok((todo, false)),
+            None => Err(deleted_since_created()),
+        }
+    }
+
+    async fn create_forest(
+        &self,
+        parent_id: Option<i64>,
+        forest: &mut [NestedTodo],
+    ) -> StorageResult<Vec<Todo>> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+
+        if let Some(parent_id) = parent_id {
+            if !exists(&mut tx, parent_id).await? {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    format!("Todo {} not found", parent_id),
+                ));
+            }
+        }
+
+        // Depth first, so every parent gets its id before its children are inserted.
+        let mut stack: Vec<(Option<i64>, &mut NestedTodo)> = forest
+            .iter_mut()
+            .rev()
+            .map(|todo| (parent_id, todo))
+            .collect();
+        let mut created = Vec::new();
+        while let Some((parent_id, todo)) = stack.pop() {
+            let record = sqlx::query_as!(
+                Todo,
+                r#"
+                INSERT INTO todos (name, description, parent_id, date, done, priority, tags)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                RETURNING id, name, done, description, parent_id, date, version, priority, tags
+                "#,
+                todo.name,
+                todo.description,
+                parent_id,
+                todo.date,
+                todo.done,
+                todo.priority.level(),
+                &todo.tags
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+            let id = record.id;
+            todo.id = Some(id);
+            created.push(record);
+            stack.extend(
+                todo.children
+                    .iter_mut()
+                    .rev()
+                    .map(|child| (Some(id), child)),
+            );
+        }
+
+        tx.commit().await.map_err(internal_error)?;
+        Ok(created)
+    }
+
+    async fn update_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        changes: &TodoChanges,
+    ) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let updated = update(&mut tx, id, version, changes).await?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok(updated)
+    }
+
+    async fn replace_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        todo: &NewTodo,
+    ) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        check_move(&mut tx, id, todo.parent_id).await?;
+        let replaced = sqlx::query_as!(
+            Todo,
+            r#"
+            UPDATE todos
+            SET name = $1, description = $2, done = $3, date = $4, parent_id = $5,
+                priority = $8, tags = $9
+            WHERE id = $6 AND ($7::BIGINT IS NULL OR version = $7)
+            RETURNING id, name, done, description, parent_id, date, version, priority, tags
+            "#,
+            todo.name,
+            todo.description,
+            todo.done,
+            todo.date,
+            todo.parent_id,
+            id,
+            version,
+            todo.priority,
+            &todo.tags
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+        let Some(replaced) = replaced else {
+            return Err(precondition_error(&mut tx, id).await);
+        };
+        tx.commit().await.map_err(internal_error)?;
+        Ok(replaced)
+    }
+
+    async fn toggle_todo(&self, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        toggle(&mut conn, id, version).await
+    }
+
+    async fn delete_todo(&self, id: i64, version: Option<i64>) -> StorageResult<(Todo, Vec<i64>)> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        delete(&mut conn, id, version).await
+    }
+
+    async fn apply_changes(&self, changes: Vec<Change>) -> Result<Vec<Changed>, ChangesError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|err| (None, internal_error(err)))?;
+        let mut results = Vec::with_capacity(changes.len());
+        for (index, change) in changes.into_iter().enumerate() {
+            // Returning early drops the transaction, which rolls it back.
+            let changed = apply(&mut tx, change)
+                .await
+                .map_err(|err| (Some(index), err))?;
+            results.push(changed);
+        }
+        tx.commit()
+            .await
+            .map_err(|err| (None, internal_error(err)))?;
+        Ok(results)
+    }
+
+    async fn archive_completed(&self, older_than_days: i32) -> StorageResult<Vec<Todo>> {
+        sqlx::query_as!(
+            Todo,
+            r#"
+            WITH RECURSIVE candidates AS (
+                SELECT id FROM todos
+                WHERE parent_id IS NULL AND done AND NOT archived
+                  AND completed_at <= now() - make_interval(days => $1)
+            ),
+            todo_hierarchy AS (
+                SELECT id AS root_id, id FROM candidates
+                UNION ALL
+                SELECT th.root_id, t.id
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+            ),
+            -- only trees that are done throughout
+            completed AS (
+                SELECT th.root_id
+                FROM todo_hierarchy th
+                INNER JOIN todos t ON t.id = th.id
+                GROUP BY th.root_id
+                HAVING bool_and(t.done)
+            ),
+            archived_todos AS (
+                UPDATE todos
+                SET archived = true
+                WHERE id IN (
+                    SELECT id FROM todo_hierarchy
+                    WHERE root_id IN (SELECT root_id FROM completed)
+                )
+                RETURNING id, name, done, description, parent_id, date, version, priority, tags
+            )
+            SELECT
+                id AS "id!", name AS "name!", done AS "done!", description,
+                parent_id, date, version AS "version!", priority AS "priority!", tags AS "tags!"
+            FROM archived_todos
+            WHERE parent_id IS NULL
+            ORDER BY id
+            "#,
+            older_than_days
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn unarchive(&self, id: i64) -> StorageResult<Todo> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        let unarchived = sqlx::query_as!(
+            Todo,
+            r#"
+            WITH RECURSIVE todo_hierarchy AS (
+                SELECT id FROM todos WHERE id = $1 AND parent_id IS NULL AND archived
+                UNION ALL
+                SELECT t.id
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+            )
+            UPDATE todos
+            SET archived = false
+            WHERE id IN (SELECT id FROM todo_hierarchy)
+            RETURNING id, name, done, description, parent_id, date, version, priority, tags
+            "#,
+            id
+        )
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(internal_error)?;
+        if let Some(todo) = unarchived.into_iter().find(|todo| todo.id == id) {
+            return Ok(todo);
+        }
+        if exists(&mut conn, id).await? {
+            Err(not_archived_root(id))
+        } else {
+            Err((StatusCode::NOT_FOUND, format!("Todo {} not found", id)))
+        }
+    }
+
+    async fn calendar_objects(&self) -> StorageResult<Vec<CalendarObject>> {
+        sqlx::query_as!(
+            CalendarObject,
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags,
+                   ical_href
+            FROM todos
+            WHERE NOT archived
+            ORDER BY id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn calendar_object(&self, resource: &str) -> StorageResult<Option<CalendarObject>> {
+        sqlx::query_as!(
+            CalendarObject,
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags,
+                   ical_href
+            FROM todos
+            WHERE NOT archived
+              AND (ical_href = $1 OR (ical_href IS NULL AND id::TEXT || '.ics' = $1))
+            ORDER BY ical_href IS NULL
+            LIMIT 1
+            "#,
+            resource
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn archived_object(&self, resource: &str) -> StorageResult<bool> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM todos
+                WHERE archived
+                  AND (ical_href = $1 OR (ical_href IS NULL AND id::TEXT || '.ics' = $1))
+            ) AS "exists!"
+            "#,
+            resource
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn client_uids(&self) -> StorageResult<HashMap<i64, String>> {
+        let rows = sqlx::query!("SELECT id, ical_uid FROM todos WHERE ical_uid IS NOT NULL")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| row.ical_uid.map(|uid| (row.id, uid)))
+            .collect())
+    }
+
+    async fn resolve_uid(&self, uid: &str) -> StorageResult<Option<(i64, bool)>> {
+        let timely_id = crate::caldav::timely_id(uid);
+        let row = sqlx::query!(
+            "SELECT id, archived FROM todos WHERE ical_uid = $1 OR (ical_uid IS NULL AND id = $2)",
+            uid,
+            timely_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(row.map(|row| (row.id, row.archived)))
+    }
+
+    async fn collection_tag(&self) -> StorageResult<i64> {
+        let tag = sqlx::query_scalar!(
+            r#"
+            SELECT GREATEST(
+                (SELECT MAX(change_seq) FROM todos),
+                (SELECT MAX(change_seq) FROM todo_tombstones)
+            )
+            "#
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(tag.unwrap_or(0))
+    }
+
+    async fn list_webhooks(&self) -> StorageResult<Vec<Webhook>> {
+        sqlx::query_as!(
+            Webhook,
+            "SELECT id, url, events, created_at FROM webhooks ORDER BY id"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn create_webhook(
+        &self,
+        url: &str,
+        secret: &str,
+        events: &[String],
+    ) -> StorageResult<Webhook> {
+        sqlx::query_as!(
+            Webhook,
+            r#"
+            INSERT INTO webhooks (url, secret, events)
+            VALUES ($1, $2, $3)
+            RETURNING id, url, events, created_at
+            "#,
+            url,
+            secret,
+            events
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn delete_webhook(&self, id: i64) -> StorageResult<bool> {
+        let deleted = sqlx::query!("DELETE FROM webhooks WHERE id = $1", id)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    async fn list_deliveries(
+        &self,
+        webhook_id: i64,
+        limit: i64,
+    ) -> StorageResult<Option<Vec<Delivery>>> {
+        let exists = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = $1) AS "exists!""#,
+            webhook_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        if !exists {
+            return Ok(None);
+        }
+        sqlx::query_as!(
+            Delivery,
+            r#"
+            SELECT id, event, status, attempts, last_status_code, last_error,
+                   created_at, next_attempt_at, delivered_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1
+            ORDER BY id DESC
+            LIMIT $2
+            "#,
+            webhook_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map(Some)
+        .map_err(internal_error)
+    }
+
+    async fn queue_deliveries(&self, event: Event, payload: &str) -> StorageResult<u64> {
+        sqlx::query!(
+            r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT id, $1, $2 FROM webhooks
+            WHERE cardinality(events) = 0 OR $1 = ANY(events)
+            "#,
+            event.as_str(),
+            payload
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(internal_error)
+    }
+
+    async fn due_deliveries(&self, limit: i64) -> StorageResult<Vec<PendingDelivery>> {
+        sqlx::query_as!(
+            PendingDelivery,
+            r#"
+            SELECT d.id, d.event, d.payload, d.attempts, w.url, w.secret
+            FROM webhook_deliveries d
+            INNER JOIN webhooks w ON w.id = d.webhook_id
+            WHERE d.status = 'pending' AND d.next_attempt_at <= now()
+            ORDER BY d.id
+            LIMIT $1
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn delivery_succeeded(&self, id: i64, status_code: i32) -> StorageResult<()> {
+        sqlx::query!(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = 'delivered', attempts = attempts + 1,
+                last_status_code = $2, last_error = NULL, delivered_at = now()
+            WHERE id = $1
+            "#,
+            id,
+            status_code
+        )
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(internal_error)
+    }
+
+    async fn delivery_failed(&self, id: i64, attempt: FailedAttempt<'_>) -> StorageResult<()> {
+        sqlx::query!(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = CASE WHEN $6 THEN 'failed' ELSE 'pending' END,
+                attempts = $2, last_status_code = $3, last_error = $4,
+                next_attempt_at = now() + $5::FLOAT8 * INTERVAL '1 second'
+            WHERE id = $1
+            "#,
+            id,
+            attempt.attempts,
+            attempt.status_code,
+            attempt.error,
+            attempt.retry_delay.as_secs_f64(),
+            attempt.give_up
+        )
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(internal_error)
+    }
+
+    async fn trim_delivery_log(&self, keep: i64) -> StorageResult<u64> {
+        sqlx::query!(
+            r#"
+            DELETE FROM webhook_deliveries
+            WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, status,
+                           row_number() OVER (PARTITION BY webhook_id ORDER BY id DESC) AS rank
+                    FROM webhook_deliveries
+                ) AS ranked
+                WHERE rank > $1 AND status <> 'pending'
+            )
+            "#,
+            keep
+        )
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(internal_error)
+    }
+
+    async fn secret(&self, name: &str, generated: &str) -> StorageResult<String> {
+        // A server starting at the same time may store its own first; both then use that.
+        sqlx::query!(
+            "INSERT INTO server_secrets (name, value) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            name,
+            generated
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        sqlx::query_scalar!("SELECT value FROM server_secrets WHERE name = $1", name)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(internal_error)
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.num_idle() as u32,
+            max: self.pool.options().get_max_connections(),
+        })
+    }
+
+    async fn todo_counts(&self, today: Date) -> StorageResult<TodoCounts> {
+        let counts = sqlx::query!(
+            r#"
+            SELECT COUNT(*) AS "total!",
+                   COUNT(*) FILTER (WHERE NOT done) AS "open!",
+                   COUNT(*) FILTER (WHERE NOT done AND date < $1) AS "overdue!"
+            FROM todos
+            WHERE NOT archived
+            "#,
+            today
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(TodoCounts {
+            total: counts.total,
+            open: counts.open,
+            overdue: counts.overdue,
+        })
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+}
@@ -0,0 +1,1288 @@
+//! The SQLite backend, for small deployments and tests (`sqlite:timely.db`, or
+//! `sqlite::memory:` for a database that lives as long as the server).
+//!
+//! SQLite has neither sequences nor triggers whose changes show in `RETURNING`, so the
+//! bookkeeping the Postgres triggers do is done here: every statement that changes todos
+//! bumps their version, stamps them with a change token from `todo_change_seq` and keeps
+//! `completed_at` and `archived` up to date, and deletions leave tombstones. Arrays are
+//! stored as JSON text.
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions};
+use sqlx::types::Json;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use time::{Date, OffsetDateTime};
+use timely_lib::{export::NestedTodo, SyncResponse, Todo};
+
+use super::{
+    archived_move, deleted_since_created, missing_parent, nested_in_itself, not_archived_root,
+    precondition_failed, Backend, Change, Changed, ChangesError, FailedAttempt, NewTodo,
+    PoolStatus, Storage, StorageResult, TodoChanges, TodoCounts, TodoFilter,
+};
+use crate::caldav::CalendarObject;
+use crate::internal_error;
+use crate::migrations;
+use crate::webhooks::{Delivery, Event, PendingDelivery, Webhook};
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(database_url: &str) -> Result<Self, String> {
+        let options = SqliteConnectOptions::from_str(database_url)
+            .map_err(|err| format!("Invalid DATABASE_URL: {}", err))?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        // A single connection, kept open: SQLite runs one write at a time anyway, and every
+        // connection to `sqlite::memory:` would get a database of its own.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await
+            .map_err(|err| format!("Could not connect to the database: {}", err))?;
+        Ok(SqliteStorage { pool })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TodoRow {
+    id: i64,
+    name: String,
+    done: bool,
+    description: Option<String>,
+    parent_id: Option<i64>,
+    date: Option<Date>,
+    version: i64,
+    priority: i16,
+    tags: Json<Vec<String>>,
+}
+
+impl From<TodoRow> for Todo {
+    fn from(row: TodoRow) -> Self {
+        Todo {
+            id: row.id,
+            name: row.name,
+            done: row.done,
+            description: row.description,
+            parent_id: row.parent_id,
+            date: row.date,
+            version: row.version,
+            priority: row.priority,
+            tags: row.tags.0,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct CalendarObjectRow {
+    #[sqlx(flatten)]
+    todo: TodoRow,
+    ical_href: Option<String>,
+}
+
+impl From<CalendarObjectRow> for CalendarObject {
+    fn from(row: CalendarObjectRow) -> Self {
+        let todo = row.todo;
+        CalendarObject {
+            id: todo.id,
+            name: todo.name,
+            done: todo.done,
+            description: todo.description,
+            parent_id: todo.parent_id,
+            date: todo.date,
+            version: todo.version,
+            priority: todo.priority,
+            tags: todo.tags.0,
+            ical_href: row.ical_href,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct WebhookRow {
+    id: i64,
+    url: String,
+    events: Json<Vec<String>>,
+    created_at: OffsetDateTime,
+}
+
+impl From<WebhookRow> for Webhook {
+    fn from(row: WebhookRow) -> Self {
+        Webhook {
+            id: row.id,
+            url: row.url,
+            events: row.events.0,
+            created_at: row.created_at,
+        }
+    }
+}
+
+/// Hands out the next change token. Called once per change, inside its transaction.
+async fn next_change(conn: &mut SqliteConnection) -> StorageResult<i64> {
+    sqlx::query_scalar("UPDATE todo_change_seq SET value = value + 1 RETURNING value")
+        .fetch_one(conn)
+        .await
+        .map_err(internal_error)
+}
+
+/// The error for a change that matched no row: tells a missing todo apart from a stale
+/// version.
+async fn precondition_error(conn: &mut SqliteConnection, id: i64) -> (StatusCode, String) {
+    match sqlx::query_scalar("SELECT version FROM todos WHERE id = $1")
+        .bind(id)
+        .fetch_optional(conn)
+        .await
+    {
+        Ok(version) => precondition_failed(id, version),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn exists(conn: &mut SqliteConnection, id: i64) -> StorageResult<bool> {
+    sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM todos WHERE id = $1)")
+        .bind(id)
+        .fetch_one(conn)
+        .await
+        .map_err(internal_error)
+}
+
+async fn creates_cycle(
+    conn: &mut SqliteConnection,
+    id: i64,
+    parent_id: i64,
+) -> StorageResult<bool> {
+    sqlx::query_scalar(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id FROM todos WHERE id = $1
+            UNION
+            SELECT t.id, t.parent_id FROM todos t
+            INNER JOIN ancestors a ON t.id = a.parent_id
+        )
+        SELECT EXISTS(SELECT 1 FROM ancestors WHERE id = $2)
+        "#,
+    )
+    .bind(parent_id)
+    .bind(id)
+    .fetch_one(conn)
+    .await
+    .map_err(internal_error)
+}
+
+async fn insert(conn: &mut SqliteConnection, todo: &NewTodo) -> StorageResult<Todo> {
+    if let Some(parent_id) = todo.parent_id {
+        if !exists(&mut *conn, parent_id).await? {
+            return Err(missing_parent(parent_id));
+        }
+    }
+    let change = next_change(&mut *conn).await?;
+    // Todos created under an archived todo are archived with it
+    sqlx::query_as::<_, TodoRow>(
+        r#"
+        INSERT INTO todos
+            (name, description, parent_id, date, done, priority, tags, ical_uid, ical_href,
+             change_seq, completed_at, archived)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
+                CASE WHEN $5 THEN datetime('now') END,
+                COALESCE((SELECT archived FROM todos WHERE id = $3), FALSE))
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        "#,
+    )
+    .bind(&todo.name)
+    .bind(&todo.description)
+    .bind(todo.parent_id)
+    .bind(todo.date)
+    .bind(todo.done)
+    .bind(todo.priority)
+    .bind(Json(&todo.tags))
+    .bind(&todo.ical_uid)
+    .bind(&todo.ical_href)
+    .bind(change)
+    .fetch_one(conn)
+    .await
+    .map(Todo::from)
+    .map_err(internal_error)
+}
+
+/// Sets the done state of the descendants of a todo.
+async fn set_descendants_done(
+    conn: &mut SqliteConnection,
+    id: i64,
+    done: bool,
+    change: i64,
+) -> StorageResult<()> {
+    sqlx::query(
+        r#"
+        WITH RECURSIVE todo_hierarchy AS (
+            SELECT id FROM todos WHERE parent_id = $1
+            UNION ALL
+            SELECT t.id
+            FROM todos t
+            INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+        )
+        UPDATE todos
+        SET done = $2,
+            completed_at = CASE WHEN NOT $2 THEN NULL WHEN done THEN completed_at
+                                ELSE datetime('now') END,
+            version = version + 1,
+            change_seq = $3
+        WHERE id IN (SELECT id FROM todo_hierarchy)
+        "#,
+    )
+    .bind(id)
+    .bind(done)
+    .bind(change)
+    .execute(conn)
+    .await
+    .map(|_| ())
+    .map_err(internal_error)
+}
+
+/// Checks that the todo can be put under `parent_id`, or at the top level for `None`.
+/// Runs several queries, so `conn` should be a transaction.
+async fn check_move(
+    conn: &mut SqliteConnection,
+    id: i64,
+    parent_id: Option<i64>,
+) -> StorageResult<()> {
+    // An archived tree is kept whole, so nothing is moved into or out of one.
+    let involves_archived: bool =
+        sqlx::query_scalar("SELECT COALESCE(MAX(archived), FALSE) FROM todos WHERE id IN ($1, $2)")
+            .bind(id)
+            .bind(parent_id)
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(internal_error)?;
+    if involves_archived {
+        return Err(archived_move());
+    }
+
+    if let Some(parent_id) = parent_id {
+        if !exists(&mut *conn, parent_id).await? {
+            return Err(missing_parent(parent_id));
+        }
+        if creates_cycle(&mut *conn, id, parent_id).await? {
+            return Err(nested_in_itself());
+        }
+    }
+    Ok(())
+}
+
+/// Runs several queries, so `conn` should be a transaction.
+async fn update(
+    conn: &mut SqliteConnection,
+    id: i64,
+    version: Option<i64>,
+    changes: &TodoChanges,
+) -> StorageResult<Todo> {
+    if let Some(parent_id) = changes.parent_id {
+        check_move(&mut *conn, id, parent_id).await?;
+    }
+
+    let change = next_change(&mut *conn).await?;
+    let updated = sqlx::query_as::<_, TodoRow>(
+        r#"
+        UPDATE todos
+        SET name = COALESCE($2, name),
+            description = CASE WHEN $3 THEN $4 ELSE description END,
+            date = CASE WHEN $5 THEN $6 ELSE date END,
+            parent_id = CASE WHEN $7 THEN $8 ELSE parent_id END,
+            done = COALESCE($9, done),
+            priority = COALESCE($10, priority),
+            tags = COALESCE($11, tags),
+            completed_at = CASE WHEN NOT COALESCE($9, done) THEN NULL WHEN done THEN completed_at
+                                ELSE datetime('now') END,
+            version = version + 1,
+            change_seq = $13
+        WHERE id = $1 AND ($12 IS NULL OR version = $12)
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        "#,
+    )
+    .bind(id)
+    .bind(&changes.name)
+    .bind(changes.description.is_some())
+    .bind(changes.description.clone().flatten())
+    .bind(changes.date.is_some())
+    .bind(changes.date.flatten())
+    .bind(changes.parent_id.is_some())
+    .bind(changes.parent_id.flatten())
+    .bind(changes.done)
+    .bind(changes.priority)
+    .bind(changes.tags.as_ref().map(Json))
+    .bind(version)
+    .bind(change)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+    let Some(updated) = updated else {
+        return Err(precondition_error(&mut *conn, id).await);
+    };
+
+    if let Some(done) = changes.done {
+        set_descendants_done(&mut *conn, id, done, change).await?;
+    }
+
+    Ok(updated.into())
+}
+
+/// Runs several queries, so `conn` should be a transaction.
+async fn toggle(conn: &mut SqliteConnection, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+    let change = next_change(&mut *conn).await?;
+    let toggled = sqlx::query_as::<_, TodoRow>(
+        r#"
+        UPDATE todos
+        SET done = NOT done,
+            completed_at = CASE WHEN done THEN NULL ELSE datetime('now') END,
+            version = version + 1,
+            change_seq = $3
+        WHERE id = $1 AND ($2 IS NULL OR version = $2)
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        "#,
+    )
+    .bind(id)
+    .bind(version)
+    .bind(change)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+    let Some(toggled) = toggled else {
+        return Err(precondition_error(&mut *conn, id).await);
+    };
+    set_descendants_done(&mut *conn, id, toggled.done, change).await?;
+    Ok(toggled.into())
+}
+
+/// Runs several queries, so `conn` should be a transaction.
+async fn delete(
+    conn: &mut SqliteConnection,
+    id: i64,
+    version: Option<i64>,
+) -> StorageResult<(Todo, Vec<i64>)> {
+    let deleted = sqlx::query_as::<_, TodoRow>(
+        r#"
+        WITH RECURSIVE todo_hierarchy AS (
+            SELECT id FROM todos WHERE id = $1 AND ($2 IS NULL OR version = $2)
+            UNION
+            SELECT t.id FROM todos t
+            INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+        )
+        DELETE FROM todos WHERE id IN (SELECT id FROM todo_hierarchy)
+        RETURNING id, name, done, description, parent_id, date, version, priority, tags
+        "#,
+    )
+    .bind(id)
+    .bind(version)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+
+    let ids: Vec<i64> = deleted.iter().map(|todo| todo.id).collect();
+    let Some(deleted_todo) = deleted.into_iter().find(|todo| todo.id == id) else {
+        return Err(precondition_error(&mut *conn, id).await);
+    };
+
+    let change = next_change(&mut *conn).await?;
+    // `WHERE true` tells the parser the ON CONFLICT belongs to the INSERT
+    sqlx::query(
+        r#"
+        INSERT INTO todo_tombstones (id, change_seq, deleted_at)
+        SELECT value, $2, datetime('now') FROM json_each($1) WHERE true
+        ON CONFLICT (id) DO UPDATE
+        SET change_seq = excluded.change_seq, deleted_at = excluded.deleted_at
+        "#,
+    )
+    .bind(Json(&ids))
+    .bind(change)
+    .execute(&mut *conn)
+    .await
+    .map_err(internal_error)?;
+
+    Ok((deleted_todo.into(), ids))
+}
+
+async fn apply(conn: &mut SqliteConnection, change: Change) -> StorageResult<Changed> {
+    match change {
+        Change::Create(todo) => insert(conn, &todo).await.map(Changed::Todo),
+        Change::Update {
+            id,
+            version,
+            changes,
+        } => update(conn, id, version, &changes).await.map(Changed::Todo),
+        Change::Toggle { id, version } => toggle(conn, id, version).await.map(Changed::Todo),
+        Change::Delete { id, version } => delete(conn, id, version)
+            .await
+            .map(|(todo, ids)| Changed::Deleted(todo, ids)),
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn backend(&self) -> Backend {
+        Backend::Sqlite
+    }
+
+    fn migrator(&self) -> Option<&'static Migrator> {
+        Some(&migrations::SQLITE)
+    }
+
+    async fn applied_migrations(&self) -> StorageResult<HashSet<i64>> {
+        // The table is created by the first migration run, so a fresh database lacks it.
+        let has_table: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        if !has_table {
+            return Ok(HashSet::new());
+        }
+        let versions: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(internal_error)?;
+        Ok(versions.into_iter().collect())
+    }
+
+    async fn run_migrations(&self) -> StorageResult<()> {
+        migrations::SQLITE
+            .run(&self.pool)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn list_todos(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: Option<i64>,
+    ) -> StorageResult<Vec<Todo>> {
+        let rows = sqlx::query_as::<_, TodoRow>(
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags
+            FROM todos
+            WHERE ($1 IS NULL OR date <= $1)
+              AND ($2 IS NULL OR date >= $2)
+              AND ($3 IS NULL OR id > $3)
+              AND ($5 IS NULL OR archived = $5)
+            ORDER BY id
+            LIMIT COALESCE($4, -1)
+            "#,
+        )
+        .bind(filter.date_less)
+        .bind(filter.date_more)
+        .bind(after)
+        .bind(limit)
+        .bind(filter.archived)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(rows.into_iter().map(Todo::from).collect())
+    }
+
+    async fn root_ids(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: i64,
+    ) -> StorageResult<Vec<i64>> {
+        sqlx::query_scalar(
+            r#"
+            SELECT id
+            FROM todos
+            WHERE parent_id IS NULL
+              AND ($1 IS NULL OR date <= $1)
+              AND ($2 IS NULL OR date >= $2)
+              AND ($3 IS NULL OR id > $3)
+              AND ($5 IS NULL OR archived = $5)
+            ORDER BY id
+            LIMIT $4
+            "#,
+        )
+        .bind(filter.date_less)
+        .bind(filter.date_more)
+        .bind(after)
+        .bind(limit)
+        .bind(filter.archived)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn subtree_todos(
+        &self,
+        root_ids: &[i64],
+        max_depth: Option<i32>,
+        filter: TodoFilter,
+    ) -> StorageResult<Vec<Todo>> {
+        let rows = sqlx::query_as::<_, TodoRow>(
+            r#"
+            WITH RECURSIVE todo_hierarchy AS (
+                SELECT id, 0 AS depth FROM todos WHERE id IN (SELECT value FROM json_each($1))
+                UNION ALL
+                SELECT t.id, th.depth + 1
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+                WHERE ($2 IS NULL OR th.depth < $2)
+                  AND ($3 IS NULL OR t.date <= $3)
+                  AND ($4 IS NULL OR t.date >= $4)
+            )
+            SELECT t.id, t.name, t.done, t.description, t.parent_id, t.date, t.version,
+                   t.priority, t.tags
+            FROM todos t
+            INNER JOIN todo_hierarchy th ON t.id = th.id
+            ORDER BY t.id
+            "#,
+        )
+        .bind(Json(root_ids))
+        .bind(max_depth)
+        .bind(filter.date_less)
+        .bind(filter.date_more)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(rows.into_iter().map(Todo::from).collect())
+    }
+
+    async fn child_counts(
+        &self,
+        parent_ids: &[i64],
+        filter: TodoFilter,
+    ) -> StorageResult<HashMap<i64, usize>> {
+        let rows: Vec<(i64, i64)> = sqlx::query_as(
+            r#"
+            SELECT parent_id, COUNT(*)
+            FROM todos
+            WHERE parent_id IN (SELECT value FROM json_each($1))
+              AND ($2 IS NULL OR date <= $2)
+              AND ($3 IS NULL OR date >= $3)
+            GROUP BY parent_id
+            "#,
+        )
+        .bind(Json(parent_ids))
+        .bind(filter.date_less)
+        .bind(filter.date_more)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(rows
+            .into_iter()
+            .map(|(parent_id, count)| (parent_id, count as usize))
+            .collect())
+    }
+
+    async fn get_todo(&self, id: i64) -> StorageResult<Option<Todo>> {
+        sqlx::query_as::<_, TodoRow>(
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags
+            FROM todos
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(Todo::from))
+        .map_err(internal_error)
+    }
+
+    async fn todo_exists(&self, id: i64) -> StorageResult<bool> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        exists(&mut conn, id).await
+    }
+
+    async fn ancestors(&self, id: i64) -> StorageResult<Option<Vec<Todo>>> {
+        let mut conn = self.pool.acquire().await.map_err(internal_error)?;
+        let ancestors = sqlx::query_as::<_, TodoRow>(
+            r#"
+            WITH RECURSIVE ancestors AS (
+                SELECT parent_id, 1 AS distance FROM todos WHERE id = $1
+                UNION ALL
+                SELECT t.parent_id, a.distance + 1
+                FROM todos t
+                INNER JOIN ancestors a ON t.id = a.parent_id
+            )
+            SELECT t.id, t.name, t.done, t.description, t.parent_id, t.date, t.version,
+                   t.priority, t.tags
+            FROM todos t
+            INNER JOIN ancestors a ON t.id = a.parent_id
+            ORDER BY a.distance DESC
+            "#,
+        )
+        .bind(id)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(internal_error)?;
+        // no ancestors: a top-level todo, or none at all
+        if ancestors.is_empty() && !exists(&mut conn, id).await? {
+            return Ok(None);
+        }
+        Ok(Some(ancestors.into_iter().map(Todo::from).collect()))
+    }
+
+    async fn find_by_name(&self, name: &str) -> StorageResult<Option<i64>> {
+        sqlx::query_scalar(
+            "SELECT id FROM todos WHERE lower(name) = lower($1) ORDER BY done, id LIMIT 1",
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn sync(&self, since: Option<i64>) -> StorageResult<SyncResponse> {
+        // A transaction reads from a single snapshot, so that the token covers exactly the
+        // changes returned. SQLite has one writer at a time, so nothing still in flight can
+        // commit below the token.
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+
+        let horizon: i64 = sqlx::query_scalar("SELECT token FROM sync_horizon")
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+        let full = since.is_none_or(|since| since < horizon);
+        let since = if full { 0 } else { since.unwrap_or(0) };
+
+        let changed = sqlx::query_as::<_, TodoRow>(
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags
+            FROM todos
+            WHERE change_seq > $1 AND NOT archived
+            ORDER BY id
+            "#,
+        )
+        .bind(since)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+
+        // Archived todos are gone as far as clients are concerned; unarchiving them changes
+        // them again, so they come back.
+        let deleted = if full {
+            Vec::new()
+        } else {
+            sqlx::query_scalar(
+                r#"
+                SELECT id FROM todo_tombstones WHERE change_seq > $1
+                UNION
+                SELECT id FROM todos WHERE change_seq > $1 AND archived
+                ORDER BY 1
+                "#,
+            )
+            .bind(since)
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(internal_error)?
+        };
+
+        let token = sqlx::query_scalar("SELECT MAX(value, $1) FROM todo_change_seq")
+            .bind(since)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+
+        tx.commit().await.map_err(internal_error)?;
+
+        Ok(SyncResponse {
+            token,
+            changed: changed.into_iter().map(Todo::from).collect(),
+            deleted,
+            full,
+        })
+    }
+
+    async fn prune_tombstones(&self, older_than_days: i32) -> StorageResult<u64> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        sqlx::query(
+            r#"
+            UPDATE sync_horizon
+            SET token = MAX(token, COALESCE((
+                SELECT MAX(change_seq) FROM todo_tombstones
+                WHERE deleted_at <= datetime('now', '-' || $1 || ' days')
+            ), 0))
+            "#,
+        )
+        .bind(older_than_days)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+        let pruned = sqlx::query(
+            "DELETE FROM todo_tombstones WHERE deleted_at <= datetime('now', '-' || $1 || ' days')",
+        )
+        .bind(older_than_days)
+        .execute(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok(pruned.rows_affected())
+    }
+
+    async fn prune_idempotency_keys(&self, older_than_days: i32) -> StorageResult<u64> {
+        sqlx::query(
+            "DELETE FROM idempotency_keys WHERE created_at <= datetime('now', '-' || $1 || ' days')",
+        )
+        .bind(older_than_days)
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(internal_error)
+    }
+
+    async fn create_todo(&self, todo: NewTodo) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let created = insert(&mut tx, &todo).await?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok(created)
+    }
+
+    async fn create_todo_once(&self, key: &str, todo: NewTodo) -> StorageResult<(Todo, bool)> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let existing: Option<i64> =
+            sqlx::query_scalar("SELECT todo_id FROM idempotency_keys WHERE key = $1")
+                .bind(key)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(internal_error)?;
+        if let Some(todo_id) = existing {
+            tx.commit().await.map_err(internal_error)?;
+            return match self.get_todo(todo_id).await? {
+                Some(todo) => Ok((todo, false)),
+                None => Err(deleted_since_created()),
+            };
+        }
+        let created = insert(&mut tx, &todo).await?;
+        sqlx::query("INSERT INTO idempotency_keys (key, todo_id) VALUES ($1, $2)")
+            .bind(key)
+            .bind(created.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok((created, true))
+    }
+
+    async fn create_forest(
+        &self,
+        parent_id: Option<i64>,
+        forest: &mut [NestedTodo],
+    ) -> StorageResult<Vec<Todo>> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+
+        if let Some(parent_id) = parent_id {
+            if !exists(&mut tx, parent_id).await? {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    format!("Todo {} not found", parent_id),
+                ));
+            }
+        }
+
+        // Depth first, so every parent gets its id before its children are inserted.
+        let mut stack: Vec<(Option<i64>, &mut NestedTodo)> = forest
+            .iter_mut()
+            .rev()
+            .map(|todo| (parent_id, todo))
+            .collect();
+        let mut created = Vec::new();
+        while let Some((parent_id, todo)) = stack.pop() {
+            let record = insert(
+                &mut tx,
+                &NewTodo {
+                    name: todo.name.clone(),
+                    description: todo.description.clone(),
+                    parent_id,
+                    date: todo.date,
+                    done: todo.done,
+                    priority: todo.priority.level(),
+                    tags: todo.tags.clone(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+            let id = record.id;
+            todo.id = Some(id);
+            created.push(record);
+            stack.extend(
+                todo.children
+                    .iter_mut()
+                    .rev()
+                    .map(|child| (Some(id), child)),
+            );
+        }
+
+        tx.commit().await.map_err(internal_error)?;
+        Ok(created)
+    }
+
+    async fn update_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        changes: &TodoChanges,
+    ) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let updated = update(&mut tx, id, version, changes).await?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok(updated)
+    }
+
+    async fn replace_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        todo: &NewTodo,
+    ) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        check_move(&mut tx, id, todo.parent_id).await?;
+        let change = next_change(&mut tx).await?;
+        let replaced = sqlx::query_as::<_, TodoRow>(
+            r#"
+            UPDATE todos
+            SET name = $1, description = $2, done = $3, date = $4, parent_id = $5,
+                priority = $8, tags = $9,
+                completed_at = CASE WHEN NOT $3 THEN NULL WHEN done THEN completed_at
+                                    ELSE datetime('now') END,
+                version = version + 1,
+                change_seq = $10
+            WHERE id = $6 AND ($7 IS NULL OR version = $7)
+            RETURNING id, name, done, description, parent_id, date, version, priority, tags
+            "#,
+        )
+        .bind(&todo.name)
+        .bind(&todo.description)
+        .bind(todo.done)
+        .bind(todo.date)
+        .bind(todo.parent_id)
+        .bind(id)
+        .bind(version)
+        .bind(todo.priority)
+        .bind(Json(&todo.tags))
+        .bind(change)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+        let Some(replaced) = replaced else {
+            return Err(precondition_error(&mut tx, id).await);
+        };
+        tx.commit().await.map_err(internal_error)?;
+        Ok(replaced.into())
+    }
+
+    async fn toggle_todo(&self, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let toggled = toggle(&mut tx, id, version).await?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok(toggled)
+    }
+
+    async fn delete_todo(&self, id: i64, version: Option<i64>) -> StorageResult<(Todo, Vec<i64>)> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let deleted = delete(&mut tx, id, version).await?;
+        tx.commit().await.map_err(internal_error)?;
+        Ok(deleted)
+    }
+
+    async fn apply_changes(&self, changes: Vec<Change>) -> Result<Vec<Changed>, ChangesError> {
+        let mut tx = self
+            .pool
+            .begin()
+            .await
+            .map_err(|err| (None, internal_error(err)))?;
+        let mut results = Vec::with_capacity(changes.len());
+        for (index, change) in changes.into_iter().enumerate() {
+            // Returning early drops the transaction, which rolls it back.
+            let changed = apply(&mut tx, change)
+                .await
+                .map_err(|err| (Some(index), err))?;
+            results.push(changed);
+        }
+        tx.commit()
+            .await
+            .map_err(|err| (None, internal_error(err)))?;
+        Ok(results)
+    }
+
+    async fn archive_completed(&self, older_than_days: i32) -> StorageResult<Vec<Todo>> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let change = next_change(&mut tx).await?;
+        let archived = sqlx::query_as::<_, TodoRow>(
+            r#"
+            WITH RECURSIVE candidates AS (
+                SELECT id FROM todos
+                WHERE parent_id IS NULL AND done AND NOT archived
+                  AND completed_at <= datetime('now', '-' || $1 || ' days')
+            ),
+            todo_hierarchy AS (
+                SELECT id AS root_id, id FROM candidates
+                UNION ALL
+                SELECT th.root_id, t.id
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+            ),
+            -- only trees that are done throughout
+            completed AS (
+                SELECT th.root_id
+                FROM todo_hierarchy th
+                INNER JOIN todos t ON t.id = th.id
+                GROUP BY th.root_id
+                HAVING MIN(t.done)
+            )
+            UPDATE todos
+            SET archived = TRUE, version = version + 1, change_seq = $2
+            WHERE id IN (
+                SELECT id FROM todo_hierarchy
+                WHERE root_id IN (SELECT root_id FROM completed)
+            )
+            RETURNING id, name, done, description, parent_id, date, version, priority, tags
+            "#,
+        )
+        .bind(older_than_days)
+        .bind(change)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+        tx.commit().await.map_err(internal_error)?;
+
+        let mut roots: Vec<Todo> = archived
+            .into_iter()
+            .filter(|todo| todo.parent_id.is_none())
+            .map(Todo::from)
+            .collect();
+        roots.sort_by_key(|todo| todo.id);
+        Ok(roots)
+    }
+
+    async fn unarchive(&self, id: i64) -> StorageResult<Todo> {
+        let mut tx = self.pool.begin().await.map_err(internal_error)?;
+        let change = next_change(&mut tx).await?;
+        let unarchived = sqlx::query_as::<_, TodoRow>(
+            r#"
+            WITH RECURSIVE todo_hierarchy AS (
+                SELECT id FROM todos WHERE id = $1 AND parent_id IS NULL AND archived
+                UNION ALL
+                SELECT t.id
+                FROM todos t
+                INNER JOIN todo_hierarchy th ON t.parent_id = th.id
+            )
+            UPDATE todos
+            SET archived = FALSE, version = version + 1, change_seq = $2
+            WHERE id IN (SELECT id FROM todo_hierarchy)
+            RETURNING id, name, done, description, parent_id, date, version, priority, tags
+            "#,
+        )
+        .bind(id)
+        .bind(change)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(internal_error)?;
+        if let Some(todo) = unarchived.into_iter().find(|todo| todo.id == id) {
+            tx.commit().await.map_err(internal_error)?;
+            return Ok(todo.into());
+        }
+        if exists(&mut tx, id).await? {
+            Err(not_archived_root(id))
+        } else {
+            Err((StatusCode::NOT_FOUND, format!("Todo {} not found", id)))
+        }
+    }
+
+    async fn calendar_objects(&self) -> StorageResult<Vec<CalendarObject>> {
+        let rows = sqlx::query_as::<_, CalendarObjectRow>(
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags,
+                   ical_href
+            FROM todos
+            WHERE NOT archived
+            ORDER BY id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(rows.into_iter().map(CalendarObject::from).collect())
+    }
+
+    async fn calendar_object(&self, resource: &str) -> StorageResult<Option<CalendarObject>> {
+        sqlx::query_as::<_, CalendarObjectRow>(
+            r#"
+            SELECT id, name, done, description, parent_id, date, version, priority, tags,
+                   ical_href
+            FROM todos
+            WHERE NOT archived
+              AND (ical_href = $1 OR (ical_href IS NULL AND CAST(id AS TEXT) || '.ics' = $1))
+            ORDER BY ical_href IS NULL
+            LIMIT 1
+            "#,
+        )
+        .bind(resource)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(CalendarObject::from))
+        .map_err(internal_error)
+    }
+
+    async fn archived_object(&self, resource: &str) -> StorageResult<bool> {
+        sqlx::query_scalar(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM todos
+                WHERE archived
+                  AND (ical_href = $1 OR (ical_href IS NULL AND CAST(id AS TEXT) || '.ics' = $1))
+            )
+            "#,
+        )
+        .bind(resource)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn client_uids(&self) -> StorageResult<HashMap<i64, String>> {
+        let rows: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, ical_uid FROM todos WHERE ical_uid IS NOT NULL")
+                .fetch_all(&self.pool)
+                .await
+                .map_err(internal_error)?;
+        Ok(rows.into_iter().collect())
+    }
+
+    async fn resolve_uid(&self, uid: &str) -> StorageResult<Option<(i64, bool)>> {
+        sqlx::query_as(
+            "SELECT id, archived FROM todos WHERE ical_uid = $1 OR (ical_uid IS NULL AND id = $2)",
+        )
+        .bind(uid)
+        .bind(crate::caldav::timely_id(uid))
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn collection_tag(&self) -> StorageResult<i64> {
+        sqlx::query_scalar("SELECT value FROM todo_change_seq")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(internal_error)
+    }
+
+    async fn list_webhooks(&self) -> StorageResult<Vec<Webhook>> {
+        let rows = sqlx::query_as::<_, WebhookRow>(
+            "SELECT id, url, events, created_at FROM webhooks ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(rows.into_iter().map(Webhook::from).collect())
+    }
+
+    async fn create_webhook(
+        &self,
+        url: &str,
+        secret: &str,
+        events: &[String],
+    ) -> StorageResult<Webhook> {
+        sqlx::query_as::<_, WebhookRow>(
+            r#"
+            INSERT INTO webhooks (url, secret, events)
+            VALUES ($1, $2, $3)
+            RETURNING id, url, events, created_at
+            "#,
+        )
+        .bind(url)
+        .bind(secret)
+        .bind(Json(events))
+        .fetch_one(&self.pool)
+        .await
+        .map(Webhook::from)
+        .map_err(internal_error)
+    }
+
+    async fn delete_webhook(&self, id: i64) -> StorageResult<bool> {
+        let deleted = sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await
+            .map_err(internal_error)?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    async fn list_deliveries(
+        &self,
+        webhook_id: i64,
+        limit: i64,
+    ) -> StorageResult<Option<Vec<Delivery>>> {
+        let exists: bool =
+            sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = $1)")
+                .bind(webhook_id)
+                .fetch_one(&self.pool)
+                .await
+                .map_err(internal_error)?;
+        if !exists {
+            return Ok(None);
+        }
+        sqlx::query_as::<_, Delivery>(
+            r#"
+            SELECT id, event, status, attempts, last_status_code, last_error,
+                   created_at, next_attempt_at, delivered_at
+            FROM webhook_deliveries
+            WHERE webhook_id = $1
+            ORDER BY id DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map(Some)
+        .map_err(internal_error)
+    }
+
+    async fn queue_deliveries(&self, event: Event, payload: &str) -> StorageResult<u64> {
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT id, $1, $2 FROM webhooks
+            WHERE json_array_length(events) = 0
+               OR EXISTS (SELECT 1 FROM json_each(events) WHERE value = $1)
+            "#,
+        )
+        .bind(event.as_str())
+        .bind(payload)
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(internal_error)
+    }
+
+    async fn due_deliveries(&self, limit: i64) -> StorageResult<Vec<PendingDelivery>> {
+        sqlx::query_as::<_, PendingDelivery>(
+            r#"
+            SELECT d.id, d.event, d.payload, d.attempts, w.url, w.secret
+            FROM webhook_deliveries d
+            INNER JOIN webhooks w ON w.id = d.webhook_id
+            WHERE d.status = 'pending' AND d.next_attempt_at <= datetime('now')
+            ORDER BY d.id
+            LIMIT $1
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(internal_error)
+    }
+
+    async fn delivery_succeeded(&self, id: i64, status_code: i32) -> StorageResult<()> {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = 'delivered', attempts = attempts + 1,
+                last_status_code = $2, last_error = NULL, delivered_at = datetime('now')
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(status_code)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(internal_error)
+    }
+
+    async fn delivery_failed(&self, id: i64, attempt: FailedAttempt<'_>) -> StorageResult<()> {
+        sqlx::query(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = CASE WHEN $6 THEN 'failed' ELSE 'pending' END,
+                attempts = $2, last_status_code = $3, last_error = $4,
+                next_attempt_at = datetime('now', '+' || $5 || ' seconds')
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(attempt.attempts)
+        .bind(attempt.status_code)
+        .bind(attempt.error)
+        .bind(attempt.retry_delay.as_secs_f64())
+        .bind(attempt.give_up)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(internal_error)
+    }
+
+    async fn trim_delivery_log(&self, keep: i64) -> StorageResult<u64> {
+        sqlx::query(
+            r#"
+            DELETE FROM webhook_deliveries
+            WHERE id IN (
+                SELECT id FROM (
+                    SELECT id, status,
+                           row_number() OVER (PARTITION BY webhook_id ORDER BY id DESC) AS rank
+                    FROM webhook_deliveries
+                ) AS ranked
+                WHERE rank > $1 AND status <> 'pending'
+            )
+            "#,
+        )
+        .bind(keep)
+        .execute(&self.pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(internal_error)
+    }
+
+    async fn secret(&self, name: &str, generated: &str) -> StorageResult<String> {
+        sqlx::query(
+            "INSERT INTO server_secrets (name, value) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(name)
+        .bind(generated)
+        .execute(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        sqlx::query_scalar("SELECT value FROM server_secrets WHERE name = $1")
+            .bind(name)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(internal_error)
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.num_idle() as u32,
+            max: self.pool.options().get_max_connections(),
+        })
+    }
+
+    async fn todo_counts(&self, today: Date) -> StorageResult<TodoCounts> {
+        let (total, open, overdue) = sqlx::query_as(
+            r#"
+            SELECT COUNT(*),
+                   COALESCE(SUM(NOT done), 0),
+                   COALESCE(SUM(NOT done AND date < $1), 0)
+            FROM todos
+            WHERE NOT archived
+            "#,
+        )
+        .bind(today)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(internal_error)?;
+        Ok(TodoCounts {
+            total,
+            open,
+            overdue,
+        })
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+}
@@ -0,0 +1,426 @@
+//! The storage behaviour every backend must share, run against `MemoryStorage` and an
+//! in-memory SQLite database. Postgres is left out, as the tests run without a server.
+
+use axum::http::StatusCode;
+use time::Date;
+use timely_lib::Todo;
+
+use super::{Change, MemoryStorage, NewTodo, SqliteStorage, Storage, TodoChanges, TodoFilter};
+use crate::migrations;
+use crate::webhooks::{Delivery, Event};
+
+/// Runs each of the tests below, which take the storage to test, once per backend.
+macro_rules! backend_tests {
+    ($($test:ident),* $(,)?) => {
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(&super::MemoryStorage::new()).await;
+                }
+            )*
+        }
+
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $test() {
+                    super::$test(&super::sqlite().await).await;
+                }
+            )*
+        }
+    };
+}
+
+backend_tests!(
+    delete_removes_the_subtree,
+    toggle_sets_descendants_to_match,
+    stale_and_missing_todos_are_told_apart,
+    updates_bump_the_version,
+    moves_are_checked,
+    replacements_are_checked_like_moves,
+    failed_changes_are_rolled_back,
+    completed_trees_are_archived_whole,
+    date_filters_leave_out_undated_todos,
+    deletions_reach_syncs_until_pruned,
+    creates_with_a_key_happen_once,
+    the_delivery_log_keeps_the_newest,
+);
+
+async fn sqlite() -> SqliteStorage {
+    let storage = SqliteStorage::connect("sqlite::memory:").await.unwrap();
+    migrations::run(&storage).await.unwrap();
+    storage
+}
+
+fn named(name: &str, parent_id: Option<i64>) -> NewTodo {
+    NewTodo {
+        name: name.to_owned(),
+        parent_id,
+        ..Default::default()
+    }
+}
+
+/// root > child > grandchild, and a separate top-level todo.
+async fn tree(storage: &dyn Storage) -> (Todo, Todo, Todo, Todo) {
+    let root = storage.create_todo(named("root", None)).await.unwrap();
+    let child = storage
+        .create_todo(named("child", Some(root.id)))
+        .await
+        .unwrap();
+    let grandchild = storage
+        .create_todo(named("grandchild", Some(child.id)))
+        .await
+        .unwrap();
+    let other = storage.create_todo(named("other", None)).await.unwrap();
+    (root, child, grandchild, other)
+}
+
+async fn delete_removes_the_subtree(storage: &dyn Storage) {
+    let (root, child, grandchild, other) = tree(storage).await;
+
+    let (deleted, ids) = storage.delete_todo(child.id, None).await.unwrap();
+    assert_eq!(deleted.id, child.id);
+    assert_eq!(ids, vec![child.id, grandchild.id]);
+
+    let left = storage
+        .list_todos(TodoFilter::default(), None, None)
+        .await
+        .unwrap();
+    let left: Vec<i64> = left.iter().map(|todo| todo.id).collect();
+    assert_eq!(left, vec![root.id, other.id]);
+
+    let sync = storage.sync(Some(0)).await.unwrap();
+    assert_eq!(sync.deleted, vec![child.id, grandchild.id]);
+}
+
+async fn toggle_sets_descendants_to_match(storage: &dyn Storage) {
+    let (root, child, grandchild, other) = tree(storage).await;
+    storage
+        .update_todo(grandchild.id, None, &TodoChanges::done(true))
+        .await
+        .unwrap();
+
+    let toggled = storage.toggle_todo(child.id, Some(1)).await.unwrap();
+    assert!(toggled.done);
+    assert_eq!(toggled.version, 2);
+    // already done, but written again like the SQL update does
+    let grandchild = storage.get_todo(grandchild.id).await.unwrap().unwrap();
+    assert!(grandchild.done);
+    assert_eq!(grandchild.version, 3);
+
+    let toggled = storage.toggle_todo(child.id, None).await.unwrap();
+    assert!(!toggled.done);
+    assert!(!storage.get_todo(grandchild.id).await.unwrap().unwrap().done);
+    assert!(!storage.get_todo(root.id).await.unwrap().unwrap().done);
+    assert_eq!(
+        storage.get_todo(other.id).await.unwrap().unwrap().version,
+        1
+    );
+}
+
+async fn stale_and_missing_todos_are_told_apart(storage: &dyn Storage) {
+    let (root, ..) = tree(storage).await;
+
+    let (status, _) = storage.toggle_todo(root.id, Some(7)).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    let (status, _) = storage.delete_todo(99, None).await.unwrap_err();
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+async fn moves_are_checked(storage: &dyn Storage) {
+    let (root, _, grandchild, other) = tree(storage).await;
+
+    let into_itself = TodoChanges::parent(Some(grandchild.id));
+    let (status, _) = storage
+        .update_todo(root.id, None, &into_itself)
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = storage
+        .update_todo(root.id, None, &TodoChanges::parent(Some(99)))
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let moved = storage
+        .update_todo(grandchild.id, None, &TodoChanges::parent(Some(other.id)))
+        .await
+        .unwrap();
+    assert_eq!(moved.parent_id, Some(other.id));
+    let ancestors = storage.ancestors(grandchild.id).await.unwrap().unwrap();
+    assert_eq!(ancestors.len(), 1);
+    assert_eq!(ancestors[0].id, other.id);
+}
+
+async fn replacements_are_checked_like_moves(storage: &dyn Storage) {
+    let (root, _, grandchild, other) = tree(storage).await;
+
+    let (status, _) = storage
+        .replace_todo(root.id, None, &named("root", Some(grandchild.id)))
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = storage
+        .replace_todo(root.id, None, &named("root", Some(99)))
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // nothing moves into or out of an archived tree
+    storage.toggle_todo(other.id, None).await.unwrap();
+    storage.archive_completed(0).await.unwrap();
+    let (status, _) = storage
+        .replace_todo(root.id, None, &named("root", Some(other.id)))
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = storage
+        .replace_todo(other.id, None, &named("other", None))
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let replaced = storage
+        .replace_todo(grandchild.id, None, &named("moved", Some(root.id)))
+        .await
+        .unwrap();
+    assert_eq!(replaced.parent_id, Some(root.id));
+    assert_eq!(replaced.name, "moved");
+}
+
+async fn failed_changes_are_rolled_back(storage: &dyn Storage) {
+    let (root, ..) = tree(storage).await;
+    let token = storage.collection_tag().await.unwrap();
+
+    let changes = vec![
+        Change::Create(named("created", None)),
+        Change::Delete {
+            id: root.id,
+            version: None,
+        },
+        Change::Toggle {
+            id: 99,
+            version: None,
+        },
+    ];
+    let (index, (status, _)) = storage.apply_changes(changes).await.err().unwrap();
+    assert_eq!(index, Some(2));
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let todos = storage
+        .list_todos(TodoFilter::default(), None, None)
+        .await
+        .unwrap();
+    assert_eq!(todos.len(), 4);
+    assert_eq!(storage.collection_tag().await.unwrap(), token);
+}
+
+async fn completed_trees_are_archived_whole(storage: &dyn Storage) {
+    let (root, child, grandchild, other) = tree(storage).await;
+    storage.toggle_todo(other.id, None).await.unwrap();
+    storage
+        .update_todo(root.id, None, &TodoChanges::done(true))
+        .await
+        .unwrap();
+    // reopening a descendant keeps the tree out of the archive
+    storage.toggle_todo(grandchild.id, None).await.unwrap();
+
+    let archived = storage.archive_completed(0).await.unwrap();
+    assert_eq!(
+        archived.iter().map(|todo| todo.id).collect::<Vec<_>>(),
+        vec![other.id]
+    );
+
+    storage.toggle_todo(grandchild.id, None).await.unwrap();
+    let token = storage.collection_tag().await.unwrap();
+    let archived = storage.archive_completed(0).await.unwrap();
+    assert_eq!(
+        archived.iter().map(|todo| todo.id).collect::<Vec<_>>(),
+        vec![root.id]
+    );
+
+    // gone for syncing clients, and new children join the archive
+    let sync = storage.sync(Some(token)).await.unwrap();
+    assert_eq!(sync.deleted, vec![root.id, child.id, grandchild.id]);
+    let added = storage
+        .create_todo(named("late", Some(child.id)))
+        .await
+        .unwrap();
+    let unarchived_filter = TodoFilter {
+        archived: Some(false),
+        ..Default::default()
+    };
+    let listed = storage
+        .list_todos(unarchived_filter, None, None)
+        .await
+        .unwrap();
+    assert!(listed.is_empty());
+
+    let (status, _) = storage.unarchive(child.id).await.unwrap_err();
+    assert_eq!(status, StatusCode::CONFLICT);
+    storage.unarchive(root.id).await.unwrap();
+    let listed = storage
+        .list_todos(unarchived_filter, None, None)
+        .await
+        .unwrap();
+    assert_eq!(listed.len(), 4);
+    assert!(listed.iter().any(|todo| todo.id == added.id));
+}
+
+async fn date_filters_leave_out_undated_todos(storage: &dyn Storage) {
+    let day = |day| Date::from_ordinal_date(2026, day).unwrap();
+    for (name, date) in [
+        ("early", Some(day(10))),
+        ("late", Some(day(20))),
+        ("none", None),
+    ] {
+        let todo = NewTodo {
+            date,
+            ..named(name, None)
+        };
+        storage.create_todo(todo).await.unwrap();
+    }
+
+    let filter = TodoFilter {
+        date_less: Some(day(15)),
+        ..Default::default()
+    };
+    let todos = storage.list_todos(filter, None, None).await.unwrap();
+    assert_eq!(todos.len(), 1);
+    assert_eq!(todos[0].name, "early");
+
+    let filter = TodoFilter {
+        date_less: Some(day(20)),
+        date_more: Some(day(10)),
+        ..Default::default()
+    };
+    assert_eq!(
+        storage.list_todos(filter, None, None).await.unwrap().len(),
+        2
+    );
+}
+
+async fn updates_bump_the_version(storage: &dyn Storage) {
+    let (root, ..) = tree(storage).await;
+    assert_eq!(root.version, 1);
+
+    let renamed = TodoChanges {
+        name: Some("renamed".to_owned()),
+        ..Default::default()
+    };
+    let updated = storage
+        .update_todo(root.id, Some(1), &renamed)
+        .await
+        .unwrap();
+    assert_eq!(updated.name, "renamed");
+    assert_eq!(updated.version, 2);
+    let (status, message) = storage
+        .update_todo(root.id, Some(1), &renamed)
+        .await
+        .unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    assert!(message.contains("version 2"), "{}", message);
+    let (status, _) = storage.delete_todo(root.id, Some(1)).await.unwrap_err();
+    assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(storage.get_todo(root.id).await.unwrap().unwrap().version, 2);
+}
+
+async fn deletions_reach_syncs_until_pruned(storage: &dyn Storage) {
+    let (root, child, grandchild, other) = tree(storage).await;
+    let token = storage.sync(None).await.unwrap().token;
+
+    storage.delete_todo(child.id, None).await.unwrap();
+    let later = storage.sync(Some(token)).await.unwrap();
+    assert!(!later.full);
+    assert!(later.changed.is_empty());
+    assert_eq!(later.deleted, vec![child.id, grandchild.id]);
+    assert!(later.token > token);
+
+    // fresh tombstones outlive a day of retention, but not none
+    assert_eq!(storage.prune_tombstones(1).await.unwrap(), 0);
+    assert_eq!(storage.prune_tombstones(0).await.unwrap(), 2);
+    let resync = storage.sync(Some(token)).await.unwrap();
+    assert!(resync.full);
+    let ids: Vec<i64> = resync.changed.iter().map(|todo| todo.id).collect();
+    assert_eq!(ids, vec![root.id, other.id]);
+    assert!(resync.deleted.is_empty());
+
+    let unchanged = storage.sync(Some(later.token)).await.unwrap();
+    assert!(!unchanged.full);
+    assert!(unchanged.changed.is_empty());
+    assert!(unchanged.deleted.is_empty());
+}
+
+async fn creates_with_a_key_happen_once(storage: &dyn Storage) {
+    let (first, created) = storage
+        .create_todo_once("key", named("first", None))
+        .await
+        .unwrap();
+    assert!(created);
+    let (again, created) = storage
+        .create_todo_once("key", named("second", None))
+        .await
+        .unwrap();
+    assert!(!created);
+    assert_eq!(again.id, first.id);
+    assert_eq!(again.name, "first");
+
+    let (other, created) = storage
+        .create_todo_once("other key", named("second", None))
+        .await
+        .unwrap();
+    assert!(created);
+    assert_ne!(other.id, first.id);
+
+    storage.delete_todo(first.id, None).await.unwrap();
+    let err = storage
+        .create_todo_once("key", named("first", None))
+        .await
+        .unwrap_err();
+    assert_eq!(err.0, StatusCode::CONFLICT);
+
+    // once its key is pruned, the create happens again
+    assert_eq!(storage.prune_idempotency_keys(1).await.unwrap(), 0);
+    assert_eq!(storage.prune_idempotency_keys(0).await.unwrap(), 2);
+    let (recreated, created) = storage
+        .create_todo_once("key", named("first", None))
+        .await
+        .unwrap();
+    assert!(created);
+    assert_ne!(recreated.id, first.id);
+}
+
+async fn the_delivery_log_keeps_the_newest(storage: &dyn Storage) {
+    let webhook = storage
+        .create_webhook("http://127.0.0.1:9/hook", "s", &[])
+        .await
+        .unwrap();
+    for _ in 0..4 {
+        storage
+            .queue_deliveries(Event::Created, "{}")
+            .await
+            .unwrap();
+    }
+    let ids = |deliveries: Vec<Delivery>| -> Vec<i64> {
+        deliveries.iter().map(|delivery| delivery.id).collect()
+    };
+    let queued = ids(storage
+        .list_deliveries(webhook.id, 10)
+        .await
+        .unwrap()
+        .unwrap());
+    // all but the second oldest are sent
+    for &id in [queued[0], queued[1], queued[3]].iter() {
+        storage.delivery_succeeded(id, 200).await.unwrap();
+    }
+
+    // the oldest is past the newest two, the pending one stays anyway
+    assert_eq!(storage.trim_delivery_log(2).await.unwrap(), 1);
+    let kept = ids(storage
+        .list_deliveries(webhook.id, 10)
+        .await
+        .unwrap()
+        .unwrap());
+    assert_eq!(kept, queued[..3]);
+}
@@ -0,0 +1,317 @@
+//! A wrapper around any backend that records how long each of its operations takes, for
+//! the `timely_db_query_duration_seconds` metric. Each runs in a debug-level `storage` span,
+//! so the events it causes (such as sqlx's statement logs) say which operation they belong to.
+
+use async_trait::async_trait;
+use prometheus::HistogramVec;
+use sqlx::migrate::Migrator;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use time::Date;
+use timely_lib::{export::NestedTodo, SyncResponse, Todo};
+use tracing::Instrument;
+
+use super::{
+    Backend, Change, Changed, ChangesError, FailedAttempt, NewTodo, PoolStatus, Storage,
+    StorageResult, TodoChanges, TodoCounts, TodoFilter,
+};
+use crate::caldav::CalendarObject;
+use crate::webhooks::{Delivery, Event, PendingDelivery, Webhook};
+
+pub struct TimedStorage {
+    inner: Arc<dyn Storage>,
+    /// Labelled with the name of the operation
+    durations: HistogramVec,
+}
+
+impl TimedStorage {
+    pub fn new(inner: Arc<dyn Storage>, durations: HistogramVec) -> Self {
+        TimedStorage { inner, durations }
+    }
+
+    async fn time<T>(&self, operation: &str, query: impl Future<Output = T>) -> T {
+        let timer = self.durations.with_label_values(&[operation]).start_timer();
+        let result = query
+            .instrument(tracing::debug_span!("storage", operation))
+            .await;
+        timer.observe_duration();
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for TimedStorage {
+    fn backend(&self) -> Backend {
+        self.inner.backend()
+    }
+
+    fn migrator(&self) -> Option<&'static Migrator> {
+        self.inner.migrator()
+    }
+
+    async fn applied_migrations(&self) -> StorageResult<HashSet<i64>> {
+        self.time("applied_migrations", self.inner.applied_migrations())
+            .await
+    }
+
+    async fn run_migrations(&self) -> StorageResult<()> {
+        self.time("run_migrations", self.inner.run_migrations())
+            .await
+    }
+
+    async fn list_todos(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: Option<i64>,
+    ) -> StorageResult<Vec<Todo>> {
+        self.time("list_todos", self.inner.list_todos(filter, after, limit))
+            .await
+    }
+
+    async fn root_ids(
+        &self,
+        filter: TodoFilter,
+        after: Option<i64>,
+        limit: i64,
+    ) -> StorageResult<Vec<i64>> {
+        self.time("root_ids", self.inner.root_ids(filter, after, limit))
+            .await
+    }
+
+    async fn subtree_todos(
+        &self,
+        root_ids: &[i64],
+        max_depth: Option<i32>,
+        filter: TodoFilter,
+    ) -> StorageResult<Vec<Todo>> {
+        self.time(
+            "subtree_todos",
+            self.inner.subtree_todos(root_ids, max_depth, filter),
+        )
+        .await
+    }
+
+    async fn child_counts(
+        &self,
+        parent_ids: &[i64],
+        filter: TodoFilter,
+    ) -> StorageResult<HashMap<i64, usize>> {
+        self.time("child_counts", self.inner.child_counts(parent_ids, filter))
+            .await
+    }
+
+    async fn get_todo(&self, id: i64) -> StorageResult<Option<Todo>> {
+        self.time("get_todo", self.inner.get_todo(id)).await
+    }
+
+    async fn todo_exists(&self, id: i64) -> StorageResult<bool> {
+        self.time("todo_exists", self.inner.todo_exists(id)).await
+    }
+
+    async fn ancestors(&self, id: i64) -> StorageResult<Option<Vec<Todo>>> {
+        self.time("ancestors", self.inner.ancestors(id)).await
+    }
+
+    async fn find_by_name(&self, name: &str) -> StorageResult<Option<i64>> {
+        self.time("find_by_name", self.inner.find_by_name(name))
+            .await
+    }
+
+    async fn sync(&self, since: Option<i64>) -> StorageResult<SyncResponse> {
+        self.time("sync", self.inner.sync(since)).await
+    }
+
+    async fn prune_tombstones(&self, older_than_days: i32) -> StorageResult<u64> {
+        self.time(
+            "prune_tombstones",
+            self.inner.prune_tombstones(older_than_days),
+        )
+        .await
+    }
+
+    async fn prune_idempotency_keys(&self, older_than_days: i32) -> StorageResult<u64> {
+        self.time(
+            "prune_idempotency_keys",
+            self.inner.prune_idempotency_keys(older_than_days),
+        )
+        .await
+    }
+
+    async fn create_todo(&self, todo: NewTodo) -> StorageResult<Todo> {
+        self.time("create_todo", self.inner.create_todo(todo)).await
+    }
+
+    async fn create_todo_once(&self, key: &str, todo: NewTodo) -> StorageResult<(Todo, bool)> {
+        self.time("create_todo_once", self.inner.create_todo_once(key, todo))
+            .await
+    }
+
+    async fn create_forest(
+        &self,
+        parent_id: Option<i64>,
+        forest: &mut [NestedTodo],
+    ) -> StorageResult<Vec<Todo>> {
+        self.time("create_forest", self.inner.create_forest(parent_id, forest))
+            .await
+    }
+
+    async fn update_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        changes: &TodoChanges,
+    ) -> StorageResult<Todo> {
+        self.time("update_todo", self.inner.update_todo(id, version, changes))
+            .await
+    }
+
+    async fn replace_todo(
+        &self,
+        id: i64,
+        version: Option<i64>,
+        todo: &NewTodo,
+    ) -> StorageResult<Todo> {
+        self.time("replace_todo", self.inner.replace_todo(id, version, todo))
+            .await
+    }
+
+    async fn toggle_todo(&self, id: i64, version: Option<i64>) -> StorageResult<Todo> {
+        self.time("toggle_todo", self.inner.toggle_todo(id, version))
+            .await
+    }
+
+    async fn delete_todo(&self, id: i64, version: Option<i64>) -> StorageResult<(Todo, Vec<i64>)> {
+        self.time("delete_todo", self.inner.delete_todo(id, version))
+            .await
+    }
+
+    async fn apply_changes(&self, changes: Vec<Change>) -> Result<Vec<Changed>, ChangesError> {
+        self.time("apply_changes", self.inner.apply_changes(changes))
+            .await
+    }
+
+    async fn archive_completed(&self, older_than_days: i32) -> StorageResult<Vec<Todo>> {
+        self.time(
+            "archive_completed",
+            self.inner.archive_completed(older_than_days),
+        )
+        .await
+    }
+
+    async fn unarchive(&self, id: i64) -> StorageResult<Todo> {
+        self.time("unarchive", self.inner.unarchive(id)).await
+    }
+
+    async fn calendar_objects(&self) -> StorageResult<Vec<CalendarObject>> {
+        self.time("calendar_objects", self.inner.calendar_objects())
+            .await
+    }
+
+    async fn calendar_object(&self, resource: &str) -> StorageResult<Option<CalendarObject>> {
+        self.time("calendar_object", self.inner.calendar_object(resource))
+            .await
+    }
+
+    async fn archived_object(&self, resource: &str) -> StorageResult<bool> {
+        self.time("archived_object", self.inner.archived_object(resource))
+            .await
+    }
+
+    async fn client_uids(&self) -> StorageResult<HashMap<i64, String>> {
+        self.time("client_uids", self.inner.client_uids()).await
+    }
+
+    async fn resolve_uid(&self, uid: &str) -> StorageResult<Option<(i64, bool)>> {
+        self.time("resolve_uid", self.inner.resolve_uid(uid)).await
+    }
+
+    async fn collection_tag(&self) -> StorageResult<i64> {
+        self.time("collection_tag", self.inner.collection_tag())
+            .await
+    }
+
+    async fn list_webhooks(&self) -> StorageResult<Vec<Webhook>> {
+        self.time("list_webhooks", self.inner.list_webhooks()).await
+    }
+
+    async fn create_webhook(
+        &self,
+        url: &str,
+        secret: &str,
+        events: &[String],
+    ) -> StorageResult<Webhook> {
+        self.time(
+            "create_webhook",
+            self.inner.create_webhook(url, secret, events),
+        )
+        .await
+    }
+
+    async fn delete_webhook(&self, id: i64) -> StorageResult<bool> {
+        self.time("delete_webhook", self.inner.delete_webhook(id))
+            .await
+    }
+
+    async fn list_deliveries(
+        &self,
+        webhook_id: i64,
+        limit: i64,
+    ) -> StorageResult<Option<Vec<Delivery>>> {
+        self.time(
+            "list_deliveries",
+            self.inner.list_deliveries(webhook_id, limit),
+        )
+        .await
+    }
+
+    async fn queue_deliveries(&self, event: Event, payload: &str) -> StorageResult<u64> {
+        self.time(
+            "queue_deliveries",
+            self.inner.queue_deliveries(event, payload),
+        )
+        .await
+    }
+
+    async fn due_deliveries(&self, limit: i64) -> StorageResult<Vec<PendingDelivery>> {
+        self.time("due_deliveries", self.inner.due_deliveries(limit))
+            .await
+    }
+
+    async fn delivery_succeeded(&self, id: i64, status_code: i32) -> StorageResult<()> {
+        self.time(
+            "delivery_succeeded",
+            self.inner.delivery_succeeded(id, status_code),
+        )
+        .await
+    }
+
+    async fn delivery_failed(&self, id: i64, attempt: FailedAttempt<'_>) -> StorageResult<()> {
+        self.time("delivery_failed", self.inner.delivery_failed(id, attempt))
+            .await
+    }
+
+    async fn trim_delivery_log(&self, keep: i64) -> StorageResult<u64> {
+        self.time("trim_delivery_log", self.inner.trim_delivery_log(keep))
+            .await
+    }
+
+    async fn secret(&self, name: &str, generated: &str) -> StorageResult<String> {
+        self.time("secret", self.inner.secret(name, generated))
+            .await
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        self.inner.pool_status()
+    }
+
+    async fn todo_counts(&self, today: Date) -> StorageResult<TodoCounts> {
+        self.time("todo_counts", self.inner.todo_counts(today))
+            .await
+    }
+
+    async fn close(&self) {
+        self.inner.close().await;
+    }
+}
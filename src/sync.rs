@@ -0,0 +1,33 @@
+//! Pruning of the tombstones that `/sync` reports deletions from. They are kept for
+//! `TOMBSTONE_RETENTION_DAYS`; a client that has not synced for longer gets the whole
+//! list, marked `full`, instead of the changes since its token.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::storage::Storage;
+
+/// How often the tombstones are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Prunes old tombstones and idempotency keys once a day, until `stop` is cancelled.
+pub async fn run_pruning(storage: Arc<dyn Storage>, retention_days: i32, stop: CancellationToken) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stop.cancelled() => return,
+        }
+        match storage.prune_tombstones(retention_days).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!(tombstones = pruned, "Pruned old tombstones"),
+            Err((_, err)) => tracing::error!(error = %err, "Pruning tombstones failed"),
+        }
+        match storage.prune_idempotency_keys(retention_days).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!(keys = pruned, "Pruned old idempotency keys"),
+            Err((_, err)) => tracing::error!(error = %err, "Pruning idempotency keys failed"),
+        }
+    }
+}
@@ -0,0 +1,1716 @@
+//! End-to-end tests of the HTTP routes. Each test serves the whole app on a local port,
+//! backed by the in-memory storage, and drives it with an HTTP client the way the web
+//! interface, the apps and CalDAV clients do.
+//!
+//! No database is needed: the Postgres queries are checked at build time against the
+//! offline cache in `.sqlx`, which `cargo sqlx prepare` regenerates when a query changes.
+
+use axum::{routing::post, Router};
+use reqwest::{header, redirect::Policy, Client, Method, RequestBuilder, Response, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tera::Tera;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::storage::{MemoryStorage, SqliteStorage, Storage};
+use crate::{app, run, AppState};
+
+const PASSWORD: &str = "correct horse";
+
+struct TestApp {
+    /// Where the app is served, with the base path if it has one
+    base: String,
+    /// Where the server is listening
+    origin: String,
+    client: Client,
+    state: AppState,
+}
+
+impl TestApp {
+    async fn spawn() -> Self {
+        TestApp::spawn_with("", false).await
+    }
+
+    async fn spawn_with(base_path: &str, trust_forwarded_prefix: bool) -> Self {
+        TestApp::spawn_on(
+            Arc::new(MemoryStorage::new()),
+            base_path,
+            trust_forwarded_prefix,
+        )
+        .await
+    }
+
+    async fn spawn_on(
+        storage: Arc<dyn Storage>,
+        base_path: &str,
+        trust_forwarded_prefix: bool,
+    ) -> Self {
+        let templates = Tera::new("templates/**/*").unwrap();
+        let state = AppState::new(
+            storage,
+            PASSWORD,
+            templates,
+            base_path.to_owned(),
+            trust_forwarded_prefix,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let router = app(state.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let base = format!("{}{}", origin, base_path);
+        // Redirects are checked, not followed.
+        let client = Client::builder().redirect(Policy::none()).build().unwrap();
+        TestApp {
+            base,
+            origin,
+            client,
+            state,
+        }
+    }
+
+    async fn calendar_token(&self) -> String {
+        self.state.calendar_token().await.unwrap()
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.base, path))
+    }
+
+    /// A request with the password in the query, as the apps send it.
+    fn authed(&self, method: Method, path: &str) -> RequestBuilder {
+        self.request(method, path).query(&[("password", PASSWORD)])
+    }
+
+    /// Logs in through the web form and returns the cookie the browser would keep.
+    async fn login(&self) -> String {
+        let response = self
+            .request(Method::POST, "/login")
+            .form(&[("password", PASSWORD)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let cookie = set_cookie(&response).expect("login sets a cookie");
+        cookie.split(';').next().unwrap().to_owned()
+    }
+
+    /// Creates a todo through the API, returning it.
+    async fn create(&self, todo: Value) -> Value {
+        let response = with_json(self.authed(Method::POST, "/api/v1/todos"), &todo)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        body_json(response).await
+    }
+
+    async fn get_json(&self, path: &str) -> Value {
+        let response = self.authed(Method::GET, path).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "GET {}", path);
+        body_json(response).await
+    }
+}
+
+fn with_json(request: RequestBuilder, body: &Value) -> RequestBuilder {
+    request
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+}
+
+async fn body_json(response: Response) -> Value {
+    let text = response.text().await.unwrap();
+    serde_json::from_str(&text).unwrap_or_else(|err| panic!("{}: {}", err, text))
+}
+
+fn header_value<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response
+        .headers()
+        .get(name)
+        .map(|value| value.to_str().unwrap())
+}
+
+fn set_cookie(response: &Response) -> Option<&str> {
+    header_value(response, "set-cookie")
+}
+
+/// The names of a list of todos, in order.
+fn names(todos: &Value) -> Vec<&str> {
+    todos
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|todo| todo["name"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn every_route_needs_the_password() {
+    let app = TestApp::spawn().await;
+    let todo = json!({ "name": "x" });
+    let routes = [
+        (Method::GET, "/todos", None),
+        (Method::POST, "/todos", Some(todo.clone())),
+        (Method::DELETE, "/todos", Some(json!(1))),
+        (Method::POST, "/todos/toggle", Some(json!(1))),
+        (
+            Method::POST,
+            "/todos/done",
+            Some(json!({ "id": 1, "done": true })),
+        ),
+        (Method::POST, "/todos/quick", Some(json!({ "text": "x" }))),
+        (Method::GET, "/todos/export?format=json", None),
+        (Method::GET, "/sync", None),
+        (Method::GET, "/api/v1/todos", None),
+        (Method::POST, "/api/v1/todos", Some(todo.clone())),
+        (Method::GET, "/api/v1/todos/tree", None),
+        (Method::GET, "/api/v1/todos/1", None),
+        (Method::PATCH, "/api/v1/todos/1", Some(todo.clone())),
+        (Method::DELETE, "/api/v1/todos/1", None),
+        (Method::GET, "/api/v1/todos/1/ancestors", None),
+        (Method::GET, "/api/v1/todos/1/subtree", None),
+        (Method::POST, "/api/v1/todos/1/children", Some(todo.clone())),
+        (
+            Method::POST,
+            "/api/v1/todos/quick",
+            Some(json!({ "text": "x" })),
+        ),
+        (Method::GET, "/api/v1/todos/export?format=csv", None),
+        (Method::GET, "/api/v1/sync", None),
+        (
+            Method::POST,
+            "/api/v1/batch",
+            Some(json!({ "operations": [] })),
+        ),
+        (Method::GET, "/api/v1/archive", None),
+        (Method::POST, "/api/v1/archive?older_than_days=0", None),
+        (Method::POST, "/api/v1/todos/1/unarchive", None),
+        (Method::GET, "/api/v1/webhooks", None),
+        (
+            Method::POST,
+            "/api/v1/webhooks",
+            Some(json!({ "url": "http://127.0.0.1:9", "secret": "s" })),
+        ),
+        (Method::DELETE, "/api/v1/webhooks/1", None),
+        (Method::GET, "/api/v1/webhooks/1/deliveries", None),
+        (Method::GET, "/fragments/todos", None),
+        (Method::GET, "/fragments/todos/1/children", None),
+    ];
+
+    for (method, path, body) in routes {
+        let wrong_password = app
+            .request(method.clone(), path)
+            .query(&[("password", "wrong")]);
+        let wrong_cookie = app
+            .request(method.clone(), path)
+            .header(header::COOKIE, "auth=wrong");
+        for request in [
+            app.request(method.clone(), path),
+            wrong_password,
+            wrong_cookie,
+        ] {
+            let request = match &body {
+                Some(body) => with_json(request, body),
+                None => request,
+            };
+            let response = request.send().await.unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    // The import takes plain text.
+    let response = app
+        .request(Method::POST, "/api/v1/todos/import?format=markdown")
+        .body("- [ ] x")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Nothing got through.
+    assert_eq!(app.get_json("/api/v1/todos").await, json!([]));
+}
+
+#[tokio::test]
+async fn login_sets_and_logout_clears_the_cookie() {
+    let app = TestApp::spawn().await;
+    app.create(json!({ "name": "Water the plants" })).await;
+
+    let page = app.request(Method::GET, "/").send().await.unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+    let page = page.text().await.unwrap();
+    assert!(page.contains("action=\"/login\""));
+    assert!(!page.contains("Water the plants"));
+
+    let response = app
+        .request(Method::POST, "/login")
+        .form(&[("password", "wrong")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&response, "location"), Some("/"));
+    assert_eq!(set_cookie(&response), None);
+
+    let cookie = app.login().await;
+    // percent-encoded, like any cookie value with a space
+    assert_eq!(cookie, "auth=correct%20horse");
+    let page = app
+        .request(Method::GET, "/")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("Your Todos"));
+    assert!(page.contains("Water the plants"));
+    let feed = format!("/calendar/{}/todos.ics", app.calendar_token().await);
+    assert!(page.contains(&feed));
+
+    // The cookie works for the API too.
+    let response = app
+        .request(Method::GET, "/api/v1/todos")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .request(Method::GET, "/logout")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&response, "location"), Some("/"));
+    let cleared = set_cookie(&response).unwrap();
+    assert!(cleared.starts_with("auth=;"));
+    assert!(cleared.contains("Max-Age=0"));
+}
+
+#[tokio::test]
+async fn legacy_routes_create_toggle_and_delete() {
+    let app = TestApp::spawn().await;
+
+    let response = with_json(
+        app.authed(Method::POST, "/todos"),
+        &json!({ "name": "Taxes", "date": "2026-04-30" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header_value(&response, "etag"), Some("\"1\""));
+    assert_eq!(header_value(&response, "deprecation"), Some("@1792281600"));
+    assert_eq!(
+        header_value(&response, "link"),
+        Some("</api/v1/todos>; rel=\"successor-version\"")
+    );
+    let taxes = body_json(response).await;
+    assert_eq!(taxes["date"], json!([2026, 120]));
+    let id = taxes["id"].as_i64().unwrap();
+    let child = with_json(
+        app.authed(Method::POST, "/todos"),
+        &json!({ "name": "Receipts", "parent_id": id }),
+    )
+    .send()
+    .await
+    .unwrap();
+    let child = body_json(child).await;
+
+    let response = with_json(app.authed(Method::POST, "/todos/toggle"), &json!(id))
+        .header(header::IF_MATCH, "\"1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header_value(&response, "etag"), Some("\"2\""));
+    assert_eq!(body_json(response).await, json!(true));
+    let child = app
+        .get_json(&format!("/api/v1/todos/{}", child["id"]))
+        .await;
+    assert_eq!(child["done"], json!(true));
+
+    // The version moved on, so the same If-Match is refused.
+    let response = with_json(app.authed(Method::POST, "/todos/toggle"), &json!(id))
+        .header(header::IF_MATCH, "\"1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let response = with_json(
+        app.authed(Method::POST, "/todos/done"),
+        &json!({ "id": id, "done": false }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["done"], json!(false));
+
+    let response = with_json(app.authed(Method::POST, "/todos/toggle"), &json!(999))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let other = app.create(json!({ "name": "Groceries" })).await;
+    let response = with_json(app.authed(Method::DELETE, "/todos"), &json!(id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let remaining = body_json(response).await;
+    assert_eq!(names(&remaining), ["Groceries"]);
+    assert_eq!(remaining[0]["id"], other["id"]);
+
+    let response = with_json(app.authed(Method::DELETE, "/todos"), &json!(id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = with_json(
+        app.authed(Method::POST, "/todos"),
+        &json!({ "name": "x", "date": "soon" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn date_filters_select_a_range() {
+    let app = TestApp::spawn().await;
+    for (name, date) in [
+        ("early", Some("2026-01-10")),
+        ("middle", Some("2026-01-20")),
+        ("late", Some("2026-01-30")),
+        ("undated", None),
+    ] {
+        app.create(json!({ "name": name, "date": date })).await;
+    }
+
+    for prefix in ["", "/api/v1"] {
+        let todos = app.get_json(&format!("{}/todos", prefix)).await;
+        assert_eq!(names(&todos), ["early", "middle", "late", "undated"]);
+        let todos = app
+            .get_json(&format!("{}/todos?date_less=2026-01-20", prefix))
+            .await;
+        assert_eq!(names(&todos), ["early", "middle"]);
+        let todos = app
+            .get_json(&format!("{}/todos?date_more=2026-01-20", prefix))
+            .await;
+        assert_eq!(names(&todos), ["middle", "late"]);
+        let todos = app
+            .get_json(&format!(
+                "{}/todos?date_more=2026-01-15&date_less=2026-01-25",
+                prefix
+            ))
+            .await;
+        assert_eq!(names(&todos), ["middle"]);
+    }
+
+    let tree = app
+        .get_json("/api/v1/todos/tree?date_more=2026-01-15&date_less=2026-01-25")
+        .await;
+    assert_eq!(tree["todos"].as_array().unwrap().len(), 1);
+    assert_eq!(tree["todos"][0]["todo"]["name"], json!("middle"));
+
+    let response = app
+        .authed(Method::GET, "/api/v1/todos?date_less=yesterday")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn todos_are_paginated_by_cursor() {
+    let app = TestApp::spawn().await;
+    for name in ["one", "two", "three"] {
+        app.create(json!({ "name": name })).await;
+    }
+
+    let response = app
+        .authed(Method::GET, "/api/v1/todos?limit=2")
+        .send()
+        .await
+        .unwrap();
+    let cursor = header_value(&response, "x-next-cursor").unwrap().to_owned();
+    assert_eq!(names(&body_json(response).await), ["one", "two"]);
+
+    let response = app
+        .authed(
+            Method::GET,
+            &format!("/api/v1/todos?limit=2&after={}", cursor),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "x-next-cursor"), None);
+    assert_eq!(names(&body_json(response).await), ["three"]);
+
+    for limit in [0, 501] {
+        let response = app
+            .authed(Method::GET, &format!("/api/v1/todos?limit={}", limit))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let tree = app.get_json("/api/v1/todos/tree?limit=1").await;
+    assert_eq!(tree["todos"][0]["todo"]["name"], json!("one"));
+    let tree = app
+        .get_json(&format!(
+            "/api/v1/todos/tree?limit=5&after={}",
+            tree["next_cursor"]
+        ))
+        .await;
+    assert_eq!(tree["todos"].as_array().unwrap().len(), 2);
+    assert_eq!(tree["next_cursor"], Value::Null);
+}
+
+#[tokio::test]
+async fn todos_are_read_changed_and_deleted() {
+    let app = TestApp::spawn().await;
+    let home = app
+        .create(json!({ "name": "Home", "priority": "high", "tags": ["house"] }))
+        .await;
+    assert_eq!(home["priority"], json!(1));
+    let home_id = home["id"].as_i64().unwrap();
+
+    let response = with_json(
+        app.authed(Method::POST, &format!("/api/v1/todos/{}/children", home_id)),
+        &json!({ "name": "Kitchen" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let kitchen = body_json(response).await;
+    assert_eq!(kitchen["parent_id"], json!(home_id));
+    let kitchen_id = kitchen["id"].as_i64().unwrap();
+    let sink = app
+        .create(json!({ "name": "Sink", "parent_id": kitchen_id }))
+        .await;
+
+    let response = with_json(
+        app.authed(Method::POST, "/api/v1/todos/999/children"),
+        &json!({ "name": "Orphan" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = with_json(
+        app.authed(Method::POST, "/api/v1/todos"),
+        &json!({ "name": "Orphan", "parent_id": 999 }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    for path in [
+        "/api/v1/todos".to_owned(),
+        format!("/api/v1/todos/{}/children", home_id),
+    ] {
+        let response = with_json(app.authed(Method::POST, &path), &json!({ "name": " " }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}",
+            path
+        );
+    }
+
+    let response = app
+        .authed(Method::GET, &format!("/api/v1/todos/{}", home_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "etag"), Some("\"1\""));
+    let response = app
+        .authed(Method::GET, "/api/v1/todos/999")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let ancestors = app
+        .get_json(&format!("/api/v1/todos/{}/ancestors", sink["id"]))
+        .await;
+    assert_eq!(names(&ancestors), ["Home", "Kitchen"]);
+    let subtree = app
+        .get_json(&format!("/api/v1/todos/{}/subtree?max_depth=1", home_id))
+        .await;
+    assert_eq!(subtree["children"][0]["todo"]["name"], json!("Kitchen"));
+    assert_eq!(subtree["children"][0]["children"], json!([]));
+    assert_eq!(subtree["children"][0]["child_count"], json!(1));
+
+    let patch = |version: &str, body: Value| {
+        with_json(
+            app.authed(Method::PATCH, &format!("/api/v1/todos/{}", home_id)),
+            &body,
+        )
+        .header(header::IF_MATCH, version)
+    };
+    let response = patch(
+        "\"1\"",
+        json!({ "name": "House", "description": "all of it" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header_value(&response, "etag"), Some("\"2\""));
+    let house = body_json(response).await;
+    assert_eq!(house["name"], json!("House"));
+    assert_eq!(house["tags"], json!(["house"]));
+    let response = patch("\"1\"", json!({ "name": "Flat" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = patch("one", json!({ "name": "Flat" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = patch("*", json!({ "parent_id": sink["id"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = patch("*", json!({ "name": " " })).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Done cascades to the descendants; a cleared description is null.
+    let response = patch("*", json!({ "done": true, "description": null }))
+        .send()
+        .await
+        .unwrap();
+    let house = body_json(response).await;
+    assert_eq!(house["description"], Value::Null);
+    let sink = app.get_json(&format!("/api/v1/todos/{}", sink["id"])).await;
+    assert_eq!(sink["done"], json!(true));
+
+    let response = app
+        .authed(Method::DELETE, &format!("/api/v1/todos/{}", home_id))
+        .header(header::IF_MATCH, "\"1\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = app
+        .authed(Method::DELETE, &format!("/api/v1/todos/{}", home_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(app.get_json("/api/v1/todos").await, json!([]));
+}
+
+#[tokio::test]
+async fn quick_add_files_under_a_named_parent() {
+    let app = TestApp::spawn().await;
+    let finances = app.create(json!({ "name": "Finances" })).await;
+
+    let response = with_json(
+        app.authed(Method::POST, "/api/v1/todos/quick"),
+        &json!({ "text": "Pay rent tomorrow !high #home under finances", "today": "2026-10-18" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let rent = body_json(response).await;
+    assert_eq!(rent["name"], json!("Pay rent"));
+    assert_eq!(rent["parent_id"], finances["id"]);
+    assert_eq!(rent["date"], json!([2026, 292]));
+    assert_eq!(rent["priority"], json!(1));
+    assert_eq!(rent["tags"], json!(["home"]));
+
+    for text in ["Call mum under nobody", "!high"] {
+        let response = with_json(
+            app.authed(Method::POST, "/todos/quick"),
+            &json!({ "text": text }),
+        )
+        .send()
+        .await
+        .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "{}",
+            text
+        );
+    }
+}
+
+#[tokio::test]
+async fn creates_with_an_idempotency_key_happen_once() {
+    let app = TestApp::spawn().await;
+    let create = |key: &str| {
+        with_json(
+            app.authed(Method::POST, "/api/v1/todos")
+                .header("idempotency-key", key),
+            &json!({ "name": "Once" }),
+        )
+        .send()
+    };
+
+    let response = create("a1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let first = body_json(response).await;
+    let again = body_json(create("a1").await.unwrap()).await;
+    assert_eq!(again["id"], first["id"]);
+    let todos = app.get_json("/api/v1/todos").await;
+    assert_eq!(todos.as_array().unwrap().len(), 1);
+
+    let response = with_json(
+        app.authed(
+            Method::POST,
+            &format!("/api/v1/todos/{}/children", first["id"]),
+        )
+        .header("idempotency-key", "a1"),
+        &json!({ "name": "Child" }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(body_json(response).await["id"], first["id"]);
+
+    let response = create(" ").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    app.authed(Method::DELETE, &format!("/api/v1/todos/{}", first["id"]))
+        .send()
+        .await
+        .unwrap();
+    let response = create("a1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn batches_apply_all_or_nothing() {
+    let app = TestApp::spawn().await;
+    let errands = app.create(json!({ "name": "Errands" })).await;
+    let id = errands["id"].as_i64().unwrap();
+
+    let batch = |operations: Value| {
+        with_json(
+            app.authed(Method::POST, "/api/v1/batch"),
+            &json!({ "operations": operations }),
+        )
+    };
+    let response = batch(json!([
+        { "op": "create", "name": "Post office", "parent_id": id },
+        { "op": "update", "id": id, "changes": { "name": "Saturday errands" } },
+        { "op": "toggle", "id": id },
+    ]))
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let results = body_json(response).await["results"].clone();
+    let statuses: Vec<i64> = results
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|result| result["status"].as_i64().unwrap())
+        .collect();
+    assert_eq!(statuses, [201, 200, 200]);
+    assert_eq!(results[2]["todo"]["done"], json!(true));
+    assert_eq!(results[2]["todo"]["version"], json!(3));
+
+    let response = batch(json!([
+        { "op": "delete", "id": id },
+        { "op": "set_done", "id": 999, "done": true },
+    ]))
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_json(response).await["index"], json!(1));
+    let todos = app.get_json("/api/v1/todos").await;
+    assert_eq!(names(&todos), ["Saturday errands", "Post office"]);
+    let response = batch(json!([{ "op": "create", "name": "  " }]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["index"], json!(0));
+
+    let response = batch(json!([{ "op": "delete", "id": id }]))
+        .send()
+        .await
+        .unwrap();
+    let results = body_json(response).await["results"].clone();
+    assert_eq!(results[0]["status"], json!(204));
+    assert_eq!(results[0]["deleted"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn batch_moves_under_a_newer_parent_keep_the_todo() {
+    let app = TestApp::spawn().await;
+    let moved = app.create(json!({ "name": "Renew passport" })).await;
+    let trip = app.create(json!({ "name": "Trip" })).await;
+    let response = with_json(
+        app.authed(Method::POST, "/api/v1/batch"),
+        &json!({ "operations": [{ "op": "move", "id": moved["id"], "parent_id": trip["id"] }] }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let exported = app.get_json("/api/v1/todos/export?format=json").await;
+    assert_eq!(names(&exported), ["Trip"]);
+    assert_eq!(exported[0]["children"][0]["name"], json!("Renew passport"));
+    let tree = app.get_json("/api/v1/todos/tree").await;
+    assert_eq!(tree["todos"].as_array().unwrap().len(), 1);
+    assert_eq!(tree["todos"][0]["todo"]["name"], json!("Trip"));
+    assert_eq!(
+        tree["todos"][0]["children"][0]["todo"]["name"],
+        json!("Renew passport")
+    );
+}
+
+#[tokio::test]
+async fn completed_trees_are_archived_and_unarchived() {
+    let app = TestApp::spawn().await;
+    let trip = app.create(json!({ "name": "Trip" })).await;
+    let id = trip["id"].as_i64().unwrap();
+    app.create(json!({ "name": "Tickets", "parent_id": id }))
+        .await;
+    app.create(json!({ "name": "Open" })).await;
+    with_json(
+        app.authed(Method::PATCH, &format!("/api/v1/todos/{}", id)),
+        &json!({ "done": true }),
+    )
+    .send()
+    .await
+    .unwrap();
+
+    let response = app
+        .authed(Method::POST, "/api/v1/archive?older_than_days=-1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = app
+        .authed(Method::POST, "/api/v1/archive?older_than_days=0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(names(&body_json(response).await), ["Trip"]);
+
+    assert_eq!(names(&app.get_json("/api/v1/todos").await), ["Open"]);
+    let everything = app.get_json("/api/v1/todos?include_archived=true").await;
+    assert_eq!(names(&everything), ["Trip", "Tickets", "Open"]);
+    let archive = app.get_json("/api/v1/archive").await;
+    assert_eq!(archive["todos"][0]["todo"]["name"], json!("Trip"));
+    assert_eq!(
+        archive["todos"][0]["children"][0]["todo"]["name"],
+        json!("Tickets")
+    );
+
+    let cookie = app.login().await;
+    let page = app
+        .request(Method::GET, "/archive")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(page.status(), StatusCode::OK);
+    assert!(page.text().await.unwrap().contains("Tickets"));
+    let response = app.request(Method::GET, "/archive").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(header_value(&response, "location"), Some("/"));
+
+    let unarchive = format!("/api/v1/todos/{}/unarchive", id);
+    let response = app.authed(Method::POST, &unarchive).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.authed(Method::POST, &unarchive).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = app
+        .authed(Method::POST, "/api/v1/todos/999/unarchive")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        app.get_json("/api/v1/todos")
+            .await
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn sync_reports_changes_since_a_token() {
+    let app = TestApp::spawn().await;
+    let kept = app.create(json!({ "name": "Kept" })).await;
+    let dropped = app.create(json!({ "name": "Dropped" })).await;
+
+    let full = app.get_json("/sync").await;
+    assert_eq!(names(&full["changed"]), ["Kept", "Dropped"]);
+    let token = full["token"].as_i64().unwrap();
+
+    app.authed(Method::DELETE, &format!("/api/v1/todos/{}", dropped["id"]))
+        .send()
+        .await
+        .unwrap();
+    with_json(
+        app.authed(Method::PATCH, &format!("/api/v1/todos/{}", kept["id"])),
+        &json!({ "name": "Renamed" }),
+    )
+    .send()
+    .await
+    .unwrap();
+
+    let changes = app.get_json(&format!("/api/v1/sync?since={}", token)).await;
+    assert_eq!(names(&changes["changed"]), ["Renamed"]);
+    assert_eq!(changes["deleted"], json!([dropped["id"]]));
+    let later = changes["token"].as_i64().unwrap();
+    assert!(later > token);
+
+    let nothing = app.get_json(&format!("/sync?since={}", later)).await;
+    assert_eq!(nothing["changed"], json!([]));
+    assert_eq!(nothing["deleted"], json!([]));
+    assert_eq!(nothing["token"], json!(later));
+    assert_eq!(nothing["full"], json!(false));
+}
+
+#[tokio::test]
+async fn syncs_from_before_pruned_tombstones_get_everything() {
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let app = TestApp::spawn_on(storage.clone(), "", false).await;
+    app.create(json!({ "name": "Kept" })).await;
+    let dropped = app.create(json!({ "name": "Dropped" })).await;
+    let token = app.get_json("/sync").await["token"].as_i64().unwrap();
+    app.authed(Method::DELETE, &format!("/api/v1/todos/{}", dropped["id"]))
+        .send()
+        .await
+        .unwrap();
+    let later = app.get_json(&format!("/sync?since={}", token)).await;
+    assert_eq!(later["deleted"], json!([dropped["id"]]));
+
+    assert_eq!(storage.prune_tombstones(0).await.unwrap(), 1);
+    let resync = app.get_json(&format!("/sync?since={}", token)).await;
+    assert_eq!(resync["full"], json!(true));
+    assert_eq!(names(&resync["changed"]), ["Kept"]);
+    assert_eq!(resync["deleted"], json!([]));
+    // tokens from after the pruned deletion still get changes only
+    let changes = app
+        .get_json(&format!("/sync?since={}", later["token"]))
+        .await;
+    assert_eq!(changes["full"], json!(false));
+    assert_eq!(changes["changed"], json!([]));
+}
+
+#[tokio::test]
+async fn todos_are_imported_and_exported() {
+    let app = TestApp::spawn().await;
+    let checklist = "- [ ] Garden\n  - [x] Mow the lawn\n- [ ] Garage";
+
+    let response = app
+        .authed(
+            Method::POST,
+            "/api/v1/todos/import?format=markdown&dry_run=true",
+        )
+        .body(checklist)
+        .send()
+        .await
+        .unwrap();
+    let result = body_json(response).await;
+    assert_eq!(result["dry_run"], json!(true));
+    assert_eq!(result["created"], json!(3));
+    assert_eq!(app.get_json("/api/v1/todos").await, json!([]));
+
+    let response = app
+        .authed(Method::POST, "/todos/import?format=markdown")
+        .body(checklist)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let result = body_json(response).await;
+    assert_eq!(
+        result["todos"][0]["children"][0]["name"],
+        json!("Mow the lawn")
+    );
+    let todos = app.get_json("/api/v1/todos").await;
+    assert_eq!(names(&todos), ["Garden", "Mow the lawn", "Garage"]);
+    assert_eq!(todos[1]["parent_id"], todos[0]["id"]);
+
+    let response = app
+        .authed(
+            Method::POST,
+            "/api/v1/todos/import?format=json&parent_id=999",
+        )
+        .body("[]")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app
+        .authed(Method::POST, "/api/v1/todos/import?format=json")
+        .body("not json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = app
+        .authed(Method::GET, "/api/v1/todos/export?format=markdown")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(header_value(&response, "content-disposition")
+        .unwrap()
+        .starts_with("attachment"));
+    let exported = response.text().await.unwrap();
+    assert!(exported.contains("- [ ] Garden"));
+    assert!(exported.contains("  - [x] Mow the lawn"));
+    let exported = app.get_json("/todos/export?format=json").await;
+    assert_eq!(exported[0]["children"][0]["name"], json!("Mow the lawn"));
+}
+
+#[tokio::test]
+async fn priorities_and_tags_survive_an_export_and_import() {
+    let app = TestApp::spawn().await;
+    let home = app
+        .create(json!({ "name": "Home", "priority": "high", "tags": ["house", "weekend"] }))
+        .await;
+    app.create(
+        json!({ "name": "Paint", "parent_id": home["id"], "priority": "low", "tags": ["diy"] }),
+    )
+    .await;
+    app.create(json!({ "name": "Call mum" })).await;
+    let priorities_and_tags = |todos: &Value| {
+        todos
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| (todo["priority"].clone(), todo["tags"].clone()))
+            .collect::<Vec<_>>()
+    };
+    let expected = priorities_and_tags(&app.get_json("/api/v1/todos").await);
+    assert_eq!(expected[0], (json!(1), json!(["house", "weekend"])));
+
+    for format in ["json", "markdown", "todotxt"] {
+        let response = app
+            .authed(
+                Method::GET,
+                &format!("/api/v1/todos/export?format={}", format),
+            )
+            .send()
+            .await
+            .unwrap();
+        let exported = response.text().await.unwrap();
+
+        let copy = TestApp::spawn().await;
+        let response = copy
+            .authed(
+                Method::POST,
+                &format!("/api/v1/todos/import?format={}", format),
+            )
+            .body(exported.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", format);
+        let todos = copy.get_json("/api/v1/todos").await;
+        assert_eq!(names(&todos), ["Home", "Paint", "Call mum"], "{}", exported);
+        assert_eq!(todos[1]["parent_id"], todos[0]["id"], "{}", exported);
+        assert_eq!(priorities_and_tags(&todos), expected, "{}", exported);
+    }
+
+    let response = app
+        .authed(Method::GET, "/api/v1/todos/export?format=csv")
+        .send()
+        .await
+        .unwrap();
+    let exported = response.text().await.unwrap();
+    assert!(exported.contains(",high,house weekend\r\n"), "{}", exported);
+}
+
+#[tokio::test]
+async fn todos_moved_under_a_newer_parent_are_exported() {
+    let app = TestApp::spawn().await;
+    let moved = app.create(json!({ "name": "Book flights" })).await;
+    app.create(json!({ "name": "Booking details", "parent_id": moved["id"] }))
+        .await;
+    let trip = app.create(json!({ "name": "Trip" })).await;
+    let response = with_json(
+        app.authed(Method::PATCH, &format!("/api/v1/todos/{}", moved["id"])),
+        &json!({ "parent_id": trip["id"] }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let exported = app.get_json("/api/v1/todos/export?format=json").await;
+    assert_eq!(names(&exported), ["Trip"]);
+    assert_eq!(exported[0]["children"][0]["name"], json!("Book flights"));
+    assert_eq!(
+        exported[0]["children"][0]["children"][0]["name"],
+        json!("Booking details")
+    );
+    let response = app
+        .authed(Method::GET, "/api/v1/todos/export?format=markdown")
+        .send()
+        .await
+        .unwrap();
+    let exported = response.text().await.unwrap();
+    assert!(exported.contains("  - [ ] Book flights"), "{}", exported);
+    assert!(
+        exported.contains("    - [ ] Booking details"),
+        "{}",
+        exported
+    );
+}
+
+#[tokio::test]
+async fn date_filtered_exports_keep_the_ancestors_of_matches() {
+    let app = TestApp::spawn().await;
+    let trip = app.create(json!({ "name": "Trip" })).await;
+    let flights = app
+        .create(json!({ "name": "Flights", "parent_id": trip["id"] }))
+        .await;
+    app.create(json!({ "name": "Book", "parent_id": flights["id"], "date": "2026-03-10" }))
+        .await;
+    app.create(json!({ "name": "Pack", "parent_id": trip["id"], "date": "2026-05-01" }))
+        .await;
+
+    let exported = app
+        .get_json("/api/v1/todos/export?format=json&date_more=2026-03-01&date_less=2026-03-31")
+        .await;
+    assert_eq!(names(&exported), ["Trip"]);
+    assert_eq!(names(&exported[0]["children"]), ["Flights"]);
+    assert_eq!(names(&exported[0]["children"][0]["children"]), ["Book"]);
+    let response = app
+        .authed(
+            Method::GET,
+            "/api/v1/todos/export?format=csv&date_more=2026-03-01&date_less=2026-03-31",
+        )
+        .send()
+        .await
+        .unwrap();
+    let exported = response.text().await.unwrap();
+    assert!(exported.contains(",Trip / Flights,Book,"), "{}", exported);
+    assert!(!exported.contains("Pack"), "{}", exported);
+}
+
+#[tokio::test]
+async fn webhooks_are_registered_and_queue_deliveries() {
+    let app = TestApp::spawn().await;
+
+    let response = with_json(
+        app.authed(Method::POST, "/api/v1/webhooks"),
+        &json!({ "url": "http://127.0.0.1:9/hook", "secret": "s", "events": ["todo.created"] }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let webhook = body_json(response).await;
+    assert_eq!(webhook["events"], json!(["todo.created"]));
+    let response = app
+        .authed(Method::GET, "/api/v1/webhooks")
+        .send()
+        .await
+        .unwrap();
+    assert!(header_value(&response, "deprecation").is_none());
+    let hooks = body_json(response).await;
+    assert_eq!(hooks[0]["url"], json!("http://127.0.0.1:9/hook"));
+    // only ever under /api/v1
+    let response = app.authed(Method::GET, "/webhooks").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let todo = app.create(json!({ "name": "Announce" })).await;
+    // not subscribed to
+    app.authed(Method::DELETE, &format!("/api/v1/todos/{}", todo["id"]))
+        .send()
+        .await
+        .unwrap();
+    let deliveries = app
+        .get_json(&format!("/api/v1/webhooks/{}/deliveries", webhook["id"]))
+        .await;
+    assert_eq!(deliveries.as_array().unwrap().len(), 1);
+    assert_eq!(deliveries[0]["event"], json!("todo.created"));
+
+    let delete = format!("/api/v1/webhooks/{}", webhook["id"]);
+    let response = app.authed(Method::DELETE, &delete).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app.authed(Method::DELETE, &delete).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app
+        .authed(Method::GET, &format!("{}/deliveries", delete))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn the_calendar_feed_needs_its_token() {
+    let app = TestApp::spawn().await;
+    app.create(json!({ "name": "Dentist", "date": "2026-11-02" }))
+        .await;
+    app.create(json!({ "name": "Someday" })).await;
+
+    let response = app
+        .request(Method::GET, "/calendar/wrong/todos.ics")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = app
+        .request(
+            Method::GET,
+            &format!("/calendar/{}/todos.ics", app.calendar_token().await),
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(header_value(&response, "content-type")
+        .unwrap()
+        .starts_with("text/calendar"));
+    let feed = response.text().await.unwrap();
+    assert!(feed.contains("SUMMARY:Dentist"));
+    assert!(!feed.contains("Someday"));
+}
+
+#[tokio::test]
+async fn the_calendar_token_is_kept_and_unguessable_from_the_password() {
+    let state = |storage: Arc<dyn Storage>| {
+        AppState::new(storage, PASSWORD, Tera::default(), String::new(), false)
+    };
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let token = state(storage.clone()).calendar_token().await.unwrap();
+
+    // the same after a restart, but not on another server with the same password
+    let restarted = state(storage);
+    assert_eq!(restarted.calendar_token().await.unwrap(), token);
+    let other = state(Arc::new(MemoryStorage::new()));
+    assert_ne!(other.calendar_token().await.unwrap(), token);
+
+    assert!(restarted.is_calendar_token(&token).await.unwrap());
+    assert!(!restarted.is_calendar_token(&token[1..]).await.unwrap());
+    assert!(!other.is_calendar_token(&token).await.unwrap());
+}
+
+#[tokio::test]
+async fn caldav_clients_read_and_write_todos() {
+    let app = TestApp::spawn().await;
+    let dav = |method: &str, path: &str| {
+        app.request(Method::from_bytes(method.as_bytes()).unwrap(), path)
+            .basic_auth("me", Some(PASSWORD))
+    };
+
+    let response = app
+        .request(Method::GET, "/.well-known/caldav")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(header_value(&response, "location"), Some("/dav/"));
+
+    let response = app
+        .request(
+            Method::from_bytes(b"PROPFIND").unwrap(),
+            "/dav/calendars/todos/",
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(header_value(&response, "www-authenticate").is_some());
+
+    let ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VTODO\r\nUID:phone-1\r\n\
+               SUMMARY:From the phone\r\nEND:VTODO\r\nEND:VCALENDAR\r\n";
+    let response = dav("PUT", "/dav/calendars/todos/phone-1.ics")
+        .header(header::CONTENT_TYPE, "text/calendar")
+        .header(header::IF_NONE_MATCH, "*")
+        .body(ics)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        names(&app.get_json("/api/v1/todos").await),
+        ["From the phone"]
+    );
+
+    for (ics, status) in [
+        (
+            "BEGIN:VTODO\r\nUID:phone-2\r\nSUMMARY: \r\nEND:VTODO\r\n",
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            "BEGIN:VTODO\r\nUID:phone-2\r\nEND:VTODO\r\n",
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        (
+            "BEGIN:VTODO\r\nUID:phone-2\r\nSUMMARY:Lost\r\nRELATED-TO:nowhere\r\nEND:VTODO\r\n",
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+    ] {
+        let response = dav("PUT", "/dav/calendars/todos/phone-2.ics")
+            .body(ics)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), status, "{}", ics);
+    }
+    let response = dav("PUT", "/dav/calendars/todos/phone-1.ics")
+        .body("BEGIN:VTODO\r\nUID:phone-1\r\nSUMMARY:Moved\r\nRELATED-TO:nowhere\r\nEND:VTODO\r\n")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        names(&app.get_json("/api/v1/todos").await),
+        ["From the phone"]
+    );
+
+    let archived = app.create(json!({ "name": "Filed away" })).await;
+    with_json(
+        app.authed(Method::PATCH, &format!("/api/v1/todos/{}", archived["id"])),
+        &json!({ "done": true }),
+    )
+    .send()
+    .await
+    .unwrap();
+    let response = app
+        .authed(Method::POST, "/api/v1/archive?older_than_days=0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let archived_path = format!("/dav/calendars/todos/{}.ics", archived["id"]);
+    let response = dav("GET", &archived_path).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = dav("PUT", &archived_path)
+        .body("BEGIN:VTODO\r\nUID:phone-3\r\nSUMMARY:Taken\r\nEND:VTODO\r\n")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    // nothing is created in or moved into an archived tree
+    let response = dav("PUT", "/dav/calendars/todos/phone-4.ics")
+        .body(format!(
+            "BEGIN:VTODO\r\nUID:phone-4\r\nSUMMARY:Late\r\n\
+             RELATED-TO:todo-{}@timely\r\nEND:VTODO\r\n",
+            archived["id"]
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let everything = app.get_json("/api/v1/todos?include_archived=true").await;
+    assert_eq!(names(&everything), ["From the phone", "Filed away"]);
+    let response = dav("PUT", "/dav/calendars/todos/phone-1.ics")
+        .body(format!(
+            "BEGIN:VTODO\r\nUID:phone-1\r\nSUMMARY:From the phone\r\n\
+             RELATED-TO:todo-{}@timely\r\nEND:VTODO\r\n",
+            archived["id"]
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = dav("PROPFIND", "/dav/calendars/todos/")
+        .header("Depth", "1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+    let listing = response.text().await.unwrap();
+    assert!(listing.contains("/dav/calendars/todos/phone-1.ics"));
+    assert!(!listing.contains(&archived_path), "{}", listing);
+
+    let response = dav("GET", "/dav/calendars/todos/phone-1.ics")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = header_value(&response, "etag").unwrap().to_owned();
+    let body = response.text().await.unwrap();
+    assert!(body.contains("UID:phone-1"));
+    assert!(body.contains("SUMMARY:From the phone"));
+
+    let response = dav("DELETE", "/dav/calendars/todos/phone-1.ics")
+        .header(header::IF_MATCH, "\"99\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = dav("DELETE", "/dav/calendars/todos/phone-1.ics")
+        .header(header::IF_MATCH, etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(app.get_json("/api/v1/todos").await, json!([]));
+}
+
+#[tokio::test]
+async fn the_web_interface_loads_fragments() {
+    let app = TestApp::spawn().await;
+    let parent = app.create(json!({ "name": "Deep" })).await;
+    let mut id = parent["id"].as_i64().unwrap();
+    for level in 1..=5 {
+        let child = app
+            .create(json!({ "name": format!("Level {}", level), "parent_id": id }))
+            .await;
+        id = child["id"].as_i64().unwrap();
+    }
+    let cookie = app.login().await;
+    let get = |path: &str| {
+        app.request(Method::GET, path)
+            .header(header::COOKIE, &cookie)
+            .send()
+    };
+
+    let page = get("/?depth=1").await.unwrap().text().await.unwrap();
+    assert!(page.contains("Level 1"));
+    assert!(!page.contains("Level 2"));
+
+    let response = get("/fragments/todos").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("Deep"));
+
+    let level_1 = parent["id"].as_i64().unwrap() + 1;
+    let response = get(&format!("/fragments/todos/{}/children?depth=0", level_1))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let fragment = response.text().await.unwrap();
+    assert!(fragment.contains("Level 2"));
+    assert!(!fragment.contains("Level 3"));
+
+    let response = get("/fragments/todos/999/children").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn the_openapi_document_is_served() {
+    let app = TestApp::spawn().await;
+    let response = app
+        .request(Method::GET, "/openapi.json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let document = body_json(response).await;
+    assert!(document["paths"]["/api/v1/todos"].is_object());
+    assert!(document["paths"]["/todos/toggle"]["post"]["deprecated"]
+        .as_bool()
+        .unwrap());
+}
+
+#[tokio::test]
+async fn everything_moves_under_the_base_path() {
+    let app = TestApp::spawn_with("/apps/todo", false).await;
+    assert!(app.base.ends_with("/apps/todo"));
+
+    let outside = app
+        .client
+        .get(format!("{}/api/v1/todos", app.origin))
+        .query(&[("password", PASSWORD)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(outside.status(), StatusCode::NOT_FOUND);
+    app.create(json!({ "name": "Nested" })).await;
+
+    let response = app
+        .request(Method::POST, "/login")
+        .form(&[("password", PASSWORD)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/apps/todo"));
+    assert!(set_cookie(&response).unwrap().contains("Path=/apps/todo"));
+    let cookie = app.login().await;
+    let page = app
+        .request(Method::GET, "")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(page.contains("Nested"));
+    assert!(page.contains("\"/apps/todo/archive\""));
+    assert!(page.contains("const base_url = \"/apps/todo\";"));
+    let archive = app
+        .request(Method::GET, "/archive")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(archive.contains("<a href=\"/apps/todo\">Back to the todos</a>"));
+
+    let response = app
+        .request(Method::GET, "/logout")
+        .header(header::COOKIE, &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/apps/todo"));
+    assert!(set_cookie(&response).unwrap().contains("Path=/apps/todo"));
+    let response = app.request(Method::GET, "/archive").send().await.unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/apps/todo"));
+
+    let response = app.authed(Method::GET, "/todos").send().await.unwrap();
+    assert_eq!(
+        header_value(&response, "link"),
+        Some("</apps/todo/api/v1/todos>; rel=\"successor-version\"")
+    );
+    let response = app
+        .request(Method::GET, "/.well-known/caldav")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/apps/todo/dav/"));
+    let document = body_json(
+        app.request(Method::GET, "/openapi.json")
+            .send()
+            .await
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(document["servers"][0]["url"], "/apps/todo");
+}
+
+#[tokio::test]
+async fn trusted_forwarded_prefix_goes_before_the_base_path() {
+    let app = TestApp::spawn_with("/todo", true).await;
+    let response = app
+        .request(Method::GET, "/logout")
+        .header(header::COOKIE, "auth=x")
+        .header("x-forwarded-prefix", "/proxy/")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/proxy/todo"));
+    assert!(set_cookie(&response).unwrap().contains("Path=/proxy/todo"));
+    let response = app
+        .request(Method::GET, "/.well-known/caldav")
+        .header("x-forwarded-prefix", "/proxy")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        header_value(&response, "location"),
+        Some("/proxy/todo/dav/")
+    );
+
+    // A prefix that could point elsewhere is ignored
+    let response = app
+        .request(Method::GET, "/logout")
+        .header(header::COOKIE, "auth=x")
+        .header("x-forwarded-prefix", "//evil.example")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/todo"));
+}
+
+#[tokio::test]
+async fn forwarded_prefix_is_ignored_unless_trusted() {
+    let app = TestApp::spawn().await;
+    let response = app
+        .request(Method::GET, "/logout")
+        .header(header::COOKIE, "auth=x")
+        .header("x-forwarded-prefix", "/proxy")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(header_value(&response, "location"), Some("/"));
+    assert!(set_cookie(&response).unwrap().contains("Path=/;"));
+}
+
+#[tokio::test]
+async fn probes_need_no_password() {
+    let app = TestApp::spawn().await;
+    let response = app.request(Method::GET, "/healthz").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    let response = app.request(Method::GET, "/readyz").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await,
+        json!({ "ready": true, "pending_migrations": [] })
+    );
+
+    let response = app.request(Method::GET, "/version").send().await.unwrap();
+    let version = body_json(response).await;
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(version["backend"], "memory");
+    assert_eq!(version["backends"], json!(["postgres", "sqlite", "memory"]));
+}
+
+#[tokio::test]
+async fn not_ready_until_migrated() {
+    let storage = Arc::new(SqliteStorage::connect("sqlite::memory:").await.unwrap());
+    let app = TestApp::spawn_on(storage.clone(), "", false).await;
+    let response = app.request(Method::GET, "/readyz").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let readiness = body_json(response).await;
+    assert_eq!(readiness["ready"], false);
+    assert!(!readiness["pending_migrations"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    crate::migrations::run(&*storage).await.unwrap();
+    let response = app.request(Method::GET, "/readyz").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn metrics_count_requests_and_todos() {
+    let app = TestApp::spawn().await;
+    app.create(json!({ "name": "Late", "date": "2000-01-01" }))
+        .await;
+    app.create(json!({ "name": "Someday" })).await;
+    let done = app.create(json!({ "name": "Done" })).await;
+    let response = with_json(
+        app.authed(Method::PATCH, &format!("/api/v1/todos/{}", done["id"])),
+        &json!({ "done": true }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    app.get_json("/api/v1/todos").await;
+
+    let response = app.request(Method::GET, "/metrics").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(header_value(&response, "content-type")
+        .unwrap()
+        .starts_with("text/plain"));
+    let metrics = response.text().await.unwrap();
+    for line in [
+        "timely_todos 3",
+        "timely_open_todos 2",
+        "timely_overdue_todos 1",
+        "timely_http_requests_total{method=\"POST\",route=\"/api/v1/todos\",status=\"201\"} 3",
+        "timely_http_requests_total{method=\"PATCH\",route=\"/api/v1/todos/{id}\",status=\"200\"} 1",
+        "timely_http_request_duration_seconds_count{method=\"GET\",route=\"/api/v1/todos\",status=\"200\"} 1",
+        "timely_db_query_duration_seconds_count{operation=\"todo_counts\"} 1",
+    ] {
+        assert!(metrics.lines().any(|l| l == line), "{} in\n{}", line, metrics);
+    }
+    assert!(!metrics.contains("route=\"/metrics\""));
+}
+
+#[tokio::test]
+async fn shutdown_drains_requests_and_sends_queued_webhooks() {
+    let (received, mut receiver) = mpsc::unbounded_channel();
+    let hook = Router::new().route(
+        "/hook",
+        post(move |body: String| async move { received.send(body).unwrap() }),
+    );
+    let hook_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_url = format!("http://{}/hook", hook_listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(hook_listener, hook).await.unwrap() });
+
+    let state = AppState::new(
+        Arc::new(MemoryStorage::new()),
+        PASSWORD,
+        Tera::new("templates/**/*").unwrap(),
+        String::new(),
+        false,
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (signal, signalled) = oneshot::channel();
+    let server = tokio::spawn(run(
+        listener,
+        state,
+        None,
+        90,
+        Duration::from_secs(10),
+        async move { signalled.await.unwrap() },
+    ));
+    let client = Client::new();
+    let response = with_json(
+        client
+            .post(format!("http://{}/api/v1/webhooks", address))
+            .query(&[("password", PASSWORD)]),
+        &json!({ "url": hook_url, "secret": "s", "events": ["todo.created"] }),
+    )
+    .send()
+    .await
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // A request whose body is still on its way when the signal comes
+    let body = json!({ "name": "Slow" }).to_string();
+    let mut connection = TcpStream::connect(address).await.unwrap();
+    let head = format!(
+        "POST /api/v1/todos?password=correct%20horse HTTP/1.1\r\nHost: {}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        address,
+        body.len()
+    );
+    connection.write_all(head.as_bytes()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    signal.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    connection.write_all(body.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    connection.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 201 Created"), "{}", response);
+
+    tokio::time::timeout(Duration::from_secs(10), server)
+        .await
+        .expect("the server stops within the drain timeout")
+        .unwrap();
+    let delivered = receiver.try_recv().expect("the webhook was sent");
+    assert!(delivered.contains("\"Slow\""), "{}", delivered);
+    assert!(client
+        .get(format!("http://{}/healthz", address))
+        .send()
+        .await
+        .is_err());
+}
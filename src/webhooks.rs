@@ -0,0 +1,533 @@
+//! Outgoing webhooks: registered URLs receive a signed JSON payload whenever a todo is
+//! created, changed, completed, reopened or deleted.
+//!
+//! Payloads are queued in `webhook_deliveries` and sent by a background task, which
+//! retries failed deliveries with exponential backoff. The same table serves as the
+//! delivery log, which keeps the newest `DELIVERY_LOG_SIZE` deliveries of each webhook.
+//! Each request carries an `X-Timely-Signature-256` header with the HMAC-SHA256 of the
+//! body, keyed with the webhook's secret.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use axum_extra::extract::cookie::CookieJar;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
+use timely_lib::Todo;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::storage::{FailedAttempt, Storage, StorageResult};
+use crate::{authenticate, extract_provided, AppState, PasswordQuery};
+
+/// Deliveries are given up on after this many failed attempts.
+const MAX_ATTEMPTS: i32 = 8;
+/// Wait before the first retry; doubled after every further failure.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How often the queue is checked for retries that became due.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 20;
+/// Finished deliveries kept in the log of each webhook; older ones are removed.
+const DELIVERY_LOG_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Event {
+    #[serde(rename = "todo.created")]
+    Created,
+    #[serde(rename = "todo.updated")]
+    Updated,
+    #[serde(rename = "todo.completed")]
+    Completed,
+    #[serde(rename = "todo.reopened")]
+    Reopened,
+    #[serde(rename = "todo.deleted")]
+    Deleted,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Created => "todo.created",
+            Event::Updated => "todo.updated",
+            Event::Completed => "todo.completed",
+            Event::Reopened => "todo.reopened",
+            Event::Deleted => "todo.deleted",
+        }
+    }
+
+    /// The event for a todo being marked as done or not done.
+    pub fn for_done(done: bool) -> Self {
+        if done {
+            Event::Completed
+        } else {
+            Event::Reopened
+        }
+    }
+}
+
+/// The body sent to webhooks. For deletions `todo` is the deleted todo as it was; its
+/// descendants were deleted along with it.
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: Event,
+    #[serde(with = "time::serde::rfc3339")]
+    occurred_at: OffsetDateTime,
+    todo: &'a Todo,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub events: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: OffsetDateTime,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateWebhook {
+    url: String,
+    secret: String,
+    // events to send; all of them if empty
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+#[derive(Clone, Serialize, ToSchema, sqlx::FromRow)]
+pub struct Delivery {
+    pub id: i64,
+    pub event: String,
+    /// `pending`, `delivered` or `failed`
+    pub status: String,
+    pub attempts: i32,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub next_attempt_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub delivered_at: Option<OffsetDateTime>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeliveryQuery {
+    /// At most this many deliveries (1 to 500, default 50)
+    limit: Option<i64>,
+}
+
+/// The webhook endpoints, merged into the document served at `/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    paths(list_webhooks, create_webhook, delete_webhook, list_deliveries),
+    components(schemas(Event, Webhook, CreateWebhook, Delivery))
+)]
+pub struct WebhooksApi;
+
+#[derive(sqlx::FromRow)]
+pub struct PendingDelivery {
+    pub id: i64,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/{id}", axum::routing::delete(delete_webhook))
+        .route("/webhooks/{id}/deliveries", get(list_deliveries))
+}
+
+/// Queues the event for every webhook subscribed to it and wakes the delivery task.
+/// The change itself has already been made, so failures are only logged.
+pub async fn enqueue(storage: &dyn Storage, notify: &Notify, event: Event, todo: &Todo) {
+    let payload = serde_json::to_string(&Payload {
+        event,
+        occurred_at: OffsetDateTime::now_utc(),
+        todo,
+    })
+    .expect("webhook payloads are always serialisable");
+    match storage.queue_deliveries(event, &payload).await {
+        Ok(queued) if queued > 0 => notify.notify_one(),
+        Ok(_) => {}
+        Err((_, err)) => tracing::error!(
+            event = event.as_str(),
+            todo_id = todo.id,
+            error = %err,
+            "Could not queue webhooks"
+        ),
+    }
+}
+
+/// Sends queued deliveries as they become due, until `stop` is cancelled. Deliveries that
+/// are due by then are sent before it returns.
+pub async fn run_deliveries(
+    storage: Arc<dyn Storage>,
+    notify: Arc<Notify>,
+    stop: CancellationToken,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .user_agent(concat!("Timely-Webhooks/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .expect("Error initializing the webhook HTTP client");
+    loop {
+        match deliver_due(&*storage, &client).await {
+            // a full batch means more may be waiting
+            Ok(sent) if sent == BATCH_SIZE as usize => continue,
+            Ok(_) => {}
+            Err((_, err)) => tracing::error!(error = %err, "Webhook delivery failed"),
+        }
+        if stop.is_cancelled() {
+            return;
+        }
+        tokio::select! {
+            _ = notify.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            // one more round for what the last requests queued
+            _ = stop.cancelled() => {}
+        }
+    }
+}
+
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Sends one batch of due deliveries, returning how many were attempted. A delivery
+/// whose outcome can't be recorded is logged and left for the next round.
+async fn deliver_due(storage: &dyn Storage, client: &reqwest::Client) -> StorageResult<usize> {
+    let due = storage.due_deliveries(BATCH_SIZE).await?;
+
+    for delivery in &due {
+        if let Err((_, err)) = deliver(storage, client, delivery).await {
+            tracing::error!(
+                delivery = delivery.id,
+                error = %err,
+                "Recording a webhook delivery failed"
+            );
+        }
+    }
+    if !due.is_empty() {
+        storage.trim_delivery_log(DELIVERY_LOG_SIZE).await?;
+    }
+    Ok(due.len())
+}
+
+/// Sends one delivery and records how it went.
+async fn deliver(
+    storage: &dyn Storage,
+    client: &reqwest::Client,
+    delivery: &PendingDelivery,
+) -> StorageResult<()> {
+    let response = client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Timely-Event", &delivery.event)
+        .header("X-Timely-Delivery", delivery.id.to_string())
+        .header(
+            "X-Timely-Signature-256",
+            signature(&delivery.secret, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+    let (status_code, error) = match response {
+        Ok(response) if response.status().is_success() => {
+            return storage
+                .delivery_succeeded(delivery.id, response.status().as_u16() as i32)
+                .await;
+        }
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            format!("The endpoint responded with {}", response.status()),
+        ),
+        Err(err) => (None, err.to_string()),
+    };
+
+    let attempts = delivery.attempts + 1;
+    tracing::warn!(
+        delivery = delivery.id,
+        attempts,
+        error = %error,
+        "Webhook delivery attempt failed"
+    );
+    storage
+        .delivery_failed(
+            delivery.id,
+            FailedAttempt {
+                attempts,
+                status_code,
+                error: &error,
+                retry_delay: FIRST_RETRY_DELAY * 2u32.pow((attempts - 1) as u32),
+                give_up: attempts >= MAX_ATTEMPTS,
+            },
+        )
+        .await
+}
+
+// -----------------
+// API Handlers
+// -----------------
+
+/// API: List the registered webhooks. Secrets are not returned.
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "The registered webhooks", body = Vec<Webhook>),
+        (status = 401, description = "Missing or wrong password"),
+    )
+)]
+async fn list_webhooks(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Webhook>>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        state.storage.list_webhooks().await.map(Json)
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Register a webhook for some (or, with no `events`, all) events.
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = CreateWebhook,
+    responses(
+        (status = 201, description = "The registered webhook", body = Webhook),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 422, description = "Invalid URL or empty secret"),
+    )
+)]
+async fn create_webhook(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Json(payload): Json<CreateWebhook>,
+) -> Result<(StatusCode, Json<Webhook>), (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        if reqwest::Url::parse(&payload.url)
+            .map_or(true, |url| !matches!(url.scheme(), "http" | "https"))
+        {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The URL must be an http or https URL".to_owned(),
+            ));
+        }
+        if payload.secret.is_empty() {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "The secret must not be empty".to_owned(),
+            ));
+        }
+        let mut events: Vec<String> = payload
+            .events
+            .iter()
+            .map(|event| event.as_str().to_owned())
+            .collect();
+        events.sort();
+        events.dedup();
+        let webhook = state
+            .storage
+            .create_webhook(&payload.url, &payload.secret, &events)
+            .await?;
+        Ok((StatusCode::CREATED, Json(webhook)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: Remove a webhook along with its queued deliveries and log.
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i64, Path, description = "Id of the webhook")),
+    responses(
+        (status = 204, description = "The webhook was removed"),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such webhook"),
+    )
+)]
+async fn delete_webhook(
+    Query(query): Query<PasswordQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        if !state.storage.delete_webhook(id).await? {
+            return Err((StatusCode::NOT_FOUND, format!("Webhook {} not found", id)));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+/// API: The delivery log of a webhook, newest first (at most `limit`, default 50).
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    params(("id" = i64, Path, description = "Id of the webhook"), DeliveryQuery),
+    responses(
+        (status = 200, description = "The deliveries, newest first", body = Vec<Delivery>),
+        (status = 401, description = "Missing or wrong password"),
+        (status = 404, description = "No such webhook"),
+    )
+)]
+async fn list_deliveries(
+    Query(query): Query<PasswordQuery>,
+    Query(delivery_query): Query<DeliveryQuery>,
+    cookies: CookieJar,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Delivery>>, (StatusCode, String)> {
+    let provided = extract_provided(&query, &cookies);
+    if provided.is_some_and(|p| authenticate(&state.hashed_password, &p)) {
+        state
+            .storage
+            .list_deliveries(
+                id,
+                delivery_query
+                    .limit
+                    .unwrap_or(50)
+                    .clamp(1, DELIVERY_LOG_SIZE),
+            )
+            .await?
+            .map(Json)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Webhook {} not found", id)))
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Failed authentication".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use axum::{http::HeaderMap, routing::post};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// Serves `/hook` on a local port, answering every request with `status` and passing
+    /// on its signature header and body. Returns the URL of the hook.
+    async fn stub(status: StatusCode) -> (String, mpsc::UnboundedReceiver<(String, String)>) {
+        let (received, receiver) = mpsc::unbounded_channel();
+        let hook = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| async move {
+                let signature = headers["x-timely-signature-256"].to_str().unwrap();
+                received.send((signature.to_owned(), body)).unwrap();
+                status
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, hook).await.unwrap() });
+        (url, receiver)
+    }
+
+    async fn only_delivery(storage: &dyn Storage, webhook_id: i64) -> Delivery {
+        let mut deliveries = storage
+            .list_deliveries(webhook_id, 10)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        deliveries.remove(0)
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed_with_the_secret() {
+        let (url, mut receiver) = stub(StatusCode::NO_CONTENT).await;
+        let storage = MemoryStorage::new();
+        let webhook = storage.create_webhook(&url, "s", &[]).await.unwrap();
+        storage
+            .queue_deliveries(Event::Created, r#"{"name":"Signed"}"#)
+            .await
+            .unwrap();
+
+        let client = reqwest::Client::new();
+        assert_eq!(deliver_due(&storage, &client).await.unwrap(), 1);
+        let (signature, body) = receiver.try_recv().unwrap();
+        assert_eq!(body, r#"{"name":"Signed"}"#);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s").unwrap();
+        mac.update(body.as_bytes());
+        let expected = format!("sha256={:x}", mac.finalize().into_bytes());
+        assert_eq!(signature, expected);
+
+        let delivery = only_delivery(&storage, webhook.id).await;
+        assert_eq!(delivery.status, "delivered");
+        assert_eq!(delivery.last_status_code, Some(204));
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_back_off_then_give_up() {
+        let (url, _receiver) = stub(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let storage = MemoryStorage::new();
+        let webhook = storage.create_webhook(&url, "s", &[]).await.unwrap();
+        storage
+            .queue_deliveries(Event::Created, "{}")
+            .await
+            .unwrap();
+
+        let client = reqwest::Client::new();
+        assert_eq!(deliver_due(&storage, &client).await.unwrap(), 1);
+        let delivery = only_delivery(&storage, webhook.id).await;
+        assert_eq!(delivery.status, "pending");
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_status_code, Some(500));
+        assert!(delivery.next_attempt_at >= delivery.created_at + FIRST_RETRY_DELAY);
+        // not due again yet
+        assert_eq!(deliver_due(&storage, &client).await.unwrap(), 0);
+
+        // as if every attempt but the last had failed already
+        storage
+            .delivery_failed(
+                delivery.id,
+                FailedAttempt {
+                    attempts: MAX_ATTEMPTS - 1,
+                    status_code: Some(500),
+                    error: "",
+                    retry_delay: Duration::ZERO,
+                    give_up: false,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(deliver_due(&storage, &client).await.unwrap(), 1);
+        let delivery = only_delivery(&storage, webhook.id).await;
+        assert_eq!(delivery.status, "failed");
+        assert_eq!(delivery.attempts, MAX_ATTEMPTS);
+        assert_eq!(deliver_due(&storage, &client).await.unwrap(), 0);
+    }
+}
@@ -1,21 +1,31 @@
 use config::{Config, ConfigError, File};
 use iced::theme::Palette;
 use iced::widget::{
-    button, checkbox, column, container, keyed_column, row, scrollable, text, text_input, Column,
-    Container, Text,
+    button, checkbox, column, container, keyed_column, row, scrollable, text, text_input, tooltip,
+    Column, Container, Text,
 };
 use iced::{alignment, font, Alignment, Element, Font, Length, Size, Task, Theme};
 use iced_aw::{date_picker::Date, widget::helpers::date_picker};
-use reqwest::{self, Client};
+use reqwest::{self, Client, StatusCode};
 use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env::home_dir;
 use std::fmt::Debug;
 use std::fs;
 use std::path::PathBuf;
 
-use timely_lib::{build_hierarchy, Todo, TodoHierarchy, TodoToSend};
+use timely_lib::{
+    build_hierarchy, export::ExportFormat, flatten_hierarchy, quick_add::parse_quick_add, Priority,
+    SyncResponse, Todo, TodoHierarchy, TodoToSend, TreePage,
+};
+use tracing_subscriber::EnvFilter;
+
+mod offline;
+use offline::{
+    is_temp_id, new_idempotency_key, next_temp_id, remap_todo_ids, replay, BulkAction, BulkTarget,
+    LocalCache, Mutation, ReplayReport,
+};
 
 // Settings
 
@@ -45,6 +55,13 @@ struct AppSettings {
     server_url: String,
     palette: String,
     password: String,
+    // levels of subtasks shown below a top-level todo before they are collapsed
+    #[serde(default = "default_tree_depth")]
+    tree_depth: usize,
+}
+
+fn default_tree_depth() -> usize {
+    3
 }
 
 impl Default for AppSettings {
@@ -53,6 +70,7 @@ impl Default for AppSettings {
             server_url: "http://localhost:3000".into(),
             palette: "light".into(),
             password: "123".into(),
+            tree_depth: default_tree_depth(),
         }
     }
 }
@@ -80,6 +98,9 @@ impl AppSettings {
     }
 }
 
+// Top-level todos shown at first, and added by each "Show more"
+const ROOT_PAGE_SIZE: usize = 50;
+
 // Fonts
 const ICONS: Font = Font::with_name("Iced-Todos-Icons");
 
@@ -91,10 +112,6 @@ fn icon(unicode: char) -> Text<'static> {
         .align_y(alignment::Vertical::Center)
 }
 
-fn edit_icon() -> Text<'static> {
-    icon('\u{F303}')
-}
-
 fn add_icon() -> Text<'static> {
     text("+")
         .size(16)
@@ -107,31 +124,73 @@ fn delete_icon() -> Text<'static> {
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
-    APIError,
-    OtherError,
+    // the server refused the request, e.g. because the todo is gone or was changed
+    APIError(String),
+    // the request failed some other way; sending it again may work
+    Failed(String),
+    // the server could not be reached
+    Offline,
 }
 impl From<reqwest::Error> for Error {
     fn from(error: reqwest::Error) -> Error {
-        dbg!(error);
-        Error::APIError
+        if error.is_connect() || error.is_timeout() {
+            Error::Offline
+        } else if error.is_request() || error.is_body() {
+            Error::Failed(error.to_string())
+        } else {
+            Error::APIError(error.to_string())
+        }
+    }
+}
+
+/// The error for an unsuccessful response. A 4xx is the server refusing the request,
+/// which sending it again won't change, except for a wrong password, a timeout or too many
+/// requests; those and 5xx errors are worth retrying.
+fn status_error(status: StatusCode, message: String) -> Error {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => {
+            Error::Failed(message)
+        }
+        status if status.is_client_error() => Error::APIError(message),
+        _ => Error::Failed(message),
+    }
+}
+
+/// Turns an unsuccessful response into an `Error` with the server's message.
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else {
+        let body = response.text().await.unwrap_or_default();
+        Err(status_error(status, format!("{} {}", status, body)))
     }
 }
 
 #[derive(Debug)]
 enum AppState {
     Loading,
+    // text of the quick-add input
     Loaded(String),
     // bool - title, description, parent id, has date, date
     AddingNewTodo(String, String, Option<i64>, bool),
     Settings,
     Errored(String),
     About,
+    // result of the last export, if any
+    Export(Option<String>),
+    // text of the "older than" days input, archived trees once loaded, result of the
+    // last action
+    Archive(String, Option<Vec<TodoHierarchy>>, Option<String>),
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 enum Message {
-    Loaded(Result<Vec<Todo>, Error>),
+    Synced(Result<SyncResponse, Error>),
+    Replayed(ReplayReport),
     Load,
     // title, description, parent id, has date
     LoadScreenAddNewTodo(String, String, Option<i64>, bool),
@@ -139,75 +198,215 @@ enum Message {
     LoadScreenAddNewTodoUpdateDate(Date),
     LoadScreenSettings,
     LoadScreenAbout,
+    LoadScreenExport,
+    Export(ExportFormat),
+    LoadScreenArchive,
+    ArchiveLoaded(Result<Vec<TodoHierarchy>, Error>),
+    ChangeArchiveDays(String),
+    ArchiveCompleted,
+    ArchivedCompleted(Result<Vec<Todo>, Error>),
+    Unarchive(i64),
+    Unarchived(Result<Todo, Error>),
     // title, description, parent id, date
     SubmitNewTodo(String, String, Option<i64>, Option<Date>),
-    SubmittedNewTodo(Result<Todo, Error>),
+    QuickAddChanged(String),
+    SubmitQuickAdd,
     GoBackToMain,
-    TodoToggled(Result<(i64, bool), Error>),
+    DismissConflicts,
     FontLoaded(Result<(), font::Error>),
     TodoMessage(i64, TodoMessage),
     ChangeUrl(String),
     ChangePassword(String),
+    ChangeTreeDepth(String),
     SaveSettings,
-    None,
+    ShowMoreTodos,
+    Bulk(BulkAction),
+    ClearSelection,
 }
 
-async fn load(client: Client, url: String, password: String) -> Result<Vec<Todo>, Error> {
-    let response: Vec<Todo> = client
-        .get(format!("{}/todos?password={}", url, password))
-        .send()
-        .await?
-        .json()
-        .await?;
+fn to_time_date(date: Date) -> time::Date {
+    time::Date::from_calendar_date(
+        date.year,
+        time::Month::nth_next(time::Month::January, date.month as u8 - 1),
+        date.day as u8,
+    )
+    .unwrap()
+}
+
+async fn sync(
+    since: Option<i64>,
+    client: Client,
+    url: String,
+    password: String,
+) -> Result<SyncResponse, Error> {
+    let mut request = client.get(format!("{}/api/v1/sync?password={}", url, password));
+    if let Some(since) = since {
+        request = request.query(&[("since", since)]);
+    }
+    let response: SyncResponse = check_status(request.send().await?).await?.json().await?;
     Ok(response)
 }
 
+/// Creates the todo, under its parent if it has one so a deleted parent reads as a
+/// conflict. The server creates it only once per idempotency `key`, so a create whose
+/// response was lost can be sent again.
 async fn submit_new_todo(
     todo_to_send: TodoToSend,
+    key: Option<String>,
     client: Client,
     url: String,
     password: String,
 ) -> Result<Todo, Error> {
-    let response: Todo = client
-        .post(format!("{}/todos?password={}", url, password))
-        .json(&todo_to_send)
-        .send()
-        .await?
-        .json()
-        .await?;
+    let path = match todo_to_send.parent_id {
+        Some(parent_id) => format!("api/v1/todos/{}/children", parent_id),
+        None => "api/v1/todos".to_owned(),
+    };
+    let mut request = client
+        .post(format!("{}/{}?password={}", url, path, password))
+        .json(&todo_to_send);
+    if let Some(key) = key {
+        request = request.header("idempotency-key", key);
+    }
+    let response = request.send().await?;
+    let response = check_status(response).await?.json().await?;
     Ok(response)
 }
 
+/// Adds an `If-Match` header when the version the change is based on is known.
+fn if_match(request: reqwest::RequestBuilder, version: Option<i64>) -> reqwest::RequestBuilder {
+    match version {
+        Some(version) => request.header(reqwest::header::IF_MATCH, format!("\"{}\"", version)),
+        None => request,
+    }
+}
+
+/// Deletes the todo and its descendants.
 async fn delete_todo(
     id: i64,
+    version: Option<i64>,
     client: Client,
     url: String,
     password: String,
-) -> Result<Vec<Todo>, Error> {
-    let response: Vec<Todo> = client
-        .delete(format!("{}/todos?password={}", url, password))
-        .json(&id)
+) -> Result<(), Error> {
+    let request = client.delete(format!("{}/api/v1/todos/{}?password={}", url, id, password));
+    let response = if_match(request, version).send().await?;
+    check_status(response).await?;
+    Ok(())
+}
+
+/// An operation of `POST /api/v1/batch`, for the bulk actions.
+#[derive(Serialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum BatchOperation {
+    SetDone {
+        id: i64,
+        done: bool,
+        version: Option<i64>,
+    },
+    Delete {
+        id: i64,
+        version: Option<i64>,
+    },
+}
+
+#[derive(Deserialize)]
+struct BatchError {
+    error: String,
+}
+
+/// Applies the operations in one transaction on the server: all of them or none.
+async fn run_batch(
+    operations: Vec<BatchOperation>,
+    client: Client,
+    url: String,
+    password: String,
+) -> Result<(), Error> {
+    let response = client
+        .post(format!("{}/api/v1/batch?password={}", url, password))
+        .json(&serde_json::json!({ "operations": operations }))
         .send()
-        .await?
-        .json()
         .await?;
-    Ok(response)
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        let body = response.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<BatchError>(&body)
+            .map_or_else(|_| format!("{} {}", status, body), |error| error.error);
+        Err(status_error(status, message))
+    }
 }
 
-async fn toggle_todo(
+async fn set_done(
     id: i64,
+    done: bool,
+    version: Option<i64>,
     client: Client,
     url: String,
     password: String,
-) -> Result<(i64, bool), Error> {
-    let response: bool = client
-        .post(format!("{}/todos/toggle?password={}", url, password))
-        .json(&id)
+) -> Result<Todo, Error> {
+    let request = client
+        .patch(format!("{}/api/v1/todos/{}?password={}", url, id, password))
+        .json(&serde_json::json!({ "done": done }));
+    let response = if_match(request, version).send().await?;
+    let response = check_status(response).await?.json().await?;
+    Ok(response)
+}
+
+/// All archived trees. The archive is only kept on the server, so it needs a connection.
+async fn get_archive(
+    client: Client,
+    url: String,
+    password: String,
+) -> Result<Vec<TodoHierarchy>, Error> {
+    let mut archived = Vec::new();
+    let mut after = None;
+    loop {
+        let mut request = client.get(format!("{}/api/v1/archive?password={}", url, password));
+        if let Some(after) = after {
+            request = request.query(&[("after", after)]);
+        }
+        let page: TreePage = check_status(request.send().await?).await?.json().await?;
+        archived.extend(page.todos);
+        match page.next_cursor {
+            Some(cursor) => after = Some(cursor),
+            None => return Ok(archived),
+        }
+    }
+}
+
+async fn archive_completed(
+    older_than_days: i32,
+    client: Client,
+    url: String,
+    password: String,
+) -> Result<Vec<Todo>, Error> {
+    let response = client
+        .post(format!("{}/api/v1/archive?password={}", url, password))
+        .query(&[("older_than_days", older_than_days)])
         .send()
-        .await?
-        .json()
         .await?;
-    Ok((id, response))
+    let response = check_status(response).await?.json().await?;
+    Ok(response)
+}
+
+async fn unarchive(id: i64, client: Client, url: String, password: String) -> Result<Todo, Error> {
+    let response = client
+        .post(format!(
+            "{}/api/v1/todos/{}/unarchive?password={}",
+            url, id, password
+        ))
+        .send()
+        .await?;
+    let response = check_status(response).await?.json().await?;
+    Ok(response)
+}
+
+fn archive_error(error: Error) -> String {
+    match error {
+        Error::Offline => "The archive needs a connection to the server".to_owned(),
+        Error::APIError(message) | Error::Failed(message) => message,
+    }
 }
 
 #[derive(Debug)]
@@ -218,33 +417,52 @@ struct App {
     palette: Palette,
     settings: AppSettings,
     selected_date: Date,
+    // change token of the last successful sync
+    sync_token: Option<i64>,
+    // mutations not yet sent to the server, oldest first
+    pending: Vec<Mutation>,
+    // whether the pending mutations are being sent right now
+    flushing: bool,
+    offline: bool,
+    // why the pending mutations stopped being sent, while the server keeps failing them
+    stalled: Option<String>,
+    conflicts: Vec<String>,
+    // number of top-level todos shown
+    shown_roots: usize,
+    // todos expanded or collapsed by hand, against what the tree depth setting says
+    toggled: HashSet<i64>,
+    // todos selected for a bulk action
+    selected: HashSet<i64>,
 }
 
 impl App {
-    fn new(server_url: String, password: String, palette: String) -> (Self, Task<Message>) {
+    fn new(settings: AppSettings) -> (Self, Task<Message>) {
         let client = Client::new();
-        let command = Task::batch([
-            font::load(include_bytes!("../fonts/icons.ttf").as_slice()).map(Message::FontLoaded),
-            Task::perform(
-                load(client.clone(), server_url.clone(), password.clone()),
-                Message::Loaded,
-            ),
-        ]);
+        let cache = LocalCache::load();
 
-        let app = App {
+        let mut app = App {
             state: AppState::Loading,
-            todos: Vec::new(),
+            todos: build_hierarchy(cache.todos),
             client,
             palette: *palette_map()
-                .get(palette.as_str())
+                .get(settings.palette.as_str())
                 .unwrap_or(&Palette::LIGHT),
-            settings: AppSettings {
-                server_url,
-                palette,
-                password,
-            },
+            settings,
             selected_date: Date::today(),
+            sync_token: cache.sync_token,
+            pending: cache.pending,
+            flushing: false,
+            offline: false,
+            stalled: None,
+            conflicts: Vec::new(),
+            shown_roots: ROOT_PAGE_SIZE,
+            toggled: HashSet::new(),
+            selected: HashSet::new(),
         };
+        let command = Task::batch([
+            font::load(include_bytes!("../fonts/icons.ttf").as_slice()).map(Message::FontLoaded),
+            app.refresh(),
+        ]);
 
         (app, command)
     }
@@ -257,6 +475,8 @@ impl App {
             AppState::AddingNewTodo(..) => "Adding new task - ",
             AppState::Settings => "Settings - ",
             AppState::About => "About - ",
+            AppState::Export(..) => "Export - ",
+            AppState::Archive(..) => "Archive - ",
         };
 
         format!("{subtitle}Timely")
@@ -266,29 +486,164 @@ impl App {
         Theme::custom("user_theme".into(), self.palette)
     }
 
+    /// Sends pending mutations if there are any, otherwise fetches changes from the server.
+    fn refresh(&mut self) -> Task<Message> {
+        if !self.pending.is_empty() {
+            self.flush()
+        } else {
+            Task::perform(
+                sync(
+                    self.sync_token,
+                    self.client.clone(),
+                    self.settings.server_url.clone(),
+                    self.settings.password.clone(),
+                ),
+                Message::Synced,
+            )
+        }
+    }
+
+    fn flush(&mut self) -> Task<Message> {
+        if self.flushing || self.pending.is_empty() {
+            return Task::none();
+        }
+        self.flushing = true;
+        Task::perform(
+            replay(
+                self.pending.clone(),
+                self.client.clone(),
+                self.settings.server_url.clone(),
+                self.settings.password.clone(),
+            ),
+            Message::Replayed,
+        )
+    }
+
+    /// Applies a mutation locally right away and queues it for the server.
+    fn mutate(&mut self, mutation: Mutation) -> Task<Message> {
+        let mut todos = flatten_hierarchy(&self.todos);
+        mutation.apply_local(&mut todos);
+        self.todos = build_hierarchy(todos);
+        self.pending.push(mutation);
+        self.save_cache();
+        self.flush()
+    }
+
+    /// The selected todos a bulk action applies to. Those with a selected ancestor are left
+    /// out, as the action on the ancestor covers them.
+    fn bulk_targets(&self) -> Vec<BulkTarget> {
+        let todos = flatten_hierarchy(&self.todos);
+        let parents: HashMap<i64, Option<i64>> =
+            todos.iter().map(|todo| (todo.id, todo.parent_id)).collect();
+        let has_selected_ancestor = |mut id: i64| {
+            while let Some(Some(parent_id)) = parents.get(&id) {
+                if self.selected.contains(parent_id) {
+                    return true;
+                }
+                id = *parent_id;
+            }
+            false
+        };
+        todos
+            .iter()
+            .filter(|todo| self.selected.contains(&todo.id) && !has_selected_ancestor(todo.id))
+            .map(|todo| BulkTarget {
+                id: todo.id,
+                name: todo.name.clone(),
+                // version 0 means the server does not track versions
+                version: Some(todo.version).filter(|version| *version > 0),
+            })
+            .collect()
+    }
+
+    fn load_archive(&self) -> Task<Message> {
+        Task::perform(
+            get_archive(
+                self.client.clone(),
+                self.settings.server_url.clone(),
+                self.settings.password.clone(),
+            ),
+            Message::ArchiveLoaded,
+        )
+    }
+
+    /// Shows the result of an archive action, if the archive is still open.
+    fn set_archive_message(&mut self, new_message: String) {
+        if let AppState::Archive(_, _, message) = &mut self.state {
+            *message = Some(new_message);
+        }
+    }
+
+    fn save_cache(&self) {
+        let cache = LocalCache {
+            todos: flatten_hierarchy(&self.todos),
+            sync_token: self.sync_token,
+            pending: self.pending.clone(),
+        };
+        if let Err(save_error) = cache.save() {
+            tracing::warn!(error = %save_error, "Could not save the local cache");
+        }
+    }
+
     fn update(&mut self, message: Message) -> Task<Message> {
         match message {
-            Message::Loaded(todos_result) => match todos_result {
-                Ok(todos) => {
-                    let hierarchy = build_hierarchy(todos);
-                    self.state = AppState::Loaded("".to_owned());
-                    self.todos = hierarchy;
+            Message::Synced(sync_result) => match sync_result {
+                Ok(changes) => {
+                    // Rebuild from the server's state, then replay what it has not seen yet.
+                    let mut todos = flatten_hierarchy(&self.todos);
+                    todos.retain(|todo| !is_temp_id(todo.id));
+                    changes.apply(&mut todos);
+                    for mutation in &self.pending {
+                        mutation.apply_local(&mut todos);
+                    }
+                    self.todos = build_hierarchy(todos);
+                    self.sync_token = Some(changes.token);
+                    self.offline = false;
+                    self.save_cache();
+                    if matches!(self.state, AppState::Loading | AppState::Errored(_)) {
+                        self.state = AppState::Loaded("".to_owned());
+                    }
+                    Task::none()
+                }
+                Err(Error::Offline) => {
+                    self.offline = true;
+                    if matches!(self.state, AppState::Loading | AppState::Errored(_)) {
+                        self.state = AppState::Loaded("".to_owned());
+                    }
                     Task::none()
                 }
-                Err(todos_error) => {
-                    self.state = AppState::Errored(format!("{:?}", todos_error));
+                Err(sync_error) => {
+                    self.state = AppState::Errored(format!("{:?}", sync_error));
                     Task::none()
                 }
             },
-            Message::Load => Task::perform(
-                load(
-                    self.client.clone(),
-                    self.settings.server_url.clone(),
-                    self.settings.password.clone(),
-                ),
-                Message::Loaded,
-            ),
-            Message::None => Task::none(),
+            Message::Replayed(report) => {
+                self.flushing = false;
+                self.pending.drain(..report.processed);
+                for mutation in &mut self.pending {
+                    mutation.remap_ids(&report.id_map);
+                }
+                let mut todos = flatten_hierarchy(&self.todos);
+                remap_todo_ids(&mut todos, &report.id_map);
+                self.todos = build_hierarchy(todos);
+                self.conflicts.extend(report.conflicts);
+                self.offline = report.offline;
+                self.stalled = report.stalled;
+                if matches!(self.state, AppState::Loading) {
+                    self.state = AppState::Loaded("".to_owned());
+                }
+                self.save_cache();
+                if self.offline || self.stalled.is_some() {
+                    Task::none()
+                } else {
+                    self.refresh()
+                }
+            }
+            Message::Load => self.refresh(),
+            Message::DismissConflicts => {
+                self.conflicts.clear();
+                Task::none()
+            }
             Message::LoadScreenAddNewTodo(title, description, parent_id, has_date) => {
                 self.state = AppState::AddingNewTodo(title, description, parent_id, has_date);
                 Task::none()
@@ -301,63 +656,83 @@ impl App {
                 self.state = AppState::Loaded("".to_owned());
                 Task::none()
             }
-            Message::SubmitNewTodo(name, description, parent_id, date) => Task::perform(
-                submit_new_todo(
-                    TodoToSend {
+            Message::SubmitNewTodo(name, description, parent_id, date) => {
+                let temp_id = next_temp_id(&flatten_hierarchy(&self.todos), &self.pending);
+                self.state = AppState::Loaded("".to_owned());
+                self.mutate(Mutation::Create {
+                    temp_id,
+                    key: Some(new_idempotency_key()),
+                    todo: TodoToSend {
                         name,
                         description,
                         parent_id,
-                        date: match date {
-                            Some(some_date) => Some(
-                                time::Date::from_calendar_date(
-                                    some_date.year,
-                                    time::Month::nth_next(
-                                        time::Month::January,
-                                        some_date.month as u8 - 1,
-                                    ),
-                                    some_date.day as u8,
-                                )
-                                .unwrap(),
-                            ),
-
-                            None => None,
-                        },
+                        date: date.map(to_time_date),
+                        priority: Priority::Normal,
+                        tags: Vec::new(),
                     },
-                    self.client.clone(),
-                    self.settings.server_url.clone(),
-                    self.settings.password.clone(),
-                ),
-                Message::SubmittedNewTodo,
-            ),
-            Message::SubmittedNewTodo(todo) => {
-                if todo.is_ok() {
-                    add_to_hierarchy(&mut self.todos, todo.unwrap());
-                    self.state = AppState::Loaded("".to_owned());
-                }
+                })
+            }
+            Message::QuickAddChanged(input) => {
+                self.state = AppState::Loaded(input);
                 Task::none()
             }
+            Message::SubmitQuickAdd => {
+                let AppState::Loaded(input) = &self.state else {
+                    return Task::none();
+                };
+                let parsed = parse_quick_add(input, to_time_date(Date::today()));
+                if parsed.name.is_empty() {
+                    return Task::none();
+                }
+                let todos = flatten_hierarchy(&self.todos);
+                // Resolved here rather than on the server so it works offline; open todos
+                // win when several have the name.
+                let parent_id = match &parsed.parent {
+                    Some(parent) => match todos
+                        .iter()
+                        .filter(|todo| todo.name.eq_ignore_ascii_case(parent))
+                        .min_by_key(|todo| (todo.done, todo.id))
+                    {
+                        Some(todo) => Some(todo.id),
+                        None => {
+                            self.conflicts
+                                .push(format!("There is no todo named \"{}\"", parent));
+                            return Task::none();
+                        }
+                    },
+                    None => None,
+                };
+                let temp_id = next_temp_id(&todos, &self.pending);
+                self.state = AppState::Loaded("".to_owned());
+                self.mutate(Mutation::Create {
+                    temp_id,
+                    key: Some(new_idempotency_key()),
+                    todo: TodoToSend {
+                        name: parsed.name,
+                        description: "".to_owned(),
+                        parent_id,
+                        date: parsed.date,
+                        priority: parsed.priority,
+                        tags: parsed.tags,
+                    },
+                })
+            }
             Message::FontLoaded(_) => Task::none(),
             Message::TodoMessage(id, message) => {
-                if let Some(_todo) = TodoHierarchy::get_hierarchy_by_id(&mut self.todos, id) {
+                if let Some(todo) = TodoHierarchy::get_hierarchy_by_id(&mut self.todos, id) {
+                    let name = todo.todo.name.clone();
+                    // version 0 means the server does not track versions
+                    let version = Some(todo.todo.version).filter(|version| *version > 0);
                     match message {
-                        TodoMessage::Done(id, _state) => Task::perform(
-                            toggle_todo(
-                                id,
-                                self.client.clone(),
-                                self.settings.server_url.clone(),
-                                self.settings.password.clone(),
-                            ),
-                            Message::TodoToggled,
-                        ),
-                        TodoMessage::Delete(id) => Task::perform(
-                            delete_todo(
-                                id,
-                                self.client.clone(),
-                                self.settings.server_url.clone(),
-                                self.settings.password.clone(),
-                            ),
-                            Message::Loaded,
-                        ),
+                        TodoMessage::Done(id, state) => self.mutate(Mutation::SetDone {
+                            id,
+                            name,
+                            done: state,
+                            version,
+                        }),
+                        TodoMessage::Delete(id) => {
+                            self.mutate(Mutation::Delete { id, name, version })
+                        }
                         TodoMessage::AddChild(parent_id) => {
                             self.state = AppState::AddingNewTodo(
                                 "".into(),
@@ -367,22 +742,25 @@ impl App {
                             );
                             Task::none()
                         }
+                        TodoMessage::Select(id, selected) => {
+                            if selected {
+                                self.selected.insert(id);
+                            } else {
+                                self.selected.remove(&id);
+                            }
+                            Task::none()
+                        }
+                        TodoMessage::ToggleExpanded(id) => {
+                            if !self.toggled.remove(&id) {
+                                self.toggled.insert(id);
+                            }
+                            Task::none()
+                        }
                     }
                 } else {
                     Task::none()
                 }
             }
-            Message::TodoToggled(result) => {
-                if result.is_ok() {
-                    let ok_result = result.unwrap();
-                    if let Some(todo) =
-                        TodoHierarchy::get_hierarchy_by_id(&mut self.todos, ok_result.0)
-                    {
-                        todo.toggle_with_children(ok_result.1);
-                    }
-                }
-                Task::none()
-            }
             Message::ChangeUrl(new_url) => {
                 self.settings.server_url = new_url;
                 Task::none()
@@ -391,9 +769,35 @@ impl App {
                 self.settings.password = new_password;
                 Task::none()
             }
+            Message::ChangeTreeDepth(new_depth) => {
+                if new_depth.is_empty() {
+                    self.settings.tree_depth = 0;
+                } else if let Ok(depth) = new_depth.parse() {
+                    self.settings.tree_depth = depth;
+                }
+                Task::none()
+            }
+            Message::Bulk(action) => {
+                let todos = self.bulk_targets();
+                self.selected.clear();
+                if todos.is_empty() {
+                    return Task::none();
+                }
+                self.mutate(Mutation::Bulk { action, todos })
+            }
+            Message::ClearSelection => {
+                self.selected.clear();
+                Task::none()
+            }
+            Message::ShowMoreTodos => {
+                self.shown_roots += ROOT_PAGE_SIZE;
+                Task::none()
+            }
             Message::SaveSettings => {
-                self.settings.save();
-                self.state = AppState::Loaded("".into());
+                self.state = match self.settings.save() {
+                    Ok(()) => AppState::Loaded("".into()),
+                    Err(save_error) => AppState::Errored(format!("{:?}", save_error)),
+                };
                 Task::none()
             }
             Message::LoadScreenSettings => {
@@ -404,13 +808,102 @@ impl App {
                 self.state = AppState::About;
                 Task::none()
             }
+            Message::LoadScreenExport => {
+                self.state = AppState::Export(None);
+                Task::none()
+            }
+            Message::Export(format) => {
+                let path = PathBuf::from(format!(
+                    "{}/timely-{}",
+                    home_dir().unwrap().to_str().unwrap(),
+                    format.file_name()
+                ));
+                let result = match fs::write(&path, format.export(&self.todos)) {
+                    Ok(()) => format!("Exported to {}", path.display()),
+                    Err(export_error) => format!("Could not export: {}", export_error),
+                };
+                self.state = AppState::Export(Some(result));
+                Task::none()
+            }
+            Message::LoadScreenArchive => {
+                self.state = AppState::Archive("30".to_owned(), None, None);
+                self.load_archive()
+            }
+            Message::ArchiveLoaded(result) => {
+                match result {
+                    Ok(todos) => {
+                        if let AppState::Archive(_, archived, _) = &mut self.state {
+                            *archived = Some(todos);
+                        }
+                    }
+                    Err(load_error) => self.set_archive_message(archive_error(load_error)),
+                }
+                Task::none()
+            }
+            Message::ChangeArchiveDays(new_days) => {
+                if let AppState::Archive(days, _, _) = &mut self.state {
+                    *days = new_days;
+                }
+                Task::none()
+            }
+            Message::ArchiveCompleted => {
+                let AppState::Archive(days, _, _) = &self.state else {
+                    return Task::none();
+                };
+                let Ok(older_than_days) = days.parse() else {
+                    return Task::none();
+                };
+                Task::perform(
+                    archive_completed(
+                        older_than_days,
+                        self.client.clone(),
+                        self.settings.server_url.clone(),
+                        self.settings.password.clone(),
+                    ),
+                    Message::ArchivedCompleted,
+                )
+            }
+            Message::ArchivedCompleted(result) => match result {
+                Ok(archived) => {
+                    self.set_archive_message(match archived.len() {
+                        0 => "No completed todos to archive".to_owned(),
+                        1 => "Archived 1 todo".to_owned(),
+                        count => format!("Archived {} todos", count),
+                    });
+                    // Sync drops the archived todos from the list.
+                    Task::batch([self.load_archive(), self.refresh()])
+                }
+                Err(archive_err) => {
+                    self.set_archive_message(archive_error(archive_err));
+                    Task::none()
+                }
+            },
+            Message::Unarchive(id) => Task::perform(
+                unarchive(
+                    id,
+                    self.client.clone(),
+                    self.settings.server_url.clone(),
+                    self.settings.password.clone(),
+                ),
+                Message::Unarchived,
+            ),
+            Message::Unarchived(result) => match result {
+                Ok(todo) => {
+                    self.set_archive_message(format!("Unarchived \"{}\"", todo.name));
+                    Task::batch([self.load_archive(), self.refresh()])
+                }
+                Err(unarchive_err) => {
+                    self.set_archive_message(archive_error(unarchive_err));
+                    Task::none()
+                }
+            },
         }
     }
 
     fn view(&self) -> Element<'_, Message, Theme, iced::Renderer> {
         let content: Element<_> = match &self.state {
             AppState::Loading => text("Loading...").into(),
-            AppState::Loaded(_input_value) => {
+            AppState::Loaded(input_value) => {
                 let control_buttons = row![
                     text("Timely").size(28),
                     button("Add new").on_press(Message::LoadScreenAddNewTodo(
@@ -420,28 +913,93 @@ impl App {
                         false,
                     )),
                     button("Refresh").on_press(Message::Load),
+                    button("Export").on_press(Message::LoadScreenExport),
+                    button("Archive").on_press(Message::LoadScreenArchive),
                     button("Settings").on_press(Message::LoadScreenSettings),
                     button("About").on_press(Message::LoadScreenAbout)
                 ]
                 .align_y(Alignment::Center)
                 .spacing(18);
+                let quick_add = text_input(
+                    "Quick add: Pay rent tomorrow !high #home under Finances",
+                    input_value,
+                )
+                .on_input(Message::QuickAddChanged)
+                .on_submit(Message::SubmitQuickAdd);
+                let mut main_column = column![control_buttons, quick_add].spacing(24);
+
+                if self.offline {
+                    main_column = main_column.push(text(format!(
+                        "Offline - {} change(s) waiting to be sent",
+                        self.pending.len()
+                    )));
+                } else if let Some(reason) = &self.stalled {
+                    main_column = main_column.push(text(format!(
+                        "{} change(s) waiting to be sent, the server failed them: {}",
+                        self.pending.len(),
+                        reason
+                    )));
+                } else if !self.pending.is_empty() {
+                    main_column = main_column
+                        .push(text(format!("Sending {} change(s)...", self.pending.len())));
+                }
+
+                if !self.selected.is_empty() {
+                    main_column = main_column.push(
+                        row![
+                            text(format!("{} selected", self.selected.len())),
+                            button("Done").on_press(Message::Bulk(BulkAction::SetDone(true))),
+                            button("Not done").on_press(Message::Bulk(BulkAction::SetDone(false))),
+                            button("Delete").on_press(Message::Bulk(BulkAction::Delete)),
+                            button("Clear").on_press(Message::ClearSelection),
+                        ]
+                        .align_y(Alignment::Center)
+                        .spacing(10),
+                    );
+                }
+
+                if !self.conflicts.is_empty() {
+                    let conflicts = self
+                        .conflicts
+                        .iter()
+                        .fold(
+                            column![text("Some changes could not be applied:")],
+                            |col, conflict| col.push(text(conflict).size(12)),
+                        )
+                        .push(button("Dismiss").on_press(Message::DismissConflicts))
+                        .spacing(8);
+                    main_column = main_column.push(conflicts);
+                }
+
                 match self.todos.len() {
-                    0 => column![control_buttons, text("No todos!")]
-                        .spacing(24)
-                        .into(),
-                    _ => column![
-                        control_buttons,
-                        scrollable(keyed_column(self.todos.iter().map(|todo| {
-                            (
-                                todo.todo.id,
-                                hierarchy_view(todo).map(move |message| {
-                                    Message::TodoMessage(todo.todo.id, message)
-                                }),
-                            )
-                        }))),
-                    ]
-                    .spacing(24)
-                    .into(),
+                    0 => main_column.push(text("No todos!")).into(),
+                    _ => {
+                        let tree = TreeView {
+                            depth: self.settings.tree_depth,
+                            toggled: &self.toggled,
+                            selected: &self.selected,
+                        };
+                        let todos =
+                            keyed_column(self.todos.iter().take(self.shown_roots).map(|todo| {
+                                (
+                                    todo.todo.id,
+                                    hierarchy_view(todo, 0, tree).map(move |message| {
+                                        Message::TodoMessage(todo.todo.id, message)
+                                    }),
+                                )
+                            }));
+                        let hidden = self.todos.len().saturating_sub(self.shown_roots);
+                        let list = if hidden > 0 {
+                            column![
+                                todos,
+                                button(text(format!("Show more ({} left)", hidden)))
+                                    .on_press(Message::ShowMoreTodos)
+                            ]
+                        } else {
+                            column![todos]
+                        };
+                        main_column.push(scrollable(list)).into()
+                    }
                 }
             }
             AppState::Errored(error) => column![
@@ -459,7 +1017,7 @@ impl App {
                 button("Go back").on_press(Message::GoBackToMain),
                 row![
                     text("Name:"),
-                    text_input("Task name", &name).on_input(|new_name| {
+                    text_input("Task name", name).on_input(|new_name| {
                         Message::LoadScreenAddNewTodo(
                             new_name,
                             description.clone(),
@@ -472,7 +1030,7 @@ impl App {
                 .spacing(10),
                 row![
                     text("Description:"),
-                    text_input("Task description", &description).on_input(|new_description| {
+                    text_input("Task description", description).on_input(|new_description| {
                         Message::LoadScreenAddNewTodo(
                             name.clone(),
                             new_description,
@@ -536,6 +1094,13 @@ impl App {
                 ]
                 .align_y(Alignment::Center)
                 .spacing(10),
+                row![
+                    text("Subtask levels shown:"),
+                    text_input("3", &self.settings.tree_depth.to_string())
+                        .on_input(Message::ChangeTreeDepth),
+                ]
+                .align_y(Alignment::Center)
+                .spacing(10),
                 button("Save").on_press(Message::SaveSettings)
             ]
             .spacing(10)
@@ -552,6 +1117,55 @@ impl App {
             ]
             .spacing(10)
             .into(),
+            AppState::Export(result) => {
+                let formats = ExportFormat::ALL
+                    .iter()
+                    .fold(row![].spacing(10), |row, format| {
+                        row.push(button(format.label()).on_press(Message::Export(*format)))
+                    });
+                column![
+                    button("Go back").on_press(Message::GoBackToMain),
+                    text("Export all todos to your home directory as:"),
+                    formats,
+                    text(result.clone().unwrap_or_default()),
+                ]
+                .spacing(10)
+                .into()
+            }
+            AppState::Archive(days, archived, message) => {
+                let valid_days = days.parse::<i32>().is_ok_and(|days| days >= 0);
+                let list: Element<_> = match archived {
+                    None => text("Loading...").into(),
+                    Some(archived) if archived.is_empty() => text("Nothing is archived").into(),
+                    Some(archived) => scrollable(Column::with_children(
+                        archived.iter().map(|todo| archived_view(todo, true)),
+                    ))
+                    .into(),
+                };
+                column![
+                    row![
+                        text("Archive").size(28),
+                        button("Go back").on_press(Message::GoBackToMain),
+                    ]
+                    .align_y(Alignment::Center)
+                    .spacing(18),
+                    row![
+                        text("Archive todos completed at least"),
+                        text_input("30", days)
+                            .on_input(Message::ChangeArchiveDays)
+                            .width(60),
+                        text("days ago"),
+                        button("Archive")
+                            .on_press_maybe(valid_days.then_some(Message::ArchiveCompleted)),
+                    ]
+                    .align_y(Alignment::Center)
+                    .spacing(10),
+                    text(message.clone().unwrap_or_default()),
+                    list,
+                ]
+                .spacing(10)
+                .into()
+            }
         };
         container(content)
             .width(Length::Fill)
@@ -561,42 +1175,61 @@ impl App {
     }
 }
 
-pub fn add_to_hierarchy(hierarchy: &mut Vec<TodoHierarchy>, todo: Todo) {
-    fn try_add_to_hierarchy(hierarchy: &mut Vec<TodoHierarchy>, todo: Todo) -> bool {
-        for sub_hierarchy in hierarchy {
-            if sub_hierarchy.todo.id == todo.parent_id.unwrap_or(-1) {
-                sub_hierarchy.children.push(TodoHierarchy::new(todo));
-                return true;
-            } else if try_add_to_hierarchy(&mut sub_hierarchy.children, todo.clone()) {
-                return true;
-            }
-        }
-        false
-    }
-
-    if !try_add_to_hierarchy(hierarchy, todo.clone()) {
-        hierarchy.push(TodoHierarchy::new(todo));
-    }
-}
-
 #[derive(Clone, Debug)]
 enum TodoMessage {
     Done(i64, bool),
     Delete(i64),
     AddChild(i64),
+    ToggleExpanded(i64),
+    Select(i64, bool),
+}
+
+/// Which todos of the tree show their children.
+#[derive(Clone, Copy)]
+struct TreeView<'a> {
+    // levels shown below a top-level todo
+    depth: usize,
+    toggled: &'a HashSet<i64>,
+    selected: &'a HashSet<i64>,
+}
+
+impl TreeView<'_> {
+    fn is_expanded(&self, id: i64, level: usize) -> bool {
+        (level < self.depth) != self.toggled.contains(&id)
+    }
 }
 
-fn hierarchy_view(hierarchy: &TodoHierarchy) -> Element<'_, TodoMessage> {
+fn hierarchy_view<'a>(
+    hierarchy: &'a TodoHierarchy,
+    level: usize,
+    tree: TreeView,
+) -> Element<'a, TodoMessage> {
+    let mut title = hierarchy.todo.name.clone();
+    match Priority::from_level(hierarchy.todo.priority) {
+        Priority::High => title.insert_str(0, "! "),
+        Priority::Normal => {}
+        Priority::Low => title.insert_str(0, "\u{2193} "),
+    }
+    for tag in &hierarchy.todo.tags {
+        title.push_str(&format!(" #{}", tag));
+    }
     let name_and_desc = if let Some(desc) = &hierarchy.todo.description {
-        if desc != "" {
-            column![text(&hierarchy.todo.name).size(16), text(desc).size(12)].padding([0, 16])
+        if !desc.is_empty() {
+            column![text(title).size(16), text(desc).size(12)].padding([0, 16])
         } else {
-            column![text(&hierarchy.todo.name).size(16)].padding([0, 16])
+            column![text(title).size(16)].padding([0, 16])
         }
     } else {
-        column![text(&hierarchy.todo.name).size(16)].padding([0, 16])
+        column![text(title).size(16)].padding([0, 16])
     };
-    let mut col: Column<TodoMessage> = column![row![
+    let expanded = tree.is_expanded(hierarchy.todo.id, level);
+    let mut todo_row = row![
+        tooltip(
+            checkbox("", tree.selected.contains(&hierarchy.todo.id))
+                .on_toggle(|selected| TodoMessage::Select(hierarchy.todo.id, selected)),
+            "Select",
+            tooltip::Position::Bottom,
+        ),
         checkbox("", hierarchy.todo.done)
             .on_toggle(|state| TodoMessage::Done(hierarchy.todo.id, state)),
         name_and_desc,
@@ -612,13 +1245,26 @@ fn hierarchy_view(hierarchy: &TodoHierarchy) -> Element<'_, TodoMessage> {
             .padding(2)
     ]
     .align_y(Alignment::Center)
-    .spacing(8)]
     .spacing(8);
-
+    if !hierarchy.children.is_empty() {
+        let label = if expanded {
+            "Hide subtasks".to_owned()
+        } else {
+            match hierarchy.children.len() {
+                1 => "Show 1 subtask".to_owned(),
+                count => format!("Show {} subtasks", count),
+            }
+        };
+        todo_row = todo_row.push(
+            button(text(label).size(12)).on_press(TodoMessage::ToggleExpanded(hierarchy.todo.id)),
+        );
+    }
+    let mut col: Column<TodoMessage> = column![todo_row].spacing(8);
+    let children: &[TodoHierarchy] = if expanded { &hierarchy.children } else { &[] };
     // Add the children recursively
-    for child in &hierarchy.children {
+    for child in children {
         col = col.push(
-            Container::new(hierarchy_view(child))
+            Container::new(hierarchy_view(child, level + 1, tree))
                 .padding([0, 8])
                 .width(Length::Fill),
         );
@@ -627,13 +1273,41 @@ fn hierarchy_view(hierarchy: &TodoHierarchy) -> Element<'_, TodoMessage> {
     Container::new(col).padding(10).width(Length::Fill).into()
 }
 
+/// An archived todo with its descendants; only top-level todos can be unarchived.
+fn archived_view(hierarchy: &TodoHierarchy, top_level: bool) -> Element<'_, Message> {
+    let mut todo_row = row![text(&hierarchy.todo.name).size(16)]
+        .align_y(Alignment::Center)
+        .spacing(8);
+    if top_level {
+        todo_row = todo_row.push(
+            button(text("Unarchive").size(12)).on_press(Message::Unarchive(hierarchy.todo.id)),
+        );
+    }
+    let col = hierarchy
+        .children
+        .iter()
+        .fold(column![todo_row].spacing(8), |col, child| {
+            col.push(Container::new(archived_view(child, false)).padding([0, 8]))
+        });
+    Container::new(col).padding(10).width(Length::Fill).into()
+}
+
 fn main() -> iced::Result {
+    // `LOG_LEVEL` filters the logs like on the server, e.g. `debug` or `timely_app=debug`.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_env("LOG_LEVEL").unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
     let settings = AppSettings::load().unwrap_or(AppSettings {
         ..Default::default()
     });
 
-    println!("Using server url: {}", settings.server_url);
-    println!("Using palette: {}", settings.palette);
+    tracing::info!(
+        server_url = %settings.server_url,
+        palette = %settings.palette,
+        "Starting"
+    );
 
     iced::application(App::title, App::update, App::view)
         .default_font(Font::DEFAULT)
@@ -644,5 +1318,5 @@ fn main() -> iced::Result {
         .theme(App::theme)
         .position(iced::window::Position::Centered)
         .antialiasing(true)
-        .run_with(|| App::new(settings.server_url, settings.password, settings.palette))
+        .run_with(|| App::new(settings))
 }
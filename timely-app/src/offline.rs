@@ -0,0 +1,366 @@
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::env::home_dir;
+use std::fs;
+use std::hash::BuildHasher;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use timely_lib::{Todo, TodoToSend};
+
+use crate::{delete_todo, run_batch, set_done, submit_new_todo, BatchOperation, Error};
+
+/// Todos created while offline get ids from this value upwards until the server assigns
+/// real ones, so they never collide with a server id.
+const TEMP_ID_BASE: i64 = 1 << 62;
+
+pub fn is_temp_id(id: i64) -> bool {
+    id >= TEMP_ID_BASE
+}
+
+/// A key no other create will use, sent with a create so that sending it twice, when the
+/// first response was lost, makes one todo.
+pub fn new_idempotency_key() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    // RandomState is seeded randomly, so the key differs even if the clock does not
+    let random = RandomState::new().hash_one(nanos);
+    format!("{:x}-{:016x}", nanos, random)
+}
+
+/// A change made in the app that still has to be sent to the server.
+/// `version` is the version of the todo the change was made on, if known.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Mutation {
+    Create {
+        temp_id: i64,
+        // missing in creates queued by older versions of the app
+        #[serde(default)]
+        key: Option<String>,
+        todo: TodoToSend,
+    },
+    SetDone {
+        id: i64,
+        name: String,
+        done: bool,
+        #[serde(default)]
+        version: Option<i64>,
+    },
+    Delete {
+        id: i64,
+        name: String,
+        #[serde(default)]
+        version: Option<i64>,
+    },
+    // the same action on several todos, sent as one batch that succeeds or fails as a whole
+    Bulk {
+        action: BulkAction,
+        todos: Vec<BulkTarget>,
+    },
+}
+
+/// What a bulk action does to each of its todos.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum BulkAction {
+    SetDone(bool),
+    Delete,
+}
+
+impl BulkAction {
+    fn describe(self, count: usize) -> String {
+        let verb = match self {
+            BulkAction::SetDone(true) => "complete",
+            BulkAction::SetDone(false) => "reopen",
+            BulkAction::Delete => "delete",
+        };
+        format!("Could not {} {} todos", verb, count)
+    }
+}
+
+/// A todo a bulk action applies to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BulkTarget {
+    pub id: i64,
+    pub name: String,
+    #[serde(default)]
+    pub version: Option<i64>,
+}
+
+impl Mutation {
+    /// Applies the mutation to a flat list of todos, as the server would.
+    pub fn apply_local(&self, todos: &mut Vec<Todo>) {
+        match self {
+            Mutation::Create { temp_id, todo, .. } => {
+                todos.push(Todo {
+                    id: *temp_id,
+                    name: todo.name.clone(),
+                    done: false,
+                    description: Some(todo.description.clone()),
+                    parent_id: todo.parent_id,
+                    date: todo.date,
+                    version: 1,
+                    priority: todo.priority.level(),
+                    tags: todo.tags.clone(),
+                });
+                todos.sort_by_key(|todo| todo.id);
+            }
+            Mutation::SetDone { id, done, .. } => {
+                // The server bumps the version of every todo in the subtree, so do the same
+                // to keep later changes to them from looking like conflicts.
+                let subtree = subtree_ids(todos, *id);
+                for todo in todos.iter_mut().filter(|todo| subtree.contains(&todo.id)) {
+                    todo.done = *done;
+                    if todo.version > 0 {
+                        todo.version += 1;
+                    }
+                }
+            }
+            Mutation::Delete { id, .. } => {
+                let subtree = subtree_ids(todos, *id);
+                todos.retain(|todo| !subtree.contains(&todo.id));
+            }
+            Mutation::Bulk {
+                action,
+                todos: targets,
+            } => {
+                for target in targets {
+                    let single = match action {
+                        BulkAction::SetDone(done) => Mutation::SetDone {
+                            id: target.id,
+                            name: target.name.clone(),
+                            done: *done,
+                            version: target.version,
+                        },
+                        BulkAction::Delete => Mutation::Delete {
+                            id: target.id,
+                            name: target.name.clone(),
+                            version: target.version,
+                        },
+                    };
+                    single.apply_local(todos);
+                }
+            }
+        }
+    }
+
+    /// Replaces temporary ids with the ones assigned by the server.
+    pub fn remap_ids(&mut self, id_map: &HashMap<i64, i64>) {
+        let remap = |id: &mut i64| {
+            if let Some(new_id) = id_map.get(id) {
+                *id = *new_id;
+            }
+        };
+        match self {
+            Mutation::Create { todo, .. } => {
+                if let Some(parent_id) = todo.parent_id.as_mut() {
+                    remap(parent_id);
+                }
+            }
+            Mutation::SetDone { id, .. } | Mutation::Delete { id, .. } => remap(id),
+            Mutation::Bulk { todos, .. } => {
+                for target in todos {
+                    remap(&mut target.id);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the next free temporary id for a todo created locally.
+pub fn next_temp_id(todos: &[Todo], pending: &[Mutation]) -> i64 {
+    let used = todos
+        .iter()
+        .map(|todo| todo.id)
+        .chain(pending.iter().filter_map(|mutation| match mutation {
+            Mutation::Create { temp_id, .. } => Some(*temp_id),
+            _ => None,
+        }));
+    used.filter(|id| is_temp_id(*id))
+        .max()
+        .map_or(TEMP_ID_BASE, |id| id + 1)
+}
+
+/// Replaces temporary ids in a flat list of todos with the ones assigned by the server.
+pub fn remap_todo_ids(todos: &mut [Todo], id_map: &HashMap<i64, i64>) {
+    for todo in todos.iter_mut() {
+        if let Some(new_id) = id_map.get(&todo.id) {
+            todo.id = *new_id;
+        }
+        if let Some(new_parent_id) = todo.parent_id.and_then(|id| id_map.get(&id)) {
+            todo.parent_id = Some(*new_parent_id);
+        }
+    }
+    todos.sort_by_key(|todo| todo.id);
+}
+
+fn subtree_ids(todos: &[Todo], root_id: i64) -> HashSet<i64> {
+    let mut ids = HashSet::from([root_id]);
+    loop {
+        let before = ids.len();
+        for todo in todos {
+            if todo
+                .parent_id
+                .is_some_and(|parent_id| ids.contains(&parent_id))
+            {
+                ids.insert(todo.id);
+            }
+        }
+        if ids.len() == before {
+            return ids;
+        }
+    }
+}
+
+/// The last known state of the todo list, kept on disk so the app works offline.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LocalCache {
+    pub todos: Vec<Todo>,
+    pub sync_token: Option<i64>,
+    pub pending: Vec<Mutation>,
+}
+
+impl LocalCache {
+    fn path() -> PathBuf {
+        PathBuf::from(format!(
+            "{}/.config/timely/cache.json",
+            home_dir().unwrap().to_str().unwrap()
+        ))
+    }
+
+    /// Loads the cache, falling back to an empty one if it is missing or unreadable.
+    pub fn load() -> Self {
+        fs::read_to_string(Self::path())
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)
+    }
+}
+
+/// Outcome of sending queued mutations to the server.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    // number of mutations from the start of the queue that were handled
+    pub processed: usize,
+    // temporary id -> id assigned by the server
+    pub id_map: HashMap<i64, i64>,
+    pub conflicts: Vec<String>,
+    // the server became unreachable before the whole queue was sent
+    pub offline: bool,
+    // why the server failed a mutation that is worth sending again later
+    pub stalled: Option<String>,
+}
+
+/// Sends the queued mutations in order. Mutations the server rejects are dropped and
+/// reported as conflicts; replay stops at the first one that cannot reach the server, or
+/// that it fails for another reason, and leaves it queued.
+pub async fn replay(
+    mutations: Vec<Mutation>,
+    client: Client,
+    url: String,
+    password: String,
+) -> ReplayReport {
+    let mut report = ReplayReport::default();
+
+    for mut mutation in mutations {
+        mutation.remap_ids(&report.id_map);
+        let result = match &mutation {
+            Mutation::Create { temp_id, key, todo } => if todo.parent_id.is_some_and(is_temp_id) {
+                Err(Error::APIError("its parent was not created".to_owned()))
+            } else {
+                submit_new_todo(
+                    todo.clone(),
+                    key.clone(),
+                    client.clone(),
+                    url.clone(),
+                    password.clone(),
+                )
+                .await
+                .map(|created| {
+                    report.id_map.insert(*temp_id, created.id);
+                })
+            }
+            .map_err(|error| (format!("Could not create \"{}\"", todo.name), error)),
+            Mutation::SetDone {
+                id,
+                name,
+                done,
+                version,
+            } => if is_temp_id(*id) {
+                Err(Error::APIError("it was never created".to_owned()))
+            } else {
+                set_done(
+                    *id,
+                    *done,
+                    *version,
+                    client.clone(),
+                    url.clone(),
+                    password.clone(),
+                )
+                .await
+                .map(|_| ())
+            }
+            .map_err(|error| {
+                let action = if *done { "complete" } else { "reopen" };
+                (format!("Could not {} \"{}\"", action, name), error)
+            }),
+            Mutation::Delete { id, name, version } => if is_temp_id(*id) {
+                Err(Error::APIError("it was never created".to_owned()))
+            } else {
+                delete_todo(*id, *version, client.clone(), url.clone(), password.clone()).await
+            }
+            .map_err(|error| (format!("Could not delete \"{}\"", name), error)),
+            Mutation::Bulk { action, todos } => if todos.iter().any(|todo| is_temp_id(todo.id)) {
+                Err(Error::APIError(
+                    "some of them were never created".to_owned(),
+                ))
+            } else {
+                let operations = todos
+                    .iter()
+                    .map(|todo| match action {
+                        BulkAction::SetDone(done) => BatchOperation::SetDone {
+                            id: todo.id,
+                            done: *done,
+                            version: todo.version,
+                        },
+                        BulkAction::Delete => BatchOperation::Delete {
+                            id: todo.id,
+                            version: todo.version,
+                        },
+                    })
+                    .collect();
+                run_batch(operations, client.clone(), url.clone(), password.clone()).await
+            }
+            .map_err(|error| (action.describe(todos.len()), error)),
+        };
+
+        match result {
+            Ok(()) => {}
+            Err((_, Error::Offline)) => {
+                report.offline = true;
+                break;
+            }
+            Err((context, Error::Failed(reason))) => {
+                report.stalled = Some(format!("{}: {}", context, reason));
+                break;
+            }
+            Err((context, Error::APIError(reason))) => {
+                report.conflicts.push(format!("{}: {}", context, reason));
+            }
+        }
+        report.processed += 1;
+    }
+
+    report
+}
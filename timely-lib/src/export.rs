@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use time::Date;
+
+use crate::{Priority, Todo, TodoHierarchy};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    #[serde(alias = "md")]
+    Markdown,
+    #[serde(alias = "todo.txt")]
+    TodoTxt,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "json" => Ok(ExportFormat::Json),
+            "csv" => Ok(ExportFormat::Csv),
+            "markdown" | "md" => Ok(ExportFormat::Markdown),
+            "todotxt" | "todo.txt" => Ok(ExportFormat::TodoTxt),
+            _ => Err(format!(
+                "unknown format {:?}, expected json, csv, markdown or todotxt",
+                format
+            )),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 4] = [
+        ExportFormat::Json,
+        ExportFormat::Csv,
+        ExportFormat::Markdown,
+        ExportFormat::TodoTxt,
+    ];
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/json",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+            ExportFormat::TodoTxt => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "todos.json",
+            ExportFormat::Csv => "todos.csv",
+            ExportFormat::Markdown => "todos.md",
+            ExportFormat::TodoTxt => "todo.txt",
+        }
+    }
+
+    /// Guesses the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(ExportFormat::Json),
+            "csv" => Some(ExportFormat::Csv),
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "txt" => Some(ExportFormat::TodoTxt),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "JSON",
+            ExportFormat::Csv => "CSV",
+            ExportFormat::Markdown => "Markdown",
+            ExportFormat::TodoTxt => "todo.txt",
+        }
+    }
+
+    /// Serialises a forest of todos in this format.
+    pub fn export(&self, hierarchies: &[TodoHierarchy]) -> String {
+        match self {
+            ExportFormat::Json => to_json(hierarchies),
+            ExportFormat::Csv => to_csv(hierarchies),
+            ExportFormat::Markdown => to_markdown(hierarchies),
+            ExportFormat::TodoTxt => to_todo_txt(hierarchies),
+        }
+    }
+}
+
+/// A todo with its children nested inside, as written by the JSON export.
+/// Unlike the API, dates are written as `YYYY-MM-DD`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct NestedTodo {
+    #[serde(default)]
+    pub id: Option<i64>,
+    pub name: String,
+    #[serde(default)]
+    pub done: bool,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default, with = "iso_date")]
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<String>, format = Date))]
+    pub date: Option<Date>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    #[cfg_attr(feature = "openapi", schema(no_recursion))]
+    pub children: Vec<NestedTodo>,
+}
+
+impl From<&TodoHierarchy> for NestedTodo {
+    fn from(hierarchy: &TodoHierarchy) -> Self {
+        NestedTodo {
+            id: Some(hierarchy.todo.id),
+            name: hierarchy.todo.name.clone(),
+            done: hierarchy.todo.done,
+            description: hierarchy.todo.description.clone(),
+            date: hierarchy.todo.date,
+            priority: Priority::from_level(hierarchy.todo.priority),
+            tags: hierarchy.todo.tags.clone(),
+            children: hierarchy.children.iter().map(NestedTodo::from).collect(),
+        }
+    }
+}
+
+mod iso_date {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use time::Date;
+
+    pub fn serialize<S: Serializer>(date: &Option<Date>, serializer: S) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => serializer.serialize_str(&date.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Date>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(date) => crate::parse_iso_date(&date)
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("invalid date {:?}", date))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Calls `visit` for every todo in depth-first order, with the names of its ancestors.
+fn walk<'a>(
+    hierarchies: &'a [TodoHierarchy],
+    path: &mut Vec<&'a str>,
+    visit: &mut impl FnMut(&'a Todo, &[&'a str]),
+) {
+    for hierarchy in hierarchies {
+        visit(&hierarchy.todo, path);
+        path.push(&hierarchy.todo.name);
+        walk(&hierarchy.children, path, visit);
+        path.pop();
+    }
+}
+
+pub fn to_json(hierarchies: &[TodoHierarchy]) -> String {
+    let nested: Vec<NestedTodo> = hierarchies.iter().map(NestedTodo::from).collect();
+    serde_json::to_string_pretty(&nested).expect("todos are always serialisable")
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// A tag as a single word, for the formats that mark tags with a prefix.
+fn tag_word(tag: &str) -> String {
+    tag.split_whitespace().collect::<Vec<_>>().join("-")
+}
+
+/// One row per todo; `path` holds the names of its ancestors separated by " / " and
+/// `tags` the tags separated by spaces.
+pub fn to_csv(hierarchies: &[TodoHierarchy]) -> String {
+    let mut csv = String::from("id,parent_id,path,name,description,done,date,priority,tags\r\n");
+    walk(hierarchies, &mut Vec::new(), &mut |todo, path| {
+        let tags: Vec<String> = todo.tags.iter().map(|tag| tag_word(tag)).collect();
+        let _ = write!(
+            csv,
+            "{},{},{},{},{},{},{},{},{}\r\n",
+            todo.id,
+            todo.parent_id.map(|id| id.to_string()).unwrap_or_default(),
+            csv_field(&path.join(" / ")),
+            csv_field(&todo.name),
+            csv_field(todo.description.as_deref().unwrap_or_default()),
+            todo.done,
+            todo.date.map(|date| date.to_string()).unwrap_or_default(),
+            Priority::from_level(todo.priority).label(),
+            csv_field(&tags.join(" ")),
+        );
+    });
+    csv
+}
+
+/// A checklist indented by two spaces per level, with `due:` tags, priorities other than
+/// normal as `!high`/`!low`, tags as `#tag` and descriptions on the line below their todo.
+pub fn to_markdown(hierarchies: &[TodoHierarchy]) -> String {
+    let mut markdown = String::new();
+    walk(hierarchies, &mut Vec::new(), &mut |todo, path| {
+        let indent = "  ".repeat(path.len());
+        let _ = write!(
+            markdown,
+            "{}- [{}] {}",
+            indent,
+            if todo.done { "x" } else { " " },
+            todo.name
+        );
+        if let Some(date) = todo.date {
+            let _ = write!(markdown, " due:{}", date);
+        }
+        let priority = Priority::from_level(todo.priority);
+        if priority != Priority::Normal {
+            let _ = write!(markdown, " !{}", priority.label());
+        }
+        for tag in &todo.tags {
+            let _ = write!(markdown, " #{}", tag_word(tag));
+        }
+        markdown.push('\n');
+        if let Some(description) = todo.description.as_deref().filter(|d| !d.is_empty()) {
+            for line in description.lines() {
+                let _ = writeln!(markdown, "{}  {}", indent, line);
+            }
+        }
+    });
+    markdown
+}
+
+/// One line per todo, high priority todos marked `(A)` and low priority ones `(C)`, with
+/// tags as `+tag` projects. todo.txt has no nesting, so the hierarchy is kept in `id:` and
+/// `parent:` tags; descriptions are not exported.
+pub fn to_todo_txt(hierarchies: &[TodoHierarchy]) -> String {
+    let mut todo_txt = String::new();
+    walk(hierarchies, &mut Vec::new(), &mut |todo, _| {
+        if todo.done {
+            todo_txt.push_str("x ");
+        }
+        match Priority::from_level(todo.priority) {
+            Priority::High => todo_txt.push_str("(A) "),
+            Priority::Normal => {}
+            Priority::Low => todo_txt.push_str("(C) "),
+        }
+        todo_txt.push_str(&todo.name.replace('\n', " "));
+        for tag in &todo.tags {
+            let _ = write!(todo_txt, " +{}", tag_word(tag));
+        }
+        if let Some(date) = todo.date {
+            let _ = write!(todo_txt, " due:{}", date);
+        }
+        let _ = write!(todo_txt, " id:{}", todo.id);
+        if let Some(parent_id) = todo.parent_id {
+            let _ = write!(todo_txt, " parent:{}", parent_id);
+        }
+        todo_txt.push('\n');
+    });
+    todo_txt
+}
@@ -0,0 +1,513 @@
+use time::{Date, OffsetDateTime};
+
+use crate::{Priority, Todo};
+
+pub const PRODID: &str = concat!("-//Timely//Timely ", env!("CARGO_PKG_VERSION"), "//EN");
+
+/// The stable UID of the VTODO for a todo.
+pub fn todo_uid(id: i64) -> String {
+    format!("todo-{}@timely", id)
+}
+
+/// The stable UID of the all-day VEVENT for a dated todo.
+pub fn event_uid(id: i64) -> String {
+    format!("event-{}@timely", id)
+}
+
+/// Escapes a TEXT value (RFC 5545, 3.3.11).
+pub fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_text(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Splits a list value on the commas that are not escaped.
+fn split_list(value: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (index, c) in value.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            ',' if !escaped => {
+                items.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => escaped = false,
+        }
+    }
+    items.push(&value[start..]);
+    items
+}
+
+fn format_date(date: Date) -> String {
+    format!(
+        "{:04}{:02}{:02}",
+        date.year(),
+        date.month() as u8,
+        date.day()
+    )
+}
+
+fn format_timestamp(timestamp: OffsetDateTime) -> String {
+    let utc = timestamp.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        format_date(utc.date()),
+        utc.hour(),
+        utc.minute(),
+        utc.second()
+    )
+}
+
+/// Writes a content line, folded to 75 octets as required by RFC 5545.
+fn write_line(ical: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            ical.push_str("\r\n ");
+            width = 1;
+        }
+        ical.push(c);
+        width += c.len_utf8();
+    }
+    ical.push_str("\r\n");
+}
+
+/// Writes the VTODO component for a todo; `uid` gives the UID of a todo by id.
+pub fn write_vtodo(
+    ical: &mut String,
+    todo: &Todo,
+    uid: impl Fn(i64) -> String,
+    stamp: OffsetDateTime,
+) {
+    write_line(ical, "BEGIN:VTODO");
+    write_line(ical, &format!("UID:{}", uid(todo.id)));
+    write_line(ical, &format!("DTSTAMP:{}", format_timestamp(stamp)));
+    write_line(ical, &format!("SUMMARY:{}", escape_text(&todo.name)));
+    if let Some(description) = todo.description.as_deref().filter(|d| !d.is_empty()) {
+        write_line(ical, &format!("DESCRIPTION:{}", escape_text(description)));
+    }
+    if let Some(date) = todo.date {
+        write_line(ical, &format!("DUE;VALUE=DATE:{}", format_date(date)));
+    }
+    if todo.done {
+        write_line(ical, "STATUS:COMPLETED");
+        write_line(ical, "PERCENT-COMPLETE:100");
+    } else {
+        write_line(ical, "STATUS:NEEDS-ACTION");
+    }
+    // RFC 5545 priorities go from 1 (highest) to 9 (lowest)
+    match Priority::from_level(todo.priority) {
+        Priority::High => write_line(ical, "PRIORITY:1"),
+        Priority::Normal => {}
+        Priority::Low => write_line(ical, "PRIORITY:9"),
+    }
+    if !todo.tags.is_empty() {
+        let categories: Vec<String> = todo.tags.iter().map(|tag| escape_text(tag)).collect();
+        write_line(ical, &format!("CATEGORIES:{}", categories.join(",")));
+    }
+    if let Some(parent_id) = todo.parent_id {
+        write_line(
+            ical,
+            &format!("RELATED-TO;RELTYPE=PARENT:{}", uid(parent_id)),
+        );
+    }
+    write_line(ical, &format!("SEQUENCE:{}", todo.version.max(0)));
+    write_line(ical, "END:VTODO");
+}
+
+/// Writes an all-day VEVENT on the todo's date; does nothing for undated todos.
+pub fn write_vevent(ical: &mut String, todo: &Todo, stamp: OffsetDateTime) {
+    let Some(date) = todo.date else {
+        return;
+    };
+    write_line(ical, "BEGIN:VEVENT");
+    write_line(ical, &format!("UID:{}", event_uid(todo.id)));
+    write_line(ical, &format!("DTSTAMP:{}", format_timestamp(stamp)));
+    write_line(ical, &format!("DTSTART;VALUE=DATE:{}", format_date(date)));
+    if let Some(next_day) = date.next_day() {
+        write_line(ical, &format!("DTEND;VALUE=DATE:{}", format_date(next_day)));
+    }
+    write_line(ical, &format!("SUMMARY:{}", escape_text(&todo.name)));
+    if let Some(description) = todo.description.as_deref().filter(|d| !d.is_empty()) {
+        write_line(ical, &format!("DESCRIPTION:{}", escape_text(description)));
+    }
+    write_line(ical, "TRANSP:TRANSPARENT");
+    write_line(ical, &format!("RELATED-TO:{}", todo_uid(todo.id)));
+    write_line(ical, &format!("SEQUENCE:{}", todo.version.max(0)));
+    write_line(ical, "END:VEVENT");
+}
+
+pub fn begin_calendar(ical: &mut String, name: &str) {
+    write_line(ical, "BEGIN:VCALENDAR");
+    write_line(ical, "VERSION:2.0");
+    write_line(ical, &format!("PRODID:{}", PRODID));
+    write_line(ical, "CALSCALE:GREGORIAN");
+    write_line(ical, &format!("X-WR-CALNAME:{}", escape_text(name)));
+}
+
+pub fn end_calendar(ical: &mut String) {
+    write_line(ical, "END:VCALENDAR");
+}
+
+/// Renders the dated todos as a calendar of VTODOs, with an all-day VEVENT for each
+/// when `with_events` is set.
+pub fn dated_todos_calendar(todos: &[Todo], with_events: bool, stamp: OffsetDateTime) -> String {
+    let mut ical = String::new();
+    begin_calendar(&mut ical, "Timely");
+    for todo in todos.iter().filter(|todo| todo.date.is_some()) {
+        write_vtodo(&mut ical, todo, todo_uid, stamp);
+        if with_events {
+            write_vevent(&mut ical, todo, stamp);
+        }
+    }
+    end_calendar(&mut ical);
+    ical
+}
+
+/// The fields Timely keeps from a VTODO sent by a client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VTodo {
+    pub uid: String,
+    pub summary: String,
+    pub description: Option<String>,
+    pub due: Option<Date>,
+    pub completed: bool,
+    pub priority: Priority,
+    pub categories: Vec<String>,
+    // UID of the parent todo (`RELATED-TO` without a `RELTYPE` or with `RELTYPE=PARENT`)
+    pub parent_uid: Option<String>,
+}
+
+/// Parses the date part of a DATE or DATE-TIME value.
+fn parse_date(value: &str) -> Option<Date> {
+    let digits = value.get(..8)?;
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let month = crate::month_num_to_month(digits[4..6].parse().ok()?)?;
+    Date::from_calendar_date(digits[..4].parse().ok()?, month, digits[6..8].parse().ok()?).ok()
+}
+
+/// Parameters of a content line, with upper-cased names.
+type Params = Vec<(String, String)>;
+
+/// Splits a content line into its upper-cased name, parameters and value.
+fn split_content_line(line: &str) -> Option<(String, Params, &str)> {
+    let mut in_quotes = false;
+    let colon = line.char_indices().find_map(|(index, c)| match c {
+        '"' => {
+            in_quotes = !in_quotes;
+            None
+        }
+        ':' if !in_quotes => Some(index),
+        _ => None,
+    })?;
+    let mut parts = line[..colon].split(';');
+    let name = parts.next()?.trim().to_uppercase();
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().to_uppercase(),
+                value.trim_matches('"').to_owned(),
+            )
+        })
+        .collect();
+    Some((name, params, &line[colon + 1..]))
+}
+
+/// Reads the first VTODO of an iCalendar object. Nested components such as alarms and
+/// properties Timely has no place for are ignored.
+pub fn parse_vtodo(ical: &str) -> Result<VTodo, String> {
+    let unfolded = ical
+        .replace("\r\n ", "")
+        .replace("\r\n\t", "")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut vtodo: Option<VTodo> = None;
+    let mut status = None;
+    let mut has_completed = false;
+    let mut start = None;
+    // depth of components nested inside the VTODO
+    let mut nested = 0;
+
+    for line in unfolded.lines() {
+        let Some((name, params, value)) = split_content_line(line) else {
+            continue;
+        };
+        let Some(todo) = vtodo.as_mut() else {
+            if name == "BEGIN" && value.eq_ignore_ascii_case("VTODO") {
+                vtodo = Some(VTodo::default());
+            }
+            continue;
+        };
+        match name.as_str() {
+            "BEGIN" => nested += 1,
+            "END" if nested > 0 => nested -= 1,
+            "END" => break,
+            _ if nested > 0 => {}
+            "UID" => todo.uid = value.trim().to_owned(),
+            "SUMMARY" => todo.summary = unescape_text(value),
+            "DESCRIPTION" => todo.description = Some(unescape_text(value)),
+            "DUE" => todo.due = parse_date(value),
+            "DTSTART" => start = parse_date(value),
+            "STATUS" => status = Some(value.trim().to_uppercase()),
+            "COMPLETED" => has_completed = true,
+            "PRIORITY" => {
+                todo.priority = match value.trim().parse::<u8>() {
+                    Ok(1..=4) => Priority::High,
+                    Ok(6..=9) => Priority::Low,
+                    _ => Priority::Normal,
+                }
+            }
+            "CATEGORIES" => todo.categories.extend(
+                split_list(value)
+                    .iter()
+                    .map(|category| unescape_text(category).trim().to_owned())
+                    .filter(|category| !category.is_empty()),
+            ),
+            "RELATED-TO" => {
+                let is_parent = params
+                    .iter()
+                    .find(|(key, _)| key == "RELTYPE")
+                    .is_none_or(|(_, reltype)| reltype.eq_ignore_ascii_case("PARENT"));
+                if is_parent {
+                    todo.parent_uid = Some(value.trim().to_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut todo = vtodo.ok_or_else(|| "no VTODO component found".to_owned())?;
+    if todo.uid.is_empty() {
+        return Err("the VTODO has no UID".to_owned());
+    }
+    todo.due = todo.due.or(start);
+    todo.completed = match status.as_deref() {
+        Some(status) => status == "COMPLETED",
+        None => has_completed,
+    };
+    Ok(todo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_iso_date;
+
+    fn day(date: &str) -> Date {
+        parse_iso_date(date).unwrap()
+    }
+
+    /// A VTODO with the given content lines, in a calendar.
+    fn calendar(lines: &[&str]) -> String {
+        let mut ical = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VTODO\r\n".to_owned();
+        for line in lines {
+            ical.push_str(line);
+            ical.push_str("\r\n");
+        }
+        ical.push_str("END:VTODO\r\nEND:VCALENDAR\r\n");
+        ical
+    }
+
+    #[test]
+    fn long_lines_are_folded_at_75_octets() {
+        let line = format!("SUMMARY:{}", "Crème brûlée — à emporter ".repeat(8));
+        let mut ical = String::new();
+        write_line(&mut ical, &line);
+        let folded: Vec<&str> = ical.split_terminator("\r\n").collect();
+        assert!(folded.len() > 1);
+        assert!(folded.iter().all(|part| part.len() <= 75), "{:?}", folded);
+        assert!(folded[1..].iter().all(|part| part.starts_with(' ')));
+        assert_eq!(ical.replace("\r\n ", ""), format!("{}\r\n", line));
+
+        let mut ical = String::new();
+        write_line(&mut ical, &"a".repeat(75));
+        assert_eq!(ical, format!("{}\r\n", "a".repeat(75)));
+    }
+
+    #[test]
+    fn text_is_escaped_and_unescaped() {
+        assert_eq!(escape_text("a,b;c\\d\r\ne"), r"a\,b\;c\\d\ne");
+        assert_eq!(unescape_text("a\\Nb\\,c"), "a\nb,c");
+        assert_eq!(unescape_text("trailing\\"), "trailing\\");
+        for text in [
+            "plain",
+            "Milk, eggs; bread",
+            "back\\slash",
+            "two\nlines",
+            "",
+        ] {
+            assert_eq!(unescape_text(&escape_text(text)), text, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn parses_a_vtodo() {
+        let ical = calendar(&[
+            "UID:phone-1",
+            "SUMMARY:Buy milk\\, eggs and a very long list of other things that needs to b",
+            " e folded",
+            "DESCRIPTION:First line\\nsecond line",
+            "DUE;TZID=Europe/Paris:20261102T090000",
+            "PRIORITY:2",
+            "CATEGORIES:home,errands\\, weekly, ",
+            "RELATED-TO;X-NOTE=\"a:b;c\";RELTYPE=PARENT:parent-1",
+            "BEGIN:VALARM",
+            "ACTION:DISPLAY",
+            "SUMMARY:Not the summary",
+            "BEGIN:X-NESTED",
+            "UID:not-the-uid",
+            "END:X-NESTED",
+            "END:VALARM",
+            "X-UNKNOWN:ignored",
+        ]);
+        let vtodo = parse_vtodo(&ical).unwrap();
+        assert_eq!(
+            vtodo,
+            VTodo {
+                uid: "phone-1".to_owned(),
+                summary:
+                    "Buy milk, eggs and a very long list of other things that needs to be folded"
+                        .to_owned(),
+                description: Some("First line\nsecond line".to_owned()),
+                due: Some(day("2026-11-02")),
+                completed: false,
+                priority: Priority::High,
+                categories: vec!["home".to_owned(), "errands, weekly".to_owned()],
+                parent_uid: Some("parent-1".to_owned()),
+            }
+        );
+    }
+
+    #[test]
+    fn only_parent_relations_are_kept() {
+        for (line, parent) in [
+            ("RELATED-TO:parent-1", Some("parent-1")),
+            ("RELATED-TO;RELTYPE=parent:parent-1", Some("parent-1")),
+            ("RELATED-TO;RELTYPE=CHILD:child-1", None),
+            ("RELATED-TO;RELTYPE=SIBLING:sibling-1", None),
+        ] {
+            let vtodo = parse_vtodo(&calendar(&["UID:a", line])).unwrap();
+            assert_eq!(vtodo.parent_uid.as_deref(), parent, "{}", line);
+        }
+    }
+
+    #[test]
+    fn status_wins_over_completed() {
+        for (lines, completed) in [
+            (&["STATUS:COMPLETED"][..], true),
+            (&["STATUS:completed"][..], true),
+            (&["COMPLETED:20261001T120000Z"][..], true),
+            (
+                &["STATUS:NEEDS-ACTION", "COMPLETED:20261001T120000Z"][..],
+                false,
+            ),
+            (&["STATUS:IN-PROCESS"][..], false),
+            (&[][..], false),
+        ] {
+            let mut lines = lines.to_vec();
+            lines.push("UID:a");
+            let vtodo = parse_vtodo(&calendar(&lines)).unwrap();
+            assert_eq!(vtodo.completed, completed, "{:?}", lines);
+        }
+    }
+
+    #[test]
+    fn dates_and_priorities() {
+        let vtodo = parse_vtodo(&calendar(&["UID:a", "DTSTART;VALUE=DATE:20261003"])).unwrap();
+        assert_eq!(vtodo.due, Some(day("2026-10-03")));
+        let vtodo = parse_vtodo(&calendar(&[
+            "UID:a",
+            "DTSTART:20261003",
+            "DUE:20261005T000000Z",
+        ]))
+        .unwrap();
+        assert_eq!(vtodo.due, Some(day("2026-10-05")));
+        let vtodo = parse_vtodo(&calendar(&["UID:a", "DUE:2026-10-05"])).unwrap();
+        assert_eq!(vtodo.due, None);
+
+        for (priority, expected) in [
+            ("1", Priority::High),
+            ("4", Priority::High),
+            ("5", Priority::Normal),
+            ("0", Priority::Normal),
+            ("9", Priority::Low),
+            ("high", Priority::Normal),
+        ] {
+            let line = format!("PRIORITY:{}", priority);
+            let vtodo = parse_vtodo(&calendar(&["UID:a", &line])).unwrap();
+            assert_eq!(vtodo.priority, expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn a_vtodo_needs_a_uid() {
+        assert!(parse_vtodo("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n").is_err());
+        assert!(parse_vtodo(&calendar(&["SUMMARY:No UID"])).is_err());
+        // unfolded with bare newlines and tabs too
+        let vtodo = parse_vtodo("BEGIN:VTODO\nUID:a\n\tb\nEND:VTODO\n").unwrap();
+        assert_eq!(vtodo.uid, "ab");
+    }
+
+    #[test]
+    fn written_vtodos_read_back() {
+        let todo = Todo {
+            id: 7,
+            name: "Pay rent, then; relax".to_owned(),
+            done: true,
+            description: Some("Line one\nLine two".to_owned()),
+            parent_id: Some(3),
+            date: Some(day("2026-11-01")),
+            version: 4,
+            priority: Priority::Low.level(),
+            tags: vec!["home".to_owned(), "money, bills".to_owned()],
+        };
+        let mut ical = String::new();
+        write_vtodo(&mut ical, &todo, todo_uid, OffsetDateTime::UNIX_EPOCH);
+        let vtodo = parse_vtodo(&ical).unwrap();
+        assert_eq!(
+            vtodo,
+            VTodo {
+                uid: todo_uid(7),
+                summary: todo.name.clone(),
+                description: todo.description.clone(),
+                due: todo.date,
+                completed: true,
+                priority: Priority::Low,
+                categories: todo.tags.clone(),
+                parent_uid: Some(todo_uid(3)),
+            }
+        );
+    }
+}
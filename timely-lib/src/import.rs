@@ -0,0 +1,552 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::export::NestedTodo;
+use crate::{parse_iso_date, Priority};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Json,
+    #[serde(alias = "md")]
+    Markdown,
+    #[serde(alias = "todo.txt")]
+    TodoTxt,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "json" => Ok(ImportFormat::Json),
+            "markdown" | "md" => Ok(ImportFormat::Markdown),
+            "todotxt" | "todo.txt" => Ok(ImportFormat::TodoTxt),
+            _ => Err(format!(
+                "unknown format {:?}, expected json, markdown or todotxt",
+                format
+            )),
+        }
+    }
+}
+
+impl ImportFormat {
+    /// Guesses the format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(ImportFormat::Json),
+            "md" | "markdown" => Some(ImportFormat::Markdown),
+            "txt" => Some(ImportFormat::TodoTxt),
+            _ => None,
+        }
+    }
+
+    /// Parses the input into a forest of todos without ids.
+    pub fn parse(&self, input: &str) -> Result<Vec<NestedTodo>, String> {
+        match self {
+            ImportFormat::Json => parse_json(input),
+            ImportFormat::Markdown => Ok(parse_markdown(input)),
+            ImportFormat::TodoTxt => Ok(parse_todo_txt(input)),
+        }
+    }
+}
+
+/// Response of `POST /todos/import`: the todos that were (or, on a dry run, would be)
+/// created, with their new ids.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ImportResult {
+    pub dry_run: bool,
+    pub created: usize,
+    pub todos: Vec<NestedTodo>,
+}
+
+pub fn count_todos(forest: &[NestedTodo]) -> usize {
+    forest
+        .iter()
+        .map(|todo| 1 + count_todos(&todo.children))
+        .sum()
+}
+
+fn new_todo(name: String, done: bool, date: Option<time::Date>) -> NestedTodo {
+    NestedTodo {
+        id: None,
+        name,
+        done,
+        description: None,
+        date,
+        priority: Priority::Normal,
+        tags: Vec::new(),
+        children: Vec::new(),
+    }
+}
+
+/// Builds a forest from todos listed with the index of their parent.
+fn into_forest(nodes: Vec<(Option<usize>, NestedTodo)>) -> Vec<NestedTodo> {
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+    let mut roots = Vec::new();
+    for (index, (parent, _)) in nodes.iter().enumerate() {
+        match parent {
+            Some(parent) => children[*parent].push(index),
+            None => roots.push(index),
+        }
+    }
+
+    fn build(
+        index: usize,
+        nodes: &mut [Option<NestedTodo>],
+        children: &[Vec<usize>],
+    ) -> NestedTodo {
+        let mut todo = nodes[index].take().expect("every todo has a single parent");
+        todo.children = children[index]
+            .iter()
+            .map(|child| build(*child, nodes, children))
+            .collect();
+        todo
+    }
+
+    let mut nodes: Vec<Option<NestedTodo>> =
+        nodes.into_iter().map(|(_, todo)| Some(todo)).collect();
+    roots
+        .into_iter()
+        .map(|root| build(root, &mut nodes, &children))
+        .collect()
+}
+
+/// Splits the `due:` tag off a todo's text.
+fn take_due_date(text: &str) -> (String, Option<time::Date>) {
+    let mut date = None;
+    let words: Vec<&str> = text
+        .split_whitespace()
+        .filter(
+            |word| match word.strip_prefix("due:").and_then(parse_iso_date) {
+                Some(due) => {
+                    date = Some(due);
+                    false
+                }
+                None => true,
+            },
+        )
+        .collect();
+    (words.join(" "), date)
+}
+
+pub fn parse_json(input: &str) -> Result<Vec<NestedTodo>, String> {
+    fn clear_ids(forest: &mut [NestedTodo]) {
+        for todo in forest {
+            todo.id = None;
+            clear_ids(&mut todo.children);
+        }
+    }
+
+    let mut forest: Vec<NestedTodo> =
+        serde_json::from_str(input).map_err(|err| format!("invalid JSON: {}", err))?;
+    clear_ids(&mut forest);
+    Ok(forest)
+}
+
+fn indentation(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 4 } else { 1 })
+        .sum()
+}
+
+/// Strips a bullet (`-`, `*`, `+`) or number (`1.`, `1)`) list marker.
+fn strip_list_marker(line: &str) -> Option<&str> {
+    if let Some(rest) = line
+        .strip_prefix("- ")
+        .or_else(|| line.strip_prefix("* "))
+        .or_else(|| line.strip_prefix("+ "))
+    {
+        return Some(rest);
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    line[digits..]
+        .strip_prefix(". ")
+        .or_else(|| line[digits..].strip_prefix(") "))
+}
+
+/// The priority as the exporter writes it: `!high`, `!normal` or `!low`.
+fn exported_priority(word: &str) -> Option<Priority> {
+    match word.strip_prefix('!')? {
+        "high" => Some(Priority::High),
+        "normal" => Some(Priority::Normal),
+        "low" => Some(Priority::Low),
+        _ => None,
+    }
+}
+
+/// A `#tag` as the exporter writes it. Numbers are issue references such as `#42`.
+fn exported_tag(word: &str) -> Option<&str> {
+    word.strip_prefix('#')
+        .filter(|tag| !tag.is_empty() && !tag.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Splits the `!priority` and `#tag` words the exporter writes after a list item's name
+/// off it. Only the words ending the item count, so `Fix issue #42` or `Ship it !1` keep
+/// their names; `due:` dates among them are left for [`take_due_date`].
+fn take_priority_and_tags(text: &str) -> (String, Priority, Vec<String>) {
+    let mut words: Vec<&str> = text.split_whitespace().collect();
+    let mut found = None;
+    let mut trailing_tags = Vec::new();
+    let mut dates = Vec::new();
+    // the first word is always part of the name
+    while words.len() > 1 {
+        let word = words[words.len() - 1];
+        if let Some(priority) = exported_priority(word) {
+            found.get_or_insert(priority);
+        } else if let Some(tag) = exported_tag(word) {
+            trailing_tags.push(tag);
+        } else if word.strip_prefix("due:").and_then(parse_iso_date).is_some() {
+            dates.push(word);
+        } else {
+            break;
+        }
+        words.pop();
+    }
+    words.extend(dates.into_iter().rev());
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in trailing_tags.into_iter().rev() {
+        if !tags.iter().any(|existing| existing == tag) {
+            tags.push(tag.to_owned());
+        }
+    }
+    (words.join(" "), found.unwrap_or(Priority::Normal), tags)
+}
+
+/// Parses a (task) list: indentation gives the nesting, `[x]` marks done todos, `!high`
+/// and `!low` ending an item the priority, `#tag`s ending it add tags and indented text
+/// below an item becomes its description. Other lines are ignored.
+pub fn parse_markdown(input: &str) -> Vec<NestedTodo> {
+    let mut nodes: Vec<(Option<usize>, NestedTodo)> = Vec::new();
+    // (indentation, index) of the items the current line may be nested in
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+
+    for line in input.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let indent = indentation(line);
+        let content = line.trim();
+
+        if let Some(item) = strip_list_marker(content) {
+            let (done, text) = if let Some(text) = item.strip_prefix("[ ] ") {
+                (false, text)
+            } else if let Some(text) = item
+                .strip_prefix("[x] ")
+                .or_else(|| item.strip_prefix("[X] "))
+            {
+                (true, text)
+            } else {
+                (false, item)
+            };
+            let (text, priority, tags) = take_priority_and_tags(text);
+            let (name, date) = take_due_date(&text);
+            if name.is_empty() {
+                continue;
+            }
+
+            while stack.last().is_some_and(|(level, _)| *level >= indent) {
+                stack.pop();
+            }
+            let parent = stack.last().map(|(_, index)| *index);
+            stack.push((indent, nodes.len()));
+            nodes.push((
+                parent,
+                NestedTodo {
+                    priority,
+                    tags,
+                    ..new_todo(name, done, date)
+                },
+            ));
+        } else if let Some((_, index)) = stack.last().filter(|(level, _)| indent > *level) {
+            let description = nodes[*index].1.description.get_or_insert_with(String::new);
+            if !description.is_empty() {
+                description.push('\n');
+            }
+            description.push_str(content);
+        }
+    }
+
+    into_forest(nodes)
+}
+
+fn is_iso_date(word: &str) -> bool {
+    word.len() == 10 && parse_iso_date(word).is_some()
+}
+
+/// Parses todo.txt lines. A leading `x` marks done todos, `(A)` makes a todo high
+/// priority and `(C)` to `(Z)` low priority, `+project`s become tags, `due:` sets the date
+/// and the `id:`/`parent:` tags written by the exporter restore the nesting (a `parent:`
+/// is the closest line above with that id, or else the first one below).
+/// Creation/completion dates are dropped.
+pub fn parse_todo_txt(input: &str) -> Vec<NestedTodo> {
+    let mut nodes: Vec<(Option<usize>, NestedTodo)> = Vec::new();
+    // parents that are only listed after their children, resolved at the end
+    let mut later_parents: Vec<(usize, String)> = Vec::new();
+    // the first and the latest line with each id, for files with duplicate ids
+    let mut first_by_key: HashMap<String, usize> = HashMap::new();
+    let mut latest_by_key: HashMap<String, usize> = HashMap::new();
+
+    for line in input.lines() {
+        let mut words = line.split_whitespace().peekable();
+        let done = words.next_if_eq(&"x").is_some();
+        if done {
+            // completion date
+            words.next_if(|word| is_iso_date(word));
+        }
+        let priority = match words.next_if(|word| {
+            word.len() == 3
+                && word.starts_with('(')
+                && word.ends_with(')')
+                && word.as_bytes()[1].is_ascii_uppercase()
+        }) {
+            Some(word) => match word.as_bytes()[1] {
+                b'A' => Priority::High,
+                b'B' => Priority::Normal,
+                _ => Priority::Low,
+            },
+            None => Priority::Normal,
+        };
+        // creation date
+        words.next_if(|word| is_iso_date(word));
+
+        let mut key = None;
+        let mut parent_key = None;
+        let mut tags: Vec<String> = Vec::new();
+        let rest: Vec<&str> = words
+            .filter(|word| {
+                if let Some(tag) = word.strip_prefix('+').filter(|tag| !tag.is_empty()) {
+                    if !tags.iter().any(|existing| existing == tag) {
+                        tags.push(tag.to_owned());
+                    }
+                    false
+                } else if let Some(id) = word.strip_prefix("id:") {
+                    key = Some(id.to_owned());
+                    false
+                } else if let Some(parent) = word.strip_prefix("parent:") {
+                    parent_key = Some(parent.to_owned());
+                    false
+                } else {
+                    true
+                }
+            })
+            .collect();
+        let (name, date) = take_due_date(&rest.join(" "));
+        if name.is_empty() {
+            continue;
+        }
+
+        // A parent is the closest line above with its id, so concatenated exports keep
+        // their own nesting.
+        let parent = parent_key.and_then(|parent_key| match latest_by_key.get(&parent_key) {
+            Some(index) => Some(*index),
+            None => {
+                later_parents.push((nodes.len(), parent_key));
+                None
+            }
+        });
+        if let Some(key) = key {
+            first_by_key.entry(key.clone()).or_insert(nodes.len());
+            latest_by_key.insert(key, nodes.len());
+        }
+        nodes.push((
+            parent,
+            NestedTodo {
+                priority,
+                tags,
+                ..new_todo(name, done, date)
+            },
+        ));
+    }
+
+    for (index, parent_key) in later_parents {
+        nodes[index].0 = first_by_key.get(&parent_key).copied();
+    }
+    // Lines whose parents point back at them would never be reached from a root.
+    for index in 0..nodes.len() {
+        let mut current = nodes[index].0;
+        for _ in 0..nodes.len() {
+            match current {
+                Some(parent) if parent == index => {
+                    nodes[index].0 = None;
+                    break;
+                }
+                Some(parent) => current = nodes[parent].0,
+                None => break,
+            }
+        }
+    }
+
+    into_forest(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names in the forest, indented by two spaces per level.
+    fn outline(forest: &[NestedTodo]) -> Vec<String> {
+        fn walk(forest: &[NestedTodo], depth: usize, lines: &mut Vec<String>) {
+            for todo in forest {
+                lines.push(format!("{}{}", "  ".repeat(depth), todo.name));
+                walk(&todo.children, depth + 1, lines);
+            }
+        }
+        let mut lines = Vec::new();
+        walk(forest, 0, &mut lines);
+        lines
+    }
+
+    #[test]
+    fn only_trailing_priorities_and_tags_are_taken() {
+        let cases = [
+            ("Fix issue #42", ("Fix issue #42", Priority::Normal, vec![])),
+            (
+                "Ship it !1 now",
+                ("Ship it !1 now", Priority::Normal, vec![]),
+            ),
+            (
+                "Use C# !high #work",
+                ("Use C#", Priority::High, vec!["work"]),
+            ),
+            (
+                "Pay rent due:2026-11-01 !low #home #money #home",
+                (
+                    "Pay rent due:2026-11-01",
+                    Priority::Low,
+                    vec!["home", "money"],
+                ),
+            ),
+            (
+                "Ask #team about it",
+                ("Ask #team about it", Priority::Normal, vec![]),
+            ),
+            ("#urgent", ("#urgent", Priority::Normal, vec![])),
+            (
+                "Close #12 #work",
+                ("Close #12", Priority::Normal, vec!["work"]),
+            ),
+        ];
+        for (text, (name, priority, tags)) in cases {
+            assert_eq!(
+                take_priority_and_tags(text),
+                (
+                    name.to_owned(),
+                    priority,
+                    tags.into_iter().map(str::to_owned).collect()
+                ),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn parses_markdown_lists() {
+        let markdown = "# Groceries\n\
+            \n\
+            - [ ] Shopping due:2026-10-20 !high #home\n\
+            \tMilk is out\n\
+            \tand bread\n\
+            \t- [x] Milk\n\
+            \t\t* Oat milk #42\n\
+            \t1. Bread\n\
+            - Fix issue #42\n\
+            Not an item\n";
+        let forest = parse_markdown(markdown);
+        assert_eq!(
+            outline(&forest),
+            [
+                "Shopping",
+                "  Milk",
+                "    Oat milk #42",
+                "  Bread",
+                "Fix issue #42"
+            ]
+        );
+        let shopping = &forest[0];
+        assert_eq!(
+            shopping.description.as_deref(),
+            Some("Milk is out\nand bread")
+        );
+        assert_eq!(shopping.date, parse_iso_date("2026-10-20"));
+        assert_eq!(shopping.priority, Priority::High);
+        assert_eq!(shopping.tags, ["home"]);
+        assert!(!shopping.done);
+        assert!(shopping.children[0].done);
+        assert!(forest[1].tags.is_empty());
+    }
+
+    #[test]
+    fn tabs_and_spaces_nest_alike() {
+        let spaces = parse_markdown("- a\n    - b\n  - c\n");
+        let tabs = parse_markdown("- a\n\t- b\n  - c\n");
+        assert_eq!(outline(&spaces), ["a", "  b", "  c"]);
+        assert_eq!(outline(&tabs), outline(&spaces));
+    }
+
+    #[test]
+    fn parses_todo_txt() {
+        let todo_txt = "x 2026-10-01 2026-09-30 Call mom +family id:1\n\
+            (A) 2026-10-02 Plan trip +travel +travel due:2026-12-01 id:2\n\
+            (C) Book hotel parent:2 id:3\n\
+            (B) Pack parent:3\n\
+            \n\
+            Orphan parent:99\n";
+        let forest = parse_todo_txt(todo_txt);
+        assert_eq!(
+            outline(&forest),
+            [
+                "Call mom",
+                "Plan trip",
+                "  Book hotel",
+                "    Pack",
+                "Orphan"
+            ]
+        );
+        assert!(forest[0].done);
+        assert_eq!(forest[0].tags, ["family"]);
+        let trip = &forest[1];
+        assert_eq!(trip.priority, Priority::High);
+        assert_eq!(trip.tags, ["travel"]);
+        assert_eq!(trip.date, parse_iso_date("2026-12-01"));
+        assert_eq!(trip.children[0].priority, Priority::Low);
+        assert_eq!(trip.children[0].children[0].priority, Priority::Normal);
+    }
+
+    #[test]
+    fn todo_txt_cycles_are_broken() {
+        let forest = parse_todo_txt("a id:1 parent:2\nb id:2 parent:1\nc id:3 parent:3\n");
+        assert_eq!(outline(&forest), ["a", "  b", "c"]);
+    }
+
+    #[test]
+    fn todo_txt_parents_are_the_closest_line_above() {
+        // two exports, one after the other
+        let todo_txt = "Early child parent:1\n\
+            First id:1\n\
+            Child one parent:1\n\
+            Second id:1\n\
+            Child two parent:1\n";
+        let forest = parse_todo_txt(todo_txt);
+        assert_eq!(
+            outline(&forest),
+            [
+                "First",
+                "  Early child",
+                "  Child one",
+                "Second",
+                "  Child two"
+            ]
+        );
+    }
+}
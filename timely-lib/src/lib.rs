@@ -1,15 +1,69 @@
-use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use time::{Date, Month};
 
+pub mod export;
+pub mod ical;
+pub mod import;
+pub mod natural_date;
+pub mod quick_add;
+
 #[derive(Debug, Serialize, Clone, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct Todo {
     pub id: i64,
     pub name: String,
     pub done: bool,
     pub description: Option<String>,
     pub parent_id: Option<i64>,
+    /// `[year, day of the year]`
+    #[cfg_attr(feature = "openapi", schema(value_type = Option<Vec<i32>>, example = json!([2026, 291])))]
     pub date: Option<Date>,
+    /// Incremented on every change; sent back in `If-Match` to detect concurrent edits.
+    #[serde(default)]
+    pub version: i64,
+    /// A `Priority` level.
+    #[serde(default)]
+    pub priority: i16,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// The number stored in the database and in `Todo::priority`.
+    pub fn level(self) -> i16 {
+        match self {
+            Priority::Low => -1,
+            Priority::Normal => 0,
+            Priority::High => 1,
+        }
+    }
+
+    pub fn from_level(level: i16) -> Self {
+        match level {
+            ..=-1 => Priority::Low,
+            0 => Priority::Normal,
+            1.. => Priority::High,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,34 +71,118 @@ pub struct Done {
     pub done: bool,
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// Changes to the todo list since a given change token, as returned by `GET /sync`.
+#[derive(Debug, Serialize, Clone, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SyncResponse {
+    /// Token to pass as `since` on the next sync.
+    pub token: i64,
+    /// Todos created or updated since the requested token.
+    pub changed: Vec<Todo>,
+    /// Ids of todos deleted since the requested token.
+    pub deleted: Vec<i64>,
+    /// Whether `changed` is the whole list, replacing what the client has: when no token
+    /// was given, or it is older than the deletions the server still remembers.
+    #[serde(default)]
+    pub full: bool,
+}
+
+impl SyncResponse {
+    /// Applies the changes to a flat list of todos, keeping it ordered by id.
+    pub fn apply(&self, todos: &mut Vec<Todo>) {
+        if self.full {
+            todos.clear();
+        }
+        let removed_ids: HashSet<i64> = self
+            .changed
+            .iter()
+            .map(|todo| todo.id)
+            .chain(self.deleted.iter().copied())
+            .collect();
+        todos.retain(|todo| !removed_ids.contains(&todo.id));
+        todos.extend(self.changed.iter().cloned());
+        todos.sort_by_key(|todo| todo.id);
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct TodoHierarchy {
     pub todo: Todo,
+    /// The date as `YYYY-M-D`
     pub todo_date: Option<String>,
+    /// How many children the todo has; more than `children` holds when the tree was cut off
+    /// at a maximum depth, so the rest can be loaded on demand.
+    #[serde(default)]
+    pub child_count: usize,
+    #[cfg_attr(feature = "openapi", schema(no_recursion))]
     pub children: Vec<TodoHierarchy>,
 }
 
-#[derive(Serialize)]
+/// A page of top-level todos with their descendants, as returned by the tree endpoints.
+#[derive(Debug, Serialize, Clone, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct TreePage {
+    /// Top-level todos with their descendants
+    pub todos: Vec<TodoHierarchy>,
+    /// Pass as `after` to get the next page; missing on the last page
+    pub next_cursor: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Clone, Deserialize)]
 pub struct TodoToSend {
     pub name: String,
     pub description: String,
     pub parent_id: Option<i64>,
     pub date: Option<time::Date>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Body of `POST /todos/done`: sets a todo and all its descendants to the given state.
+#[derive(Debug, Serialize, Clone, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SetDone {
+    pub id: i64,
+    pub done: bool,
 }
 
 impl TodoHierarchy {
     pub fn new(todo: Todo) -> TodoHierarchy {
         TodoHierarchy {
-            todo_date: if let Some(date) = todo.date {
-                Some(convert_date_to_string(date))
-            } else {
-                None
-            },
+            todo_date: todo.date.map(convert_date_to_string),
             todo,
+            child_count: 0,
             children: Vec::new(),
         }
     }
 
+    /// Sets the child counts of the todos whose children were not loaded.
+    pub fn set_unloaded_child_counts(&mut self, counts: &HashMap<i64, usize>) {
+        if self.children.is_empty() {
+            if let Some(count) = counts.get(&self.todo.id) {
+                self.child_count = *count;
+            }
+        }
+        for child in &mut self.children {
+            child.set_unloaded_child_counts(counts);
+        }
+    }
+
+    /// Ids of the todos in the tree that have no children loaded.
+    pub fn leaf_ids(&self) -> Vec<i64> {
+        if self.children.is_empty() {
+            vec![self.todo.id]
+        } else {
+            self.children
+                .iter()
+                .flat_map(TodoHierarchy::leaf_ids)
+                .collect()
+        }
+    }
+
     pub fn get_hierarchy_by_id(
         hierarchies: &mut Vec<TodoHierarchy>,
         id: i64,
@@ -72,48 +210,80 @@ impl TodoHierarchy {
     }
 }
 
-pub fn build_hierarchy(mut todos: Vec<Todo>) -> Vec<TodoHierarchy> {
-    let mut todo_map: IndexMap<i64, TodoHierarchy> = IndexMap::new();
-
-    todos.reverse();
-    for todo in todos {
-        let id = todo.id;
-        todo_map.insert(
-            id,
-            TodoHierarchy {
-                todo_date: if let Some(date) = todo.date {
-                    Some(convert_date_to_string(date))
-                } else {
-                    None
-                },
-                todo,
-                children: Vec::new(),
-            },
-        );
+/// Flattens a forest of hierarchies back into a list of todos, ordered by id.
+pub fn flatten_hierarchy(hierarchies: &[TodoHierarchy]) -> Vec<Todo> {
+    fn collect(hierarchies: &[TodoHierarchy], todos: &mut Vec<Todo>) {
+        for hierarchy in hierarchies {
+            todos.push(hierarchy.todo.clone());
+            collect(&hierarchy.children, todos);
+        }
     }
 
-    let mut root_todos: Vec<TodoHierarchy> = Vec::new();
-
-    // Collect all the keys first
-    let todo_ids: Vec<i64> = todo_map.keys().copied().collect();
+    let mut todos = Vec::new();
+    collect(hierarchies, &mut todos);
+    todos.sort_by_key(|todo| todo.id);
+    todos
+}
 
-    for id in todo_ids {
-        if let Some(hierarchy) = todo_map.swap_remove(&id) {
-            // if it has a parent
-            if let Some(parent_id) = hierarchy.todo.parent_id {
-                // if the parent is available in todo_map
-                if let Some(parent_hierarchy) = todo_map.get_mut(&parent_id) {
-                    parent_hierarchy.children.push(hierarchy);
-                }
-            } else {
-                root_todos.push(hierarchy);
+/// Builds the forest of a flat list of todos: top-level todos in the order of the list,
+/// each with its children in reverse order (newest first for a list ordered by id). A
+/// child is attached wherever it comes in the list, so todos moved under a newer parent
+/// keep their place. Todos whose parent is not in the list are left out.
+pub fn build_hierarchy(todos: Vec<Todo>) -> Vec<TodoHierarchy> {
+    let ids: HashSet<i64> = todos.iter().map(|todo| todo.id).collect();
+    let mut roots = Vec::new();
+    let mut children: HashMap<i64, Vec<Todo>> = HashMap::new();
+    for todo in todos {
+        match todo.parent_id {
+            None => roots.push(todo),
+            Some(parent_id) if ids.contains(&parent_id) => {
+                children.entry(parent_id).or_default().push(todo)
             }
+            Some(_) => {}
         }
     }
+    for siblings in children.values_mut() {
+        siblings.reverse();
+    }
+    roots
+        .into_iter()
+        .map(|root| attach_children(root, &mut children))
+        .collect()
+}
 
-    root_todos.reverse();
+/// The hierarchy of `todo` with the children found in `children`, which are taken out of
+/// it, so a parent cycle can't recurse forever.
+fn attach_children(todo: Todo, children: &mut HashMap<i64, Vec<Todo>>) -> TodoHierarchy {
+    let mut hierarchy = TodoHierarchy::new(todo);
+    hierarchy.children = children
+        .remove(&hierarchy.todo.id)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|child| attach_children(child, children))
+        .collect();
+    hierarchy.child_count = hierarchy.children.len();
+    hierarchy
+}
 
-    root_todos
+/// Builds the trees under `root_ids` from a flat list of the roots and their descendants,
+/// in the order of `root_ids`, with children in the order of the list. Roots missing from
+/// the list are left out.
+pub fn build_subtrees(root_ids: &[i64], todos: Vec<Todo>) -> Vec<TodoHierarchy> {
+    let root_set: HashSet<i64> = root_ids.iter().copied().collect();
+    let mut roots: HashMap<i64, Todo> = HashMap::new();
+    let mut children: HashMap<i64, Vec<Todo>> = HashMap::new();
+    for todo in todos {
+        if root_set.contains(&todo.id) {
+            roots.insert(todo.id, todo);
+        } else if let Some(parent_id) = todo.parent_id {
+            children.entry(parent_id).or_default().push(todo);
+        }
+    }
+    root_ids
+        .iter()
+        .filter_map(|id| roots.remove(id))
+        .map(|root| attach_children(root, &mut children))
+        .collect()
 }
 
 pub fn month_num_to_month(num: i32) -> Option<Month> {
@@ -134,6 +304,81 @@ pub fn month_num_to_month(num: i32) -> Option<Month> {
     }
 }
 
+/// Parses a `YYYY-MM-DD` date, as sent by `<input type="date">`.
+pub fn parse_iso_date(date: &str) -> Option<Date> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i32 = parts.next()?.parse().ok()?;
+    let month = month_num_to_month(parts.next()?.parse().ok()?)?;
+    let day: u8 = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
 pub fn convert_date_to_string(date: Date) -> String {
     format!("{}-{}-{}", date.year(), date.month() as u8, date.day())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn todo(id: i64, parent_id: Option<i64>) -> Todo {
+        Todo {
+            id,
+            name: format!("todo {}", id),
+            done: false,
+            description: None,
+            parent_id,
+            date: None,
+            version: 1,
+            priority: 0,
+            tags: Vec::new(),
+        }
+    }
+
+    fn shape(hierarchies: &[TodoHierarchy]) -> Vec<(i64, Vec<i64>)> {
+        let mut shape = Vec::new();
+        for hierarchy in hierarchies {
+            let children = hierarchy.children.iter().map(|c| c.todo.id).collect();
+            shape.push((hierarchy.todo.id, children));
+            shape.extend(self::shape(&hierarchy.children));
+        }
+        shape
+    }
+
+    #[test]
+    fn hierarchy_keeps_children_listed_before_their_parent() {
+        // 2 was moved under 5, and 1 under 2
+        let todos = vec![
+            todo(1, Some(2)),
+            todo(2, Some(5)),
+            todo(3, None),
+            todo(4, Some(3)),
+            todo(5, None),
+            todo(6, Some(3)),
+        ];
+        let forest = build_hierarchy(todos);
+        assert_eq!(
+            shape(&forest),
+            [
+                (3, vec![6, 4]),
+                (6, vec![]),
+                (4, vec![]),
+                (5, vec![2]),
+                (2, vec![1]),
+                (1, vec![]),
+            ]
+        );
+        assert_eq!(forest[1].child_count, 1);
+    }
+
+    #[test]
+    fn hierarchy_leaves_out_orphans_and_cycles() {
+        let todos = vec![
+            todo(1, None),
+            todo(2, Some(9)),
+            todo(3, Some(4)),
+            todo(4, Some(3)),
+        ];
+        assert_eq!(shape(&build_hierarchy(todos)), [(1, vec![])]);
+    }
+}
@@ -0,0 +1,269 @@
+//! Parses dates written the way people type them: "tomorrow", "friday", "next friday",
+//! "in 3 days", "next month", "march 5" or "2026-03-05".
+//!
+//! A bare weekday ("friday", "this friday") is the next such day, counting today;
+//! "next friday" is the next one after today.
+
+use time::{Date, Duration, Month, Weekday};
+
+use crate::{month_num_to_month, parse_iso_date};
+
+/// Longest phrase `find_date` looks for, in words.
+const MAX_PHRASE_WORDS: usize = 4;
+
+fn normalize(word: &str) -> String {
+    word.trim_end_matches([',', '.', ';', '!', '?'])
+        .to_lowercase()
+}
+
+fn weekday(word: &str) -> Option<Weekday> {
+    // Short forms that are also common words ("sun", "sat", "mon") are left out.
+    match word {
+        "monday" => Some(Weekday::Monday),
+        "tuesday" | "tue" | "tues" => Some(Weekday::Tuesday),
+        "wednesday" | "wed" => Some(Weekday::Wednesday),
+        "thursday" | "thu" | "thur" | "thurs" => Some(Weekday::Thursday),
+        "friday" | "fri" => Some(Weekday::Friday),
+        "saturday" => Some(Weekday::Saturday),
+        "sunday" => Some(Weekday::Sunday),
+        _ => None,
+    }
+}
+
+fn month(word: &str) -> Option<Month> {
+    let number = match word {
+        "january" | "jan" => 1,
+        "february" | "feb" => 2,
+        "march" | "mar" => 3,
+        "april" | "apr" => 4,
+        "may" => 5,
+        "june" | "jun" => 6,
+        "july" | "jul" => 7,
+        "august" | "aug" => 8,
+        "september" | "sep" | "sept" => 9,
+        "october" | "oct" => 10,
+        "november" | "nov" => 11,
+        "december" | "dec" => 12,
+        _ => return None,
+    };
+    month_num_to_month(number)
+}
+
+/// A day of the month, optionally with an ordinal suffix ("5", "5th").
+fn day_of_month(word: &str) -> Option<u8> {
+    let digits = word
+        .strip_suffix("st")
+        .or_else(|| word.strip_suffix("nd"))
+        .or_else(|| word.strip_suffix("rd"))
+        .or_else(|| word.strip_suffix("th"))
+        .unwrap_or(word);
+    digits.parse().ok().filter(|day| (1..=31).contains(day))
+}
+
+fn count(word: &str) -> Option<i64> {
+    match word {
+        "a" | "an" | "one" => Some(1),
+        "two" => Some(2),
+        "three" => Some(3),
+        "four" => Some(4),
+        "five" => Some(5),
+        "six" => Some(6),
+        "seven" => Some(7),
+        "eight" => Some(8),
+        "nine" => Some(9),
+        "ten" => Some(10),
+        _ => word
+            .parse()
+            .ok()
+            .filter(|count| (0..=10_000).contains(count)),
+    }
+}
+
+/// The next `weekday` on or after `today`, or strictly after it.
+fn upcoming(today: Date, weekday: Weekday, include_today: bool) -> Date {
+    let mut days = (weekday.number_days_from_monday() as i64
+        - today.weekday().number_days_from_monday() as i64)
+        .rem_euclid(7);
+    if days == 0 && !include_today {
+        days = 7;
+    }
+    today + Duration::days(days)
+}
+
+/// Adds calendar months, moving to the last day of the month when the day doesn't exist.
+pub fn add_months(date: Date, months: i64) -> Option<Date> {
+    let index = date.year() as i64 * 12 + date.month() as i64 - 1 + months;
+    let year = i32::try_from(index.div_euclid(12)).ok()?;
+    let month = month_num_to_month(index.rem_euclid(12) as i32 + 1)?;
+    let day = date.day().min(month.length(year));
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+/// The next occurrence of a day and month, this year or next.
+fn next_month_day(today: Date, month: Month, day: u8) -> Option<Date> {
+    match Date::from_calendar_date(today.year(), month, day) {
+        Ok(date) if date >= today => Some(date),
+        _ => Date::from_calendar_date(today.year() + 1, month, day).ok(),
+    }
+}
+
+fn offset(today: Date, amount: i64, unit: &str) -> Option<Date> {
+    match unit {
+        "day" | "days" => today.checked_add(Duration::days(amount)),
+        "week" | "weeks" => today.checked_add(Duration::weeks(amount)),
+        "month" | "months" => add_months(today, amount),
+        "year" | "years" => add_months(today, amount * 12),
+        _ => None,
+    }
+}
+
+/// Parses a phrase that is a date as a whole, with the words already normalized.
+fn parse_words(words: &[&str], today: Date) -> Option<Date> {
+    match words {
+        ["today" | "tonight"] => Some(today),
+        ["tomorrow" | "tmr" | "tmrw"] => today.next_day(),
+        ["yesterday"] => today.previous_day(),
+        ["day", "after", "tomorrow"] => today.checked_add(Duration::days(2)),
+        [word] => weekday(word)
+            .map(|weekday| upcoming(today, weekday, true))
+            .or_else(|| {
+                // only full dates, so "2026" or "5" in a name stay part of it
+                (word.len() == 10).then(|| parse_iso_date(word)).flatten()
+            }),
+        ["this", word] => weekday(word).map(|weekday| upcoming(today, weekday, true)),
+        ["next", "week"] => today.checked_add(Duration::weeks(1)),
+        ["next", "month"] => add_months(today, 1),
+        ["next", "year"] => add_months(today, 12),
+        ["next", word] => weekday(word).map(|weekday| upcoming(today, weekday, false)),
+        ["in", amount, unit] => offset(today, count(amount)?, unit),
+        [first, second] => match (month(first), day_of_month(second)) {
+            (Some(month), Some(day)) => next_month_day(today, month, day),
+            _ => next_month_day(today, month(second)?, day_of_month(first)?),
+        },
+        _ => None,
+    }
+}
+
+/// Parses text that is a date as a whole, e.g. "next friday".
+pub fn parse_natural_date(text: &str, today: Date) -> Option<Date> {
+    let words: Vec<String> = text.split_whitespace().map(normalize).collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let words = match words.as_slice() {
+        ["on" | "due" | "by", rest @ ..] if !rest.is_empty() => rest,
+        words => words,
+    };
+    parse_words(words, today)
+}
+
+/// Finds the first date phrase in `words`, preferring the longest one starting at a
+/// word. Returns the range of words it takes up (including a leading "on", "due" or
+/// "by") and the date.
+pub fn find_date(words: &[&str], today: Date) -> Option<(std::ops::Range<usize>, Date)> {
+    let normalized: Vec<String> = words.iter().map(|word| normalize(word)).collect();
+    let normalized: Vec<&str> = normalized.iter().map(String::as_str).collect();
+    for start in 0..normalized.len() {
+        let longest = MAX_PHRASE_WORDS.min(normalized.len() - start);
+        for length in (1..=longest).rev() {
+            if let Some(date) = parse_words(&normalized[start..start + length], today) {
+                let end = start + length;
+                let start = if start > 0 && matches!(normalized[start - 1], "on" | "due" | "by") {
+                    start - 1
+                } else {
+                    start
+                };
+                return Some((start..end, date));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ops::Range;
+
+    /// Words, then the expected range of the phrase and its date.
+    type FindCase = (
+        &'static [&'static str],
+        Option<(Range<usize>, &'static str)>,
+    );
+
+    fn day(date: &str) -> Date {
+        parse_iso_date(date).unwrap()
+    }
+
+    #[test]
+    fn parses_date_phrases() {
+        // a Wednesday
+        let today = day("2026-10-14");
+        let cases = [
+            ("today", Some("2026-10-14")),
+            ("Tomorrow", Some("2026-10-15")),
+            ("yesterday", Some("2026-10-13")),
+            ("day after tomorrow", Some("2026-10-16")),
+            ("in 3 days", Some("2026-10-17")),
+            ("in two weeks", Some("2026-10-28")),
+            ("in a month", Some("2026-11-14")),
+            ("wednesday", Some("2026-10-14")),
+            ("friday", Some("2026-10-16")),
+            ("this fri", Some("2026-10-16")),
+            ("monday", Some("2026-10-19")),
+            ("next wednesday", Some("2026-10-21")),
+            ("next friday", Some("2026-10-16")),
+            ("next week", Some("2026-10-21")),
+            ("next month", Some("2026-11-14")),
+            ("next year", Some("2027-10-14")),
+            ("march 5", Some("2027-03-05")),
+            ("5th march", Some("2027-03-05")),
+            ("december 1st", Some("2026-12-01")),
+            ("on 2026-11-02", Some("2026-11-02")),
+            ("by friday,", Some("2026-10-16")),
+            ("someday", None),
+            ("2026", None),
+            ("march 32", None),
+            ("next tuesday please", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(
+                parse_natural_date(text, today),
+                expected.map(day),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_first_longest_phrase() {
+        let today = day("2026-10-14");
+        let cases: [FindCase; 4] = [
+            (&["Pay", "rent", "on", "friday"], Some((2..4, "2026-10-16"))),
+            (
+                &["Call", "next", "week", "tomorrow"],
+                Some((1..3, "2026-10-21")),
+            ),
+            (
+                &["Dentist", "march", "5th", "at", "9"],
+                Some((1..3, "2027-03-05")),
+            ),
+            (&["Read", "chapter", "5"], None),
+        ];
+        for (words, expected) in cases {
+            assert_eq!(
+                find_date(words, today),
+                expected.map(|(range, date)| (range, day(date))),
+                "{:?}",
+                words
+            );
+        }
+    }
+
+    #[test]
+    fn months_are_added_up_to_the_last_day() {
+        assert_eq!(add_months(day("2026-01-31"), 1), Some(day("2026-02-28")));
+        assert_eq!(add_months(day("2028-01-31"), 1), Some(day("2028-02-29")));
+        assert_eq!(add_months(day("2026-11-15"), 3), Some(day("2027-02-15")));
+        assert_eq!(add_months(day("2026-03-15"), -3), Some(day("2025-12-15")));
+    }
+}
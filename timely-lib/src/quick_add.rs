@@ -0,0 +1,206 @@
+use serde::{Deserialize, Serialize};
+use time::Date;
+
+use crate::natural_date::find_date;
+use crate::Priority;
+
+/// A todo typed as a single line, e.g. "Pay rent tomorrow !high #home under Finances".
+///
+/// - `!high`, `!normal`, `!low` (or `!1` to `!3`) set the priority,
+/// - `#word` adds a tag,
+/// - everything after the last "under" names the parent todo, except a date phrase ending
+///   the line ("... under Work next week"),
+/// - a date phrase understood by `natural_date` ("tomorrow", "next friday", "in 3 days",
+///   "on march 5") sets the date; one before "under" wins over one ending the line,
+///
+/// and the remaining words make up the name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuickAdd {
+    pub name: String,
+    pub date: Option<Date>,
+    pub priority: Priority,
+    pub tags: Vec<String>,
+    /// Name of the todo to add this one under.
+    pub parent: Option<String>,
+}
+
+pub(crate) fn priority(word: &str) -> Option<Priority> {
+    match word.strip_prefix('!')?.to_lowercase().as_str() {
+        "high" | "h" | "1" | "!!" => Some(Priority::High),
+        "normal" | "medium" | "med" | "m" | "2" | "!" => Some(Priority::Normal),
+        "low" | "l" | "3" => Some(Priority::Low),
+        _ => None,
+    }
+}
+
+pub(crate) fn tag(word: &str) -> Option<&str> {
+    let tag = word
+        .strip_prefix('#')?
+        .trim_end_matches([',', '.', ';', '!', '?']);
+    (!tag.is_empty()).then_some(tag)
+}
+
+/// Takes the first date phrase out of `words`; with `at_end`, only one ending the words.
+fn take_date(words: &mut Vec<&str>, today: Date, at_end: bool) -> Option<Date> {
+    let (range, date) = find_date(words, today)?;
+    if at_end && range.end != words.len() {
+        return None;
+    }
+    words.drain(range);
+    Some(date)
+}
+
+pub fn parse_quick_add(input: &str, today: Date) -> QuickAdd {
+    let mut priority_found = None;
+    let mut tags: Vec<String> = Vec::new();
+    let mut words: Vec<&str> = Vec::new();
+    for word in input.split_whitespace() {
+        if let Some(found) = priority(word) {
+            priority_found = Some(found);
+        } else if let Some(tag) = tag(word) {
+            if !tags
+                .iter()
+                .any(|existing| existing.eq_ignore_ascii_case(tag))
+            {
+                tags.push(tag.to_owned());
+            }
+        } else {
+            words.push(word);
+        }
+    }
+
+    // "under" as the first word is part of the name ("Under-sink cleanup" aside).
+    let mut parent_words = match words
+        .iter()
+        .rposition(|word| word.eq_ignore_ascii_case("under"))
+    {
+        Some(index) if index > 0 && index + 1 < words.len() => {
+            let parent = words.split_off(index + 1);
+            words.pop();
+            parent
+        }
+        _ => Vec::new(),
+    };
+
+    // Taken even when the name has a date, so that it never ends up in the parent's name.
+    let trailing_date = take_date(&mut parent_words, today, true);
+    let date = take_date(&mut words, today, false).or(trailing_date);
+
+    QuickAdd {
+        name: words.join(" "),
+        date,
+        priority: priority_found.unwrap_or_default(),
+        tags,
+        parent: (!parent_words.is_empty()).then(|| parent_words.join(" ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_iso_date;
+
+    /// Input, then the expected name, date, priority, tags and parent.
+    type Case = (
+        &'static str,
+        &'static str,
+        Option<&'static str>,
+        Priority,
+        &'static [&'static str],
+        Option<&'static str>,
+    );
+
+    #[test]
+    fn parses_quick_adds() {
+        // a Wednesday
+        let today = parse_iso_date("2026-10-14").unwrap();
+        let cases: [Case; 9] = [
+            (
+                "Pay rent tomorrow !high #home under Finances",
+                "Pay rent",
+                Some("2026-10-15"),
+                Priority::High,
+                &["home"],
+                Some("Finances"),
+            ),
+            (
+                "Call mum next friday",
+                "Call mum",
+                Some("2026-10-16"),
+                Priority::Normal,
+                &[],
+                None,
+            ),
+            (
+                "Water plants in 3 days !low",
+                "Water plants",
+                Some("2026-10-17"),
+                Priority::Low,
+                &[],
+                None,
+            ),
+            (
+                "Meet on friday under Work next week",
+                "Meet",
+                Some("2026-10-16"),
+                Priority::Normal,
+                &[],
+                Some("Work"),
+            ),
+            (
+                "Plan trip under Holidays next month",
+                "Plan trip",
+                Some("2026-11-14"),
+                Priority::Normal,
+                &[],
+                Some("Holidays"),
+            ),
+            (
+                "Send report by monday under Work #q4 !1",
+                "Send report",
+                Some("2026-10-19"),
+                Priority::High,
+                &["q4"],
+                Some("Work"),
+            ),
+            (
+                "Fix bike #diy #DIY #bike,",
+                "Fix bike",
+                None,
+                Priority::Normal,
+                &["diy", "bike"],
+                None,
+            ),
+            (
+                "Under the sink",
+                "Under the sink",
+                None,
+                Priority::Normal,
+                &[],
+                None,
+            ),
+            (
+                "Sort photos under Home under Attic",
+                "Sort photos under Home",
+                None,
+                Priority::Normal,
+                &[],
+                Some("Attic"),
+            ),
+        ];
+        for (input, name, date, priority, tags, parent) in cases {
+            assert_eq!(
+                parse_quick_add(input, today),
+                QuickAdd {
+                    name: name.to_owned(),
+                    date: date.and_then(parse_iso_date),
+                    priority,
+                    tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                    parent: parent.map(str::to_owned),
+                },
+                "{}",
+                input
+            );
+        }
+    }
+}